@@ -0,0 +1,157 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The bridge pairing an MQTT source and reaction.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use drasi_mqtt_connection::MqttConnectionManager;
+use drasi_reaction_mqtt::client::ClientWrapper;
+use drasi_reaction_mqtt::config::{MqttProtocol, PRIMARY_BROKER};
+use drasi_reaction_mqtt::MqttReaction;
+use drasi_source_mqtt::MqttSource;
+
+use crate::config::MqttBridgeConfig;
+use crate::echo::{EchoClient, EchoGuard};
+
+/// An MQTT source and reaction over one shared connection, where the source
+/// skips the echoes of the reaction's own publishes.
+///
+/// Add both parts to DrasiLib with [`into_parts`](Self::into_parts). The
+/// reaction's publish hook is used by the bridge, so `audit_log_path` and
+/// `MqttReaction::with_on_publish` are not available.
+pub struct MqttBridge {
+    connection: Arc<MqttConnectionManager>,
+    source: MqttSource,
+    reaction: MqttReaction,
+}
+
+impl MqttBridge {
+    /// Create the bridge's source and reaction. Nothing connects until one
+    /// of them is started.
+    pub fn new(config: MqttBridgeConfig) -> Result<Self> {
+        if !matches!(config.reaction.protocol, MqttProtocol::V311) {
+            anyhow::bail!("[{}] The bridge reaction must use protocol v311", config.id);
+        }
+
+        let connection = MqttConnectionManager::new(config.connection().build_mqtt_options()?);
+
+        let echoes = Arc::new(EchoGuard::new(config.echo_window));
+        let received = echoes.clone();
+        let source = MqttSource::with_connection(config.source, connection.clone())?
+            .with_message_filter(Arc::new(move |topic, payload| {
+                !received.take(topic, payload)
+            }));
+        let reaction = MqttReaction::with_connection(config.reaction, connection.clone())
+            .with_client_wrapper(echo_recorder(echoes));
+
+        Ok(Self {
+            connection,
+            source,
+            reaction,
+        })
+    }
+
+    /// The connection shared by the source and the reaction.
+    pub fn connection(&self) -> &Arc<MqttConnectionManager> {
+        &self.connection
+    }
+
+    /// The source and the reaction, to add to DrasiLib.
+    pub fn into_parts(self) -> (MqttSource, MqttReaction) {
+        (self.source, self.reaction)
+    }
+}
+
+/// Records the messages the reaction publishes over the shared connection,
+/// before they are handed to it.
+fn echo_recorder(echoes: Arc<EchoGuard>) -> ClientWrapper {
+    Arc::new(move |broker, client| {
+        if broker == PRIMARY_BROKER {
+            Arc::new(EchoClient::new(client, echoes.clone()))
+        } else {
+            client
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use drasi_lib::{Reaction, Source};
+    use drasi_mqtt_connection::fake_broker::{FakeBroker, PUBLISH};
+    use drasi_reaction_mqtt::MqttReactionConfig;
+    use drasi_source_mqtt::MqttSourceConfig;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_ingests_state_but_not_own_publish() {
+        let broker = FakeBroker::bind().await;
+
+        let source = MqttSourceConfig::builder("state", "unused", "things/#")
+            .id_field("id")
+            .debug_ring(8)
+            .build();
+        // Snapshots land under the source's "things/#" filter.
+        let reaction = MqttReactionConfig::builder(
+            "set",
+            "unused",
+            "things/{{id}}/set",
+            vec!["desired".to_string()],
+        )
+        .snapshot("things/{{query_id}}/snapshot", None)
+        .build();
+        let config = MqttBridgeConfig::builder("things", "127.0.0.1", source, reaction)
+            .port(broker.port())
+            .build();
+        let (source, reaction) = MqttBridge::new(config).unwrap().into_parts();
+        source.start().await.unwrap();
+        reaction.start().await.unwrap();
+
+        let mut client = broker.accept().await;
+        client.handshake().await;
+
+        // The reaction publishes through its fan-out, whose client records
+        // the message for the source before sending it.
+        reaction.publish_snapshot("desired").await.unwrap();
+        let published = client.expect(PUBLISH).await;
+        assert!(published.contains(b"things/desired/snapshot"));
+
+        // The broker echoes it to the "things/#" subscription, then the device
+        // reports its new state.
+        client.write(&published.encode()).await;
+        client
+            .publish("things/lamp/state", r#"{"id":"lamp","on":true}"#)
+            .await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while source.recent_messages().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        // Events arrive in order, so the echo has already been skipped.
+        let topics: Vec<String> = source
+            .recent_messages()
+            .into_iter()
+            .map(|m| m.topic)
+            .collect();
+        assert_eq!(topics, vec!["things/lamp/state"]);
+
+        reaction.stop().await.unwrap();
+        source.stop().await.unwrap();
+    }
+}
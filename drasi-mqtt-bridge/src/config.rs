@@ -0,0 +1,108 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration for the MQTT bridge.
+
+use drasi_mqtt_connection::ConnectionConfig;
+use drasi_reaction_mqtt::MqttReactionConfig;
+use drasi_source_mqtt::MqttSourceConfig;
+use serde::Deserialize;
+
+/// Configuration for the MQTT bridge.
+///
+/// The `connection` here is used for the shared connection; those of the
+/// `source` and `reaction` sections are not.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttBridgeConfig {
+    /// Bridge identifier, used in logs.
+    pub id: String,
+    /// How to connect to the broker. The client id defaults to
+    /// `"drasi-bridge-{id}"`.
+    #[serde(flatten)]
+    pub connection: ConnectionConfig,
+    /// Published messages remembered while waiting for their echo
+    /// (default: 1000). When full, the oldest is forgotten.
+    #[serde(default = "default_echo_window")]
+    pub echo_window: usize,
+    /// The ingesting side.
+    pub source: MqttSourceConfig,
+    /// The publishing side. Must use MQTT 3.1.1.
+    pub reaction: MqttReactionConfig,
+}
+
+fn default_echo_window() -> usize {
+    1000
+}
+
+impl MqttBridgeConfig {
+    /// Start building a new config with the required fields.
+    pub fn builder(
+        id: impl Into<String>,
+        broker_host: impl Into<String>,
+        source: MqttSourceConfig,
+        reaction: MqttReactionConfig,
+    ) -> MqttBridgeConfigBuilder {
+        MqttBridgeConfigBuilder {
+            config: MqttBridgeConfig {
+                id: id.into(),
+                connection: ConnectionConfig::new("", broker_host, 1883),
+                echo_window: default_echo_window(),
+                source,
+                reaction,
+            },
+        }
+    }
+
+    /// Connection settings of the shared connection.
+    pub fn connection(&self) -> ConnectionConfig {
+        let mut connection = self.connection.clone();
+        if connection.client_id.is_empty() {
+            connection.client_id = format!("drasi-bridge-{}", self.id);
+        }
+        connection
+    }
+}
+
+/// Builder for [`MqttBridgeConfig`].
+pub struct MqttBridgeConfigBuilder {
+    config: MqttBridgeConfig,
+}
+
+impl MqttBridgeConfigBuilder {
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.connection.port = port;
+        self
+    }
+
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.config.connection.client_id = client_id.into();
+        self
+    }
+
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.config.connection = self.config.connection.credentials(username, password);
+        self
+    }
+
+    /// Remember up to `size` published messages while waiting for their echo.
+    pub fn echo_window(mut self, size: usize) -> Self {
+        self.config.echo_window = size;
+        self
+    }
+
+    /// Build the config.
+    pub fn build(self) -> MqttBridgeConfig {
+        self.config
+    }
+}
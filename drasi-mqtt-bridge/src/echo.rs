@@ -0,0 +1,214 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recognition of the bridge's own publishes coming back from the broker.
+//!
+//! Messages are matched by topic and payload alone: the bridge's shared
+//! connection uses MQTT 3.1.1, which has no user properties to tag them
+//! with. A device publishing the same payload on the same topic while one
+//! of the bridge's messages awaits its echo is therefore taken for the echo
+//! and not ingested; the echo that follows is ingested in its place.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use drasi_reaction_mqtt::client::PublishClient;
+use rumqttc::QoS;
+
+/// Messages published by the bridge whose echo has not been received yet,
+/// oldest first.
+pub struct EchoGuard {
+    capacity: usize,
+    pending: Mutex<VecDeque<(String, Vec<u8>)>>,
+}
+
+impl EchoGuard {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Remember a published message, forgetting the oldest when full.
+    pub fn record(&self, topic: &str, payload: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() == self.capacity {
+            pending.pop_front();
+        }
+        pending.push_back((topic.to_string(), payload.to_vec()));
+    }
+
+    /// Whether a received message is the echo of a published one. Each
+    /// published message is matched by one received message at most.
+    pub fn take(&self, topic: &str, payload: &[u8]) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.iter().position(|(t, p)| t == topic && p == payload) {
+            Some(index) => {
+                pending.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget the latest record of a message that was not published after
+    /// all, so no echo is expected for it.
+    pub fn forget(&self, topic: &str, payload: &[u8]) {
+        let mut pending = self.pending.lock().unwrap();
+        let latest = pending
+            .iter()
+            .rposition(|(t, p)| t == topic && p == payload);
+        if let Some(index) = latest {
+            pending.remove(index);
+        }
+    }
+}
+
+/// Records each message before handing it to the inner client, so that its
+/// echo cannot arrive before the record. The record is forgotten if the
+/// client fails, or is cancelled, before accepting the message.
+pub struct EchoClient {
+    inner: Arc<dyn PublishClient>,
+    echoes: Arc<EchoGuard>,
+}
+
+impl EchoClient {
+    pub fn new(inner: Arc<dyn PublishClient>, echoes: Arc<EchoGuard>) -> Self {
+        Self { inner, echoes }
+    }
+}
+
+/// A record that is forgotten on drop unless the publish succeeded.
+struct Pending<'a> {
+    echoes: &'a EchoGuard,
+    topic: String,
+    payload: Vec<u8>,
+    published: bool,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if !self.published {
+            self.echoes.forget(&self.topic, &self.payload);
+        }
+    }
+}
+
+#[async_trait]
+impl PublishClient for EchoClient {
+    async fn publish(&self, topic: String, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<()> {
+        self.publish_with_user_properties(topic, qos, retain, payload, Vec::new())
+            .await
+    }
+
+    async fn publish_with_user_properties(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+    ) -> Result<()> {
+        self.echoes.record(&topic, &payload);
+        let mut pending = Pending {
+            echoes: &self.echoes,
+            topic: topic.clone(),
+            payload: payload.clone(),
+            published: false,
+        };
+        self.inner
+            .publish_with_user_properties(topic, qos, retain, payload, user_properties)
+            .await?;
+        pending.published = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_each_publish_matches_one_echo() {
+        let guard = EchoGuard::new(2);
+        guard.record("things/lamp/set", b"on");
+        guard.record("things/lamp/set", b"on");
+
+        assert!(!guard.take("things/lamp/set", b"off"));
+        assert!(!guard.take("things/lamp/state", b"on"));
+        assert!(guard.take("things/lamp/set", b"on"));
+        assert!(guard.take("things/lamp/set", b"on"));
+        // A device sending the same payload is not an echo.
+        assert!(!guard.take("things/lamp/set", b"on"));
+    }
+
+    #[test]
+    fn test_forgets_oldest_when_full() {
+        let guard = EchoGuard::new(1);
+        guard.record("things/a/set", b"1");
+        guard.record("things/b/set", b"1");
+
+        assert!(!guard.take("things/a/set", b"1"));
+        assert!(guard.take("things/b/set", b"1"));
+    }
+
+    /// Accepts every message, or fails every one.
+    struct FakeClient {
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl PublishClient for FakeClient {
+        async fn publish(&self, _: String, _: QoS, _: bool, _: Vec<u8>) -> Result<()> {
+            if self.fail {
+                anyhow::bail!("connection closed");
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_records_before_publishing_and_forgets_failures() {
+        let echoes = Arc::new(EchoGuard::new(4));
+        let sent = EchoClient::new(Arc::new(FakeClient { fail: false }), echoes.clone());
+        let failed = EchoClient::new(Arc::new(FakeClient { fail: true }), echoes.clone());
+
+        sent.publish(
+            "things/a/set".into(),
+            QoS::AtLeastOnce,
+            false,
+            b"1".to_vec(),
+        )
+        .await
+        .unwrap();
+        failed
+            .publish(
+                "things/b/set".into(),
+                QoS::AtLeastOnce,
+                false,
+                b"1".to_vec(),
+            )
+            .await
+            .unwrap_err();
+
+        assert!(echoes.take("things/a/set", b"1"));
+        assert!(!echoes.take("things/b/set", b"1"));
+    }
+}
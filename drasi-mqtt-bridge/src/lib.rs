@@ -0,0 +1,48 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bidirectional MQTT bridge for drasi-lib.
+//!
+//! An [`MqttBridge`] pairs an MQTT source ingesting device state with an MQTT
+//! reaction publishing commands, over one shared broker connection. When the
+//! source's topic filters also match the reaction's topics, the broker sends
+//! every command back to the bridge; the bridge recognizes these echoes and
+//! does not ingest them, so a command cannot trigger itself again.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use drasi_mqtt_bridge::{MqttBridge, MqttBridgeConfig};
+//!
+//! let source = MqttSourceConfig::builder("things-state", "broker.local", "things/#")
+//!     .id_field("id")
+//!     .build();
+//! let reaction = MqttReactionConfig::builder(
+//!     "things-set",
+//!     "broker.local",
+//!     "things/{{id}}/set",
+//!     vec!["desired-state".to_string()],
+//! )
+//! .build();
+//! let config = MqttBridgeConfig::builder("things", "broker.local", source, reaction).build();
+//!
+//! let (source, reaction) = MqttBridge::new(config)?.into_parts();
+//! ```
+
+pub mod bridge;
+pub mod config;
+pub mod echo;
+
+pub use bridge::MqttBridge;
+pub use config::{MqttBridgeConfig, MqttBridgeConfigBuilder};
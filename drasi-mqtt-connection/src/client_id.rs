@@ -0,0 +1,161 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Guarding against two clients sharing one client id.
+//!
+//! A broker allows one session per client id: when a second client connects
+//! with the same id, it disconnects the first, which reconnects and
+//! disconnects the second in turn. Two instances deployed with the same
+//! configuration ping-pong like this indefinitely, losing messages on every
+//! takeover. A [`ClientIdSuffix`] makes their ids differ, and a
+//! [`TakeoverDetector`] spots the pattern so it can be reported.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::time::Instant;
+
+/// Appended to configured client ids, so instances sharing a configuration
+/// connect with distinct ids.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIdSuffix {
+    /// A random suffix, new on every start. Sessions are not resumed
+    /// across restarts.
+    RandomPerStart,
+    /// The name of the local host, stable across restarts.
+    Hostname,
+}
+
+impl ClientIdSuffix {
+    /// Generate the suffix, including its leading `-`.
+    pub fn generate(&self) -> String {
+        match self {
+            ClientIdSuffix::RandomPerStart => {
+                let random = uuid::Uuid::new_v4().simple().to_string();
+                format!("-{}", &random[..8])
+            }
+            ClientIdSuffix::Hostname => {
+                format!("-{}", gethostname::gethostname().to_string_lossy())
+            }
+        }
+    }
+}
+
+/// A connection that drops within this long of its ConnAck counts as taken
+/// over.
+pub const TAKEOVER_LIFETIME: Duration = Duration::from_secs(10);
+/// Takeovers within this window that indicate a client id clash.
+pub const TAKEOVER_WINDOW: Duration = Duration::from_secs(120);
+/// Takeovers within [`TAKEOVER_WINDOW`] reported as a likely clash.
+pub const TAKEOVER_THRESHOLD: usize = 3;
+
+/// Detects connections repeatedly dropping shortly after connecting, the
+/// pattern of another client using the same client id.
+#[derive(Debug, Default)]
+pub struct TakeoverDetector {
+    connected_at: Option<Instant>,
+    takeovers: VecDeque<Instant>,
+}
+
+impl TakeoverDetector {
+    /// Record a ConnAck at `now`.
+    pub fn on_connack(&mut self, now: Instant) {
+        self.connected_at = Some(now);
+    }
+
+    /// Record a connection error at `now`.
+    ///
+    /// Returns the number of short-lived connections within
+    /// [`TAKEOVER_WINDOW`] once it reaches [`TAKEOVER_THRESHOLD`], then
+    /// starts counting afresh, so a persisting clash is reported once per
+    /// threshold.
+    pub fn on_disconnect(&mut self, now: Instant) -> Option<usize> {
+        let connected_at = self.connected_at.take()?;
+        if now.saturating_duration_since(connected_at) > TAKEOVER_LIFETIME {
+            return None;
+        }
+        while self
+            .takeovers
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) > TAKEOVER_WINDOW)
+        {
+            self.takeovers.pop_front();
+        }
+        self.takeovers.push_back(now);
+        if self.takeovers.len() < TAKEOVER_THRESHOLD {
+            return None;
+        }
+        let count = self.takeovers.len();
+        self.takeovers.clear();
+        Some(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suffixes() {
+        let random = ClientIdSuffix::RandomPerStart.generate();
+        assert_eq!(random.len(), 9);
+        assert!(random.starts_with('-'));
+        assert_ne!(random, ClientIdSuffix::RandomPerStart.generate());
+
+        let hostname = ClientIdSuffix::Hostname.generate();
+        assert_eq!(hostname, ClientIdSuffix::Hostname.generate());
+        assert_eq!(
+            hostname,
+            format!("-{}", gethostname::gethostname().to_string_lossy())
+        );
+    }
+
+    #[test]
+    fn test_repeated_short_connections_detected() {
+        let mut detector = TakeoverDetector::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // Errors without a preceding ConnAck (failed connects) don't count.
+        assert_eq!(detector.on_disconnect(at(0)), None);
+
+        for (connack, drop) in [(1, 3), (5, 7)] {
+            detector.on_connack(at(connack));
+            assert_eq!(detector.on_disconnect(at(drop)), None);
+        }
+        // A long-lived connection is a normal disconnect.
+        detector.on_connack(at(10));
+        assert_eq!(detector.on_disconnect(at(60)), None);
+
+        detector.on_connack(at(61));
+        assert_eq!(detector.on_disconnect(at(62)), Some(3));
+        // Reported once; counting starts again.
+        detector.on_connack(at(63));
+        assert_eq!(detector.on_disconnect(at(64)), None);
+    }
+
+    #[test]
+    fn test_takeovers_outside_window_forgotten() {
+        let mut detector = TakeoverDetector::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        for (connack, drop) in [(0, 1), (2, 3), (300, 301), (302, 303)] {
+            detector.on_connack(at(connack));
+            assert_eq!(detector.on_disconnect(at(drop)), None);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Time source of the MQTT plugins.
+//!
+//! The plugins read the time through a [`Clock`] instead of calling
+//! `SystemTime::now` or `Instant::now` directly, so tests can inject a
+//! [`ManualClock`] and move time deterministically.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::time::Instant;
+
+/// A source of wall-clock and monotonic time.
+pub trait Clock: Send + Sync {
+    /// Wall-clock time in nanoseconds since the Unix epoch.
+    fn now_nanos(&self) -> u64;
+
+    /// Monotonic time, for measuring durations.
+    fn now_instant(&self) -> Instant;
+
+    /// Wall-clock time as a [`SystemTime`].
+    fn now_system(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.now_nanos())
+    }
+}
+
+/// A clock shared by a plugin and its tasks.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The system clock. Its instants follow tokio's clock, so paused tokio
+/// tests see them advance with `tokio::time::advance`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_nanos(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+    }
+
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// The system clock, shared.
+pub fn system_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// A clock that only moves when [`advance`](Self::advance)d.
+#[derive(Debug)]
+pub struct ManualClock {
+    nanos: AtomicU64,
+    instant: Mutex<Instant>,
+}
+
+impl ManualClock {
+    /// A clock at `nanos` past the Unix epoch.
+    pub fn new(nanos: u64) -> Self {
+        Self {
+            nanos: AtomicU64::new(nanos),
+            instant: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Move both the wall clock and the monotonic clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        let mut instant = self.instant.lock().unwrap();
+        *instant += by;
+        self.nanos.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_nanos(&self) -> u64 {
+        self.nanos.load(Ordering::SeqCst)
+    }
+
+    fn now_instant(&self) -> Instant {
+        *self.instant.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock_moves_only_when_advanced() {
+        let clock = ManualClock::new(1_700_000_000_000_000_000);
+        let start = clock.now_instant();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(clock.now_instant(), start);
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now_instant() - start, Duration::from_secs(2));
+        assert_eq!(clock.now_nanos(), 1_700_000_002_000_000_000);
+        assert_eq!(
+            clock.now_system(),
+            UNIX_EPOCH + Duration::from_secs(1_700_000_002)
+        );
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A scripted MQTT 3.1.1 broker for tests. It listens on a local port and
+//! reads and writes whole packets, leaving the conversation to the test.
+
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub const CONNECT: u8 = 1;
+pub const CONNACK: u8 = 2;
+pub const PUBLISH: u8 = 3;
+pub const PUBACK: u8 = 4;
+pub const SUBSCRIBE: u8 = 8;
+pub const SUBACK: u8 = 9;
+pub const UNSUBSCRIBE: u8 = 10;
+pub const DISCONNECT: u8 = 14;
+
+/// How long to wait for a connection or a packet before failing the test.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A listener on a local port, accepting clients one at a time.
+pub struct FakeBroker {
+    listener: TcpListener,
+}
+
+impl FakeBroker {
+    pub async fn bind() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Self { listener }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.listener.local_addr().unwrap().port()
+    }
+
+    /// The next client connection, failing the test if none arrives.
+    pub async fn accept(&self) -> FakeClient {
+        self.try_accept(TIMEOUT)
+            .await
+            .expect("no client connected to the fake broker")
+    }
+
+    /// The next client connection, or `None` if none arrives within
+    /// `timeout`.
+    pub async fn try_accept(&self, timeout: Duration) -> Option<FakeClient> {
+        let (socket, _) = tokio::time::timeout(timeout, self.listener.accept())
+            .await
+            .ok()?
+            .unwrap();
+        Some(FakeClient { socket })
+    }
+}
+
+/// A local port nothing listens on, so connections to it are refused.
+pub async fn closed_port() -> u16 {
+    FakeBroker::bind().await.port()
+}
+
+/// One control packet: its fixed header byte and everything after the
+/// remaining length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub header: u8,
+    pub body: Vec<u8>,
+}
+
+impl Packet {
+    /// The packet type, e.g. [`SUBSCRIBE`].
+    pub fn kind(&self) -> u8 {
+        self.header >> 4
+    }
+
+    /// The packet id leading the body of a SUBSCRIBE, UNSUBSCRIBE or
+    /// acknowledgement.
+    pub fn packet_id(&self) -> u16 {
+        u16::from_be_bytes([self.body[0], self.body[1]])
+    }
+
+    pub fn contains(&self, needle: &[u8]) -> bool {
+        self.body.windows(needle.len()).any(|w| w == needle)
+    }
+
+    /// The packet as sent on the wire.
+    pub fn encode(&self) -> Vec<u8> {
+        encode_packet(self.header, &self.body)
+    }
+
+    /// Read one packet from `reader`.
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+        let header = reader.read_u8().await?;
+        let mut len = 0usize;
+        for shift in (0..28).step_by(7) {
+            let byte = reader.read_u8().await?;
+            len |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).await?;
+                return Ok(Self { header, body });
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "remaining length longer than 4 bytes",
+        ))
+    }
+}
+
+/// The fixed header byte, the remaining length and `body`.
+pub fn encode_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// A QoS 0 PUBLISH of `payload` on `topic`.
+pub fn publish_packet(topic: &str, payload: impl AsRef<[u8]>) -> Vec<u8> {
+    let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+    body.extend_from_slice(topic.as_bytes());
+    body.extend_from_slice(payload.as_ref());
+    encode_packet(PUBLISH << 4, &body)
+}
+
+/// A QoS 1 PUBLISH of `payload` on `topic` with `packet_id`.
+pub fn publish_qos1_packet(topic: &str, packet_id: u16, payload: impl AsRef<[u8]>) -> Vec<u8> {
+    let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+    body.extend_from_slice(topic.as_bytes());
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    body.extend_from_slice(payload.as_ref());
+    encode_packet((PUBLISH << 4) | 0x02, &body)
+}
+
+/// The broker's end of one client connection.
+pub struct FakeClient {
+    socket: TcpStream,
+}
+
+impl FakeClient {
+    /// Read one packet, failing the test if none arrives or the client
+    /// closed the connection.
+    pub async fn read_packet(&mut self) -> Packet {
+        tokio::time::timeout(TIMEOUT, self.next_packet())
+            .await
+            .expect("no packet from the client")
+            .expect("the client closed the connection")
+    }
+
+    /// Read one packet, or `None` once the client closed the connection.
+    pub async fn next_packet(&mut self) -> Option<Packet> {
+        Packet::read_from(&mut self.socket).await.ok()
+    }
+
+    /// Read one packet, failing the test unless it is of type `kind`.
+    pub async fn expect(&mut self, kind: u8) -> Packet {
+        let packet = self.read_packet().await;
+        assert_eq!(packet.kind(), kind, "unexpected packet {packet:?}");
+        packet
+    }
+
+    pub async fn connack(&mut self, return_code: u8) {
+        self.write(&[CONNACK << 4, 0x02, 0x00, return_code]).await;
+    }
+
+    /// Read the CONNECT, accept it, and read the SUBSCRIBE that follows.
+    pub async fn handshake(&mut self) -> Packet {
+        self.expect(CONNECT).await;
+        self.connack(0).await;
+        self.expect(SUBSCRIBE).await
+    }
+
+    /// Acknowledge a single-filter SUBSCRIBE.
+    pub async fn suback(&mut self, packet_id: u16, return_code: u8) {
+        let [hi, lo] = packet_id.to_be_bytes();
+        self.write(&[SUBACK << 4, 0x03, hi, lo, return_code]).await;
+    }
+
+    /// Send a QoS 0 PUBLISH.
+    pub async fn publish(&mut self, topic: &str, payload: impl AsRef<[u8]>) {
+        self.write(&publish_packet(topic, payload)).await;
+    }
+
+    pub async fn write(&mut self, bytes: &[u8]) {
+        self.socket.write_all(bytes).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(body_len: usize) -> (Vec<u8>, Packet) {
+        let body: Vec<u8> = (0..body_len).map(|i| i as u8).collect();
+        let encoded = encode_packet(PUBLISH << 4, &body);
+        let decoded = Packet::read_from(&mut &encoded[..]).await.unwrap();
+        assert_eq!(decoded.body, body);
+        (encoded, decoded)
+    }
+
+    #[tokio::test]
+    async fn test_remaining_length_uses_as_many_bytes_as_needed() {
+        for (body_len, length_bytes) in [(0, 1), (127, 1), (128, 2), (16_383, 2), (16_384, 3)] {
+            let (encoded, decoded) = round_trip(body_len).await;
+            assert_eq!(encoded.len(), 1 + length_bytes + body_len, "{body_len}");
+            assert_eq!(decoded.encode(), encoded);
+        }
+        assert_eq!(&encode_packet(0x30, &[0; 321])[..3], &[0x30, 0xc1, 0x02]);
+    }
+
+    #[tokio::test]
+    async fn test_long_publish_keeps_topic_and_payload() {
+        let payload = "x".repeat(300);
+        let encoded = publish_qos1_packet("sensors/a", 7, &payload);
+        let packet = Packet::read_from(&mut &encoded[..]).await.unwrap();
+        assert_eq!(packet.header, 0x32);
+        assert_eq!(&packet.body[..11], b"\x00\x09sensors/a");
+        assert_eq!(&packet.body[11..13], &[0x00, 0x07]);
+        assert_eq!(&packet.body[13..], payload.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_overlong_remaining_length_rejected() {
+        let encoded = [0x30, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert!(Packet::read_from(&mut &encoded[..]).await.is_err());
+    }
+}
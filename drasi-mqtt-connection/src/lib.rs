@@ -0,0 +1,57 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! MQTT connection shared by the drasi-lib MQTT plugins.
+//!
+//! A source and a reaction talking to the same broker normally open one
+//! session each. An [`MqttConnectionManager`] owns a single client and
+//! eventloop instead, and hands out [`ConnectionHandle`]s to the plugins
+//! built with `with_connection`. Incoming publishes are routed to every
+//! handle; outgoing publishes from every handle share the session.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use drasi_mqtt_connection::MqttConnectionManager;
+//! use drasi_reaction_mqtt::MqttReaction;
+//! use drasi_source_mqtt::MqttSource;
+//! use rumqttc::MqttOptions;
+//!
+//! let manager = MqttConnectionManager::new(MqttOptions::new("gateway", "broker.local", 1883));
+//! let source = MqttSource::with_connection(source_config, manager.clone())?;
+//! let reaction = MqttReaction::with_connection(reaction_config, manager.clone());
+//! ```
+//!
+//! The crate also holds the [`LogLimiter`] both plugins use to keep errors
+//! repeated on every message from flooding the logs, the [`Clock`] they
+//! read the time from, and the [`ClientIdSuffix`] and [`TakeoverDetector`]
+//! guarding against two clients sharing a client id. Both plugins embed a
+//! [`ConnectionConfig`] per broker and build their rumqttc options from it,
+//! asking a [`CredentialsProvider`] for fresh credentials if one is set.
+
+pub mod client_id;
+pub mod clock;
+#[cfg(any(test, feature = "test-util"))]
+pub mod fake_broker;
+pub mod log_limit;
+pub mod manager;
+pub mod options;
+#[cfg(feature = "test-util")]
+pub mod trace_capture;
+
+pub use client_id::{ClientIdSuffix, TakeoverDetector};
+pub use clock::{system_clock, Clock, ManualClock, SharedClock, SystemClock};
+pub use log_limit::{LogLimiter, Suppressed};
+pub use manager::{ConnectionEvent, ConnectionEvents, ConnectionHandle, MqttConnectionManager};
+pub use options::{ConnectionConfig, CredentialsFn, CredentialsProvider, TlsConfig};
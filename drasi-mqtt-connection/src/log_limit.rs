@@ -0,0 +1,187 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rate limiting of repeated error logs.
+//!
+//! A device publishing garbage several times a second would otherwise log
+//! the same error on every message. A [`LogLimiter`] lets the first
+//! occurrence of each error class on each key (a topic, a query) be logged,
+//! then counts further occurrences until the window ends and reports them as
+//! one [`Suppressed`] summary. A key that stays quiet for a whole window is
+//! forgotten, so its next error is logged again.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Window after the first logged occurrence during which repeats are only
+/// counted.
+pub const DEFAULT_SUPPRESSION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Decides which occurrences of repeated errors are logged.
+#[derive(Debug)]
+pub struct LogLimiter {
+    window: Duration,
+    entries: HashMap<(String, &'static str), Entry>,
+    /// Earliest end of a window, when summaries may be due.
+    next_due: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    window_end: Instant,
+    suppressed: u64,
+}
+
+/// Occurrences of `class` on `key` not logged during the last `window`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suppressed {
+    pub key: String,
+    pub class: &'static str,
+    pub count: u64,
+    pub window: Duration,
+}
+
+impl fmt::Display for Suppressed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "suppressed {} {} error(s) on {} in the last {}s",
+            self.count,
+            self.class,
+            self.key,
+            self.window.as_secs()
+        )
+    }
+}
+
+impl Default for LogLimiter {
+    fn default() -> Self {
+        Self::new(DEFAULT_SUPPRESSION_WINDOW)
+    }
+}
+
+impl LogLimiter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: HashMap::new(),
+            next_due: None,
+        }
+    }
+
+    /// Record an occurrence of `class` on `key` at `now`. Returns whether
+    /// it should be logged; if not, it is counted for the next summary.
+    pub fn admit(&mut self, key: &str, class: &'static str, now: Instant) -> bool {
+        if let Some(entry) = self.entries.get_mut(&(key.to_string(), class)) {
+            // Counted until reported, even past the end of the window.
+            if now < entry.window_end || entry.suppressed > 0 {
+                entry.suppressed += 1;
+                return false;
+            }
+        }
+        let window_end = now + self.window;
+        self.entries.insert(
+            (key.to_string(), class),
+            Entry {
+                window_end,
+                suppressed: 0,
+            },
+        );
+        self.next_due = Some(self.next_due.map_or(window_end, |due| due.min(window_end)));
+        true
+    }
+
+    /// Summaries of the windows ended by `now` that suppressed anything.
+    /// Keys with suppressed occurrences start a new window; quiet ones are
+    /// forgotten.
+    pub fn summaries(&mut self, now: Instant) -> Vec<Suppressed> {
+        if self.next_due.is_none_or(|due| now < due) {
+            return Vec::new();
+        }
+        let mut summaries = Vec::new();
+        let window = self.window;
+        self.entries.retain(|(key, class), entry| {
+            if now < entry.window_end {
+                return true;
+            }
+            if entry.suppressed == 0 {
+                return false;
+            }
+            summaries.push(Suppressed {
+                key: key.clone(),
+                class,
+                count: entry.suppressed,
+                window,
+            });
+            entry.window_end = now + window;
+            entry.suppressed = 0;
+            true
+        });
+        self.next_due = self.entries.values().map(|entry| entry.window_end).min();
+        summaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_suppressed_and_summarized() {
+        let mut limiter = LogLimiter::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(limiter.admit("sensors/bad/temp", "parse", start));
+        for i in 1..=1243 {
+            let now = start + Duration::from_millis(i * 10);
+            assert!(!limiter.admit("sensors/bad/temp", "parse", now));
+        }
+        // Other keys and classes are limited separately.
+        assert!(limiter.admit("sensors/ok/temp", "parse", start));
+        assert!(limiter.admit("sensors/bad/temp", "encoding", start));
+
+        assert!(limiter
+            .summaries(start + Duration::from_secs(59))
+            .is_empty());
+        let summaries = limiter.summaries(start + Duration::from_secs(60));
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(
+            summaries[0].to_string(),
+            "suppressed 1243 parse error(s) on sensors/bad/temp in the last 60s"
+        );
+    }
+
+    #[test]
+    fn test_quiet_key_logged_again() {
+        let mut limiter = LogLimiter::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(limiter.admit("t", "parse", start));
+        assert!(!limiter.admit("t", "parse", start + Duration::from_secs(1)));
+
+        // The summary starts a new window in which repeats stay suppressed.
+        let after_first = start + Duration::from_secs(60);
+        assert_eq!(limiter.summaries(after_first).len(), 1);
+        assert!(!limiter.admit("t", "parse", after_first + Duration::from_secs(1)));
+        let after_second = after_first + Duration::from_secs(60);
+        assert_eq!(limiter.summaries(after_second)[0].count, 1);
+
+        // Nothing during a whole window: forgotten, so logged again.
+        let after_quiet = after_second + Duration::from_secs(60);
+        assert!(limiter.summaries(after_quiet).is_empty());
+        assert!(limiter.admit("t", "parse", after_quiet));
+    }
+}
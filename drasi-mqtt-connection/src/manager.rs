@@ -0,0 +1,504 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The shared connection and the handles given to its users.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use rumqttc::{
+    AsyncClient, ClientError, ConnectionError, Event, Incoming, MqttOptions, Outgoing, QoS,
+    SubscribeFilter,
+};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+/// An eventloop poll result, as seen by every user of the connection.
+pub type ConnectionEvent = Result<Event, Arc<ConnectionError>>;
+
+/// Events buffered per event stream before the connection waits for it.
+const EVENT_BUFFER_CAPACITY: usize = 1024;
+
+/// Capacity of the client's request channel.
+const REQUEST_CAPACITY: usize = 100;
+
+/// How long the last release waits for the disconnect to be sent.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Senders of every open [`ConnectionEvents`] stream.
+type Subscribers = Arc<std::sync::Mutex<Vec<mpsc::Sender<ConnectionEvent>>>>;
+
+/// Owns one MQTT client and eventloop shared by several plugins.
+///
+/// The connection is opened by the first [`acquire`](Self::acquire) and
+/// closed when the last [`ConnectionHandle`] is released; a later acquire
+/// opens a new one.
+pub struct MqttConnectionManager {
+    options: MqttOptions,
+    subscribers: Subscribers,
+    /// Set by the driver on a ConnAck, cleared on an error or disconnect.
+    connected: Arc<AtomicBool>,
+    /// Topic filters subscribed through handles, with the handles using them.
+    filters: std::sync::Mutex<HashMap<String, FilterUse>>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    users: usize,
+    client: Option<AsyncClient>,
+    driver: Option<JoinHandle<()>>,
+}
+
+/// A broker subscription and how many handles use it.
+struct FilterUse {
+    qos: QoS,
+    users: usize,
+}
+
+impl MqttConnectionManager {
+    /// A manager connecting with `options`. Nothing connects until the
+    /// first handle is acquired.
+    pub fn new(options: MqttOptions) -> Arc<Self> {
+        Arc::new(Self {
+            options,
+            subscribers: Subscribers::default(),
+            connected: Arc::new(AtomicBool::new(false)),
+            filters: std::sync::Mutex::new(HashMap::new()),
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Register a user of the connection, connecting if it is the first.
+    pub async fn acquire(self: &Arc<Self>) -> ConnectionHandle {
+        let mut state = self.state.lock().await;
+        let client = match &state.client {
+            Some(client) => client.clone(),
+            None => {
+                let (client, eventloop) = AsyncClient::new(self.options.clone(), REQUEST_CAPACITY);
+                state.driver = Some(self.spawn_driver(eventloop));
+                state.client = Some(client.clone());
+                client
+            }
+        };
+        state.users += 1;
+        ConnectionHandle {
+            manager: self.clone(),
+            client,
+            filters: std::sync::Mutex::new(HashSet::new()),
+            released: false,
+        }
+    }
+
+    /// Number of handles not yet released.
+    pub async fn users(&self) -> usize {
+        self.state.lock().await.users
+    }
+
+    /// Topic filters subscribed through at least one handle.
+    pub fn filters(&self) -> Vec<String> {
+        let mut filters: Vec<String> = self.filters.lock().unwrap().keys().cloned().collect();
+        filters.sort();
+        filters
+    }
+
+    /// Poll the eventloop and hand every result to each event stream until
+    /// disconnected. A stream whose buffer is full holds the eventloop back
+    /// rather than missing events.
+    fn spawn_driver(&self, mut eventloop: rumqttc::EventLoop) -> JoinHandle<()> {
+        let subscribers = self.subscribers.clone();
+        let connected = self.connected.clone();
+        connected.store(false, Ordering::SeqCst);
+        let client_id = self.options.client_id();
+        tokio::spawn(async move {
+            info!("[{client_id}] Shared MQTT connection started");
+            loop {
+                let event = eventloop.poll().await;
+                let disconnected = matches!(event, Ok(Event::Outgoing(Outgoing::Disconnect)));
+                let failed = event.is_err();
+                match &event {
+                    Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                        connected.store(true, Ordering::SeqCst);
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_) => {
+                        connected.store(false, Ordering::SeqCst);
+                    }
+                    Ok(_) => {}
+                }
+                if let Err(e) = &event {
+                    warn!("[{client_id}] Shared MQTT connection error (will reconnect): {e}");
+                }
+                let event = event.map_err(Arc::new);
+                let senders = subscribers.lock().unwrap().clone();
+                for tx in senders {
+                    // A closed stream is removed below.
+                    let _ = tx.send(event.clone()).await;
+                }
+                subscribers.lock().unwrap().retain(|tx| !tx.is_closed());
+                if disconnected {
+                    break;
+                }
+                if failed {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+            info!("[{client_id}] Shared MQTT connection closed");
+        })
+    }
+
+    /// Count a handle as a user of `filters`, returning those the broker
+    /// must be subscribed to: new ones, and those now wanted at a higher QoS.
+    fn use_filters(&self, filters: &[SubscribeFilter]) -> Vec<SubscribeFilter> {
+        let mut used = self.filters.lock().unwrap();
+        let mut subscribe = Vec::new();
+        for filter in filters {
+            let entry = used.entry(filter.path.clone()).or_insert(FilterUse {
+                qos: filter.qos,
+                users: 0,
+            });
+            if entry.users == 0 || qos_level(filter.qos) > qos_level(entry.qos) {
+                entry.qos = filter.qos;
+                subscribe.push(SubscribeFilter::new(filter.path.clone(), filter.qos));
+            }
+            entry.users += 1;
+        }
+        subscribe
+    }
+
+    /// Stop counting a handle as a user of `filters`, returning those no
+    /// handle uses anymore.
+    fn unuse_filters(&self, filters: impl IntoIterator<Item = String>) -> Vec<String> {
+        let mut used = self.filters.lock().unwrap();
+        let mut unsubscribe = Vec::new();
+        for filter in filters {
+            let Some(entry) = used.get_mut(&filter) else {
+                continue;
+            };
+            entry.users -= 1;
+            if entry.users == 0 {
+                used.remove(&filter);
+                unsubscribe.push(filter);
+            }
+        }
+        unsubscribe
+    }
+
+    async fn release(&self, filters: HashSet<String>) {
+        let unused = self.unuse_filters(filters);
+        let mut state = self.state.lock().await;
+        state.users = state.users.saturating_sub(1);
+        if state.users > 0 {
+            if let Some(client) = &state.client {
+                for filter in unused {
+                    let _ = client.unsubscribe(filter).await;
+                }
+            }
+            return;
+        }
+        self.filters.lock().unwrap().clear();
+        if let Some(client) = state.client.take() {
+            let _ = client.try_disconnect();
+        }
+        if let Some(mut driver) = state.driver.take() {
+            // The driver ends once the disconnect is sent; while the broker
+            // is unreachable it never is.
+            if tokio::time::timeout(DISCONNECT_TIMEOUT, &mut driver)
+                .await
+                .is_err()
+            {
+                driver.abort();
+            }
+        }
+    }
+}
+
+/// Rank of `qos`, for comparing subscriptions.
+fn qos_level(qos: QoS) -> u8 {
+    match qos {
+        QoS::AtMostOnce => 0,
+        QoS::AtLeastOnce => 1,
+        QoS::ExactlyOnce => 2,
+    }
+}
+
+/// One user's share of a managed connection.
+///
+/// Should be given back with [`release`](Self::release); the connection
+/// stays open while any handle is held. A handle dropped without a release
+/// is released in the background.
+pub struct ConnectionHandle {
+    manager: Arc<MqttConnectionManager>,
+    client: AsyncClient,
+    /// Topic filters this handle subscribed to.
+    filters: std::sync::Mutex<HashSet<String>>,
+    released: bool,
+}
+
+impl ConnectionHandle {
+    /// The shared client, for publishing.
+    ///
+    /// Subscribe through [`subscribe_many`](Self::subscribe_many) instead,
+    /// so other users' subscriptions are not removed.
+    pub fn client(&self) -> &AsyncClient {
+        &self.client
+    }
+
+    /// Receive the connection's events from now on.
+    ///
+    /// Once its buffer is full, the connection waits for the stream to be
+    /// read, so drop a stream that is no longer read.
+    pub fn events(&self) -> ConnectionEvents {
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER_CAPACITY);
+        self.manager.subscribers.lock().unwrap().push(tx);
+        ConnectionEvents { rx }
+    }
+
+    /// Subscribe to `filters` on behalf of this handle.
+    ///
+    /// Filters another handle already subscribed to are only sent to the
+    /// broker again if this handle wants them at a higher QoS.
+    pub async fn subscribe_many(&self, filters: Vec<SubscribeFilter>) -> Result<(), ClientError> {
+        let filters: Vec<SubscribeFilter> = {
+            let mut own = self.filters.lock().unwrap();
+            filters
+                .into_iter()
+                .filter(|filter| own.insert(filter.path.clone()))
+                .collect()
+        };
+        let subscribe = self.manager.use_filters(&filters);
+        if subscribe.is_empty() {
+            return Ok(());
+        }
+        let result = self.client.subscribe_many(subscribe).await;
+        if result.is_err() {
+            let paths: Vec<String> = filters.into_iter().map(|filter| filter.path).collect();
+            let mut own = self.filters.lock().unwrap();
+            for path in &paths {
+                own.remove(path);
+            }
+            self.manager.unuse_filters(paths);
+        }
+        result
+    }
+
+    /// Send `filters`, already subscribed to through this handle, to the
+    /// broker again after it lost the session. Never waits, so it can be
+    /// called while handling the connection's events.
+    pub fn resubscribe(&self, filters: Vec<SubscribeFilter>) -> Result<(), ClientError> {
+        self.client.try_subscribe_many(filters)
+    }
+
+    /// Whether the connection is up, as of the last event it delivered.
+    pub fn is_connected(&self) -> bool {
+        self.manager.connected.load(Ordering::SeqCst)
+    }
+
+    /// Stop using `filter`, unsubscribing from it once no handle uses it.
+    pub async fn unsubscribe(&self, filter: &str) -> Result<(), ClientError> {
+        if !self.filters.lock().unwrap().remove(filter) {
+            return Ok(());
+        }
+        for unused in self.manager.unuse_filters([filter.to_string()]) {
+            self.client.unsubscribe(unused).await?;
+        }
+        Ok(())
+    }
+
+    /// Give up this handle and its subscriptions, closing the connection if
+    /// it was the last one.
+    pub async fn release(mut self) {
+        self.released = true;
+        let filters = std::mem::take(&mut *self.filters.lock().unwrap());
+        self.manager.release(filters).await;
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        let manager = self.manager.clone();
+        let filters = std::mem::take(&mut *self.filters.lock().unwrap());
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move { manager.release(filters).await });
+            }
+            Err(_) => warn!(
+                "[{}] Shared MQTT connection handle dropped outside a runtime; it stays in use",
+                manager.options.client_id()
+            ),
+        }
+    }
+}
+
+/// Stream of a managed connection's events.
+///
+/// Every stream sees every event, including publishes on topics subscribed
+/// by other users, which it is up to the receiver to filter.
+pub struct ConnectionEvents {
+    rx: mpsc::Receiver<ConnectionEvent>,
+}
+
+impl ConnectionEvents {
+    /// The next event.
+    pub async fn next(&mut self) -> ConnectionEvent {
+        match self.rx.recv().await {
+            Some(event) => event,
+            // The manager keeps the sender for as long as the stream is open.
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_broker::{FakeBroker, CONNECT, DISCONNECT, PUBLISH, SUBSCRIBE, UNSUBSCRIBE};
+
+    #[tokio::test]
+    async fn test_one_connection_for_incoming_and_outgoing() {
+        let broker = FakeBroker::bind().await;
+        let manager =
+            MqttConnectionManager::new(MqttOptions::new("shared", "127.0.0.1", broker.port()));
+
+        let source = manager.acquire().await;
+        let reaction = manager.acquire().await;
+        let mut source_events = source.events();
+        assert_eq!(manager.users().await, 2);
+
+        let mut client = broker.accept().await;
+        client.expect(CONNECT).await;
+        client.connack(0).await;
+
+        // Incoming: a QoS 0 publish on "sensors/a" reaches the source.
+        client.publish("sensors/a", "42").await;
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(Event::Incoming(Incoming::Publish(p))) = source_events.next().await {
+                    return p;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received.topic, "sensors/a");
+        assert_eq!(&received.payload[..], b"42");
+
+        // Outgoing: the reaction publishes over the same socket.
+        reaction
+            .client()
+            .publish("alerts/a", QoS::AtMostOnce, false, "hot")
+            .await
+            .unwrap();
+        assert!(client.expect(PUBLISH).await.contains(b"alerts/a"));
+
+        // The connection outlives the first release and closes with the last.
+        reaction.release().await;
+        assert_eq!(manager.users().await, 1);
+        source.release().await;
+        client.expect(DISCONNECT).await;
+        assert_eq!(manager.users().await, 0);
+
+        let second = broker.try_accept(Duration::from_millis(200)).await;
+        assert!(second.is_none(), "expected a single broker connection");
+    }
+
+    #[tokio::test]
+    async fn test_slow_event_stream_misses_nothing() {
+        let broker = FakeBroker::bind().await;
+        let manager =
+            MqttConnectionManager::new(MqttOptions::new("shared", "127.0.0.1", broker.port()));
+        let handle = manager.acquire().await;
+        let mut events = handle.events();
+
+        let mut client = broker.accept().await;
+        client.expect(CONNECT).await;
+        client.connack(0).await;
+
+        // More publishes than the stream buffers, sent before it is read.
+        let sent = EVENT_BUFFER_CAPACITY + 100;
+        for i in 0..sent {
+            client.publish("sensors/a", i.to_string()).await;
+        }
+
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while received.len() < sent {
+                if let Ok(Event::Incoming(Incoming::Publish(p))) = events.next().await {
+                    received.push(String::from_utf8(p.payload.to_vec()).unwrap());
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let expected: Vec<String> = (0..sent).map(|i| i.to_string()).collect();
+        assert_eq!(received, expected);
+        handle.release().await;
+    }
+
+    #[tokio::test]
+    async fn test_filter_kept_while_another_handle_uses_it() {
+        let broker = FakeBroker::bind().await;
+        let manager =
+            MqttConnectionManager::new(MqttOptions::new("shared", "127.0.0.1", broker.port()));
+        let source = manager.acquire().await;
+        let bridge = manager.acquire().await;
+
+        let mut client = broker.accept().await;
+        client.expect(CONNECT).await;
+        client.connack(0).await;
+
+        let filter = || vec![SubscribeFilter::new("sensors/#".into(), QoS::AtLeastOnce)];
+        source.subscribe_many(filter()).await.unwrap();
+        assert!(client.expect(SUBSCRIBE).await.contains(b"sensors/#"));
+        bridge.subscribe_many(filter()).await.unwrap();
+        assert_eq!(manager.filters(), vec!["sensors/#"]);
+
+        // The bridge still uses the filter: no UNSUBSCRIBE, and the next
+        // packet is the bridge's publish.
+        source.release().await;
+        bridge
+            .client()
+            .publish("alerts/a", QoS::AtMostOnce, false, "hot")
+            .await
+            .unwrap();
+        assert_eq!(client.read_packet().await.kind(), PUBLISH);
+        assert_eq!(manager.filters(), vec!["sensors/#"]);
+
+        bridge.unsubscribe("sensors/#").await.unwrap();
+        assert!(client.expect(UNSUBSCRIBE).await.contains(b"sensors/#"));
+        assert!(manager.filters().is_empty());
+        bridge.release().await;
+    }
+
+    #[tokio::test]
+    async fn test_dropped_handle_is_released() {
+        let broker = FakeBroker::bind().await;
+        let manager =
+            MqttConnectionManager::new(MqttOptions::new("shared", "127.0.0.1", broker.port()));
+        let handle = manager.acquire().await;
+
+        let mut client = broker.accept().await;
+        client.expect(CONNECT).await;
+        client.connack(0).await;
+
+        drop(handle);
+        client.expect(DISCONNECT).await;
+        assert_eq!(manager.users().await, 0);
+    }
+}
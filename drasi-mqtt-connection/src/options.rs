@@ -0,0 +1,289 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Broker connection settings and the rumqttc options built from them.
+//!
+//! Both plugins embed a [`ConnectionConfig`] for each broker, so TLS,
+//! credentials and keep-alive behave the same for the source, the reaction
+//! and the bridge.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use rumqttc::{MqttOptions, Transport};
+use serde::Deserialize;
+
+/// TLS settings for a broker connection.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// Path to the PEM-encoded CA certificate used to verify the broker.
+    pub ca_cert: String,
+    /// Optional path to a PEM-encoded client certificate (mutual TLS).
+    #[serde(default)]
+    pub client_cert: Option<String>,
+    /// Optional path to the PEM-encoded client private key (mutual TLS).
+    #[serde(default)]
+    pub client_key: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn new(ca_cert: impl Into<String>) -> Self {
+        Self {
+            ca_cert: ca_cert.into(),
+            client_cert: None,
+            client_key: None,
+        }
+    }
+
+    /// Use a client certificate and key for mutual TLS.
+    pub fn client_auth(mut self, cert: impl Into<String>, key: impl Into<String>) -> Self {
+        self.client_cert = Some(cert.into());
+        self.client_key = Some(key.into());
+        self
+    }
+}
+
+/// Returns fresh `(username, password)` credentials, e.g. a short-lived token.
+pub type CredentialsFn = dyn Fn() -> (String, String) + Send + Sync;
+
+/// A [`CredentialsFn`] called before every connection attempt.
+#[derive(Clone)]
+pub struct CredentialsProvider(pub Arc<CredentialsFn>);
+
+impl CredentialsProvider {
+    pub fn credentials(&self) -> (String, String) {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for CredentialsProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CredentialsProvider(..)")
+    }
+}
+
+/// How to connect to one broker.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct ConnectionConfig {
+    /// MQTT broker hostname or IP, also accepted as `broker_host`.
+    #[serde(alias = "broker_host")]
+    pub host: String,
+    /// MQTT broker port (default: 1883).
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// MQTT client ID. When empty, the plugin using the connection picks
+    /// one from its id.
+    #[serde(default)]
+    pub client_id: String,
+    /// Optional MQTT username for authentication.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Optional MQTT password for authentication.
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Keep-alive interval in seconds (default: 30).
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    /// Optional TLS settings. Plain TCP is used when absent.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+impl ConnectionConfig {
+    pub fn new(client_id: impl Into<String>, host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            client_id: client_id.into(),
+            username: None,
+            password: None,
+            keep_alive_secs: default_keep_alive_secs(),
+            tls: None,
+        }
+    }
+
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self.password = Some(password.into());
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive_secs = keep_alive.as_secs();
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// MQTT 3.1.1 options, loading TLS material if configured.
+    pub fn build_mqtt_options(&self) -> Result<MqttOptions> {
+        self.check_client_id()?;
+        let mut mqtt_opts = MqttOptions::new(&self.client_id, &self.host, self.port);
+        mqtt_opts.set_keep_alive(Duration::from_secs(self.keep_alive_secs));
+
+        if let (Some(user), Some(pass)) = (&self.username, &self.password) {
+            mqtt_opts.set_credentials(user, pass);
+        }
+        if let Some(transport) = self.tls_transport()? {
+            mqtt_opts.set_transport(transport);
+        }
+
+        Ok(mqtt_opts)
+    }
+
+    /// MQTT 5 options, loading TLS material if configured.
+    pub fn build_mqtt5_options(&self) -> Result<rumqttc::v5::MqttOptions> {
+        self.check_client_id()?;
+        let mut mqtt_opts = rumqttc::v5::MqttOptions::new(&self.client_id, &self.host, self.port);
+        mqtt_opts.set_keep_alive(Duration::from_secs(self.keep_alive_secs));
+
+        if let (Some(user), Some(pass)) = (&self.username, &self.password) {
+            mqtt_opts.set_credentials(user, pass);
+        }
+        if let Some(transport) = self.tls_transport()? {
+            mqtt_opts.set_transport(transport);
+        }
+
+        Ok(mqtt_opts)
+    }
+
+    /// rumqttc panics on an empty client id; fail with an error instead.
+    fn check_client_id(&self) -> Result<()> {
+        if self.client_id.is_empty() {
+            anyhow::bail!("Broker '{}:{}': client_id is empty", self.host, self.port);
+        }
+        Ok(())
+    }
+
+    /// TLS transport for the broker, or `None` if TLS is not configured.
+    #[cfg(feature = "tls")]
+    fn tls_transport(&self) -> Result<Option<Transport>> {
+        use anyhow::Context;
+
+        if let Some(tls) = &self.tls {
+            let ca = std::fs::read(&tls.ca_cert)
+                .with_context(|| format!("Failed to read CA certificate '{}'", tls.ca_cert))?;
+            let client_auth = match (&tls.client_cert, &tls.client_key) {
+                (Some(cert), Some(key)) => Some((
+                    std::fs::read(cert)
+                        .with_context(|| format!("Failed to read client certificate '{cert}'"))?,
+                    std::fs::read(key)
+                        .with_context(|| format!("Failed to read client key '{key}'"))?,
+                )),
+                (None, None) => None,
+                _ => anyhow::bail!(
+                    "Broker '{}:{}': client_cert and client_key must be set together",
+                    self.host,
+                    self.port
+                ),
+            };
+            return Ok(Some(Transport::tls(ca, client_auth, None)));
+        }
+
+        Ok(None)
+    }
+
+    /// Without the `tls` feature, configuring TLS is an error.
+    #[cfg(not(feature = "tls"))]
+    fn tls_transport(&self) -> Result<Option<Transport>> {
+        match &self.tls {
+            Some(_) => anyhow::bail!(
+                "Broker '{}:{}' is configured for TLS, but drasi-mqtt-connection was built without the `tls` feature",
+                self.host,
+                self.port
+            ),
+            None => Ok(None),
+        }
+    }
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_keep_alive_secs() -> u64 {
+    30
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_options() {
+        let config = ConnectionConfig::new("sensor-1", "broker.local", 1884)
+            .credentials("user", "secret")
+            .keep_alive(Duration::from_secs(10));
+
+        let opts = config.build_mqtt_options().unwrap();
+        assert_eq!(opts.client_id(), "sensor-1");
+        assert_eq!(opts.broker_address(), ("broker.local".to_string(), 1884));
+        assert_eq!(opts.keep_alive(), Duration::from_secs(10));
+        assert_eq!(
+            opts.credentials(),
+            Some(("user".to_string(), "secret".to_string()))
+        );
+
+        let opts = config.build_mqtt5_options().unwrap();
+        assert_eq!(opts.client_id(), "sensor-1");
+        assert_eq!(opts.keep_alive(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_deserialize_defaults() {
+        let config: ConnectionConfig =
+            serde_json::from_str(r#"{"host": "broker.local", "client_id": "gw"}"#).unwrap();
+        assert_eq!(config, ConnectionConfig::new("gw", "broker.local", 1883));
+
+        let config: ConnectionConfig =
+            serde_json::from_str(r#"{"broker_host": "broker.local"}"#).unwrap();
+        assert_eq!(config.host, "broker.local");
+        let err = config.build_mqtt_options().unwrap_err();
+        assert!(err.to_string().contains("client_id is empty"), "{err}");
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls_loads_certificates() {
+        let config = ConnectionConfig::new("gw", "localhost", 8883)
+            .tls(TlsConfig::new("/nonexistent/ca.pem"));
+
+        let err = config.build_mqtt_options().unwrap_err();
+        assert!(
+            err.to_string().contains("Failed to read CA certificate"),
+            "{err}"
+        );
+    }
+
+    #[cfg(not(feature = "tls"))]
+    #[test]
+    fn test_tls_rejected_without_feature() {
+        let config = ConnectionConfig::new("gw", "localhost", 8883)
+            .tls(TlsConfig::new("/nonexistent/ca.pem"));
+        for err in [
+            config.build_mqtt_options().unwrap_err(),
+            config.build_mqtt5_options().unwrap_err(),
+        ] {
+            assert!(
+                err.to_string().contains("without the `tls` feature"),
+                "{err}"
+            );
+        }
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Collects `tracing` events together with the fields of their spans, and
+//! closed spans with their final fields, so tests can check the context a
+//! log line carries.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::DefaultGuard;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// One captured event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedEvent {
+    pub level: Level,
+    /// The event's fields, including its `message`, over those of its
+    /// spans, outermost first.
+    pub fields: BTreeMap<String, String>,
+}
+
+impl CapturedEvent {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+}
+
+/// One closed span.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedSpan {
+    pub name: &'static str,
+    /// The span's fields, including those recorded after it was created.
+    pub fields: BTreeMap<String, String>,
+}
+
+impl CapturedSpan {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+}
+
+/// A [`Layer`] recording every event and closed span.
+#[derive(Clone, Default)]
+pub struct EventCapture {
+    events: Arc<Mutex<Vec<CapturedEvent>>>,
+    spans: Arc<Mutex<Vec<CapturedSpan>>>,
+}
+
+impl EventCapture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Capture the events of the current thread until the guard is dropped.
+    /// Tasks spawned on a current-thread runtime are included.
+    pub fn set_default(&self) -> DefaultGuard {
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(self.clone()))
+    }
+
+    pub fn events(&self) -> Vec<CapturedEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Closed spans, in the order they closed.
+    pub fn spans(&self) -> Vec<CapturedSpan> {
+        self.spans.lock().unwrap().clone()
+    }
+
+    /// Captured events at `level`.
+    pub fn at(&self, level: Level) -> Vec<CapturedEvent> {
+        self.events()
+            .into_iter()
+            .filter(|event| event.level == level)
+            .collect()
+    }
+}
+
+/// Field values of a span or event, as text.
+#[derive(Default)]
+struct Fields(BTreeMap<String, String>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}"));
+    }
+}
+
+impl<S> Layer<S> for EventCapture
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(span_fields) = span.extensions().get::<Fields>() {
+                    fields.0.extend(span_fields.0.clone());
+                }
+            }
+        }
+        event.record(&mut fields);
+        self.events.lock().unwrap().push(CapturedEvent {
+            level: *event.metadata().level(),
+            fields: fields.0,
+        });
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            let fields = span
+                .extensions()
+                .get::<Fields>()
+                .map(|fields| fields.0.clone())
+                .unwrap_or_default();
+            self.spans.lock().unwrap().push(CapturedSpan {
+                name: span.name(),
+                fields,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_carry_span_fields() {
+        let capture = EventCapture::new();
+        let _guard = capture.set_default();
+
+        let span = tracing::info_span!("outer", query_id = "q1", sequence = 7);
+        let _entered = span.enter();
+        tracing::error!(topic = "alerts/a", "Failed: {}", "broken");
+
+        let events = capture.at(Level::ERROR);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].field("message"), Some("Failed: broken"));
+        assert_eq!(events[0].field("query_id"), Some("q1"));
+        assert_eq!(events[0].field("sequence"), Some("7"));
+        assert_eq!(events[0].field("topic"), Some("alerts/a"));
+    }
+
+    #[test]
+    fn test_closed_spans_keep_recorded_fields() {
+        let capture = EventCapture::new();
+        let _guard = capture.set_default();
+
+        let span = tracing::info_span!("message", outcome = tracing::field::Empty);
+        span.record("outcome", "dispatched");
+        drop(span);
+
+        let spans = capture.spans();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "message");
+        assert_eq!(spans[0].field("outcome"), Some("dispatched"));
+    }
+}
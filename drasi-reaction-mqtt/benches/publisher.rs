@@ -0,0 +1,72 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of result rendering, without a broker.
+//!
+//! Run with `cargo bench -p drasi-reaction-mqtt`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde_json::Value;
+
+use drasi_reaction_mqtt::publisher::{template_registry, DiffBatch, Renderer};
+use drasi_reaction_mqtt::SerializeContext;
+
+/// Result rows as a continuous query would emit them.
+const RESULTS: &[u8] = include_bytes!("fixtures/results.json");
+
+fn batch(rows: usize) -> DiffBatch {
+    let results: Vec<Value> = serde_json::from_slice(RESULTS).unwrap();
+    DiffBatch {
+        added: results.into_iter().cycle().take(rows).collect(),
+        ..Default::default()
+    }
+}
+
+fn bench_result_to_payload(c: &mut Criterion) {
+    let registry = template_registry();
+    let ctx = SerializeContext::new("bench", 1);
+
+    let renderers = [
+        ("batch", Renderer::new(&registry, "plant/alerts", None)),
+        (
+            "split_dynamic_topic",
+            Renderer::new(&registry, "plant/{{site}}/{{device}}/alert", None),
+        ),
+        (
+            "split_payload_template",
+            Renderer::new(
+                &registry,
+                "plant/{{site}}/{{device}}/alert",
+                Some(
+                    r#"{"device":"{{device}}","temperature":{{temperature}},"status":"{{status}}"}"#,
+                ),
+            ),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("result_to_payload");
+    for rows in [1, 100] {
+        let batch = batch(rows);
+        group.throughput(Throughput::Elements(rows as u64));
+        for (name, renderer) in &renderers {
+            group.bench_with_input(BenchmarkId::new(*name, rows), &batch, |b, batch| {
+                b.iter(|| renderer.result_to_payload("q1", batch, &ctx).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_result_to_payload);
+criterion_main!(benches);
@@ -0,0 +1,197 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Audit trail of publish attempts.
+
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use tracing::{error, warn};
+
+use crate::serializer::Op;
+
+/// Callback invoked with the outcome of every publish attempt, per broker.
+///
+/// Called from the broker publishing tasks, never from the result processing
+/// loop; it should still return quickly.
+pub type PublishHook = Arc<dyn Fn(PublishRecord) + Send + Sync>;
+
+/// Messages buffered for the audit file writer before records are dropped.
+const FILE_HOOK_CAPACITY: usize = 1024;
+
+/// How a publish attempt ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublishOutcome {
+    /// The broker's client accepted the message.
+    Published,
+    /// The client rejected the message.
+    Failed(String),
+    /// The broker's buffer was full and the message was dropped.
+    Dropped,
+}
+
+/// The query result a message was produced from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishOrigin {
+    pub query_id: String,
+    /// Sequence number of the result within this reaction run.
+    pub sequence: u64,
+    /// The operation of the message's item; for a batch message, the
+    /// operation shared by all its items, if any.
+    pub op: Option<Op>,
+}
+
+/// One publish attempt to one broker.
+#[derive(Debug, Clone)]
+pub struct PublishRecord {
+    /// Broker name from the configuration.
+    pub broker: String,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    /// `None` for messages not produced from a query result (e.g. heartbeats).
+    pub origin: Option<PublishOrigin>,
+    pub outcome: PublishOutcome,
+}
+
+impl PublishRecord {
+    /// The record as a JSON object; the payload is decoded as (lossy) UTF-8.
+    pub fn to_json(&self) -> Value {
+        let (outcome, error) = match &self.outcome {
+            PublishOutcome::Published => ("published", None),
+            PublishOutcome::Failed(e) => ("failed", Some(e.as_str())),
+            PublishOutcome::Dropped => ("dropped", None),
+        };
+        serde_json::json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "broker": self.broker,
+            "topic": self.topic,
+            "payload": String::from_utf8_lossy(&self.payload),
+            "query_id": self.origin.as_ref().map(|o| o.query_id.as_str()),
+            "sequence": self.origin.as_ref().map(|o| o.sequence),
+            "op": self.origin.as_ref().and_then(|o| o.op).map(|op| op.as_str()),
+            "outcome": outcome,
+            "error": error,
+        })
+    }
+}
+
+/// A hook appending each record as a JSON line to the file at `path`.
+///
+/// Records are handed to a dedicated writer thread through a bounded buffer;
+/// when the buffer is full, records are dropped with a warning rather than
+/// blocking the publisher.
+pub fn jsonl_file_hook(path: &str) -> Result<PublishHook> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open audit log '{path}'"))?;
+
+    let (tx, rx) = mpsc::sync_channel::<PublishRecord>(FILE_HOOK_CAPACITY);
+    let writer_path = path.to_string();
+    std::thread::spawn(move || {
+        let mut out = BufWriter::new(file);
+        for record in rx {
+            let line = record.to_json().to_string();
+            if let Err(e) = writeln!(out, "{line}").and_then(|_| out.flush()) {
+                error!("Failed to write audit log '{writer_path}': {e}");
+            }
+        }
+    });
+
+    Ok(Arc::new(move |record: PublishRecord| {
+        if tx.try_send(record).is_err() {
+            warn!("Audit log buffer full or closed; dropping record");
+        }
+    }))
+}
+
+/// A hook collecting records in memory, for tests.
+#[cfg(test)]
+pub(crate) fn collecting_hook() -> (PublishHook, Arc<std::sync::Mutex<Vec<PublishRecord>>>) {
+    let records = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = records.clone();
+    let hook: PublishHook = Arc::new(move |record| sink.lock().unwrap().push(record));
+    (hook, records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn record(outcome: PublishOutcome) -> PublishRecord {
+        PublishRecord {
+            broker: "primary".to_string(),
+            topic: "alerts/d1".to_string(),
+            payload: br#"{"temp":41}"#.to_vec(),
+            origin: Some(PublishOrigin {
+                query_id: "q1".to_string(),
+                sequence: 3,
+                op: Some(Op::Insert),
+            }),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn test_jsonl_file_hook_appends_lines() {
+        let path = std::env::temp_dir().join(format!("mqtt-audit-{}.jsonl", unique_suffix()));
+        let path = path.to_str().unwrap().to_string();
+
+        let hook = jsonl_file_hook(&path).unwrap();
+        hook(record(PublishOutcome::Published));
+        hook(record(PublishOutcome::Failed(
+            "connection closed".to_string(),
+        )));
+
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            lines = std::fs::read_to_string(&path)
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str::<Value>(l).unwrap())
+                .collect::<Vec<_>>();
+            if lines.len() == 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["outcome"], "published");
+        assert_eq!(lines[0]["payload"], r#"{"temp":41}"#);
+        assert_eq!(lines[0]["query_id"], "q1");
+        assert_eq!(lines[0]["sequence"], 3);
+        assert_eq!(lines[0]["op"], "insert");
+        assert_eq!(lines[1]["outcome"], "failed");
+        assert_eq!(lines[1]["error"], "connection closed");
+    }
+
+    fn unique_suffix() -> String {
+        format!(
+            "{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        )
+    }
+}
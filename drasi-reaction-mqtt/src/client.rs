@@ -0,0 +1,237 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! MQTT client construction and the publish abstraction used by the reaction.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+
+use crate::config::BrokerEndpoint;
+
+/// The publishing half of an MQTT client.
+///
+/// Implemented for [`AsyncClient`] and [`BrokerClient`]; tests substitute
+/// fake clients.
+#[async_trait]
+pub trait PublishClient: Send + Sync {
+    async fn publish(&self, topic: String, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<()>;
+
+    /// Publish with MQTT 5 user properties. The default, for clients without
+    /// MQTT 5, publishes without them.
+    async fn publish_with_user_properties(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+    ) -> Result<()> {
+        let _ = user_properties;
+        self.publish(topic, qos, retain, payload).await
+    }
+
+    /// Publish a message the broker already accepted once, e.g. a retained
+    /// message it lost in a failover. The default publishes it like any
+    /// other; wrappers that skip repeats must pass it through.
+    async fn republish(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+    ) -> Result<()> {
+        self.publish_with_user_properties(topic, qos, retain, payload, user_properties)
+            .await
+    }
+}
+
+/// Wraps the client publishing to the named broker, e.g. to observe each
+/// message right before it is handed to the connection.
+pub type ClientWrapper =
+    Arc<dyn Fn(&str, Arc<dyn PublishClient>) -> Arc<dyn PublishClient> + Send + Sync>;
+
+#[async_trait]
+impl PublishClient for AsyncClient {
+    async fn publish(&self, topic: String, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<()> {
+        AsyncClient::publish(self, topic, qos, retain, payload).await?;
+        Ok(())
+    }
+}
+
+/// A client connected with either MQTT 3.1.1 or MQTT 5.
+#[derive(Clone)]
+pub enum BrokerClient {
+    V4(AsyncClient),
+    V5(rumqttc::v5::AsyncClient),
+    /// An MQTT 3.1.1 client on a connection owned by an
+    /// [`MqttConnectionManager`](drasi_mqtt_connection::MqttConnectionManager),
+    /// which is left connected on disconnect.
+    Shared(AsyncClient),
+}
+
+impl BrokerClient {
+    pub async fn disconnect(&self) -> Result<()> {
+        match self {
+            BrokerClient::V4(client) => client.disconnect().await?,
+            BrokerClient::V5(client) => client.disconnect().await?,
+            BrokerClient::Shared(_) => {}
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PublishClient for BrokerClient {
+    async fn publish(&self, topic: String, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<()> {
+        match self {
+            BrokerClient::V4(client) | BrokerClient::Shared(client) => {
+                PublishClient::publish(client, topic, qos, retain, payload).await
+            }
+            BrokerClient::V5(client) => {
+                client.publish(topic, v5_qos(qos), retain, payload).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn publish_with_user_properties(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+    ) -> Result<()> {
+        match self {
+            BrokerClient::V5(client) if !user_properties.is_empty() => {
+                let properties = rumqttc::v5::mqttbytes::v5::PublishProperties {
+                    user_properties,
+                    ..Default::default()
+                };
+                client
+                    .publish_with_properties(topic, v5_qos(qos), retain, payload, properties)
+                    .await?;
+                Ok(())
+            }
+            _ => self.publish(topic, qos, retain, payload).await,
+        }
+    }
+}
+
+/// The MQTT 5 equivalent of a QoS level.
+pub fn v5_qos(qos: QoS) -> rumqttc::v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => rumqttc::v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => rumqttc::v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+/// Build rumqttc options for a broker endpoint, loading TLS material if configured.
+pub fn mqtt_options(endpoint: &BrokerEndpoint) -> Result<MqttOptions> {
+    endpoint.connection().build_mqtt_options()
+}
+
+/// Build MQTT 5 options for a broker endpoint, loading TLS material if configured.
+pub fn mqtt5_options(endpoint: &BrokerEndpoint) -> Result<rumqttc::v5::MqttOptions> {
+    endpoint.connection().build_mqtt5_options()
+}
+
+/// Fake clients for unit tests.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A publish captured by [`RecordingClient`].
+    #[derive(Debug, Clone)]
+    pub(crate) struct Published {
+        pub topic: String,
+        pub qos: QoS,
+        pub retain: bool,
+        pub payload: Vec<u8>,
+    }
+
+    /// Records every publish it receives.
+    #[derive(Default)]
+    pub(crate) struct RecordingClient {
+        pub published: Mutex<Vec<Published>>,
+    }
+
+    impl RecordingClient {
+        pub fn topics(&self) -> Vec<String> {
+            self.published
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|p| p.topic.clone())
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl PublishClient for RecordingClient {
+        async fn publish(
+            &self,
+            topic: String,
+            qos: QoS,
+            retain: bool,
+            payload: Vec<u8>,
+        ) -> Result<()> {
+            self.published.lock().unwrap().push(Published {
+                topic,
+                qos,
+                retain,
+                payload,
+            });
+            Ok(())
+        }
+    }
+
+    /// Simulates an unreachable broker: publishes never complete.
+    pub(crate) struct StalledClient;
+
+    #[async_trait]
+    impl PublishClient for StalledClient {
+        async fn publish(
+            &self,
+            _topic: String,
+            _qos: QoS,
+            _retain: bool,
+            _payload: Vec<u8>,
+        ) -> Result<()> {
+            std::future::pending().await
+        }
+    }
+
+    /// Rejects every publish, as a client whose eventloop has stopped would.
+    pub(crate) struct FailingClient;
+
+    #[async_trait]
+    impl PublishClient for FailingClient {
+        async fn publish(
+            &self,
+            _topic: String,
+            _qos: QoS,
+            _retain: bool,
+            _payload: Vec<u8>,
+        ) -> Result<()> {
+            anyhow::bail!("eventloop stopped")
+        }
+    }
+}
@@ -14,29 +14,400 @@
 
 //! Configuration types for the MQTT reaction plugin.
 
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use drasi_mqtt_connection::{ClientIdSuffix, ConnectionConfig};
 use serde::Deserialize;
+use serde_json::Value;
+
+pub use drasi_mqtt_connection::{CredentialsFn, CredentialsProvider, TlsConfig};
+
+use crate::publisher::{self, DiffBatch};
+use crate::serializer::{Op, ResultSerializer, SerializeContext, TemplateSerializer};
+use crate::transform::ResultTransform;
+
+/// What to do with a `ResultDiff` variant the reaction does not publish.
+///
+/// Only `Add`, `Update` and `Delete` diffs are turned into MQTT messages;
+/// `Aggregation` and `Noop` diffs are currently not handled.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnhandledDiffPolicy {
+    /// Silently skip the diff (default).
+    #[default]
+    Ignore,
+    /// Skip the diff and log a warning.
+    Log,
+    /// Fail the whole result batch; nothing from it is published.
+    Error,
+}
+
+/// Which message a full broker buffer drops.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BufferDropPolicy {
+    /// Drop the message being published (default).
+    #[default]
+    DropNewest,
+    /// Drop the oldest buffered message to make room, for reactions where
+    /// only recent results matter.
+    DropOldest,
+}
+
+/// What the message published for a deleted result row contains.
+#[derive(Debug, Clone, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletePayloadMode {
+    /// The row's last-known body, rendered like inserts and updates (default).
+    #[default]
+    Full,
+    /// Only the named id field of the row, as `{"<field>": <value>}`. In
+    /// batch payloads, `removed` lists these objects.
+    IdOnly(String),
+    /// A Handlebars template used for delete messages instead of the payload
+    /// template. Batch payloads keep the full rows in `removed`.
+    Custom(String),
+}
+
+/// Metadata keys a split payload built without a payload template may carry.
+pub const SPLIT_METADATA_KEYS: [&str; 5] =
+    ["query_id", "sequence", "op", "_meta", "correlation_id"];
+
+/// Which metadata keys are added to split payloads built without a payload
+/// template, and under which names. Template contexts are not affected.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct MetadataConfig {
+    /// Keys to add, out of [`SPLIT_METADATA_KEYS`] (default: all). `_meta`
+    /// and `correlation_id` are only added when enabled.
+    #[serde(default = "default_split_metadata_keys")]
+    pub include: Vec<String>,
+    /// New names for included keys, e.g. `{"op": "event"}`.
+    #[serde(default)]
+    pub rename: HashMap<String, String>,
+}
+
+fn default_split_metadata_keys() -> Vec<String> {
+    SPLIT_METADATA_KEYS
+        .iter()
+        .map(|key| key.to_string())
+        .collect()
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        Self {
+            include: default_split_metadata_keys(),
+            rename: HashMap::new(),
+        }
+    }
+}
+
+impl MetadataConfig {
+    /// Fail on keys that are not metadata keys.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for key in self.include.iter().chain(self.rename.keys()) {
+            if !SPLIT_METADATA_KEYS.contains(&key.as_str()) {
+                anyhow::bail!(
+                    "Unknown split_metadata key '{key}'; expected one of {}",
+                    SPLIT_METADATA_KEYS.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The name `key` is published under, or `None` if it is left out.
+    pub fn name_for<'a>(&'a self, key: &'a str) -> Option<&'a str> {
+        self.include
+            .iter()
+            .any(|included| included == key)
+            .then(|| self.rename.get(key).map_or(key, String::as_str))
+    }
+}
+
+/// MQTT protocol version used to connect to brokers.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+pub enum MqttProtocol {
+    /// MQTT 3.1.1 (default).
+    #[default]
+    #[serde(rename = "v311")]
+    V311,
+    /// MQTT 5. Repeat topics are sent as topic aliases when the broker allows them.
+    #[serde(rename = "v5")]
+    V5,
+}
+
+/// What identifies a message for exactly-once deduplication.
+///
+/// Only an explicit idempotency field is supported: keying on message
+/// content would drop legitimate repeats, such as `on`, `off`, `on` sent to
+/// the same topic.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupKey {
+    /// The value of this top-level field of the JSON payload. Messages
+    /// without the field are never deduplicated.
+    Field(String),
+}
+
+/// An additional broker the reaction publishes every message to.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BrokerEndpoint {
+    /// Name used in logs and per-broker stats (e.g. `"cloud"`).
+    pub name: String,
+    /// How to connect. An empty client id defaults to
+    /// `"drasi-reaction-{id}-{name}"`.
+    #[serde(flatten)]
+    pub connection: ConnectionConfig,
+}
+
+impl BrokerEndpoint {
+    pub fn new(name: impl Into<String>, host: impl Into<String>, port: u16) -> Self {
+        Self {
+            name: name.into(),
+            connection: ConnectionConfig::new("", host, port),
+        }
+    }
+
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.connection.client_id = client_id.into();
+        self
+    }
+
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.connection = self.connection.credentials(username, password);
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.connection = self.connection.tls(tls);
+        self
+    }
+
+    /// Connection settings for this broker. An empty client id defaults to
+    /// `"drasi-reaction-{name}"`.
+    pub fn connection(&self) -> ConnectionConfig {
+        let mut connection = self.connection.clone();
+        if connection.client_id.is_empty() {
+            connection.client_id = format!("drasi-reaction-{}", self.name);
+        }
+        connection
+    }
+}
+
+fn default_broker_buffer_capacity() -> usize {
+    1000
+}
+
+fn default_retained_cache_capacity() -> usize {
+    1000
+}
+
+fn default_publish_retries() -> u32 {
+    3
+}
+
+fn default_dedup_capacity() -> usize {
+    10_000
+}
+
+fn default_auto_start() -> bool {
+    true
+}
+
+fn default_heartbeat_interval_ms() -> u64 {
+    30_000
+}
+
+fn default_snapshot_capacity() -> usize {
+    10_000
+}
+
+fn default_degraded_after_ms() -> u64 {
+    10_000
+}
+
+/// Name of the broker configured through the top-level `connection`.
+pub const PRIMARY_BROKER: &str = "primary";
 
 /// Configuration for the MQTT reaction.
 #[derive(Debug, Clone, Deserialize)]
 pub struct MqttReactionConfig {
     /// Unique reaction identifier.
     pub id: String,
-    /// MQTT broker hostname or IP.
-    pub broker_host: String,
-    /// MQTT broker port (default: 1883).
-    pub port: u16,
+    /// How to connect to the primary broker. The client id defaults to
+    /// `"drasi-reaction-{id}"`.
+    #[serde(flatten)]
+    pub connection: ConnectionConfig,
     /// MQTT topic template to publish results to (e.g. `devices/{{device_id}}/commands`).
     pub topic: String,
     /// Optional payload template (Handlebars). If not provided, default JSON serialization is used.
     pub payload_template: Option<String>,
-    /// MQTT client ID. Defaults to `"drasi-reaction-{id}"`.
-    pub client_id: String,
-    /// Optional MQTT username for authentication.
-    pub username: Option<String>,
-    /// Optional MQTT password for authentication.
-    pub password: Option<String>,
+    /// Prefix prepended to every rendered topic, e.g. `tenants/acme/`.
+    /// Exactly one `/` separates the prefix from the topic.
+    #[serde(default)]
+    pub topic_prefix: Option<String>,
+    /// Per-query topic templates, keyed by query ID, overriding `topic`.
+    #[serde(default)]
+    pub query_topics: HashMap<String, String>,
+    /// Per-query payload templates, keyed by query ID, overriding `payload_template`.
+    #[serde(default)]
+    pub query_payload_templates: HashMap<String, String>,
+    /// Handlebars partials by name, shared by all templates, e.g. a
+    /// `header` partial included with `{{> header}}`.
+    #[serde(default)]
+    pub partials: HashMap<String, String>,
+    /// Pretty-print JSON payloads built without a payload template (default: false).
+    #[serde(default)]
+    pub json_pretty: bool,
+    /// Sort object keys in JSON payloads built without a payload template (default: false).
+    #[serde(default)]
+    pub sort_keys: bool,
+    /// Add publish-time metadata: a `_meta` object in per-item template
+    /// contexts and JSON payloads, and `published_at` on batch payloads (default: false).
+    #[serde(default)]
+    pub include_meta: bool,
+    /// Add a generated `correlation_id` to every per-item template context
+    /// and JSON payload, e.g. to match device acks to commands (default: false).
+    #[serde(default)]
+    pub correlation_ids: bool,
+    /// What delete messages contain (default: `full`).
+    #[serde(default)]
+    pub delete_payload: DeletePayloadMode,
+    /// Metadata keys of split payloads built without a payload template
+    /// (default: all, under their own names).
+    #[serde(default)]
+    pub split_metadata: MetadataConfig,
+    /// JMESPath expression applied to each result item before it is
+    /// templated or serialized, e.g. `{device: s.device_id}` to unwrap a
+    /// returned node. Items it fails on are skipped.
+    #[serde(default)]
+    pub result_transform: Option<String>,
+    /// Result field the added, updated and removed items of each result are
+    /// sorted by before publishing, after `result_transform`. Items without
+    /// the field come last (default: arrival order).
+    #[serde(default)]
+    pub order_by: Option<String>,
+    /// MQTT 5 user properties added to every result message, as (name, value
+    /// template) pairs. Values are rendered with `query_id`, `sequence`, `op`
+    /// (that of the message's item; null for a batch mixing operations) and
+    /// `reaction_id`. Not sent with MQTT 3.1.1.
+    #[serde(default)]
+    pub user_properties: Vec<(String, String)>,
+    /// Appended to the client id of every broker connection, so instances
+    /// deployed with the same configuration don't take over each other's
+    /// session (default: none).
+    #[serde(default)]
+    pub client_id_suffix: Option<ClientIdSuffix>,
     /// List of query IDs this reaction subscribes to.
     pub queries: Vec<String>,
+    /// Policy for result diffs that are not published (default: `ignore`).
+    #[serde(default)]
+    pub on_unhandled_diff: UnhandledDiffPolicy,
+    /// Protocol version used for every broker (default: `v311`).
+    #[serde(default)]
+    pub protocol: MqttProtocol,
+    /// Publish result messages with the retain flag set (default: false).
+    #[serde(default)]
+    pub retain: bool,
+    /// In split mode, result field whose value (0, 1 or 2; clamped) sets
+    /// the QoS of the item's messages. Items without it use QoS 1.
+    #[serde(default)]
+    pub qos_field: Option<String>,
+    /// In split mode, boolean result field overriding `retain` for the
+    /// item's messages.
+    #[serde(default)]
+    pub retain_field: Option<String>,
+    /// Republish the last retained message of each topic when a broker
+    /// connection is re-established, restoring state lost by a broker
+    /// failover (default: false).
+    #[serde(default)]
+    pub republish_retained_on_reconnect: bool,
+    /// Topics whose last retained message is kept for republishing (default: 1000).
+    #[serde(default = "default_retained_cache_capacity")]
+    pub retained_cache_capacity: usize,
+    /// In split mode, publish an empty retained message on the topic of
+    /// each removed item, clearing its retained state on the broker
+    /// (default: false).
+    #[serde(default)]
+    pub clear_retained_on_remove: bool,
+    /// With `clear_retained_on_remove`, still publish the normal delete
+    /// message before the clearing one (default: false).
+    #[serde(default)]
+    pub keep_remove_payload: bool,
+    /// Further brokers that receive every published message.
+    #[serde(default)]
+    pub additional_brokers: Vec<BrokerEndpoint>,
+    /// Messages buffered per broker before new ones are dropped for that
+    /// broker (default: 1000). Bounds how far a slow or unreachable broker
+    /// can fall behind without blocking the others.
+    #[serde(default = "default_broker_buffer_capacity")]
+    pub broker_buffer_capacity: usize,
+    /// Which message a full broker buffer drops (default: `drop_newest`).
+    #[serde(default)]
+    pub buffer_drop_policy: BufferDropPolicy,
+    /// Buffered messages, counting the one being published, at which a
+    /// broker counts as backed up: a warning is logged and `status()`
+    /// reports `Error` until the buffer drains below it. Disabled when unset.
+    #[serde(default)]
+    pub buffer_high_water_mark: Option<usize>,
+    /// Longest a single publish may wait on a broker's client request queue
+    /// before it is abandoned and retried. Waits indefinitely when unset.
+    /// Per-broker wait times and timeouts are reported by `broker_stats()`.
+    #[serde(default)]
+    pub publish_timeout_ms: Option<u64>,
+    /// Retries of a publish that timed out before the message is given up
+    /// for that broker and reported as failed (default: 3).
+    #[serde(default = "default_publish_retries")]
+    pub publish_retries: u32,
+    /// Publish each message at most once per broker, by this key. A retry
+    /// after a publish timeout waits for the abandoned attempt instead of
+    /// sending the message again, and messages whose key a broker has
+    /// already accepted are skipped. Disabled when unset.
+    #[serde(default)]
+    pub dedup: Option<DedupKey>,
+    /// Accepted keys remembered per broker for `dedup`, oldest forgotten
+    /// first (default: 10000).
+    #[serde(default = "default_dedup_capacity")]
+    pub dedup_capacity: usize,
+    /// File to append a JSON line to for every publish attempt (audit trail).
+    #[serde(default)]
+    pub audit_log_path: Option<String>,
+    /// Topic for periodic status messages about the reaction itself.
+    /// Heartbeats are disabled when unset.
+    #[serde(default)]
+    pub heartbeat_topic: Option<String>,
+    /// Interval between heartbeats in milliseconds (default: 30000).
+    #[serde(default = "default_heartbeat_interval_ms")]
+    pub heartbeat_interval_ms: u64,
+    /// Topic template for full result set snapshots, rendered with
+    /// `query_id` and `reaction_id` (e.g. `snapshots/{{query_id}}`). The
+    /// reaction folds each query's diffs into its current result set only
+    /// when set.
+    #[serde(default)]
+    pub snapshot_topic: Option<String>,
+    /// Interval between snapshots of every query in milliseconds. Without
+    /// it, snapshots are only published by `MqttReaction::publish_snapshot`.
+    #[serde(default)]
+    pub snapshot_interval_ms: Option<u64>,
+    /// Rows kept per query for snapshots; rows beyond it are dropped and the
+    /// snapshot is marked truncated (default: 10000).
+    #[serde(default = "default_snapshot_capacity")]
+    pub snapshot_capacity: usize,
+    /// How long a broker connection must be down continuously before the
+    /// reaction reports itself as degraded (default: 10000 ms). Shorter
+    /// outages keep the reaction `Running`.
+    #[serde(default = "default_degraded_after_ms")]
+    pub degraded_after_ms: u64,
+    /// Whether DrasiLib starts the reaction together with itself (default:
+    /// `true`). When `false`, the reaction stays stopped until started explicitly.
+    #[serde(default = "default_auto_start")]
+    pub auto_start: bool,
+    /// Supplies the primary broker's credentials at every (re)connect,
+    /// replacing `username` and `password`. Set through the builder only.
+    #[serde(skip)]
+    pub credentials_provider: Option<CredentialsProvider>,
 }
 
 impl MqttReactionConfig {
@@ -49,35 +420,177 @@ impl MqttReactionConfig {
     ) -> MqttReactionConfigBuilder {
         let id = id.into();
         MqttReactionConfigBuilder {
-            id: id.clone(),
-            broker_host: broker_host.into(),
+            connection: ConnectionConfig::new(format!("drasi-reaction-{id}"), broker_host, 1883),
+            id,
             topic: topic.into(),
             payload_template: None,
-            port: 1883,
-            client_id: format!("drasi-reaction-{id}"),
-            username: None,
-            password: None,
+            topic_prefix: None,
+            query_topics: HashMap::new(),
+            query_payload_templates: HashMap::new(),
+            partials: HashMap::new(),
+            json_pretty: false,
+            sort_keys: false,
+            include_meta: false,
+            correlation_ids: false,
+            delete_payload: DeletePayloadMode::Full,
+            split_metadata: MetadataConfig::default(),
+            result_transform: None,
+            order_by: None,
+            user_properties: Vec::new(),
+            client_id_suffix: None,
             queries,
+            on_unhandled_diff: UnhandledDiffPolicy::Ignore,
+            protocol: MqttProtocol::default(),
+            retain: false,
+            qos_field: None,
+            retain_field: None,
+            republish_retained_on_reconnect: false,
+            retained_cache_capacity: default_retained_cache_capacity(),
+            clear_retained_on_remove: false,
+            keep_remove_payload: false,
+            additional_brokers: Vec::new(),
+            broker_buffer_capacity: default_broker_buffer_capacity(),
+            buffer_drop_policy: BufferDropPolicy::DropNewest,
+            buffer_high_water_mark: None,
+            publish_timeout_ms: None,
+            publish_retries: default_publish_retries(),
+            dedup: None,
+            dedup_capacity: default_dedup_capacity(),
+            audit_log_path: None,
+            heartbeat_topic: None,
+            heartbeat_interval_ms: default_heartbeat_interval_ms(),
+            snapshot_topic: None,
+            snapshot_interval_ms: None,
+            snapshot_capacity: default_snapshot_capacity(),
+            degraded_after_ms: default_degraded_after_ms(),
+            auto_start: default_auto_start(),
+            credentials_provider: None,
         }
     }
+
+    /// Render `item`, a result row of `query_id` with operation `op`, into
+    /// the (topic, payload) pairs the reaction would publish for it, to check
+    /// templates without a broker. `result_transform` is applied first.
+    /// Payloads are decoded as UTF-8, lossily.
+    ///
+    /// Uses the template serializer; a serializer set with
+    /// `MqttReaction::with_serializer` is not taken into account.
+    pub fn preview_render(
+        &self,
+        query_id: &str,
+        item: &Value,
+        op: Op,
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        let mut registry = publisher::template_registry();
+        publisher::register_partials(&mut registry, &self.partials)?;
+        let serializer = TemplateSerializer::from_config(Arc::new(registry), self);
+        serializer.validate()?;
+
+        let mut batch = DiffBatch::default();
+        match op {
+            Op::Insert => batch.added.push(item.clone()),
+            Op::Update => batch.updated.push(item.clone()),
+            Op::Delete => batch.removed.push(item.clone()),
+        }
+        if let Some(expression) = &self.result_transform {
+            let transform = ResultTransform::compile(expression)?;
+            if let Some(e) = transform.apply_batch(&mut batch).pop() {
+                return Err(e);
+            }
+        }
+        let ctx = SerializeContext::new(&self.id, 1);
+        serializer
+            .serialize_batch(query_id, &batch, &ctx)?
+            .into_iter()
+            .map(|(topic, payload)| {
+                publisher::validate_topic(&topic)?;
+                Ok((topic, String::from_utf8_lossy(&payload).into_owned()))
+            })
+            .collect()
+    }
+
+    /// All brokers this reaction publishes to, the primary broker first.
+    ///
+    /// Client ids carry `client_id_suffix`; a random suffix is generated
+    /// anew on every call.
+    pub fn brokers(&self) -> Vec<BrokerEndpoint> {
+        let mut primary = BrokerEndpoint {
+            name: PRIMARY_BROKER.to_string(),
+            connection: self.connection.clone(),
+        };
+        if primary.connection.client_id.is_empty() {
+            primary.connection.client_id = format!("drasi-reaction-{}", self.id);
+        }
+
+        let mut brokers = vec![primary];
+        for broker in &self.additional_brokers {
+            let mut broker = broker.clone();
+            if broker.connection.client_id.is_empty() {
+                broker.connection.client_id = format!("drasi-reaction-{}-{}", self.id, broker.name);
+            }
+            brokers.push(broker);
+        }
+        if let Some(suffix) = self.client_id_suffix.map(|suffix| suffix.generate()) {
+            for broker in &mut brokers {
+                broker.connection.client_id.push_str(&suffix);
+            }
+        }
+        brokers
+    }
 }
 
 /// Builder for [`MqttReactionConfig`].
 pub struct MqttReactionConfigBuilder {
     id: String,
-    broker_host: String,
+    connection: ConnectionConfig,
     topic: String,
     payload_template: Option<String>,
-    port: u16,
-    client_id: String,
-    username: Option<String>,
-    password: Option<String>,
+    topic_prefix: Option<String>,
+    query_topics: HashMap<String, String>,
+    query_payload_templates: HashMap<String, String>,
+    partials: HashMap<String, String>,
+    json_pretty: bool,
+    sort_keys: bool,
+    include_meta: bool,
+    correlation_ids: bool,
+    delete_payload: DeletePayloadMode,
+    split_metadata: MetadataConfig,
+    result_transform: Option<String>,
+    order_by: Option<String>,
+    user_properties: Vec<(String, String)>,
+    client_id_suffix: Option<ClientIdSuffix>,
     queries: Vec<String>,
+    on_unhandled_diff: UnhandledDiffPolicy,
+    protocol: MqttProtocol,
+    retain: bool,
+    qos_field: Option<String>,
+    retain_field: Option<String>,
+    republish_retained_on_reconnect: bool,
+    retained_cache_capacity: usize,
+    clear_retained_on_remove: bool,
+    keep_remove_payload: bool,
+    additional_brokers: Vec<BrokerEndpoint>,
+    broker_buffer_capacity: usize,
+    buffer_drop_policy: BufferDropPolicy,
+    buffer_high_water_mark: Option<usize>,
+    publish_timeout_ms: Option<u64>,
+    publish_retries: u32,
+    dedup: Option<DedupKey>,
+    dedup_capacity: usize,
+    audit_log_path: Option<String>,
+    heartbeat_topic: Option<String>,
+    heartbeat_interval_ms: u64,
+    snapshot_topic: Option<String>,
+    snapshot_interval_ms: Option<u64>,
+    snapshot_capacity: usize,
+    degraded_after_ms: u64,
+    auto_start: bool,
+    credentials_provider: Option<CredentialsProvider>,
 }
 
 impl MqttReactionConfigBuilder {
     pub fn port(mut self, port: u16) -> Self {
-        self.port = port;
+        self.connection.port = port;
         self
     }
 
@@ -86,18 +599,263 @@ impl MqttReactionConfigBuilder {
         self
     }
 
+    /// Prepend `prefix` to every topic, e.g. `tenants/acme/`.
+    pub fn topic_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.topic_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Use `template` as the topic for results of `query_id`.
+    pub fn query_topic(mut self, query_id: impl Into<String>, template: impl Into<String>) -> Self {
+        self.query_topics.insert(query_id.into(), template.into());
+        self
+    }
+
+    /// Use `template` as the payload template for results of `query_id`.
+    pub fn query_payload_template(
+        mut self,
+        query_id: impl Into<String>,
+        template: impl Into<String>,
+    ) -> Self {
+        self.query_payload_templates
+            .insert(query_id.into(), template.into());
+        self
+    }
+
+    pub fn json_pretty(mut self, pretty: bool) -> Self {
+        self.json_pretty = pretty;
+        self
+    }
+
+    pub fn sort_keys(mut self, sort: bool) -> Self {
+        self.sort_keys = sort;
+        self
+    }
+
+    pub fn include_meta(mut self, include: bool) -> Self {
+        self.include_meta = include;
+        self
+    }
+
+    /// Stamp every per-item message with a new `correlation_id`.
+    pub fn correlation_ids(mut self, enabled: bool) -> Self {
+        self.correlation_ids = enabled;
+        self
+    }
+
+    /// Add the MQTT 5 user property `name` to every result message, with the
+    /// value rendered from `value_template`, e.g. `"{{query_id}}"`.
+    pub fn user_property(
+        mut self,
+        name: impl Into<String>,
+        value_template: impl Into<String>,
+    ) -> Self {
+        self.user_properties
+            .push((name.into(), value_template.into()));
+        self
+    }
+
+    /// Choose what delete messages contain.
+    pub fn delete_payload(mut self, mode: DeletePayloadMode) -> Self {
+        self.delete_payload = mode;
+        self
+    }
+
+    /// Choose the metadata keys of split payloads built without a payload
+    /// template, e.g. to drop `sequence` or publish `op` as `event`.
+    pub fn split_metadata(mut self, metadata: MetadataConfig) -> Self {
+        self.split_metadata = metadata;
+        self
+    }
+
+    /// Register a Handlebars partial that templates include with `{{> name}}`.
+    pub fn partial(mut self, name: impl Into<String>, template: impl Into<String>) -> Self {
+        self.partials.insert(name.into(), template.into());
+        self
+    }
+
+    /// Reshape each result item with a JMESPath expression before templating.
+    pub fn result_transform(mut self, expression: impl Into<String>) -> Self {
+        self.result_transform = Some(expression.into());
+        self
+    }
+
+    /// Publish the items of each result sorted by `field`.
+    pub fn order_by(mut self, field: impl Into<String>) -> Self {
+        self.order_by = Some(field.into());
+        self
+    }
+
     pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
-        self.client_id = client_id.into();
+        self.connection.client_id = client_id.into();
+        self
+    }
+
+    /// Append `suffix` to the client ids, e.g. to deploy several instances
+    /// with one configuration.
+    pub fn client_id_suffix(mut self, suffix: ClientIdSuffix) -> Self {
+        self.client_id_suffix = Some(suffix);
         self
     }
 
     pub fn username(mut self, username: impl Into<String>) -> Self {
-        self.username = Some(username.into());
+        self.connection.username = Some(username.into());
         self
     }
 
     pub fn password(mut self, password: impl Into<String>) -> Self {
-        self.password = Some(password.into());
+        self.connection.password = Some(password.into());
+        self
+    }
+
+    pub fn on_unhandled_diff(mut self, policy: UnhandledDiffPolicy) -> Self {
+        self.on_unhandled_diff = policy;
+        self
+    }
+
+    /// Connect to the primary broker over TLS.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.connection = self.connection.tls(tls);
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: std::time::Duration) -> Self {
+        self.connection = self.connection.keep_alive(keep_alive);
+        self
+    }
+
+    pub fn protocol(mut self, protocol: MqttProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Take the QoS of each item's messages from `field` in split mode.
+    pub fn qos_field(mut self, field: impl Into<String>) -> Self {
+        self.qos_field = Some(field.into());
+        self
+    }
+
+    /// Take the retain flag of each item's messages from `field` in split mode.
+    pub fn retain_field(mut self, field: impl Into<String>) -> Self {
+        self.retain_field = Some(field.into());
+        self
+    }
+
+    /// Republish retained messages after reconnects, remembering up to
+    /// `capacity` topics.
+    pub fn republish_retained_on_reconnect(mut self, capacity: usize) -> Self {
+        self.republish_retained_on_reconnect = true;
+        self.retained_cache_capacity = capacity;
+        self
+    }
+
+    /// Clear the retained message of removed items in split mode by
+    /// publishing an empty retained payload on their topic, after the normal
+    /// delete message if `keep_remove_payload` is set.
+    pub fn clear_retained_on_remove(mut self, keep_remove_payload: bool) -> Self {
+        self.clear_retained_on_remove = true;
+        self.keep_remove_payload = keep_remove_payload;
+        self
+    }
+
+    /// Also publish every message to `broker`.
+    pub fn add_broker(mut self, broker: BrokerEndpoint) -> Self {
+        self.additional_brokers.push(broker);
+        self
+    }
+
+    pub fn broker_buffer_capacity(mut self, capacity: usize) -> Self {
+        self.broker_buffer_capacity = capacity;
+        self
+    }
+
+    /// Drop the oldest or newest message when a broker's buffer is full.
+    pub fn buffer_drop_policy(mut self, policy: BufferDropPolicy) -> Self {
+        self.buffer_drop_policy = policy;
+        self
+    }
+
+    /// Report the reaction as degraded while a broker has `depth` or more
+    /// messages buffered.
+    pub fn buffer_high_water_mark(mut self, depth: usize) -> Self {
+        self.buffer_high_water_mark = Some(depth);
+        self
+    }
+
+    pub fn publish_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.publish_timeout_ms = Some(timeout.as_millis() as u64);
+        self
+    }
+
+    /// Give a message up for a broker after `retries` publish timeouts in a
+    /// row, instead of the default 3.
+    pub fn publish_retries(mut self, retries: u32) -> Self {
+        self.publish_retries = retries;
+        self
+    }
+
+    /// Publish each message at most once per broker, identified by `key`,
+    /// remembering up to `capacity` accepted keys.
+    pub fn dedup(mut self, key: DedupKey, capacity: usize) -> Self {
+        self.dedup = Some(key);
+        self.dedup_capacity = capacity;
+        self
+    }
+
+    /// Append a JSON line per publish attempt to the file at `path`.
+    pub fn audit_log_path(mut self, path: impl Into<String>) -> Self {
+        self.audit_log_path = Some(path.into());
+        self
+    }
+
+    /// Publish a status message to `topic` every `interval`, plus a final
+    /// `stopping` message when the reaction stops.
+    pub fn heartbeat(mut self, topic: impl Into<String>, interval: std::time::Duration) -> Self {
+        self.heartbeat_topic = Some(topic.into());
+        self.heartbeat_interval_ms = interval.as_millis() as u64;
+        self
+    }
+
+    /// Keep the full current result set of each query and publish it to the
+    /// `topic` template every `interval`, or only on demand without one.
+    pub fn snapshot(
+        mut self,
+        topic: impl Into<String>,
+        interval: Option<std::time::Duration>,
+    ) -> Self {
+        self.snapshot_topic = Some(topic.into());
+        self.snapshot_interval_ms = interval.map(|interval| interval.as_millis() as u64);
+        self
+    }
+
+    /// Keep at most `capacity` rows per query for snapshots.
+    pub fn snapshot_capacity(mut self, capacity: usize) -> Self {
+        self.snapshot_capacity = capacity;
+        self
+    }
+
+    /// Report the reaction as degraded once a broker connection has been
+    /// down for `grace`.
+    pub fn degraded_after(mut self, grace: std::time::Duration) -> Self {
+        self.degraded_after_ms = grace.as_millis() as u64;
+        self
+    }
+
+    /// Fetch the primary broker's credentials from `provider` before every
+    /// connection attempt, e.g. to refresh short-lived tokens.
+    pub fn with_credentials_provider(mut self, provider: Arc<CredentialsFn>) -> Self {
+        self.credentials_provider = Some(CredentialsProvider(provider));
+        self
+    }
+
+    /// Whether DrasiLib starts the reaction automatically (default: `true`).
+    pub fn auto_start(mut self, auto_start: bool) -> Self {
+        self.auto_start = auto_start;
         self
     }
 
@@ -105,14 +863,126 @@ impl MqttReactionConfigBuilder {
     pub fn build(self) -> MqttReactionConfig {
         MqttReactionConfig {
             id: self.id,
-            broker_host: self.broker_host,
-            port: self.port,
+            connection: self.connection,
             topic: self.topic,
             payload_template: self.payload_template,
-            client_id: self.client_id,
-            username: self.username,
-            password: self.password,
+            topic_prefix: self.topic_prefix,
+            query_topics: self.query_topics,
+            query_payload_templates: self.query_payload_templates,
+            partials: self.partials,
+            json_pretty: self.json_pretty,
+            sort_keys: self.sort_keys,
+            include_meta: self.include_meta,
+            correlation_ids: self.correlation_ids,
+            delete_payload: self.delete_payload,
+            split_metadata: self.split_metadata,
+            result_transform: self.result_transform,
+            order_by: self.order_by,
+            user_properties: self.user_properties,
+            client_id_suffix: self.client_id_suffix,
             queries: self.queries,
+            on_unhandled_diff: self.on_unhandled_diff,
+            protocol: self.protocol,
+            retain: self.retain,
+            qos_field: self.qos_field,
+            retain_field: self.retain_field,
+            republish_retained_on_reconnect: self.republish_retained_on_reconnect,
+            retained_cache_capacity: self.retained_cache_capacity,
+            clear_retained_on_remove: self.clear_retained_on_remove,
+            keep_remove_payload: self.keep_remove_payload,
+            additional_brokers: self.additional_brokers,
+            broker_buffer_capacity: self.broker_buffer_capacity,
+            buffer_drop_policy: self.buffer_drop_policy,
+            buffer_high_water_mark: self.buffer_high_water_mark,
+            publish_timeout_ms: self.publish_timeout_ms,
+            publish_retries: self.publish_retries,
+            dedup: self.dedup,
+            dedup_capacity: self.dedup_capacity,
+            audit_log_path: self.audit_log_path,
+            heartbeat_topic: self.heartbeat_topic,
+            heartbeat_interval_ms: self.heartbeat_interval_ms,
+            snapshot_topic: self.snapshot_topic,
+            snapshot_interval_ms: self.snapshot_interval_ms,
+            snapshot_capacity: self.snapshot_capacity,
+            degraded_after_ms: self.degraded_after_ms,
+            auto_start: self.auto_start,
+            credentials_provider: self.credentials_provider,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn builder(topic: &str) -> MqttReactionConfigBuilder {
+        MqttReactionConfig::builder("r1", "localhost", topic, vec!["q1".to_string()])
+    }
+
+    #[test]
+    fn test_preview_render_batch() {
+        let config = builder("plant/alerts").build();
+        let messages = config
+            .preview_render("q1", &json!({"device": "d1"}), Op::Delete)
+            .unwrap();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, "plant/alerts");
+        let payload: Value = serde_json::from_str(&messages[0].1).unwrap();
+        assert_eq!(payload["removed"], json!([{"device": "d1"}]));
+        assert_eq!(payload["query_id"], "q1");
+    }
+
+    #[test]
+    fn test_preview_render_templates() {
+        let config = builder("devices/{{device}}/cmd")
+            .topic_prefix("tenants/acme")
+            .payload_template("{{op}}:{{level}}")
+            .query_topic("q2", "alerts/{{device}}")
+            .build();
+        let item = json!({"device": "d1", "level": 3});
+
+        assert_eq!(
+            config.preview_render("q1", &item, Op::Update).unwrap(),
+            vec![(
+                "tenants/acme/devices/d1/cmd".to_string(),
+                "update:3".to_string()
+            )]
+        );
+        assert_eq!(
+            config.preview_render("q2", &item, Op::Insert).unwrap()[0].0,
+            "tenants/acme/alerts/d1"
+        );
+    }
+
+    #[test]
+    fn test_preview_render_errors() {
+        let broken = builder("devices/{{device").build();
+        assert!(broken.preview_render("q1", &json!({}), Op::Insert).is_err());
+
+        // Renders to a topic with a wildcard, which is never published.
+        let wildcard = builder("devices/{{device}}").build();
+        assert!(wildcard
+            .preview_render("q1", &json!({"device": "#"}), Op::Insert)
+            .is_err());
+    }
+
+    #[test]
+    fn test_preview_render_partials() {
+        let config = builder("alerts")
+            .partial("header", r#""source": "{{query_id}}""#)
+            .payload_template(r#"{ {{> header}}, "level": {{level}} }"#)
+            .build();
+        let messages = config
+            .preview_render("q1", &json!({"level": 3}), Op::Insert)
+            .unwrap();
+        let payload: Value = serde_json::from_str(&messages[0].1).unwrap();
+        assert_eq!(payload, json!({"source": "q1", "level": 3}));
+
+        let missing = builder("alerts").payload_template("{{> footer}}").build();
+        assert!(missing
+            .preview_render("q1", &json!({}), Op::Insert)
+            .is_err());
+    }
+}
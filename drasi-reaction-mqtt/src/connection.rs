@@ -0,0 +1,96 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Broker connection state, as observed by the eventloop drivers.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use drasi_mqtt_connection::{system_clock, SharedClock};
+use tokio::time::Instant;
+
+/// Connection state of one broker, updated by its eventloop driver.
+///
+/// The connection counts as down from the first eventloop error, including
+/// failed initial connects, until the next ConnAck.
+pub struct ConnectionState {
+    disconnected_since: Mutex<Option<Instant>>,
+    clock: SharedClock,
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        Self::with_clock(system_clock())
+    }
+}
+
+impl ConnectionState {
+    /// State taking disconnect times from `clock`.
+    pub fn with_clock(clock: SharedClock) -> Self {
+        Self {
+            disconnected_since: Mutex::new(None),
+            clock,
+        }
+    }
+
+    /// Record a ConnAck: the connection is up.
+    pub fn on_connack(&self) {
+        *self.disconnected_since.lock().unwrap() = None;
+    }
+
+    /// Record an eventloop error: the connection is down, since the first
+    /// error if it already was.
+    pub fn on_error(&self) {
+        self.disconnected_since
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| self.clock.now_instant());
+    }
+
+    /// When the connection went down, if it is down.
+    pub fn disconnected_since(&self) -> Option<Instant> {
+        *self.disconnected_since.lock().unwrap()
+    }
+
+    /// Whether the connection has been down continuously for at least
+    /// `degraded_after` at `now`.
+    pub fn is_degraded(&self, now: Instant, degraded_after: Duration) -> bool {
+        self.disconnected_since()
+            .is_some_and(|since| now.saturating_duration_since(since) >= degraded_after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_degraded_after_grace_period() {
+        let state = ConnectionState::default();
+        let grace = Duration::from_secs(10);
+        assert!(!state.is_degraded(Instant::now(), grace));
+
+        state.on_error();
+        tokio::time::advance(Duration::from_secs(5)).await;
+        // Further errors keep the time of the first one.
+        state.on_error();
+        assert!(!state.is_degraded(Instant::now(), grace));
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(state.is_degraded(Instant::now(), grace));
+
+        state.on_connack();
+        assert!(!state.is_degraded(Instant::now(), grace));
+        assert_eq!(state.disconnected_since(), None);
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exactly-once publishing per broker.
+//!
+//! A publish that times out is abandoned by the fan-out and retried, but the
+//! abandoned attempt may still reach the broker. [`DedupClient`] runs each
+//! attempt to completion in its own task, so a retry of a message still in
+//! flight waits for that attempt's outcome instead of sending it again, and
+//! remembers the keys of accepted messages so repeats are skipped.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rumqttc::QoS;
+use tokio::sync::watch;
+use tracing::debug;
+
+use crate::client::PublishClient;
+use crate::config::DedupKey;
+
+/// Outcome of a publish attempt, shared with retries waiting on it.
+type Outcome = Option<Result<(), String>>;
+
+/// Keys accepted by the broker, oldest first, and attempts in flight.
+#[derive(Default)]
+struct DedupState {
+    capacity: usize,
+    accepted: HashSet<u64>,
+    order: VecDeque<u64>,
+    in_flight: HashMap<u64, watch::Receiver<Outcome>>,
+}
+
+impl DedupState {
+    fn accept(&mut self, key: u64) {
+        if self.capacity == 0 || !self.accepted.insert(key) {
+            return;
+        }
+        self.order.push_back(key);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.accepted.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Publishes each message at most once through `inner`, identified by its
+/// [`DedupKey`]. Messages without a key are passed through unchanged.
+pub struct DedupClient {
+    inner: Arc<dyn PublishClient>,
+    key: DedupKey,
+    state: Arc<Mutex<DedupState>>,
+}
+
+impl DedupClient {
+    /// Deduplicate publishes through `inner`, remembering up to `capacity`
+    /// accepted keys.
+    pub fn new(inner: Arc<dyn PublishClient>, key: DedupKey, capacity: usize) -> Self {
+        Self {
+            inner,
+            key,
+            state: Arc::new(Mutex::new(DedupState {
+                capacity,
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn key_of(&self, payload: &[u8]) -> Option<u64> {
+        let mut hasher = DefaultHasher::new();
+        match &self.key {
+            DedupKey::Field(field) => {
+                let json: serde_json::Value = serde_json::from_slice(payload).ok()?;
+                json.get(field)?.to_string().hash(&mut hasher);
+            }
+        }
+        Some(hasher.finish())
+    }
+}
+
+#[async_trait]
+impl PublishClient for DedupClient {
+    async fn publish(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        self.publish_with_user_properties(topic, qos, retain, payload, Vec::new())
+            .await
+    }
+
+    async fn publish_with_user_properties(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+    ) -> anyhow::Result<()> {
+        let Some(key) = self.key_of(&payload) else {
+            return self
+                .inner
+                .publish_with_user_properties(topic, qos, retain, payload, user_properties)
+                .await;
+        };
+
+        let mut outcome = {
+            let mut state = self.state.lock().unwrap();
+            if state.accepted.contains(&key) {
+                debug!("Skipping duplicate message on topic '{topic}'");
+                return Ok(());
+            }
+            match state.in_flight.get(&key) {
+                Some(outcome) => outcome.clone(),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    state.in_flight.insert(key, rx.clone());
+                    let inner = self.inner.clone();
+                    let shared = self.state.clone();
+                    tokio::spawn(async move {
+                        let result = inner
+                            .publish_with_user_properties(
+                                topic,
+                                qos,
+                                retain,
+                                payload,
+                                user_properties,
+                            )
+                            .await;
+                        let mut state = shared.lock().unwrap();
+                        state.in_flight.remove(&key);
+                        if result.is_ok() {
+                            state.accept(key);
+                        }
+                        let _ = tx.send(Some(result.map_err(|e| e.to_string())));
+                    });
+                    rx
+                }
+            }
+        };
+
+        let result = outcome
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| anyhow::anyhow!("Publish attempt was aborted"))?
+            .clone()
+            .expect("waited for an outcome");
+        result.map_err(anyhow::Error::msg)
+    }
+
+    /// Replays are meant to reach the broker again, so they are never
+    /// skipped.
+    async fn republish(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+    ) -> anyhow::Result<()> {
+        self.inner
+            .republish(topic, qos, retain, payload, user_properties)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::RecordingClient;
+    use crate::fanout::{FanOut, OutgoingMessage, PublishTimeout};
+    use std::time::Duration;
+
+    /// Accepts every publish, but only after longer than the fan-out's
+    /// publish timeout.
+    #[derive(Default)]
+    struct SlowClient {
+        inner: RecordingClient,
+    }
+
+    #[async_trait]
+    impl PublishClient for SlowClient {
+        async fn publish(
+            &self,
+            topic: String,
+            qos: QoS,
+            retain: bool,
+            payload: Vec<u8>,
+        ) -> anyhow::Result<()> {
+            self.inner.publish(topic, qos, retain, payload).await?;
+            tokio::time::sleep(Duration::from_millis(80)).await;
+            Ok(())
+        }
+    }
+
+    fn event_id() -> DedupKey {
+        DedupKey::Field("event_id".to_string())
+    }
+
+    async fn send(client: &DedupClient, topic: &str, payload: &str) {
+        client
+            .publish(
+                topic.to_string(),
+                QoS::AtLeastOnce,
+                false,
+                payload.as_bytes().to_vec(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_after_timeout_is_not_published_twice() {
+        let slow = Arc::new(SlowClient::default());
+        let client = DedupClient::new(slow.clone(), event_id(), 10);
+        let fanout = FanOut::new(
+            "r1",
+            10,
+            Some(PublishTimeout::new(Duration::from_millis(50))),
+            None,
+            vec![("b".to_string(), Arc::new(client) as Arc<dyn PublishClient>)],
+        );
+
+        fanout.publish(OutgoingMessage {
+            topic: "alerts/a".to_string(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            payload: br#"{"event_id": 1}"#.to_vec(),
+            user_properties: Vec::new(),
+            origin: None,
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        assert_eq!(slow.inner.topics(), vec!["alerts/a"]);
+        let stats = &fanout.stats()[0];
+        assert_eq!(stats.published, 1);
+        assert_eq!(stats.timed_out, 1);
+    }
+
+    #[tokio::test]
+    async fn test_accepted_keys_are_skipped() {
+        let recording = Arc::new(RecordingClient::default());
+        let client = DedupClient::new(recording.clone(), event_id(), 10);
+
+        send(&client, "alerts/a", r#"{"event_id": 1, "v": 1}"#).await;
+        send(&client, "alerts/b", r#"{"event_id": 1, "v": 2}"#).await;
+        send(&client, "alerts/c", r#"{"event_id": 2}"#).await;
+        send(&client, "alerts/d", r#"{"v": 3}"#).await;
+        send(&client, "alerts/d", r#"{"v": 3}"#).await;
+
+        assert_eq!(
+            recording.topics(),
+            vec!["alerts/a", "alerts/c", "alerts/d", "alerts/d"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replays_of_accepted_keys_are_published() {
+        let recording = Arc::new(RecordingClient::default());
+        let client = DedupClient::new(recording.clone(), event_id(), 10);
+
+        send(&client, "state/a", r#"{"event_id": 1}"#).await;
+        client
+            .republish(
+                "state/a".to_string(),
+                QoS::AtLeastOnce,
+                true,
+                br#"{"event_id": 1}"#.to_vec(),
+                Vec::new(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(recording.topics(), vec!["state/a", "state/a"]);
+    }
+
+    #[tokio::test]
+    async fn test_oldest_keys_are_forgotten() {
+        let recording = Arc::new(RecordingClient::default());
+        let client = DedupClient::new(recording.clone(), event_id(), 1);
+
+        send(&client, "alerts/a", r#"{"event_id": 1}"#).await;
+        send(&client, "alerts/a", r#"{"event_id": 1}"#).await;
+        send(&client, "alerts/b", r#"{"event_id": 2}"#).await;
+        send(&client, "alerts/a", r#"{"event_id": 1}"#).await;
+
+        assert_eq!(recording.topics(), vec!["alerts/a", "alerts/b", "alerts/a"]);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_commands_are_all_published() {
+        let recording = Arc::new(RecordingClient::default());
+        let client = DedupClient::new(recording.clone(), event_id(), 10);
+
+        send(&client, "things/lamp/set", r#"{"state": "on"}"#).await;
+        send(&client, "things/lamp/set", r#"{"state": "off"}"#).await;
+        send(&client, "things/lamp/set", r#"{"state": "on"}"#).await;
+
+        let payloads: Vec<Vec<u8>> = recording
+            .published
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| p.payload.clone())
+            .collect();
+        assert_eq!(
+            payloads,
+            vec![
+                br#"{"state": "on"}"#.to_vec(),
+                br#"{"state": "off"}"#.to_vec(),
+                br#"{"state": "on"}"#.to_vec(),
+            ]
+        );
+    }
+}
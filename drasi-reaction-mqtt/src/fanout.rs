@@ -0,0 +1,1121 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fan-out of published messages to one or more brokers.
+//!
+//! Every broker gets its own bounded buffer and publishing task, so a slow or
+//! unreachable broker only ever fills its own buffer and never stalls
+//! delivery to the others.
+//!
+//! With a publish timeout configured, a publish that waits longer than the
+//! timeout on the client's request queue is abandoned, counted and retried
+//! before any later message for that broker. After the configured number of
+//! retries the message is given up for that broker and reported as failed,
+//! to the publish hook like any failure, so a stalled broker cannot hold its
+//! buffer forever.
+//!
+//! Waiting never blocks result processing; once the buffer is full, the
+//! newest or oldest message for that broker is dropped and counted, per
+//! [`BufferDropPolicy`]. The message being
+//! published is out of the buffer, so it is never dropped, but it still
+//! counts toward the broker's depth. A broker whose depth reaches the
+//! high-water mark counts as backed up.
+//!
+//! Messages replayed to a broker, like retained messages restored after a
+//! reconnect, queue in the same buffer, so they never overtake a newer
+//! message for the same topic.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use drasi_mqtt_connection::{system_clock, LogLimiter, SharedClock};
+use rumqttc::QoS;
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+use crate::audit::{PublishHook, PublishOrigin, PublishOutcome, PublishRecord};
+use crate::client::PublishClient;
+use crate::config::BufferDropPolicy;
+
+/// A message to be published to every broker.
+#[derive(Debug, Clone)]
+pub struct OutgoingMessage {
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: Vec<u8>,
+    /// MQTT 5 user properties; not sent to MQTT 3.1.1 brokers.
+    pub user_properties: Vec<(String, String)>,
+    /// The query result the message was produced from, for the publish hook.
+    pub origin: Option<PublishOrigin>,
+}
+
+impl OutgoingMessage {
+    fn query_id(&self) -> Option<&str> {
+        self.origin.as_ref().map(|origin| origin.query_id.as_str())
+    }
+
+    fn sequence(&self) -> Option<u64> {
+        self.origin.as_ref().map(|origin| origin.sequence)
+    }
+}
+
+/// Per-broker publish counters.
+#[derive(Debug, Default)]
+pub struct BrokerStats {
+    published: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+    timed_out: AtomicU64,
+    wait_micros_total: AtomicU64,
+    wait_micros_max: AtomicU64,
+}
+
+impl BrokerStats {
+    fn record_wait(&self, waited: Duration) {
+        let micros = u64::try_from(waited.as_micros()).unwrap_or(u64::MAX);
+        self.wait_micros_total.fetch_add(micros, Ordering::Relaxed);
+        self.wait_micros_max.fetch_max(micros, Ordering::Relaxed);
+    }
+}
+
+/// Point-in-time copy of a broker's publish counters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokerStatsSnapshot {
+    /// Broker name from the configuration.
+    pub broker: String,
+    /// Messages handed to the broker's client successfully.
+    pub published: u64,
+    /// Messages the client rejected.
+    pub failed: u64,
+    /// Messages dropped because the broker's buffer was full.
+    pub dropped: u64,
+    /// Publish attempts abandoned after the publish timeout (and retried,
+    /// up to the retry limit).
+    pub timed_out: u64,
+    /// Total time spent waiting for the client to accept publishes.
+    pub publish_wait_total: Duration,
+    /// Longest single wait for the client to accept a publish.
+    pub publish_wait_max: Duration,
+    /// Messages buffered or being published, not yet handed to the client.
+    pub queue_depth: usize,
+}
+
+/// Publish outcomes and processing errors of one query's results.
+///
+/// Publish outcomes are counted per broker, so a message delivered to two
+/// brokers counts twice.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryMetrics {
+    /// Messages handed to a broker's client successfully.
+    pub published: u64,
+    /// Messages a broker's client rejected.
+    pub failed: u64,
+    /// Messages dropped because a broker's buffer was full.
+    pub dropped: u64,
+    /// Results, or items of them, that could not be turned into messages.
+    pub errors: u64,
+    /// Results discarded because the query was disabled.
+    pub muted: u64,
+}
+
+/// Per-query counters, keyed by query id.
+#[derive(Debug, Default)]
+struct QueryCounters {
+    queries: Mutex<HashMap<String, QueryMetrics>>,
+}
+
+impl QueryCounters {
+    fn update(&self, query_id: &str, update: impl FnOnce(&mut QueryMetrics)) {
+        let mut queries = self.queries.lock().unwrap();
+        match queries.get_mut(query_id) {
+            Some(metrics) => update(metrics),
+            None => update(queries.entry(query_id.to_string()).or_default()),
+        }
+    }
+
+    /// Count the outcome of publishing `msg` to one broker against its query.
+    fn record(&self, msg: &OutgoingMessage, outcome: &PublishOutcome) {
+        let Some(origin) = &msg.origin else {
+            return;
+        };
+        self.update(&origin.query_id, |metrics| match outcome {
+            PublishOutcome::Published => metrics.published += 1,
+            PublishOutcome::Failed(_) => metrics.failed += 1,
+            PublishOutcome::Dropped => metrics.dropped += 1,
+        });
+    }
+}
+
+/// Bounds of each broker's buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferLimits {
+    /// Messages buffered before one is dropped.
+    pub capacity: usize,
+    /// Which message a full buffer drops.
+    pub drop_policy: BufferDropPolicy,
+    /// Depth at which the broker counts as backed up; never when unset.
+    pub high_water_mark: Option<usize>,
+}
+
+impl BufferLimits {
+    /// `capacity` messages, dropping the newest, without a high-water mark.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            drop_policy: BufferDropPolicy::DropNewest,
+            high_water_mark: None,
+        }
+    }
+}
+
+/// How long one attempt to hand a message to a broker's client may wait,
+/// and how often it is retried after timing out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublishTimeout {
+    /// Longest a single attempt waits.
+    pub limit: Duration,
+    /// Attempts after the first before the message is reported as failed.
+    pub retries: u32,
+}
+
+impl PublishTimeout {
+    /// `limit` per attempt, retried 3 times.
+    pub fn new(limit: Duration) -> Self {
+        Self { limit, retries: 3 }
+    }
+}
+
+/// A buffered message for one broker.
+struct Queued {
+    msg: OutgoingMessage,
+    /// The broker already accepted the message once; see
+    /// [`PublishClient::republish`].
+    replay: bool,
+}
+
+/// The buffer between [`FanOut::publish`] and a broker's publishing task.
+struct BrokerBuffer {
+    messages: Mutex<VecDeque<Queued>>,
+    limits: BufferLimits,
+    ready: Notify,
+    closed: AtomicBool,
+    /// A popped message is being published and not yet finished with.
+    in_flight: AtomicBool,
+    /// Depth was at or above the high-water mark when last checked.
+    backed_up: AtomicBool,
+}
+
+impl BrokerBuffer {
+    fn new(limits: BufferLimits) -> Self {
+        Self {
+            messages: Mutex::new(VecDeque::new()),
+            limits: BufferLimits {
+                capacity: limits.capacity.max(1),
+                ..limits
+            },
+            ready: Notify::new(),
+            closed: AtomicBool::new(false),
+            in_flight: AtomicBool::new(false),
+            backed_up: AtomicBool::new(false),
+        }
+    }
+
+    /// Buffer `queued`, returning the message dropped to make room, if any.
+    fn push(&self, queued: Queued) -> Option<OutgoingMessage> {
+        let dropped = {
+            let mut messages = self.messages.lock().unwrap();
+            if messages.len() < self.limits.capacity {
+                messages.push_back(queued);
+                None
+            } else {
+                match self.limits.drop_policy {
+                    BufferDropPolicy::DropNewest => Some(queued.msg),
+                    BufferDropPolicy::DropOldest => {
+                        let oldest = messages.pop_front();
+                        messages.push_back(queued);
+                        oldest.map(|oldest| oldest.msg)
+                    }
+                }
+            }
+        };
+        self.ready.notify_one();
+        dropped
+    }
+
+    /// The next message, waiting for one; `None` once closed and drained.
+    /// The message stays in flight until [`finish`](Self::finish).
+    async fn pop(&self) -> Option<Queued> {
+        loop {
+            let next = {
+                let mut messages = self.messages.lock().unwrap();
+                let next = messages.pop_front();
+                self.in_flight.store(next.is_some(), Ordering::SeqCst);
+                next
+            };
+            if next.is_some() || self.closed.load(Ordering::SeqCst) {
+                return next;
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    /// The popped message reached its final outcome.
+    fn finish(&self) {
+        self.in_flight.store(false, Ordering::SeqCst);
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.ready.notify_one();
+    }
+
+    /// Buffered messages, plus the one in flight.
+    fn depth(&self) -> usize {
+        let messages = self.messages.lock().unwrap();
+        messages.len() + usize::from(self.in_flight.load(Ordering::SeqCst))
+    }
+
+    fn is_backed_up(&self) -> bool {
+        self.limits
+            .high_water_mark
+            .is_some_and(|mark| self.depth() >= mark)
+    }
+
+    /// Log when the buffer crosses the high-water mark, in either direction.
+    fn check_high_water_mark(&self, reaction_id: &str, broker: &str) {
+        let Some(mark) = self.limits.high_water_mark else {
+            return;
+        };
+        let backed_up = self.is_backed_up();
+        if self.backed_up.swap(backed_up, Ordering::SeqCst) == backed_up {
+            return;
+        }
+        if backed_up {
+            warn!(
+                "[{reaction_id}] Broker '{broker}' is backed up: {} message(s) buffered, high-water mark {mark}",
+                self.depth()
+            );
+        } else {
+            info!("[{reaction_id}] Broker '{broker}' buffer back below the high-water mark {mark}");
+        }
+    }
+}
+
+struct BrokerLink {
+    name: String,
+    buffer: Arc<BrokerBuffer>,
+    stats: Arc<BrokerStats>,
+}
+
+/// Publishes each message to all configured brokers.
+pub struct FanOut {
+    reaction_id: String,
+    links: Vec<BrokerLink>,
+    on_publish: Option<PublishHook>,
+    queries: Arc<QueryCounters>,
+}
+
+impl Drop for FanOut {
+    fn drop(&mut self) {
+        for link in &self.links {
+            link.buffer.close();
+        }
+    }
+}
+
+/// Count the outcome of publishing `msg` to `broker` and report it to the
+/// hook, if any.
+fn notify(
+    on_publish: &Option<PublishHook>,
+    queries: &QueryCounters,
+    broker: &str,
+    msg: &OutgoingMessage,
+    outcome: PublishOutcome,
+) {
+    queries.record(msg, &outcome);
+    if let Some(hook) = on_publish {
+        hook(PublishRecord {
+            broker: broker.to_string(),
+            topic: msg.topic.clone(),
+            payload: msg.payload.clone(),
+            origin: msg.origin.clone(),
+            outcome,
+        });
+    }
+}
+
+impl FanOut {
+    /// Spawn one publishing task per `(name, client)` pair, each with a
+    /// buffer of `buffer_capacity` messages, dropping the newest when full.
+    ///
+    /// `publish_timeout` bounds each attempt to hand a message to the client
+    /// and how often a timed-out message is retried; `None` waits
+    /// indefinitely. `on_publish` is called with the final outcome
+    /// of every message for every broker. The tasks exit once the `FanOut` is
+    /// dropped and their buffers drain.
+    pub fn new(
+        reaction_id: impl Into<String>,
+        buffer_capacity: usize,
+        publish_timeout: Option<PublishTimeout>,
+        on_publish: Option<PublishHook>,
+        clients: Vec<(String, Arc<dyn PublishClient>)>,
+    ) -> Self {
+        Self::with_limits(
+            reaction_id,
+            BufferLimits::new(buffer_capacity),
+            publish_timeout,
+            on_publish,
+            system_clock(),
+            clients,
+        )
+    }
+
+    /// Like [`new`](Self::new), with each broker's buffer bounded by `limits`
+    /// and publish waits measured, and errors rate-limited, by `clock`.
+    pub fn with_limits(
+        reaction_id: impl Into<String>,
+        limits: BufferLimits,
+        publish_timeout: Option<PublishTimeout>,
+        on_publish: Option<PublishHook>,
+        clock: SharedClock,
+        clients: Vec<(String, Arc<dyn PublishClient>)>,
+    ) -> Self {
+        let reaction_id = reaction_id.into();
+        let queries = Arc::new(QueryCounters::default());
+        let links = clients
+            .into_iter()
+            .map(|(name, client)| {
+                let buffer = Arc::new(BrokerBuffer::new(limits));
+                let stats = Arc::new(BrokerStats::default());
+
+                let task_buffer = buffer.clone();
+                let task_stats = stats.clone();
+                let task_name = name.clone();
+                let task_reaction_id = reaction_id.clone();
+                let task_on_publish = on_publish.clone();
+                let task_queries = queries.clone();
+                let clock = clock.clone();
+                tokio::spawn(async move {
+                    let mut error_log = LogLimiter::default();
+                    while let Some(Queued { msg, replay }) = task_buffer.pop().await {
+                        for suppressed in error_log.summaries(clock.now_instant()) {
+                            warn!("[{task_reaction_id}] Broker '{task_name}': {suppressed}");
+                        }
+                        let mut timeouts = 0;
+                        loop {
+                            let started = clock.now_instant();
+                            let publish = if replay {
+                                client.republish(
+                                    msg.topic.clone(),
+                                    msg.qos,
+                                    msg.retain,
+                                    msg.payload.clone(),
+                                    msg.user_properties.clone(),
+                                )
+                            } else {
+                                client.publish_with_user_properties(
+                                    msg.topic.clone(),
+                                    msg.qos,
+                                    msg.retain,
+                                    msg.payload.clone(),
+                                    msg.user_properties.clone(),
+                                )
+                            };
+                            let outcome = match publish_timeout {
+                                Some(timeout) => {
+                                    tokio::time::timeout(timeout.limit, publish).await.ok()
+                                }
+                                None => Some(publish.await),
+                            };
+                            task_stats.record_wait(clock.now_instant() - started);
+
+                            match outcome {
+                                Some(Ok(())) => {
+                                    task_stats.published.fetch_add(1, Ordering::Relaxed);
+                                    notify(
+                                        &task_on_publish,
+                                        &task_queries,
+                                        &task_name,
+                                        &msg,
+                                        PublishOutcome::Published,
+                                    );
+                                }
+                                Some(Err(e)) => {
+                                    task_stats.failed.fetch_add(1, Ordering::Relaxed);
+                                    if error_log.admit(&msg.topic, "publish", clock.now_instant()) {
+                                        error!(
+                                            reaction_id = %task_reaction_id,
+                                            broker = %task_name,
+                                            topic = %msg.topic,
+                                            query_id = msg.query_id(),
+                                            sequence = msg.sequence(),
+                                            "[{task_reaction_id}] Failed to publish to broker '{task_name}' on topic '{}': {e}",
+                                            msg.topic
+                                        );
+                                    }
+                                    notify(
+                                        &task_on_publish,
+                                        &task_queries,
+                                        &task_name,
+                                        &msg,
+                                        PublishOutcome::Failed(e.to_string()),
+                                    );
+                                }
+                                None => {
+                                    task_stats.timed_out.fetch_add(1, Ordering::Relaxed);
+                                    let retries = publish_timeout.map_or(0, |t| t.retries);
+                                    if timeouts >= retries {
+                                        task_stats.failed.fetch_add(1, Ordering::Relaxed);
+                                        let attempts = timeouts + 1;
+                                        if error_log.admit(&msg.topic, "publish", clock.now_instant()) {
+                                            error!(
+                                                reaction_id = %task_reaction_id,
+                                                broker = %task_name,
+                                                topic = %msg.topic,
+                                                query_id = msg.query_id(),
+                                                sequence = msg.sequence(),
+                                                "[{task_reaction_id}] Giving up publishing to broker '{task_name}' on topic '{}': timed out {attempts} times",
+                                                msg.topic
+                                            );
+                                        }
+                                        notify(
+                                            &task_on_publish,
+                                            &task_queries,
+                                            &task_name,
+                                            &msg,
+                                            PublishOutcome::Failed(format!(
+                                                "publish timed out {attempts} times"
+                                            )),
+                                        );
+                                        break;
+                                    }
+                                    timeouts += 1;
+                                    if error_log.admit(&msg.topic, "timeout", clock.now_instant()) {
+                                        warn!(
+                                            reaction_id = %task_reaction_id,
+                                            broker = %task_name,
+                                            topic = %msg.topic,
+                                            query_id = msg.query_id(),
+                                            sequence = msg.sequence(),
+                                            "[{task_reaction_id}] Publish to broker '{task_name}' on topic '{}' timed out; retrying",
+                                            msg.topic
+                                        );
+                                    }
+                                    continue;
+                                }
+                            }
+                            break;
+                        }
+                        task_buffer.finish();
+                        task_buffer.check_high_water_mark(&task_reaction_id, &task_name);
+                    }
+                });
+
+                BrokerLink {
+                    name,
+                    buffer,
+                    stats,
+                }
+            })
+            .collect();
+
+        Self {
+            reaction_id,
+            links,
+            on_publish,
+            queries,
+        }
+    }
+
+    /// Queue `msg` for every broker without waiting for any of them.
+    ///
+    /// Brokers whose buffer is full drop a message, per their
+    /// [`BufferDropPolicy`], and count it.
+    pub fn publish(&self, msg: OutgoingMessage) {
+        for link in &self.links {
+            self.enqueue(
+                link,
+                Queued {
+                    msg: msg.clone(),
+                    replay: false,
+                },
+            );
+        }
+    }
+
+    /// Queue `messages`, which `broker` already accepted once, behind the
+    /// messages buffered for it, e.g. retained messages the broker lost in a
+    /// failover. Returns `false` if there is no broker named `broker`.
+    pub fn replay(&self, broker: &str, messages: Vec<OutgoingMessage>) -> bool {
+        let Some(link) = self.links.iter().find(|link| link.name == broker) else {
+            return false;
+        };
+        for msg in messages {
+            self.enqueue(link, Queued { msg, replay: true });
+        }
+        true
+    }
+
+    /// Buffer `queued` for `link`, counting and reporting any dropped message.
+    fn enqueue(&self, link: &BrokerLink, queued: Queued) {
+        if let Some(dropped) = link.buffer.push(queued) {
+            link.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                reaction_id = %self.reaction_id,
+                broker = %link.name,
+                topic = %dropped.topic,
+                query_id = dropped.query_id(),
+                sequence = dropped.sequence(),
+                "[{}] Dropping message for broker '{}' on topic '{}': buffer full",
+                self.reaction_id,
+                link.name,
+                dropped.topic
+            );
+            notify(
+                &self.on_publish,
+                &self.queries,
+                &link.name,
+                &dropped,
+                PublishOutcome::Dropped,
+            );
+        }
+        link.buffer
+            .check_high_water_mark(&self.reaction_id, &link.name);
+    }
+
+    /// Messages buffered or being published across all brokers, not yet
+    /// handed to a client.
+    pub fn queue_depth(&self) -> usize {
+        self.links.iter().map(|link| link.buffer.depth()).sum()
+    }
+
+    /// Whether some broker's buffer is at or above its high-water mark.
+    pub fn is_backed_up(&self) -> bool {
+        self.links.iter().any(|link| link.buffer.is_backed_up())
+    }
+
+    /// Count `count` results of `query_id`, or items of them, that could not
+    /// be turned into messages.
+    pub fn record_query_errors(&self, query_id: &str, count: u64) {
+        self.queries
+            .update(query_id, |metrics| metrics.errors += count);
+    }
+
+    /// Count a result of `query_id` discarded because the query was disabled.
+    pub fn record_query_muted(&self, query_id: &str) {
+        self.queries.update(query_id, |metrics| metrics.muted += 1);
+    }
+
+    /// Current counters for every query that produced messages or errors.
+    pub fn query_metrics(&self) -> HashMap<String, QueryMetrics> {
+        self.queries.queries.lock().unwrap().clone()
+    }
+
+    /// Current counters for every broker, in configuration order.
+    pub fn stats(&self) -> Vec<BrokerStatsSnapshot> {
+        self.links
+            .iter()
+            .map(|link| BrokerStatsSnapshot {
+                broker: link.name.clone(),
+                published: link.stats.published.load(Ordering::Relaxed),
+                failed: link.stats.failed.load(Ordering::Relaxed),
+                dropped: link.stats.dropped.load(Ordering::Relaxed),
+                timed_out: link.stats.timed_out.load(Ordering::Relaxed),
+                publish_wait_total: Duration::from_micros(
+                    link.stats.wait_micros_total.load(Ordering::Relaxed),
+                ),
+                publish_wait_max: Duration::from_micros(
+                    link.stats.wait_micros_max.load(Ordering::Relaxed),
+                ),
+                queue_depth: link.buffer.depth(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::{FailingClient, RecordingClient, StalledClient};
+    use async_trait::async_trait;
+    use drasi_mqtt_connection::ManualClock;
+    use rumqttc::{AsyncClient, MqttOptions};
+    use std::sync::atomic::AtomicBool;
+
+    fn message(topic: &str) -> OutgoingMessage {
+        OutgoingMessage {
+            topic: topic.to_string(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            payload: b"{}".to_vec(),
+            user_properties: Vec::new(),
+            origin: None,
+        }
+    }
+
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn test_publishes_to_every_broker() {
+        let local = Arc::new(RecordingClient::default());
+        let cloud = Arc::new(RecordingClient::default());
+        let fanout = FanOut::new(
+            "r1",
+            10,
+            None,
+            None,
+            vec![
+                ("local".to_string(), local.clone() as Arc<dyn PublishClient>),
+                ("cloud".to_string(), cloud.clone() as Arc<dyn PublishClient>),
+            ],
+        );
+
+        fanout.publish(message("alerts/a"));
+        fanout.publish(message("alerts/b"));
+        settle().await;
+
+        assert_eq!(local.topics(), vec!["alerts/a", "alerts/b"]);
+        assert_eq!(cloud.topics(), vec!["alerts/a", "alerts/b"]);
+        assert!(fanout.stats().iter().all(|s| s.published == 2));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_broker_does_not_block_others() {
+        let local = Arc::new(RecordingClient::default());
+        let fanout = FanOut::new(
+            "r1",
+            2,
+            None,
+            None,
+            vec![
+                ("local".to_string(), local.clone() as Arc<dyn PublishClient>),
+                (
+                    "cloud".to_string(),
+                    Arc::new(StalledClient) as Arc<dyn PublishClient>,
+                ),
+            ],
+        );
+
+        for i in 0..5 {
+            fanout.publish(message(&format!("alerts/{i}")));
+            settle().await;
+        }
+
+        assert_eq!(local.topics().len(), 5);
+
+        let stats = fanout.stats();
+        assert_eq!(stats[0].broker, "local");
+        assert_eq!(stats[0].published, 5);
+        assert_eq!(stats[0].dropped, 0);
+        // One message is stuck in the stalled publish, two fill the buffer.
+        assert_eq!(stats[1].broker, "cloud");
+        assert_eq!(stats[1].published, 0);
+        assert_eq!(stats[1].dropped, 2);
+    }
+
+    #[tokio::test]
+    async fn test_query_metrics_count_outcomes_per_query() {
+        let fanout = FanOut::new(
+            "r1",
+            10,
+            None,
+            None,
+            vec![
+                (
+                    "ok".to_string(),
+                    Arc::new(RecordingClient::default()) as Arc<dyn PublishClient>,
+                ),
+                (
+                    "broken".to_string(),
+                    Arc::new(FailingClient) as Arc<dyn PublishClient>,
+                ),
+            ],
+        );
+        let from = |query_id: &str| OutgoingMessage {
+            origin: Some(PublishOrigin {
+                query_id: query_id.to_string(),
+                sequence: 1,
+                op: None,
+            }),
+            ..message("alerts/a")
+        };
+
+        fanout.publish(from("q1"));
+        fanout.publish(from("q1"));
+        fanout.publish(from("q2"));
+        fanout.record_query_errors("q2", 3);
+        settle().await;
+
+        let metrics = fanout.query_metrics();
+        assert_eq!(
+            metrics["q1"],
+            QueryMetrics {
+                published: 2,
+                failed: 2,
+                dropped: 0,
+                errors: 0,
+                muted: 0,
+            }
+        );
+        assert_eq!(
+            metrics["q2"],
+            QueryMetrics {
+                published: 1,
+                failed: 1,
+                dropped: 0,
+                errors: 3,
+                muted: 0,
+            }
+        );
+    }
+
+    fn stalled(limits: BufferLimits, on_publish: Option<PublishHook>) -> FanOut {
+        FanOut::with_limits(
+            "r1",
+            limits,
+            None,
+            on_publish,
+            system_clock(),
+            vec![(
+                "cloud".to_string(),
+                Arc::new(StalledClient) as Arc<dyn PublishClient>,
+            )],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_keeps_newest_messages() {
+        let (hook, records) = crate::audit::collecting_hook();
+        let fanout = stalled(
+            BufferLimits {
+                drop_policy: BufferDropPolicy::DropOldest,
+                ..BufferLimits::new(3)
+            },
+            Some(hook),
+        );
+
+        // The first message is stuck in the stalled publish.
+        fanout.publish(message("alerts/0"));
+        settle().await;
+        for i in 1..6 {
+            fanout.publish(message(&format!("alerts/{i}")));
+        }
+
+        // Three buffered, plus the one in flight.
+        let stats = &fanout.stats()[0];
+        assert_eq!(stats.dropped, 2);
+        assert_eq!(stats.queue_depth, 4);
+        let dropped: Vec<String> = records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|record| record.topic.clone())
+            .collect();
+        assert_eq!(dropped, vec!["alerts/1", "alerts/2"]);
+    }
+
+    #[tokio::test]
+    async fn test_high_water_mark_marks_broker_backed_up() {
+        let fanout = stalled(
+            BufferLimits {
+                high_water_mark: Some(3),
+                ..BufferLimits::new(10)
+            },
+            None,
+        );
+
+        // The stuck message counts toward the depth.
+        fanout.publish(message("alerts/0"));
+        settle().await;
+        fanout.publish(message("alerts/1"));
+        assert!(!fanout.is_backed_up());
+        fanout.publish(message("alerts/2"));
+        assert!(fanout.is_backed_up());
+        assert_eq!(fanout.stats()[0].queue_depth, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retried_message_counts_toward_depth() {
+        let fanout = FanOut::with_limits(
+            "r1",
+            BufferLimits {
+                high_water_mark: Some(1),
+                ..BufferLimits::new(10)
+            },
+            Some(PublishTimeout::new(Duration::from_millis(50))),
+            None,
+            system_clock(),
+            vec![(
+                "cloud".to_string(),
+                Arc::new(StalledClient) as Arc<dyn PublishClient>,
+            )],
+        );
+
+        fanout.publish(message("alerts/a"));
+        tokio::time::sleep(Duration::from_millis(120)).await;
+
+        let stats = &fanout.stats()[0];
+        assert_eq!(stats.timed_out, 2);
+        assert_eq!(stats.queue_depth, 1);
+        assert_eq!(fanout.queue_depth(), 1);
+        assert!(fanout.is_backed_up());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_full_request_queue_times_out_without_blocking() {
+        // Request channel of one and no eventloop draining it: the first
+        // publish fills the channel, the second waits on it.
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("t", "localhost", 1883), 1);
+        let local = Arc::new(RecordingClient::default());
+        let fanout = FanOut::new(
+            "r1",
+            10,
+            Some(PublishTimeout::new(Duration::from_millis(50))),
+            None,
+            vec![
+                (
+                    "tiny".to_string(),
+                    Arc::new(client) as Arc<dyn PublishClient>,
+                ),
+                ("local".to_string(), local.clone() as Arc<dyn PublishClient>),
+            ],
+        );
+
+        fanout.publish(message("alerts/a"));
+        fanout.publish(message("alerts/b"));
+        tokio::time::sleep(Duration::from_millis(175)).await;
+
+        assert_eq!(local.topics(), vec!["alerts/a", "alerts/b"]);
+        let tiny = &fanout.stats()[0];
+        assert_eq!(tiny.published, 1);
+        assert_eq!(tiny.failed, 0);
+        assert_eq!(tiny.dropped, 0);
+        assert_eq!(tiny.timed_out, 3);
+        assert_eq!(tiny.publish_wait_max, Duration::from_millis(50));
+        assert_eq!(tiny.publish_wait_total, Duration::from_millis(150));
+    }
+
+    /// Never completes its first publish, then records like [`RecordingClient`].
+    #[derive(Default)]
+    struct StallsOnceClient {
+        stalled: AtomicBool,
+        inner: RecordingClient,
+    }
+
+    #[async_trait]
+    impl PublishClient for StallsOnceClient {
+        async fn publish(
+            &self,
+            topic: String,
+            qos: QoS,
+            retain: bool,
+            payload: Vec<u8>,
+        ) -> anyhow::Result<()> {
+            if !self.stalled.swap(true, Ordering::SeqCst) {
+                std::future::pending::<()>().await;
+            }
+            self.inner.publish(topic, qos, retain, payload).await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timed_out_message_is_retried_in_order() {
+        let client = Arc::new(StallsOnceClient::default());
+        let fanout = FanOut::new(
+            "r1",
+            10,
+            Some(PublishTimeout::new(Duration::from_millis(50))),
+            None,
+            vec![("b".to_string(), client.clone() as Arc<dyn PublishClient>)],
+        );
+
+        fanout.publish(message("alerts/a"));
+        fanout.publish(message("alerts/b"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(client.inner.topics(), vec!["alerts/a", "alerts/b"]);
+        let stats = &fanout.stats()[0];
+        assert_eq!(stats.published, 2);
+        assert_eq!(stats.timed_out, 1);
+    }
+
+    /// Moves a manual clock forward on every publish, then records.
+    struct AdvancingClient {
+        clock: Arc<ManualClock>,
+        inner: RecordingClient,
+    }
+
+    #[async_trait]
+    impl PublishClient for AdvancingClient {
+        async fn publish(
+            &self,
+            topic: String,
+            qos: QoS,
+            retain: bool,
+            payload: Vec<u8>,
+        ) -> anyhow::Result<()> {
+            self.clock.advance(Duration::from_secs(2));
+            self.inner.publish(topic, qos, retain, payload).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_waits_measured_by_injected_clock() {
+        let clock = Arc::new(ManualClock::new(0));
+        let client = Arc::new(AdvancingClient {
+            clock: clock.clone(),
+            inner: RecordingClient::default(),
+        });
+        let fanout = FanOut::with_limits(
+            "r1",
+            BufferLimits::new(10),
+            None,
+            None,
+            clock,
+            vec![("b".to_string(), client.clone() as Arc<dyn PublishClient>)],
+        );
+
+        fanout.publish(message("alerts/a"));
+        fanout.publish(message("alerts/b"));
+        settle().await;
+
+        assert_eq!(client.inner.topics(), vec!["alerts/a", "alerts/b"]);
+        let stats = &fanout.stats()[0];
+        assert_eq!(stats.publish_wait_max, Duration::from_secs(2));
+        assert_eq!(stats.publish_wait_total, Duration::from_secs(4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_message_given_up_after_retries() {
+        let (hook, records) = crate::audit::collecting_hook();
+        let client = Arc::new(StallsOnceClient::default());
+        let fanout = FanOut::new(
+            "r1",
+            10,
+            Some(PublishTimeout {
+                limit: Duration::from_millis(50),
+                retries: 0,
+            }),
+            Some(hook),
+            vec![("b".to_string(), client.clone() as Arc<dyn PublishClient>)],
+        );
+
+        fanout.publish(message("alerts/a"));
+        fanout.publish(message("alerts/b"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(client.inner.topics(), vec!["alerts/b"]);
+        let stats = &fanout.stats()[0];
+        assert_eq!(stats.published, 1);
+        assert_eq!(stats.failed, 1);
+        assert_eq!(stats.timed_out, 1);
+        assert_eq!(stats.queue_depth, 0);
+        let records = records.lock().unwrap();
+        assert_eq!(records[0].topic, "alerts/a");
+        assert!(matches!(records[0].outcome, PublishOutcome::Failed(_)));
+    }
+
+    #[tokio::test]
+    async fn test_publish_hook_records_outcomes() {
+        let (hook, records) = crate::audit::collecting_hook();
+        let fanout = FanOut::new(
+            "r1",
+            10,
+            None,
+            Some(hook),
+            vec![
+                (
+                    "ok".to_string(),
+                    Arc::new(RecordingClient::default()) as Arc<dyn PublishClient>,
+                ),
+                (
+                    "broken".to_string(),
+                    Arc::new(FailingClient) as Arc<dyn PublishClient>,
+                ),
+            ],
+        );
+
+        let origin = PublishOrigin {
+            query_id: "q1".to_string(),
+            sequence: 4,
+            op: Some(crate::serializer::Op::Insert),
+        };
+        fanout.publish(OutgoingMessage {
+            user_properties: Vec::new(),
+            origin: Some(origin.clone()),
+            ..message("alerts/a")
+        });
+        settle().await;
+
+        let mut records = records.lock().unwrap().clone();
+        records.sort_by(|a, b| a.broker.cmp(&b.broker));
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].broker, "broken");
+        assert!(matches!(records[0].outcome, PublishOutcome::Failed(_)));
+        assert_eq!(records[1].broker, "ok");
+        assert_eq!(records[1].outcome, PublishOutcome::Published);
+        for record in &records {
+            assert_eq!(record.topic, "alerts/a");
+            assert_eq!(record.payload, b"{}");
+            assert_eq!(record.origin.as_ref(), Some(&origin));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_failure_event_carries_context() {
+        use drasi_mqtt_connection::trace_capture::EventCapture;
+
+        let capture = EventCapture::new();
+        let _guard = capture.set_default();
+        let fanout = FanOut::new(
+            "r1",
+            10,
+            None,
+            None,
+            vec![(
+                "broken".to_string(),
+                Arc::new(FailingClient) as Arc<dyn PublishClient>,
+            )],
+        );
+        fanout.publish(OutgoingMessage {
+            origin: Some(PublishOrigin {
+                query_id: "q1".to_string(),
+                sequence: 4,
+                op: None,
+            }),
+            ..message("alerts/a")
+        });
+        settle().await;
+
+        let errors = capture.at(tracing::Level::ERROR);
+        assert_eq!(errors.len(), 1);
+        for (name, value) in [
+            ("reaction_id", "r1"),
+            ("broker", "broken"),
+            ("topic", "alerts/a"),
+            ("query_id", "q1"),
+            ("sequence", "4"),
+        ] {
+            assert_eq!(errors[0].field(name), Some(value), "{name}");
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Periodic status messages describing the reaction itself.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rumqttc::QoS;
+use tokio::task::JoinHandle;
+
+use crate::fanout::{FanOut, OutgoingMessage};
+
+/// Status reported while the reaction is processing results.
+pub const STATUS_RUNNING: &str = "running";
+/// Status reported once, when the reaction is stopped.
+pub const STATUS_STOPPING: &str = "stopping";
+
+/// Build a heartbeat payload, e.g. `{"status":"running","published":1234,"queue_depth":0}`.
+///
+/// `published` counts messages produced by the reaction; `queue_depth` is the
+/// number of messages buffered for brokers but not yet handed to a client.
+pub fn heartbeat_payload(
+    reaction_id: &str,
+    status: &str,
+    published: u64,
+    queue_depth: usize,
+) -> Vec<u8> {
+    serde_json::json!({
+        "reaction_id": reaction_id,
+        "status": status,
+        "published": published,
+        "queue_depth": queue_depth,
+    })
+    .to_string()
+    .into_bytes()
+}
+
+/// Spawn a task publishing a `running` heartbeat to `topic` every `interval`.
+///
+/// The first heartbeat is sent immediately. Abort the returned handle to stop it.
+pub fn spawn(
+    reaction_id: String,
+    topic: String,
+    interval: Duration,
+    fanout: Arc<FanOut>,
+    published: Arc<AtomicU64>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let payload = heartbeat_payload(
+                &reaction_id,
+                STATUS_RUNNING,
+                published.load(Ordering::Relaxed),
+                fanout.queue_depth(),
+            );
+            fanout.publish(OutgoingMessage {
+                topic: topic.clone(),
+                qos: QoS::AtLeastOnce,
+                retain: false,
+                payload,
+                user_properties: Vec::new(),
+                origin: None,
+            });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::RecordingClient;
+    use crate::client::PublishClient;
+    use serde_json::Value;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat_published_on_interval() {
+        let client = Arc::new(RecordingClient::default());
+        let fanout = Arc::new(FanOut::new(
+            "r1",
+            10,
+            None,
+            None,
+            vec![(
+                "primary".to_string(),
+                client.clone() as Arc<dyn PublishClient>,
+            )],
+        ));
+        let published = Arc::new(AtomicU64::new(42));
+
+        let handle = spawn(
+            "r1".to_string(),
+            "status/r1".to_string(),
+            Duration::from_millis(100),
+            fanout,
+            published,
+        );
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        handle.abort();
+        tokio::task::yield_now().await;
+
+        let records = client.published.lock().unwrap().clone();
+        // Ticks at 0ms, 100ms and 200ms.
+        assert_eq!(records.len(), 3);
+        for record in records {
+            assert_eq!(record.topic, "status/r1");
+            assert_eq!(record.qos, QoS::AtLeastOnce);
+            assert!(!record.retain);
+            let body: Value = serde_json::from_slice(&record.payload).unwrap();
+            assert_eq!(body["status"], "running");
+            assert_eq!(body["published"], 42);
+            assert_eq!(body["queue_depth"], 0);
+        }
+    }
+
+    #[test]
+    fn test_stopping_payload() {
+        let body: Value =
+            serde_json::from_slice(&heartbeat_payload("r1", STATUS_STOPPING, 7, 2)).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "reaction_id": "r1",
+                "status": "stopping",
+                "published": 7,
+                "queue_depth": 2,
+            })
+        );
+    }
+}
@@ -35,9 +35,28 @@
 //! // Pass `reaction` to DrasiLib::builder().with_reaction(reaction)
 //! ```
 
+pub mod audit;
+pub mod client;
 pub mod config;
+pub mod connection;
+pub mod dedup;
+pub mod fanout;
+pub mod heartbeat;
 pub mod publisher;
 pub mod reaction;
+pub mod retained;
+pub mod serializer;
+pub mod snapshot;
+pub mod topic_alias;
+pub mod transform;
 
-pub use config::{MqttReactionConfig, MqttReactionConfigBuilder};
+pub use audit::{PublishHook, PublishOrigin, PublishOutcome, PublishRecord};
+pub use config::{
+    BrokerEndpoint, BufferDropPolicy, CredentialsFn, DedupKey, DeletePayloadMode, MetadataConfig,
+    MqttProtocol, MqttReactionConfig, MqttReactionConfigBuilder, TlsConfig, UnhandledDiffPolicy,
+};
+pub use fanout::{BrokerStatsSnapshot, BufferLimits, PublishTimeout, QueryMetrics};
+pub use drasi_mqtt_connection::{ClientIdSuffix, ConnectionConfig, MqttConnectionManager};
 pub use reaction::MqttReaction;
+pub use serializer::{Op, ResultSerializer, SerializeContext, TemplateSerializer};
+pub use transform::ResultTransform;
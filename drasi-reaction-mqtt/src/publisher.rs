@@ -14,96 +14,481 @@
 
 //! Utility functions for serializing query results to MQTT payloads.
 
-use handlebars::Handlebars;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use drasi_lib::channels::ResultDiff;
+use handlebars::{handlebars_helper, Handlebars};
 use serde_json::Value;
+use tracing::warn;
+
+use crate::config::{DeletePayloadMode, MetadataConfig, UnhandledDiffPolicy};
+use crate::serializer::{Op, SerializeContext};
+
+/// Result diffs of a single query result, split by operation.
+#[derive(Debug, Default)]
+pub struct DiffBatch {
+    pub added: Vec<Value>,
+    pub updated: Vec<Value>,
+    pub removed: Vec<Value>,
+}
+
+impl DiffBatch {
+    /// Iterate over all items in publish order: inserts, updates, then deletes.
+    pub fn items(&self) -> impl Iterator<Item = (Op, &Value)> {
+        self.added
+            .iter()
+            .map(|item| (Op::Insert, item))
+            .chain(self.updated.iter().map(|item| (Op::Update, item)))
+            .chain(self.removed.iter().map(|item| (Op::Delete, item)))
+    }
+
+    /// The operation shared by every item, if the batch holds only one kind.
+    pub fn single_op(&self) -> Option<Op> {
+        match (
+            self.added.is_empty(),
+            self.updated.is_empty(),
+            self.removed.is_empty(),
+        ) {
+            (false, true, true) => Some(Op::Insert),
+            (true, false, true) => Some(Op::Update),
+            (true, true, false) => Some(Op::Delete),
+            _ => None,
+        }
+    }
+
+    /// Sort each list by the value of `field`, keeping arrival order for
+    /// equal values. Items without the field, or with `null`, come last.
+    /// Values of different types order as booleans, numbers, strings, then
+    /// arrays and objects.
+    pub fn sort_by_field(&mut self, field: &str) {
+        for items in [&mut self.added, &mut self.updated, &mut self.removed] {
+            items.sort_by(|a, b| compare_keys(a.get(field), b.get(field)));
+        }
+    }
+}
+
+fn compare_keys(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Bool(_) => 0,
+            Value::Number(_) => 1,
+            Value::String(_) => 2,
+            Value::Array(_) | Value::Object(_) => 3,
+            Value::Null => 4,
+        }
+    }
+    let (a, b) = (a.unwrap_or(&Value::Null), b.unwrap_or(&Value::Null));
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            _ => a
+                .as_f64()
+                .unwrap_or(f64::NAN)
+                .total_cmp(&b.as_f64().unwrap_or(f64::NAN)),
+        },
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Array(_) | Value::Object(_), Value::Array(_) | Value::Object(_)) => {
+            a.to_string().cmp(&b.to_string())
+        }
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
 
-/// Serialize a query result into a list of (topic, payload) pairs.
+/// Split a query result's diffs into added/updated/removed lists.
 ///
-/// * `topic_template`: The MQTT topic (can be a Handlebars template).
-/// * `payload_template`: Optional Handlebars template for the payload.
+/// `Add`, `Update` (its `after` state) and `Delete` are handled.
+/// `Aggregation` and `Noop` are not published and are dealt with according
+/// to `policy`.
+pub fn partition_diffs(
+    query_id: &str,
+    results: &[ResultDiff],
+    policy: UnhandledDiffPolicy,
+) -> anyhow::Result<DiffBatch> {
+    let mut batch = DiffBatch::default();
+
+    for diff in results {
+        let unhandled = match diff {
+            ResultDiff::Add { data } => {
+                batch.added.push(data.clone());
+                continue;
+            }
+            ResultDiff::Delete { data } => {
+                batch.removed.push(data.clone());
+                continue;
+            }
+            ResultDiff::Update { after, .. } => {
+                batch.updated.push(after.clone());
+                continue;
+            }
+            ResultDiff::Aggregation { .. } => "aggregation",
+            ResultDiff::Noop => "noop",
+        };
+
+        match policy {
+            UnhandledDiffPolicy::Ignore => {}
+            UnhandledDiffPolicy::Log => {
+                warn!("Ignoring unhandled '{unhandled}' diff from query '{query_id}'");
+            }
+            UnhandledDiffPolicy::Error => {
+                anyhow::bail!("Unhandled '{unhandled}' diff from query '{query_id}'");
+            }
+        }
+    }
+
+    Ok(batch)
+}
+
+/// JSON formatting for payloads produced without a payload template.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JsonFormat {
+    /// Pretty-print with indentation and newlines.
+    pub pretty: bool,
+    /// Emit object keys in lexicographic order at every level.
+    pub sort_keys: bool,
+}
+
+/// Serialize `value` as JSON according to `format`.
+pub fn to_json_bytes(value: &Value, format: JsonFormat) -> anyhow::Result<Vec<u8>> {
+    let sorted;
+    let value = if format.sort_keys {
+        sorted = sort_keys(value);
+        &sorted
+    } else {
+        value
+    };
+
+    let bytes = if format.pretty {
+        serde_json::to_vec_pretty(value)?
+    } else {
+        serde_json::to_vec(value)?
+    };
+    Ok(bytes)
+}
+
+/// Copy of `value` whose objects have their keys inserted in sorted order.
+fn sort_keys(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k.clone(), sort_keys(v)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.iter().map(sort_keys).collect()),
+        other => other.clone(),
+    }
+}
+
+/// Publish-time metadata exposed to templates as `_meta`: `reaction_id`,
+/// `hostname`, `published_at` (RFC 3339), `published_at_ms` (Unix epoch
+/// milliseconds) and `result_timestamp` (RFC 3339, or null if unknown).
+pub fn meta_object(ctx: &SerializeContext) -> Value {
+    serde_json::json!({
+        "reaction_id": ctx.reaction_id,
+        "hostname": ctx.hostname,
+        "published_at": ctx.published_at.to_rfc3339(),
+        "published_at_ms": ctx.published_at.timestamp_millis(),
+        "result_timestamp": ctx.result_timestamp.map(|ts| ts.to_rfc3339()),
+    })
+}
+
+/// A new correlation id: a random UUID.
+pub fn correlation_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+handlebars_helper!(json_pointer: |value: Json, pointer: str| {
+    value.pointer(pointer).cloned().unwrap_or(Value::Null)
+});
+
+/// Handlebars registry with the helpers available to templates:
 ///
-/// Logic:
-/// 1. If `topic_template` contains "{{" OR `payload_template` is Some, we split the batch.
-///    For each item in added/updated/removed, we render the topic and payload.
-/// 2. Otherwise, we publish a single batched message to the static topic.
-pub fn result_to_payload(
+/// * `ptr`: the value at a JSON Pointer (RFC 6901) into its argument, e.g.
+///   `{{ptr this "/devices/0/id"}}`; empty if there is none.
+pub fn template_registry() -> Handlebars<'static> {
+    let mut registry = Handlebars::new();
+    registry.register_helper("ptr", Box::new(json_pointer));
+    registry
+}
+
+/// Register `partials`, by name, for templates rendered with `registry`.
+pub fn register_partials(
+    registry: &mut Handlebars<'static>,
+    partials: &HashMap<String, String>,
+) -> anyhow::Result<()> {
+    for (name, template) in partials {
+        registry
+            .register_partial(name, template)
+            .map_err(|e| anyhow::anyhow!("Invalid template 'partials.{name}': {e}"))?;
+    }
+    Ok(())
+}
+
+/// Compile `template` to check its syntax; `name` identifies it in the error.
+pub fn validate_template(name: &str, template: &str) -> anyhow::Result<()> {
+    handlebars::Template::compile(template)
+        .map(|_| ())
+        .map_err(|e| anyhow::anyhow!("Invalid template '{name}': {e}"))
+}
+
+/// Render the value templates of `user_properties` for a result of
+/// `query_id` with `sequence` and `op` (`None` for a batch message mixing
+/// operations); `reaction_id` is also available to the templates.
+pub fn render_user_properties(
+    registry: &Handlebars,
+    user_properties: &[(String, String)],
+    reaction_id: &str,
     query_id: &str,
     sequence: u64,
-    added: &[Value],
-    updated: &[Value],
-    removed: &[Value],
-    registry: &Handlebars,
-    topic_template: &str,
-    payload_template: Option<&str>,
-) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
-    let mut messages = Vec::new();
-
-    let split_mode = topic_template.contains("{{") || payload_template.is_some();
-
-    if split_mode {
-        // Helper to process a list
-        let mut process_list = |list: &[Value], op: &str| -> anyhow::Result<()> {
-            for item in list {
-                // Prepare context
-                let mut context = serde_json::to_value(item)?;
-                if let Value::Object(ref mut map) = context {
-                    map.insert("query_id".to_string(), query_id.into());
-                    map.insert("sequence".to_string(), sequence.into());
-                    map.insert("op".to_string(), op.into());
-                }
+    op: Option<Op>,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let context = serde_json::json!({
+        "reaction_id": reaction_id,
+        "query_id": query_id,
+        "sequence": sequence,
+        "op": op.as_ref().map(Op::as_str),
+    });
+    user_properties
+        .iter()
+        .map(|(name, template)| Ok((name.clone(), registry.render_template(template, &context)?)))
+        .collect()
+}
+
+/// Prepend `prefix` to `topic`, joined by exactly one `/`.
+///
+/// An empty `topic` stays empty, so that it is rejected by [`validate_topic`]
+/// rather than published to the bare prefix.
+pub fn prefixed_topic(prefix: Option<&str>, topic: &str) -> String {
+    match prefix {
+        Some(prefix) if !prefix.is_empty() && !topic.is_empty() => format!(
+            "{}/{}",
+            prefix.trim_end_matches('/'),
+            topic.trim_start_matches('/')
+        ),
+        _ => topic.to_string(),
+    }
+}
+
+/// Check that `topic` can be published to.
+///
+/// Rejects empty topics (e.g. a template whose fields were all missing) and
+/// topics containing wildcards or NUL, which brokers treat as a protocol
+/// error and answer by closing the connection. Empty levels, as in
+/// `"a//b"` or `"a/b/"`, are valid.
+pub fn validate_topic(topic: &str) -> anyhow::Result<()> {
+    if topic.is_empty() {
+        anyhow::bail!("Topic is empty");
+    }
+    if topic.contains(['+', '#', '\0']) {
+        anyhow::bail!("Topic '{topic}' contains a wildcard or NUL character");
+    }
+    Ok(())
+}
+
+/// Whether results are published one message per item rather than as a batch.
+pub fn is_split_mode(topic_template: &str, payload_template: Option<&str>) -> bool {
+    topic_template.contains("{{") || payload_template.is_some()
+}
+
+/// Renders query results into (topic, payload) pairs.
+///
+/// * `topic_template`: The MQTT topic (can be a Handlebars template).
+/// * `payload_template`: Optional Handlebars template for the payload.
+/// * `json`: Formatting of JSON payloads built without a payload template.
+/// * `include_meta`: Add publish-time metadata (see [`meta_object`]).
+/// * `correlation_ids`: Add a new `correlation_id` (see [`correlation_id`])
+///   to every item.
+/// * `topic_prefix`: Prepended to every topic (see [`prefixed_topic`]).
+/// * `delete_payload`: What delete messages contain.
+/// * `split_metadata`: Metadata keys of per-item JSON payloads; all of them
+///   when `None`.
+pub struct Renderer<'a> {
+    pub registry: &'a Handlebars<'a>,
+    pub topic_template: &'a str,
+    pub payload_template: Option<&'a str>,
+    pub json: JsonFormat,
+    pub include_meta: bool,
+    pub correlation_ids: bool,
+    pub topic_prefix: Option<&'a str>,
+    pub delete_payload: &'a DeletePayloadMode,
+    pub split_metadata: Option<&'a MetadataConfig>,
+}
+
+/// `{"<field>": <value>}` with the value of `field` in `item`, null if absent.
+fn id_only(item: &Value, field: &str) -> Value {
+    let id = item.get(field).cloned().unwrap_or(Value::Null);
+    Value::Object(serde_json::Map::from_iter([(field.to_string(), id)]))
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(
+        registry: &'a Handlebars<'a>,
+        topic_template: &'a str,
+        payload_template: Option<&'a str>,
+    ) -> Self {
+        static FULL: DeletePayloadMode = DeletePayloadMode::Full;
+        Self {
+            registry,
+            topic_template,
+            payload_template,
+            json: JsonFormat::default(),
+            include_meta: false,
+            correlation_ids: false,
+            topic_prefix: None,
+            delete_payload: &FULL,
+            split_metadata: None,
+        }
+    }
 
-                // Render Topic
-                let topic = registry.render_template(topic_template, &context)?;
+    /// Render a single result item into a (topic, payload) pair.
+    ///
+    /// The item is rendered with `query_id`, `sequence` and `op` (and `_meta`
+    /// and `correlation_id` when enabled) merged into its context. Without a payload template the
+    /// item is serialized as JSON with the metadata picked by `split_metadata`.
+    /// Deletes follow `delete_payload`.
+    pub fn render_item(
+        &self,
+        query_id: &str,
+        op: Op,
+        item: &Value,
+        ctx: &SerializeContext,
+    ) -> anyhow::Result<(String, Vec<u8>)> {
+        // Prepare context
+        let mut metadata = vec![
+            ("query_id", Value::from(query_id)),
+            ("sequence", ctx.sequence.into()),
+            ("op", op.as_str().into()),
+        ];
+        if self.include_meta {
+            metadata.push(("_meta", meta_object(ctx)));
+        }
+        if self.correlation_ids {
+            metadata.push(("correlation_id", correlation_id().into()));
+        }
+        let mut context = item.clone();
+        if let Value::Object(ref mut map) = context {
+            for (key, value) in &metadata {
+                map.insert(key.to_string(), value.clone());
+            }
+        }
 
-                // Render Payload
-                let payload = if let Some(tmpl) = payload_template {
-                    registry.render_template(tmpl, &context)?.into_bytes()
-                } else {
-                    // If no payload template but we are splitting (due to dynamic topic),
-                    // we serialize the single item + metadata as JSON.
-                    serde_json::to_vec(&context)?
-                };
+        // Render Topic
+        let topic = self
+            .registry
+            .render_template(self.topic_template, &context)?;
+        let topic = prefixed_topic(self.topic_prefix, &topic);
 
-                messages.push((topic, payload));
+        // Render Payload
+        let payload = match (op, self.delete_payload, self.payload_template) {
+            (Op::Delete, DeletePayloadMode::IdOnly(field), _) => {
+                to_json_bytes(&id_only(item, field), self.json)?
             }
-            Ok(())
+            (Op::Delete, DeletePayloadMode::Custom(tmpl), _) => {
+                self.registry.render_template(tmpl, &context)?.into_bytes()
+            }
+            (_, _, Some(tmpl)) => self.registry.render_template(tmpl, &context)?.into_bytes(),
+            // If no payload template but we are splitting (due to dynamic topic),
+            // we serialize the single item + metadata as JSON.
+            (_, _, None) => match self.split_metadata {
+                None => to_json_bytes(&context, self.json)?,
+                Some(split_metadata) => {
+                    let mut payload = item.clone();
+                    if let Value::Object(ref mut map) = payload {
+                        for (key, value) in metadata {
+                            if let Some(name) = split_metadata.name_for(key) {
+                                map.insert(name.to_string(), value);
+                            }
+                        }
+                    }
+                    to_json_bytes(&payload, self.json)?
+                }
+            },
         };
 
-        process_list(added, "insert")?;
-        process_list(updated, "update")?;
-        process_list(removed, "delete")?;
-    } else {
-        // Batch mode: Static topic, default massive JSON payload
-        let payload = serde_json::json!({
-            "query_id": query_id,
-            "sequence": sequence,
-            "added": added,
-            "updated": updated,
-            "removed": removed,
-        });
-        let bytes = serde_json::to_vec(&payload)?;
-        messages.push((topic_template.to_string(), bytes));
+        Ok((topic, payload))
     }
 
-    Ok(messages)
+    /// Serialize a query result into a list of (topic, payload) pairs.
+    ///
+    /// Logic:
+    /// 1. If `topic_template` contains "{{" OR `payload_template` is Some, we split the batch.
+    ///    For each item in added/updated/removed, we render the topic and payload.
+    /// 2. Otherwise, we publish a single batched message to the static topic.
+    pub fn result_to_payload(
+        &self,
+        query_id: &str,
+        batch: &DiffBatch,
+        ctx: &SerializeContext,
+    ) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        let mut messages = Vec::new();
+
+        if is_split_mode(self.topic_template, self.payload_template) {
+            for (op, item) in batch.items() {
+                messages.push(self.render_item(query_id, op, item, ctx)?);
+            }
+        } else {
+            // Batch mode: Static topic, default massive JSON payload
+            let removed = match self.delete_payload {
+                DeletePayloadMode::IdOnly(field) => batch
+                    .removed
+                    .iter()
+                    .map(|item| id_only(item, field))
+                    .collect(),
+                _ => batch.removed.clone(),
+            };
+            let mut payload = serde_json::json!({
+                "query_id": query_id,
+                "sequence": ctx.sequence,
+                "added": batch.added,
+                "updated": batch.updated,
+                "removed": removed,
+            });
+            if self.include_meta {
+                payload["published_at"] = ctx.published_at.to_rfc3339().into();
+            }
+            let bytes = to_json_bytes(&payload, self.json)?;
+            let topic = prefixed_topic(self.topic_prefix, self.topic_template);
+            messages.push((topic, bytes));
+        }
+
+        Ok(messages)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::DateTime;
+    use std::collections::HashMap;
+
+    fn ctx() -> SerializeContext<'static> {
+        SerializeContext::new("r1", 1)
+    }
+
+    fn added(items: Vec<Value>) -> DiffBatch {
+        DiffBatch {
+            added: items,
+            ..Default::default()
+        }
+    }
 
     #[test]
     fn test_batch_mode() {
         let registry = Handlebars::new();
-        let added = vec![serde_json::json!({"name": "sensor-1", "temp": 35.0})];
-        let messages = result_to_payload(
-            "q1", 1, &added, &[], &[], 
-            &registry, "static/topic", None
-        ).unwrap();
-        
+        let batch = added(vec![serde_json::json!({"name": "sensor-1", "temp": 35.0})]);
+        let messages = Renderer::new(&registry, "static/topic", None)
+            .result_to_payload("q1", &batch, &ctx())
+            .unwrap();
+
         assert_eq!(messages.len(), 1);
         assert_eq!(messages[0].0, "static/topic");
-        
+
         let parsed: Value = serde_json::from_slice(&messages[0].1).unwrap();
         assert_eq!(parsed["query_id"], "q1");
         assert_eq!(parsed["added"][0]["name"], "sensor-1");
@@ -112,15 +497,14 @@ mod tests {
     #[test]
     fn test_split_mode_dynamic_topic() {
         let registry = Handlebars::new();
-        let added = vec![
+        let batch = added(vec![
             serde_json::json!({"device": "d1", "val": 1}),
             serde_json::json!({"device": "d2", "val": 2}),
-        ];
-        
-        let messages = result_to_payload(
-            "q1", 1, &added, &[], &[], 
-            &registry, "devices/{{device}}/data", None
-        ).unwrap();
+        ]);
+
+        let messages = Renderer::new(&registry, "devices/{{device}}/data", None)
+            .result_to_payload("q1", &batch, &ctx())
+            .unwrap();
 
         assert_eq!(messages.len(), 2);
         assert_eq!(messages[0].0, "devices/d1/data");
@@ -130,15 +514,428 @@ mod tests {
     #[test]
     fn test_split_mode_payload_template() {
         let registry = Handlebars::new();
-        let added = vec![serde_json::json!({"device": "d1"})];
-        
-        let messages = result_to_payload(
-            "q1", 1, &added, &[], &[], 
-            &registry, "static/topic", Some("Alert: {{device}}")
-        ).unwrap();
+        let batch = added(vec![serde_json::json!({"device": "d1"})]);
+
+        let messages = Renderer::new(&registry, "static/topic", Some("Alert: {{device}}"))
+            .result_to_payload("q1", &batch, &ctx())
+            .unwrap();
 
         assert_eq!(messages.len(), 1);
         assert_eq!(messages[0].0, "static/topic");
         assert_eq!(String::from_utf8(messages[0].1.clone()).unwrap(), "Alert: d1");
     }
+
+    #[test]
+    fn test_json_pretty_output() {
+        let registry = Handlebars::new();
+        let batch = added(vec![serde_json::json!({"device": "d1"})]);
+        let renderer = Renderer {
+            json: JsonFormat {
+                pretty: true,
+                sort_keys: false,
+            },
+            ..Renderer::new(&registry, "static/topic", None)
+        };
+
+        let batch_payload = &renderer.result_to_payload("q1", &batch, &ctx()).unwrap()[0].1;
+        let item_payload = &renderer
+            .render_item("q1", Op::Insert, &batch.added[0], &ctx())
+            .unwrap()
+            .1;
+
+        assert!(batch_payload.contains(&b'\n'));
+        assert!(item_payload.contains(&b'\n'));
+    }
+
+    #[test]
+    fn test_json_sort_keys() {
+        let registry = Handlebars::new();
+        let mut item = serde_json::Map::new();
+        item.insert("zeta".into(), 1.into());
+        item.insert("alpha".into(), serde_json::json!({"y": 1, "b": 2}));
+        let batch = added(vec![Value::Object(item)]);
+        let renderer = Renderer {
+            json: JsonFormat {
+                pretty: false,
+                sort_keys: true,
+            },
+            ..Renderer::new(&registry, "devices/{{zeta}}", None)
+        };
+
+        let messages = renderer.result_to_payload("q1", &batch, &ctx()).unwrap();
+
+        assert_eq!(
+            String::from_utf8(messages[0].1.clone()).unwrap(),
+            r#"{"alpha":{"b":2,"y":1},"op":"insert","query_id":"q1","sequence":1,"zeta":1}"#
+        );
+    }
+
+    #[test]
+    fn test_meta_renders_in_split_mode() {
+        let registry = Handlebars::new();
+        let batch = added(vec![serde_json::json!({"device": "d1"})]);
+        let ctx = SerializeContext {
+            hostname: "gw-1",
+            published_at: DateTime::from_timestamp_millis(1_700_000_000_000).unwrap(),
+            result_timestamp: DateTime::from_timestamp_millis(1_699_999_999_000),
+            ..ctx()
+        };
+        let renderer = Renderer {
+            include_meta: true,
+            ..Renderer::new(
+                &registry,
+                "static/topic",
+                Some("{{_meta.reaction_id}}@{{_meta.hostname}} {{_meta.published_at}} {{_meta.published_at_ms}} {{_meta.result_timestamp}}"),
+            )
+        };
+
+        let messages = renderer.result_to_payload("q1", &batch, &ctx).unwrap();
+
+        assert_eq!(
+            String::from_utf8(messages[0].1.clone()).unwrap(),
+            "r1@gw-1 2023-11-14T22:13:20+00:00 1700000000000 2023-11-14T22:13:19+00:00"
+        );
+    }
+
+    #[test]
+    fn test_meta_disabled_keeps_default_shapes() {
+        let registry = Handlebars::new();
+        let batch = added(vec![serde_json::json!({"device": "d1"})]);
+
+        let batch_msg = Renderer::new(&registry, "static/topic", None)
+            .result_to_payload("q1", &batch, &ctx())
+            .unwrap();
+        let split_msg = Renderer::new(&registry, "devices/{{device}}", None)
+            .result_to_payload("q1", &batch, &ctx())
+            .unwrap();
+
+        let batch_body: Value = serde_json::from_slice(&batch_msg[0].1).unwrap();
+        let split_body: Value = serde_json::from_slice(&split_msg[0].1).unwrap();
+        assert!(batch_body.get("published_at").is_none());
+        assert!(split_body.get("_meta").is_none());
+    }
+
+    #[test]
+    fn test_meta_enabled_in_batch_and_default_json() {
+        let registry = Handlebars::new();
+        let batch = added(vec![serde_json::json!({"device": "d1"})]);
+        let ctx = SerializeContext {
+            published_at: DateTime::from_timestamp_millis(1_700_000_000_000).unwrap(),
+            ..ctx()
+        };
+        let enabled = |topic| Renderer {
+            include_meta: true,
+            ..Renderer::new(&registry, topic, None)
+        };
+
+        let batch_msg = enabled("static/topic")
+            .result_to_payload("q1", &batch, &ctx)
+            .unwrap();
+        let split_msg = enabled("devices/{{device}}")
+            .result_to_payload("q1", &batch, &ctx)
+            .unwrap();
+
+        let batch_body: Value = serde_json::from_slice(&batch_msg[0].1).unwrap();
+        let split_body: Value = serde_json::from_slice(&split_msg[0].1).unwrap();
+        assert_eq!(batch_body["published_at"], "2023-11-14T22:13:20+00:00");
+        assert_eq!(split_body["_meta"]["published_at_ms"], 1_700_000_000_000i64);
+        assert_eq!(split_body["_meta"]["result_timestamp"], Value::Null);
+    }
+
+    #[test]
+    fn test_correlation_ids_are_unique() {
+        let ids: std::collections::HashSet<String> = (0..1000).map(|_| correlation_id()).collect();
+        assert_eq!(ids.len(), 1000);
+    }
+
+    #[test]
+    fn test_correlation_id_per_item() {
+        let registry = Handlebars::new();
+        let batch = added(vec![
+            serde_json::json!({"device": "d1"}),
+            serde_json::json!({"device": "d2"}),
+        ]);
+        let renderer = Renderer {
+            correlation_ids: true,
+            ..Renderer::new(
+                &registry,
+                "devices/{{device}}/command",
+                Some(r#"{"cmd": "reboot", "cid": "{{correlation_id}}"}"#),
+            )
+        };
+
+        let messages = renderer.result_to_payload("q1", &batch, &ctx()).unwrap();
+
+        let cids: Vec<String> = messages
+            .iter()
+            .map(|(_, payload)| {
+                let body: Value = serde_json::from_slice(payload).unwrap();
+                body["cid"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert!(cids.iter().all(|cid| uuid::Uuid::parse_str(cid).is_ok()));
+        assert_ne!(cids[0], cids[1]);
+    }
+
+    #[test]
+    fn test_topic_prefix_static_and_templated() {
+        let registry = Handlebars::new();
+        let batch = added(vec![serde_json::json!({"device": "d1"})]);
+        let prefixed = |topic| Renderer {
+            topic_prefix: Some("tenants/acme"),
+            ..Renderer::new(&registry, topic, None)
+        };
+
+        let batch_msg = prefixed("alerts")
+            .result_to_payload("q1", &batch, &ctx())
+            .unwrap();
+        let split_msg = prefixed("devices/{{device}}")
+            .result_to_payload("q1", &batch, &ctx())
+            .unwrap();
+
+        assert_eq!(batch_msg[0].0, "tenants/acme/alerts");
+        assert_eq!(split_msg[0].0, "tenants/acme/devices/d1");
+    }
+
+    #[test]
+    fn test_topic_prefix_slash_handling() {
+        assert_eq!(prefixed_topic(Some("tenants/acme/"), "alerts"), "tenants/acme/alerts");
+        assert_eq!(prefixed_topic(Some("tenants/acme"), "/alerts"), "tenants/acme/alerts");
+        assert_eq!(prefixed_topic(Some("tenants/acme/"), "/alerts"), "tenants/acme/alerts");
+        assert_eq!(prefixed_topic(Some(""), "alerts"), "alerts");
+        assert_eq!(prefixed_topic(None, "/alerts"), "/alerts");
+        assert_eq!(prefixed_topic(Some("tenants/acme"), ""), "");
+    }
+
+    #[test]
+    fn test_empty_and_odd_rendered_topics() {
+        let registry = Handlebars::new();
+        let batch = added(vec![
+            serde_json::json!({"device": "d1"}),
+            serde_json::json!({"temp": 20}),
+            serde_json::json!({"device": "d1/"}),
+            serde_json::json!({"device": "+"}),
+        ]);
+        let renderer = Renderer {
+            topic_prefix: Some("tenants/acme"),
+            ..Renderer::new(&registry, "{{device}}", None)
+        };
+
+        let topics: Vec<String> = renderer
+            .result_to_payload("q1", &batch, &ctx())
+            .unwrap()
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect();
+        assert_eq!(
+            topics,
+            vec!["tenants/acme/d1", "", "tenants/acme/d1/", "tenants/acme/+"]
+        );
+
+        let valid: Vec<bool> = topics.iter().map(|t| validate_topic(t).is_ok()).collect();
+        assert_eq!(valid, vec![true, false, true, false]);
+        assert!(validate_topic("/").is_ok());
+        assert!(validate_topic("a//b").is_ok());
+    }
+
+    fn mixed_diffs() -> Vec<ResultDiff> {
+        vec![
+            ResultDiff::Add { data: serde_json::json!({"id": 1}) },
+            ResultDiff::Noop,
+            ResultDiff::Delete { data: serde_json::json!({"id": 2}) },
+        ]
+    }
+
+    #[test]
+    fn test_partition_log_policy_skips_unhandled() {
+        let batch = partition_diffs("q1", &mixed_diffs(), UnhandledDiffPolicy::Log).unwrap();
+
+        assert_eq!(batch.added.len(), 1);
+        assert!(batch.updated.is_empty());
+        assert_eq!(batch.removed.len(), 1);
+    }
+
+    #[test]
+    fn test_partition_error_policy_fails_batch() {
+        let err = partition_diffs("q1", &mixed_diffs(), UnhandledDiffPolicy::Error).unwrap_err();
+        assert!(err.to_string().contains("noop"));
+    }
+
+    #[test]
+    fn test_topic_from_json_pointer() {
+        let registry = template_registry();
+        let item = serde_json::json!({
+            "site": {"name": "plant-3", "devices": [{"id": "d1"}, {"id": "d2"}]}
+        });
+        let renderer = Renderer::new(
+            &registry,
+            r#"sites/{{ptr this "/site/name"}}/devices/{{ptr this "/site/devices/1/id"}}/cmd"#,
+            None,
+        );
+
+        let (topic, _) = renderer
+            .render_item("q1", Op::Insert, &item, &ctx())
+            .unwrap();
+        assert_eq!(topic, "sites/plant-3/devices/d2/cmd");
+
+        let (topic, _) = Renderer::new(&registry, r#"devices/{{ptr this "/missing/0"}}"#, None)
+            .render_item("q1", Op::Insert, &item, &ctx())
+            .unwrap();
+        assert_eq!(topic, "devices/");
+    }
+
+    #[test]
+    fn test_split_metadata_omits_and_renames_keys() {
+        let registry = Handlebars::new();
+        let item = serde_json::json!({"device": "d1", "temp": 35});
+        let metadata = MetadataConfig {
+            include: vec!["query_id".into(), "op".into()],
+            rename: HashMap::from([("op".to_string(), "event".to_string())]),
+        };
+        let renderer = Renderer {
+            split_metadata: Some(&metadata),
+            ..Renderer::new(&registry, "devices/{{device}}/{{op}}", None)
+        };
+
+        let (topic, payload) = renderer
+            .render_item("q1", Op::Insert, &item, &ctx())
+            .unwrap();
+        // Templates still see the standard names.
+        assert_eq!(topic, "devices/d1/insert");
+        let body: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"device": "d1", "temp": 35, "query_id": "q1", "event": "insert"})
+        );
+    }
+
+    #[test]
+    fn test_split_metadata_rejects_unknown_keys() {
+        assert!(MetadataConfig::default().validate().is_ok());
+        let metadata = MetadataConfig {
+            include: vec!["seq".into()],
+            ..MetadataConfig::default()
+        };
+        assert!(metadata.validate().is_err());
+    }
+
+    #[test]
+    fn test_delete_payload_modes() {
+        let registry = Handlebars::new();
+        let item = serde_json::json!({"device": "d1", "temp": 35});
+        let delete_payload = |mode: &DeletePayloadMode| {
+            let renderer = Renderer {
+                delete_payload: mode,
+                ..Renderer::new(&registry, "devices/{{device}}", None)
+            };
+            let (_, payload) = renderer
+                .render_item("q1", Op::Delete, &item, &ctx())
+                .unwrap();
+            String::from_utf8(payload).unwrap()
+        };
+
+        let full: Value = serde_json::from_str(&delete_payload(&DeletePayloadMode::Full)).unwrap();
+        assert_eq!(full["temp"], 35);
+        assert_eq!(full["op"], "delete");
+
+        let id_only = DeletePayloadMode::IdOnly("device".to_string());
+        assert_eq!(delete_payload(&id_only), r#"{"device":"d1"}"#);
+
+        let custom = DeletePayloadMode::Custom("{{device}} removed".to_string());
+        assert_eq!(delete_payload(&custom), "d1 removed");
+    }
+
+    #[test]
+    fn test_delete_payload_only_affects_deletes() {
+        let registry = Handlebars::new();
+        let mode = DeletePayloadMode::Custom("gone".to_string());
+        let renderer = Renderer {
+            delete_payload: &mode,
+            ..Renderer::new(&registry, "devices/{{device}}", Some("{{device}}"))
+        };
+        let item = serde_json::json!({"device": "d1"});
+        let (_, payload) = renderer
+            .render_item("q1", Op::Update, &item, &ctx())
+            .unwrap();
+        assert_eq!(payload, b"d1");
+    }
+
+    #[test]
+    fn test_delete_payload_id_only_in_batch() {
+        let registry = Handlebars::new();
+        let mode = DeletePayloadMode::IdOnly("device".to_string());
+        let renderer = Renderer {
+            delete_payload: &mode,
+            ..Renderer::new(&registry, "static/topic", None)
+        };
+        let batch = DiffBatch {
+            removed: vec![serde_json::json!({"device": "d1", "temp": 35})],
+            ..Default::default()
+        };
+
+        let messages = renderer.result_to_payload("q1", &batch, &ctx()).unwrap();
+        let parsed: Value = serde_json::from_slice(&messages[0].1).unwrap();
+        assert_eq!(parsed["removed"], serde_json::json!([{"device": "d1"}]));
+    }
+
+    #[test]
+    fn test_user_properties_from_result_context() {
+        let registry = template_registry();
+        let user_properties = vec![
+            ("query".to_string(), "{{query_id}}".to_string()),
+            ("op".to_string(), "{{op}}".to_string()),
+            (
+                "seq".to_string(),
+                "{{reaction_id}}-{{sequence}}".to_string(),
+            ),
+        ];
+
+        let rendered =
+            render_user_properties(&registry, &user_properties, "r1", "q1", 7, Some(Op::Delete))
+                .unwrap();
+        assert_eq!(
+            rendered,
+            vec![
+                ("query".to_string(), "q1".to_string()),
+                ("op".to_string(), "delete".to_string()),
+                ("seq".to_string(), "r1-7".to_string()),
+            ]
+        );
+
+        // A batch message mixing operations has no single op.
+        let rendered =
+            render_user_properties(&registry, &user_properties, "r1", "q1", 8, None).unwrap();
+        assert_eq!(rendered[1], ("op".to_string(), String::new()));
+    }
+
+    #[test]
+    fn test_sort_by_field() {
+        let mut batch = DiffBatch {
+            added: vec![
+                serde_json::json!({"id": "a", "seq": 3}),
+                serde_json::json!({"id": "b"}),
+                serde_json::json!({"id": "c", "seq": 1.5}),
+                serde_json::json!({"id": "d", "seq": "2"}),
+                serde_json::json!({"id": "e", "seq": 1}),
+                serde_json::json!({"id": "f", "seq": null}),
+                serde_json::json!({"id": "g", "seq": true}),
+            ],
+            removed: vec![
+                serde_json::json!({"id": "x", "seq": 2}),
+                serde_json::json!({"id": "y", "seq": 1}),
+            ],
+            ..Default::default()
+        };
+
+        batch.sort_by_field("seq");
+
+        let ids = |items: &[Value]| -> Vec<String> {
+            items
+                .iter()
+                .map(|item| item["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(ids(&batch.added), ["g", "e", "c", "a", "d", "b", "f"]);
+        assert_eq!(ids(&batch.removed), ["y", "x"]);
+    }
 }
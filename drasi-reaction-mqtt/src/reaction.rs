@@ -14,138 +14,594 @@
 
 //! MQTT reaction implementation of the [`Reaction`] trait.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
+use chrono::DateTime;
+use drasi_mqtt_connection::{
+    system_clock, ConnectionHandle, LogLimiter, MqttConnectionManager, SharedClock,
+    TakeoverDetector,
+};
 use handlebars::Handlebars;
-use log::{error, info, warn};
-use rumqttc::{AsyncClient, MqttOptions, QoS};
+use rumqttc::{AsyncClient, Event, Incoming, Outgoing, QoS};
 use serde_json::Value;
 use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
 
 use drasi_lib::channels::ComponentStatus;
 use drasi_lib::context::ReactionRuntimeContext;
 use drasi_lib::reactions::{ReactionBase, ReactionBaseParams};
 use drasi_lib::Reaction;
 
-use crate::config::MqttReactionConfig;
+use crate::audit::{self, PublishHook, PublishOrigin};
+use crate::client::{self, BrokerClient, ClientWrapper, PublishClient};
+use crate::config::{MqttProtocol, MqttReactionConfig, PRIMARY_BROKER};
+use crate::connection::ConnectionState;
+use crate::dedup::DedupClient;
+use crate::fanout::{
+    BrokerStatsSnapshot, BufferLimits, FanOut, OutgoingMessage, PublishTimeout, QueryMetrics,
+};
+use crate::heartbeat;
 use crate::publisher;
+use crate::retained::{FanOutSlot, Republisher, RetainedCache};
+use crate::serializer::{result_messages, ResultSerializer, SerializeContext, TemplateSerializer};
+use crate::snapshot::{self, Snapshots};
+use crate::topic_alias::{AliasLimit, AliasingClient};
+use crate::transform::ResultTransform;
 
 /// MQTT reaction plugin for drasi-lib.
 ///
 /// Subscribes to Drasi query results via [`ReactionBase`] and publishes each
 /// result batch as a JSON payload to an MQTT topic.
 /// Supports dynamic topics and payloads via Handlebars templates.
+///
+/// While running, once any broker connection has been down continuously for
+/// `degraded_after_ms`, [`status`](Reaction::status) reports
+/// [`ComponentStatus::Error`]; it returns to `Running` once reconnected. It
+/// also reports `Error` while a broker's buffer is at or above
+/// `buffer_high_water_mark`.
 pub struct MqttReaction {
     base: ReactionBase,
     config: MqttReactionConfig,
-    /// MQTT client handle (set on start, cleared on stop).
-    client: Arc<RwLock<Option<AsyncClient>>>,
+    /// MQTT client handles, one per broker (set on start, cleared on stop).
+    clients: Arc<RwLock<Vec<BrokerClient>>>,
+    /// Connection state of each broker (set on start, cleared on stop).
+    connection_states: Arc<RwLock<Vec<Arc<ConnectionState>>>>,
+    /// Shared connection used instead of connecting to the primary broker.
+    shared: Option<Arc<MqttConnectionManager>>,
+    /// Eventloop drivers of the broker connections (set on start, stopped on
+    /// stop).
+    drivers: Arc<RwLock<Vec<JoinHandle<()>>>>,
+    /// Handle on the shared connection (set on start, released on stop).
+    connection: Arc<RwLock<Option<SharedConnection>>>,
+    /// Fan-out to all brokers (set on start, cleared on stop).
+    fanout: Arc<RwLock<Option<Arc<FanOut>>>>,
+    /// Number of messages produced for publishing.
+    published: Arc<AtomicU64>,
+    /// Number of result items skipped because `result_transform` failed on them.
+    transform_errors: Arc<AtomicU64>,
+    /// Queries whose results are dequeued but not published.
+    disabled_queries: Arc<std::sync::RwLock<HashSet<String>>>,
+    /// Heartbeat publishing task (set on start when enabled, aborted on stop).
+    heartbeat_task: Arc<RwLock<Option<JoinHandle<()>>>>,
+    /// Result sets of the subscribed queries (set on start when
+    /// `snapshot_topic` is configured, cleared on stop).
+    snapshots: Arc<RwLock<Option<Arc<Snapshots>>>>,
+    /// Snapshot publishing task (set on start when enabled, aborted on stop).
+    snapshot_task: Arc<RwLock<Option<JoinHandle<()>>>>,
     /// Handlebars registry for rendering templates.
     registry: Arc<Handlebars<'static>>,
+    /// Custom serializer replacing the template-based default.
+    serializer: Option<Arc<dyn ResultSerializer>>,
+    /// Called with the outcome of every publish attempt.
+    on_publish: Option<PublishHook>,
+    /// Wraps each broker's client, beneath deduplication.
+    client_wrapper: Option<ClientWrapper>,
+    /// Time source for publish timestamps and connection health.
+    clock: SharedClock,
+}
+
+/// How long `stop` waits for the eventloop drivers to send their
+/// disconnects before aborting them.
+const DRIVER_STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The reaction's share of a managed connection.
+struct SharedConnection {
+    handle: ConnectionHandle,
+    /// Watches the connection's events for reconnects.
+    watcher: JoinHandle<()>,
 }
 
 impl MqttReaction {
     /// Create a new MQTT reaction from the given config.
     pub fn new(config: MqttReactionConfig) -> Self {
-        let params = ReactionBaseParams::new(&config.id, config.queries.clone());
+        let params = ReactionBaseParams::new(&config.id, config.queries.clone())
+            .with_auto_start(config.auto_start);
         let base = ReactionBase::new(params);
-        let registry = Arc::new(Handlebars::new());
+        let mut registry = publisher::template_registry();
+        // An invalid partial is reported by start().
+        let _ = publisher::register_partials(&mut registry, &config.partials);
+        let registry = Arc::new(registry);
 
         Self {
             base,
             config,
-            client: Arc::new(RwLock::new(None)),
+            clients: Arc::new(RwLock::new(Vec::new())),
+            connection_states: Arc::new(RwLock::new(Vec::new())),
+            shared: None,
+            drivers: Arc::new(RwLock::new(Vec::new())),
+            connection: Arc::new(RwLock::new(None)),
+            fanout: Arc::new(RwLock::new(None)),
+            published: Arc::new(AtomicU64::new(0)),
+            transform_errors: Arc::new(AtomicU64::new(0)),
+            disabled_queries: Arc::default(),
+            heartbeat_task: Arc::new(RwLock::new(None)),
+            snapshots: Arc::new(RwLock::new(None)),
+            snapshot_task: Arc::new(RwLock::new(None)),
             registry,
+            serializer: None,
+            on_publish: None,
+            client_wrapper: None,
+            clock: system_clock(),
         }
     }
-}
 
-#[async_trait]
-impl Reaction for MqttReaction {
-    fn id(&self) -> &str {
-        &self.base.id
+    /// Create a reaction publishing to the primary broker over the
+    /// connection owned by `manager`, e.g. one shared with an MQTT source.
+    ///
+    /// The primary broker's host, port, client id, TLS and credentials are
+    /// not used; additional brokers still get their own connections. Only
+    /// MQTT 3.1.1 is supported.
+    pub fn with_connection(
+        config: MqttReactionConfig,
+        manager: Arc<MqttConnectionManager>,
+    ) -> Self {
+        let mut reaction = Self::new(config);
+        reaction.shared = Some(manager);
+        reaction
     }
 
-    fn type_name(&self) -> &str {
-        "mqtt"
+    /// Use a custom [`ResultSerializer`] instead of the configured templates.
+    ///
+    /// When set, `topic` and `payload_template` are not used to build messages.
+    pub fn with_serializer(mut self, serializer: Arc<dyn ResultSerializer>) -> Self {
+        self.serializer = Some(serializer);
+        self
     }
 
-    fn properties(&self) -> HashMap<String, Value> {
-        let mut props = HashMap::new();
-        props.insert("broker_host".into(), Value::String(self.config.broker_host.clone()));
-        props.insert("port".into(), Value::Number(self.config.port.into()));
-        props.insert("topic".into(), Value::String(self.config.topic.clone()));
-        props
+    /// Register a callback invoked with the outcome of every publish attempt
+    /// to every broker, e.g. for an audit trail.
+    ///
+    /// Replaces the file hook configured with `audit_log_path`.
+    pub fn with_on_publish(mut self, hook: PublishHook) -> Self {
+        self.on_publish = Some(hook);
+        self
     }
 
-    fn query_ids(&self) -> Vec<String> {
-        self.base.queries.clone()
+    /// Publish to each broker through the client `wrapper` returns for it,
+    /// e.g. to note each message just before it goes out. Deduplication
+    /// happens before the wrapper sees a message.
+    pub fn with_client_wrapper(mut self, wrapper: ClientWrapper) -> Self {
+        self.client_wrapper = Some(wrapper);
+        self
     }
 
-    fn auto_start(&self) -> bool {
-        self.base.get_auto_start()
+    /// Read the time from `clock` instead of the system clock, e.g. to get
+    /// deterministic `published_at` timestamps in tests.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
     }
 
-    async fn initialize(&self, context: ReactionRuntimeContext) {
-        self.base.initialize(context).await;
+    /// Publish counters for each broker, or an empty list when not running.
+    pub async fn broker_stats(&self) -> Vec<BrokerStatsSnapshot> {
+        match self.fanout.read().await.as_ref() {
+            Some(fanout) => fanout.stats(),
+            None => Vec::new(),
+        }
     }
 
-    async fn start(&self) -> Result<()> {
+    /// Publish and error counters for each query, or an empty map when not
+    /// running.
+    pub async fn metrics(&self) -> HashMap<String, QueryMetrics> {
+        match self.fanout.read().await.as_ref() {
+            Some(fanout) => fanout.query_metrics(),
+            None => HashMap::new(),
+        }
+    }
+
+    /// Number of result items skipped because `result_transform` failed on them.
+    pub fn transform_errors(&self) -> u64 {
+        self.transform_errors.load(Ordering::Relaxed)
+    }
+
+    /// Mute or unmute a query's publishes without stopping the reaction.
+    ///
+    /// Results of a disabled query are still dequeued, so they don't pile up,
+    /// but are neither rendered nor published; they are counted as `muted`
+    /// in [`metrics`](Self::metrics). All queries start enabled, and the
+    /// setting survives restarts.
+    pub async fn set_query_enabled(&self, query_id: &str, enabled: bool) {
+        let changed = {
+            let mut disabled = self.disabled_queries.write().unwrap();
+            if enabled {
+                disabled.remove(query_id)
+            } else {
+                disabled.insert(query_id.to_string())
+            }
+        };
+        if changed {
+            let state = if enabled { "enabled" } else { "disabled" };
+            info!(
+                "[{}] Publishing for query '{query_id}' {state}",
+                self.config.id
+            );
+        }
+    }
+
+    /// Whether results of `query_id` are published.
+    pub fn is_query_enabled(&self, query_id: &str) -> bool {
+        !self.disabled_queries.read().unwrap().contains(query_id)
+    }
+
+    /// Publish the full current result set of `query_id` to `snapshot_topic`
+    /// as one message.
+    ///
+    /// Fails unless the reaction is running with `snapshot_topic` set and
+    /// subscribed to `query_id`. The result set is built from the diffs
+    /// received since the reaction was started.
+    pub async fn publish_snapshot(&self, query_id: &str) -> Result<()> {
+        let snapshots = self.snapshots.read().await.clone().ok_or_else(|| {
+            anyhow::anyhow!(
+                "[{}] Snapshots are not enabled or the reaction is not running",
+                self.config.id
+            )
+        })?;
+        let message = snapshots.message(&self.registry, query_id)?;
+        match self.fanout.read().await.as_ref() {
+            Some(fanout) => fanout.publish(message),
+            None => anyhow::bail!("[{}] The reaction is not running", self.config.id),
+        }
+        Ok(())
+    }
+}
+
+/// Whether a result of `query_id` is published, counting it as muted if the
+/// query is disabled.
+fn admit_result(
+    disabled_queries: &std::sync::RwLock<HashSet<String>>,
+    fanout: &FanOut,
+    query_id: &str,
+) -> bool {
+    if disabled_queries.read().unwrap().contains(query_id) {
+        fanout.record_query_muted(query_id);
+        return false;
+    }
+    true
+}
+
+/// Span around the processing of one dequeued result, so that the events
+/// logged for it carry its query and sequence number.
+fn result_span(reaction_id: &str, query_id: &str, sequence: u64) -> tracing::Span {
+    tracing::info_span!("result", reaction_id, query_id, sequence)
+}
+
+impl MqttReaction {
+    /// Connect to the brokers, subscribe to the queries and spawn the
+    /// processing loop. On failure, the tasks already spawned are left for
+    /// [`abort_tasks`](Self::abort_tasks).
+    async fn start_publishing(&self) -> Result<()> {
         info!(
             "[{}] Starting MQTT reaction (broker={}:{}, topic={})",
-            self.config.id, self.config.broker_host, self.config.port, self.config.topic
+            self.config.id,
+            self.config.connection.host,
+            self.config.connection.port,
+            self.config.topic
         );
 
-        // Build MQTT options.
-        let mut mqtt_opts = MqttOptions::new(
-            &self.config.client_id,
-            &self.config.broker_host,
-            self.config.port,
-        );
-        mqtt_opts.set_keep_alive(std::time::Duration::from_secs(30));
+        let serializer: Arc<dyn ResultSerializer> = match &self.serializer {
+            Some(serializer) => serializer.clone(),
+            None => {
+                let serializer = TemplateSerializer::from_config(self.registry.clone(), &self.config);
+                serializer.validate()?;
+                Arc::new(serializer)
+            }
+        };
+
+        let transform = self
+            .config
+            .result_transform
+            .as_deref()
+            .map(ResultTransform::compile)
+            .transpose()?;
+
+        for (name, template) in &self.config.partials {
+            publisher::validate_template(&format!("partials.{name}"), template)?;
+        }
+        for (name, template) in &self.config.user_properties {
+            publisher::validate_template(&format!("user_properties.{name}"), template)?;
+        }
+
+        let retained_cache = self
+            .config
+            .republish_retained_on_reconnect
+            .then(|| Arc::new(RetainedCache::new(self.config.retained_cache_capacity)));
+        let fanout_slot = FanOutSlot::default();
+
+        if self.shared.is_some() && !matches!(self.config.protocol, MqttProtocol::V311) {
+            anyhow::bail!("A shared MQTT connection only supports protocol v311");
+        }
+
+        // Connect to every broker; each gets its own eventloop driver.
+        let mut publish_clients: Vec<(String, Arc<dyn PublishClient>)> = Vec::new();
+        let mut clients = Vec::new();
+        let mut connection_states = Vec::new();
+        for broker in self.config.brokers() {
+            let eventloop_id = self.config.id.clone();
+            let clock = self.clock.clone();
+            let state = Arc::new(ConnectionState::with_clock(self.clock.clone()));
+            connection_states.push(state.clone());
+            let broker_name = broker.name.clone();
+            let credentials = self
+                .config
+                .credentials_provider
+                .clone()
+                .filter(|_| broker.name == PRIMARY_BROKER);
+            let republisher = || {
+                retained_cache.clone().map(|cache| {
+                    Republisher::new(&self.config.id, &broker.name, cache, fanout_slot.clone())
+                })
+            };
+
+            if let Some(manager) = self
+                .shared
+                .as_ref()
+                .filter(|_| broker.name == PRIMARY_BROKER)
+            {
+                let handle = manager.acquire().await;
+                let client = handle.client().clone();
+                let publish_client: Arc<dyn PublishClient> = Arc::new(client.clone());
+                let mut republisher = republisher();
+                let mut events = handle.events();
+
+                // The manager drives the eventloop; only ConnAcks and errors
+                // matter here.
+                let watcher = tokio::spawn(async move {
+                    loop {
+                        match events.next().await {
+                            Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                                state.on_connack();
+                                if let Some(republisher) = &mut republisher {
+                                    republisher.on_connack();
+                                }
+                            }
+                            Ok(_) => {}
+                            Err(_) => state.on_error(),
+                        }
+                    }
+                });
+                *self.connection.write().await = Some(SharedConnection { handle, watcher });
+
+                publish_clients.push((broker.name, publish_client));
+                clients.push(BrokerClient::Shared(client));
+                continue;
+            }
+
+            let (client, publish_client): (BrokerClient, Arc<dyn PublishClient>) =
+                match self.config.protocol {
+                    MqttProtocol::V311 => {
+                        let mut mqtt_opts = client::mqtt_options(&broker)?;
+                        if let Some(provider) = &credentials {
+                            let (username, password) = provider.credentials();
+                            mqtt_opts.set_credentials(username, password);
+                        }
+                        let client_id = mqtt_opts.client_id();
+                        let (client, mut eventloop) = AsyncClient::new(mqtt_opts, 100);
+                        let publish_client: Arc<dyn PublishClient> = Arc::new(client.clone());
+                        let mut republisher = republisher();
+
+                        // Spawn the MQTT eventloop driver (keeps connection alive).
+                        let driver = tokio::spawn(async move {
+                            let mut takeovers = TakeoverDetector::default();
+                            loop {
+                                match eventloop.poll().await {
+                                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                                    Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                                        state.on_connack();
+                                        takeovers.on_connack(clock.now_instant());
+                                        if let Some(republisher) = &mut republisher {
+                                            republisher.on_connack();
+                                        }
+                                    }
+                                    Ok(_) => {}
+                                    Err(e) => {
+                                        state.on_error();
+                                        warn!(
+                                            "[{eventloop_id}] MQTT eventloop error on broker '{broker_name}' (will reconnect): {e}"
+                                        );
+                                        if let Some(n) = takeovers.on_disconnect(clock.now_instant()) {
+                                            warn!(
+                                                "[{eventloop_id}] Connection to broker '{broker_name}' dropped {n} times shortly after connecting; another client is probably using client id '{client_id}' (set a unique client_id or a client_id_suffix)"
+                                            );
+                                        }
+                                        tokio::time::sleep(Duration::from_secs(1)).await;
+                                        if let Some(provider) = &credentials {
+                                            let (username, password) = provider.credentials();
+                                            eventloop.mqtt_options.set_credentials(username, password);
+                                        }
+                                    }
+                                }
+                            }
+                        });
+                        self.drivers.write().await.push(driver);
+
+                        (BrokerClient::V4(client), publish_client)
+                    }
+                    MqttProtocol::V5 => {
+                        let mut mqtt_opts = client::mqtt5_options(&broker)?;
+                        if let Some(provider) = &credentials {
+                            let (username, password) = provider.credentials();
+                            mqtt_opts.set_credentials(username, password);
+                        }
+                        let client_id = mqtt_opts.client_id();
+                        let (client, mut eventloop) =
+                            rumqttc::v5::AsyncClient::new(mqtt_opts, 100);
+                        let alias_limit = Arc::new(AliasLimit::default());
+                        let publish_client: Arc<dyn PublishClient> =
+                            Arc::new(AliasingClient::new(client.clone(), alias_limit.clone()));
+                        let mut republisher = republisher();
+
+                        // The driver also tracks the broker's topic alias maximum.
+                        let driver = tokio::spawn(async move {
+                            let mut takeovers = TakeoverDetector::default();
+                            loop {
+                                match eventloop.poll().await {
+                                    Ok(rumqttc::v5::Event::Outgoing(Outgoing::Disconnect)) => break,
+                                    Ok(rumqttc::v5::Event::Incoming(
+                                        rumqttc::v5::mqttbytes::v5::Packet::ConnAck(ack),
+                                    )) => {
+                                        state.on_connack();
+                                        takeovers.on_connack(clock.now_instant());
+                                        alias_limit.on_connack(
+                                            ack.properties.and_then(|p| p.topic_alias_max),
+                                        );
+                                        if let Some(republisher) = &mut republisher {
+                                            republisher.on_connack();
+                                        }
+                                    }
+                                    Ok(_) => {}
+                                    Err(e) => {
+                                        state.on_error();
+                                        warn!(
+                                            "[{eventloop_id}] MQTT eventloop error on broker '{broker_name}' (will reconnect): {e}"
+                                        );
+                                        if let Some(n) = takeovers.on_disconnect(clock.now_instant()) {
+                                            warn!(
+                                                "[{eventloop_id}] Connection to broker '{broker_name}' dropped {n} times shortly after connecting; another client is probably using client id '{client_id}' (set a unique client_id or a client_id_suffix)"
+                                            );
+                                        }
+                                        tokio::time::sleep(Duration::from_secs(1)).await;
+                                        if let Some(provider) = &credentials {
+                                            let (username, password) = provider.credentials();
+                                            eventloop.options.set_credentials(username, password);
+                                        }
+                                    }
+                                }
+                            }
+                        });
+                        self.drivers.write().await.push(driver);
 
-        if let (Some(user), Some(pass)) = (&self.config.username, &self.config.password) {
-            mqtt_opts.set_credentials(user, pass);
+                        (BrokerClient::V5(client), publish_client)
+                    }
+                };
+
+            publish_clients.push((broker.name, publish_client));
+            clients.push(client);
         }
+        *self.clients.write().await = clients;
+        *self.connection_states.write().await = connection_states;
 
-        let (client, mut eventloop) = AsyncClient::new(mqtt_opts, 100);
-        *self.client.write().await = Some(client.clone());
+        if let Some(wrapper) = &self.client_wrapper {
+            for (name, client) in &mut publish_clients {
+                *client = wrapper(name, client.clone());
+            }
+        }
+        if let Some(key) = &self.config.dedup {
+            for (_, client) in &mut publish_clients {
+                *client = Arc::new(DedupClient::new(
+                    client.clone(),
+                    key.clone(),
+                    self.config.dedup_capacity,
+                ));
+            }
+        }
+
+        let on_publish = match (&self.on_publish, &self.config.audit_log_path) {
+            (Some(hook), _) => Some(hook.clone()),
+            (None, Some(path)) => Some(audit::jsonl_file_hook(path)?),
+            (None, None) => None,
+        };
+        let fanout = Arc::new(FanOut::with_limits(
+            &self.config.id,
+            BufferLimits {
+                capacity: self.config.broker_buffer_capacity,
+                drop_policy: self.config.buffer_drop_policy,
+                high_water_mark: self.config.buffer_high_water_mark,
+            },
+            self.config.publish_timeout_ms.map(|ms| PublishTimeout {
+                limit: Duration::from_millis(ms),
+                retries: self.config.publish_retries,
+            }),
+            on_publish,
+            self.clock.clone(),
+            publish_clients,
+        ));
+        let _ = fanout_slot.set(Arc::downgrade(&fanout));
+        *self.fanout.write().await = Some(fanout.clone());
+
+        if let Some(topic) = &self.config.heartbeat_topic {
+            let task = heartbeat::spawn(
+                self.config.id.clone(),
+                topic.clone(),
+                Duration::from_millis(self.config.heartbeat_interval_ms.max(1)),
+                fanout.clone(),
+                self.published.clone(),
+            );
+            *self.heartbeat_task.write().await = Some(task);
+        }
+
+        let snapshots = self.config.snapshot_topic.as_ref().map(|topic| {
+            Arc::new(Snapshots::new(
+                &self.config.id,
+                topic,
+                &self.config.queries,
+                self.config.snapshot_capacity,
+            ))
+        });
+        let snapshot_interval = self
+            .config
+            .snapshot_interval_ms
+            .map(|ms| Duration::from_millis(ms.max(1)));
+        if let (Some(snapshots), Some(interval)) = (&snapshots, snapshot_interval) {
+            let task = snapshot::spawn(
+                snapshots.clone(),
+                interval,
+                self.registry.clone(),
+                fanout.clone(),
+            );
+            *self.snapshot_task.write().await = Some(task);
+        }
+        *self.snapshots.write().await = snapshots.clone();
 
         // Subscribe to all configured queries.
         self.base.subscribe_to_queries().await?;
 
         // Clone what we need for the spawned tasks.
         let base = self.base.clone_shared();
-        let topic_template = self.config.topic.clone();
-        let payload_template = self.config.payload_template.clone();
         let reaction_id = self.config.id.clone();
+        let on_unhandled_diff = self.config.on_unhandled_diff;
+        let published = self.published.clone();
+        let transform_errors = self.transform_errors.clone();
+        let disabled_queries = self.disabled_queries.clone();
+        let config = self.config.clone();
         let registry = self.registry.clone();
+        let user_properties = self.config.user_properties.clone();
+        let clock = self.clock.clone();
 
         // Create shutdown channel.
         let shutdown_rx = self.base.create_shutdown_channel().await;
 
-        // Spawn the MQTT eventloop driver (keeps connection alive).
-        let eventloop_id = reaction_id.clone();
-        tokio::spawn(async move {
-            loop {
-                match eventloop.poll().await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        warn!("[{eventloop_id}] MQTT eventloop error (will reconnect): {e}");
-                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                    }
-                }
-            }
-        });
-
         // Spawn the main processing loop: dequeue from priority queue → publish to MQTT.
         let handle = tokio::spawn(async move {
             info!("[{reaction_id}] Processing loop started");
             let mut sequence: u64 = 0;
             let mut shutdown_rx = shutdown_rx;
+            let mut error_log = LogLimiter::default();
 
             loop {
                 tokio::select! {
@@ -154,46 +610,115 @@ impl Reaction for MqttReaction {
                         break;
                     }
                     result = base.priority_queue.dequeue() => {
+                        let query_id = &result.query_id;
+                        if let Some(snapshots) = &snapshots {
+                            snapshots.apply(query_id, &result.results);
+                        }
+                        if !admit_result(&disabled_queries, &fanout, query_id) {
+                            continue;
+                        }
                         sequence += 1;
+                        let span = result_span(&reaction_id, query_id, sequence);
+                        let _entered = span.enter();
 
-                        use drasi_lib::channels::ResultDiff;
-                        
-                        let query_id = &result.query_id;
-                        let mut added = Vec::new();
-                        let mut updated = Vec::new();
-                        let mut removed = Vec::new();
-
-                        for diff in &result.results {
-                            match diff {
-                                ResultDiff::Add { data } => added.push(data.clone()),
-                                ResultDiff::Delete { data } => removed.push(data.clone()),
-                                ResultDiff::Update { after, .. } => updated.push(after.clone()),
-                                _ => {}
+                        let now = clock.now_instant();
+                        for suppressed in error_log.summaries(now) {
+                            warn!("[{reaction_id}] {suppressed}");
+                        }
+                        let mut batch = match publisher::partition_diffs(
+                            query_id,
+                            &result.results,
+                            on_unhandled_diff,
+                        ) {
+                            Ok(batch) => batch,
+                            Err(e) => {
+                                fanout.record_query_errors(query_id, 1);
+                                if error_log.admit(query_id, "result", now) {
+                                    error!("[{reaction_id}] Failed to process result: {e}");
+                                }
+                                continue;
                             }
+                        };
+                        if let Some(transform) = &transform {
+                            let errors = transform.apply_batch(&mut batch);
+                            transform_errors.fetch_add(errors.len() as u64, Ordering::Relaxed);
+                            fanout.record_query_errors(query_id, errors.len() as u64);
+                            if let Some(e) = errors.first() {
+                                if error_log.admit(query_id, "transform", now) {
+                                    error!("[{reaction_id}] Skipping {} result item(s): {e}", errors.len());
+                                }
+                            }
+                        }
+                        if let Some(field) = &config.order_by {
+                            batch.sort_by_field(field);
                         }
 
-                        match publisher::result_to_payload(
-                            query_id, 
-                            sequence, 
-                            &added, 
-                            &updated, 
-                            &removed,
-                            &registry,
-                            &topic_template,
-                            payload_template.as_deref()
+                        let ctx = SerializeContext {
+                            published_at: DateTime::from_timestamp_nanos(clock.now_nanos() as i64),
+                            result_timestamp: Some(result.timestamp),
+                            ..SerializeContext::new(&reaction_id, sequence)
+                        };
+                        match result_messages(
+                            serializer.as_ref(),
+                            query_id,
+                            &batch,
+                            &ctx,
+                            &config,
                         ) {
                             Ok(messages) => {
-                                for (topic, payload) in messages {
-                                    if let Err(e) = client
-                                        .publish(topic, QoS::AtLeastOnce, false, payload)
-                                        .await
-                                    {
-                                        error!("[{reaction_id}] Failed to publish to MQTT: {e}");
+                                for message in messages {
+                                    let origin = PublishOrigin {
+                                        query_id: query_id.clone(),
+                                        sequence,
+                                        op: message.op,
+                                    };
+                                    let properties = match publisher::render_user_properties(
+                                        &registry,
+                                        &user_properties,
+                                        &reaction_id,
+                                        query_id,
+                                        sequence,
+                                        message.op,
+                                    ) {
+                                        Ok(properties) => properties,
+                                        Err(e) => {
+                                            fanout.record_query_errors(query_id, 1);
+                                            if error_log.admit(query_id, "render", now) {
+                                                error!("[{reaction_id}] Failed to render user properties: {e}");
+                                            }
+                                            continue;
+                                        }
+                                    };
+                                    if let Err(e) = publisher::validate_topic(&message.topic) {
+                                        fanout.record_query_errors(query_id, 1);
+                                        if error_log.admit(query_id, "topic", now) {
+                                            error!(
+                                                topic = %message.topic,
+                                                "[{reaction_id}] Skipping message for query '{query_id}': {e}"
+                                            );
+                                        }
+                                        continue;
                                     }
+                                    published.fetch_add(1, Ordering::Relaxed);
+                                    let msg = OutgoingMessage {
+                                        topic: message.topic,
+                                        qos: message.qos,
+                                        retain: message.retain,
+                                        payload: message.payload,
+                                        user_properties: properties,
+                                        origin: Some(origin),
+                                    };
+                                    if let Some(cache) = &retained_cache {
+                                        cache.record(&msg);
+                                    }
+                                    fanout.publish(msg);
                                 }
                             }
                             Err(e) => {
-                                error!("[{reaction_id}] Failed to process result: {e}");
+                                fanout.record_query_errors(query_id, 1);
+                                if error_log.admit(query_id, "serialize", now) {
+                                    error!("[{reaction_id}] Failed to process result: {e}");
+                                }
                             }
                         }
                     }
@@ -206,14 +731,340 @@ impl Reaction for MqttReaction {
         Ok(())
     }
 
+    /// Abort the broker connection drivers, heartbeat and snapshot tasks,
+    /// and release the shared connection, after a failed start.
+    async fn abort_tasks(&self) {
+        for driver in self.drivers.write().await.drain(..) {
+            driver.abort();
+        }
+        if let Some(task) = self.heartbeat_task.write().await.take() {
+            task.abort();
+        }
+        if let Some(task) = self.snapshot_task.write().await.take() {
+            task.abort();
+        }
+        if let Some(shared) = self.connection.write().await.take() {
+            shared.watcher.abort();
+            shared.handle.release().await;
+        }
+        *self.snapshots.write().await = None;
+        *self.fanout.write().await = None;
+        self.clients.write().await.clear();
+        self.connection_states.write().await.clear();
+    }
+}
+
+#[async_trait]
+impl Reaction for MqttReaction {
+    fn id(&self) -> &str {
+        &self.base.id
+    }
+
+    fn type_name(&self) -> &str {
+        "mqtt"
+    }
+
+    fn properties(&self) -> HashMap<String, Value> {
+        let mut props = HashMap::new();
+        props.insert("broker_host".into(), Value::String(self.config.connection.host.clone()));
+        props.insert("port".into(), Value::Number(self.config.connection.port.into()));
+        props.insert("topic".into(), Value::String(self.config.topic.clone()));
+        if !self.config.additional_brokers.is_empty() {
+            let brokers = self
+                .config
+                .brokers()
+                .into_iter()
+                .map(|b| {
+                    let connection = &b.connection;
+                    Value::String(format!(
+                        "{}={}:{}",
+                        b.name, connection.host, connection.port
+                    ))
+                })
+                .collect();
+            props.insert("brokers".into(), Value::Array(brokers));
+        }
+        let enabled = self
+            .base
+            .queries
+            .iter()
+            .filter(|query_id| self.is_query_enabled(query_id))
+            .map(|query_id| Value::String(query_id.clone()))
+            .collect();
+        props.insert("enabled_queries".into(), Value::Array(enabled));
+        props
+    }
+
+    fn query_ids(&self) -> Vec<String> {
+        self.base.queries.clone()
+    }
+
+    fn auto_start(&self) -> bool {
+        self.base.get_auto_start()
+    }
+
+    async fn initialize(&self, context: ReactionRuntimeContext) {
+        self.base.initialize(context).await;
+    }
+
+    async fn start(&self) -> Result<()> {
+        let result = self.start_publishing().await;
+        if result.is_err() {
+            self.abort_tasks().await;
+        }
+        result
+    }
+
     async fn stop(&self) -> Result<()> {
-        if let Some(client) = self.client.write().await.take() {
+        if let Some(task) = self.heartbeat_task.write().await.take() {
+            task.abort();
+        }
+        if let Some(task) = self.snapshot_task.write().await.take() {
+            task.abort();
+        }
+        *self.snapshots.write().await = None;
+        let fanout = self.fanout.write().await.take();
+        let queue_depth = fanout.as_ref().map(|f| f.queue_depth()).unwrap_or(0);
+
+        for client in self.clients.write().await.drain(..) {
+            // Publish the final status on the client directly so it is
+            // queued ahead of the disconnect request.
+            if let Some(topic) = &self.config.heartbeat_topic {
+                let payload = heartbeat::heartbeat_payload(
+                    &self.config.id,
+                    heartbeat::STATUS_STOPPING,
+                    self.published.load(Ordering::Relaxed),
+                    queue_depth,
+                );
+                if let Err(e) = client
+                    .publish(topic.clone(), QoS::AtLeastOnce, false, payload)
+                    .await
+                {
+                    warn!("[{}] Failed to publish stopping heartbeat: {e}", self.config.id);
+                }
+            }
             let _ = client.disconnect().await;
         }
+        // Each driver ends once it has sent its disconnect.
+        let deadline = tokio::time::Instant::now() + DRIVER_STOP_TIMEOUT;
+        for mut driver in self.drivers.write().await.drain(..) {
+            let ended = tokio::time::timeout_at(deadline, &mut driver).await;
+            if ended.is_err() {
+                driver.abort();
+            }
+        }
+        if let Some(shared) = self.connection.write().await.take() {
+            shared.watcher.abort();
+            shared.handle.release().await;
+        }
+        self.connection_states.write().await.clear();
         self.base.stop_common().await
     }
 
     async fn status(&self) -> ComponentStatus {
-        self.base.get_status().await
+        let status = self.base.get_status().await;
+        let degraded_after = Duration::from_millis(self.config.degraded_after_ms);
+        let now = self.clock.now_instant();
+        let degraded = self
+            .connection_states
+            .read()
+            .await
+            .iter()
+            .any(|state| state.is_degraded(now, degraded_after));
+        let backed_up = self
+            .fanout
+            .read()
+            .await
+            .as_ref()
+            .is_some_and(|fanout| fanout.is_backed_up());
+        match status {
+            ComponentStatus::Running if degraded || backed_up => ComponentStatus::Error,
+            status => status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(auto_start: Option<bool>) -> MqttReactionConfig {
+        let builder = MqttReactionConfig::builder("r", "localhost", "alerts", vec!["q1".into()]);
+        match auto_start {
+            Some(auto_start) => builder.auto_start(auto_start).build(),
+            None => builder.build(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auto_start_reflects_config() {
+        assert!(MqttReaction::new(config(None)).auto_start());
+
+        let gated = MqttReaction::new(config(Some(false)));
+        assert!(!gated.auto_start());
+        assert_eq!(gated.status().await, ComponentStatus::Stopped);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_query_is_muted() {
+        use crate::audit::PublishOrigin;
+        use crate::client::testing::RecordingClient;
+
+        let config =
+            MqttReactionConfig::builder("r", "localhost", "alerts", vec!["q1".into(), "q2".into()])
+                .build();
+        let reaction = MqttReaction::new(config);
+        let recorder = Arc::new(RecordingClient::default());
+        let fanout = FanOut::new(
+            "r",
+            10,
+            None,
+            None,
+            vec![(
+                "local".to_string(),
+                recorder.clone() as Arc<dyn PublishClient>,
+            )],
+        );
+
+        for sequence in 0..6 {
+            match sequence {
+                2 => reaction.set_query_enabled("q1", false).await,
+                4 => reaction.set_query_enabled("q1", true).await,
+                _ => {}
+            }
+            if sequence == 3 {
+                assert_eq!(
+                    reaction.properties()["enabled_queries"],
+                    serde_json::json!(["q2"])
+                );
+            }
+            if admit_result(&reaction.disabled_queries, &fanout, "q1") {
+                fanout.publish(OutgoingMessage {
+                    topic: format!("alerts/{sequence}"),
+                    qos: QoS::AtLeastOnce,
+                    retain: false,
+                    payload: b"{}".to_vec(),
+                    user_properties: Vec::new(),
+                    origin: Some(PublishOrigin {
+                        query_id: "q1".to_string(),
+                        sequence,
+                        op: None,
+                    }),
+                });
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            recorder.topics(),
+            vec!["alerts/0", "alerts/1", "alerts/4", "alerts/5"]
+        );
+        let metrics = &fanout.query_metrics()["q1"];
+        assert_eq!((metrics.published, metrics.muted), (4, 2));
+        assert_eq!(
+            reaction.properties()["enabled_queries"],
+            serde_json::json!(["q1", "q2"])
+        );
+    }
+
+    #[test]
+    fn test_result_span_fields_on_events() {
+        use drasi_mqtt_connection::trace_capture::EventCapture;
+
+        let capture = EventCapture::new();
+        let _guard = capture.set_default();
+        let span = result_span("r1", "q1", 7);
+        let _entered = span.enter();
+        error!(topic = "alerts/a", "Failed to render user properties");
+
+        let errors = capture.at(tracing::Level::ERROR);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field("reaction_id"), Some("r1"));
+        assert_eq!(errors[0].field("query_id"), Some("q1"));
+        assert_eq!(errors[0].field("sequence"), Some("7"));
+        assert_eq!(errors[0].field("topic"), Some("alerts/a"));
+    }
+
+    #[tokio::test]
+    async fn test_status_reflects_broker_connection() {
+        use drasi_mqtt_connection::fake_broker::{FakeBroker, CONNECT};
+
+        let broker = FakeBroker::bind().await;
+        let config = MqttReactionConfig::builder("r", "127.0.0.1", "alerts", vec!["q1".into()])
+            .port(broker.port())
+            .degraded_after(Duration::ZERO)
+            .build();
+        let reaction = MqttReaction::new(config);
+        reaction.start().await.unwrap();
+        reaction.base.set_status(ComponentStatus::Running).await;
+
+        let wait_for = |expected: ComponentStatus| {
+            let reaction = &reaction;
+            async move {
+                for _ in 0..100 {
+                    if reaction.status().await == expected {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                panic!("status never became {expected:?}");
+            }
+        };
+        let accept = || async {
+            let mut client = broker.accept().await;
+            client.expect(CONNECT).await;
+            client.connack(0).await;
+            client
+        };
+
+        // Accept the connection, ConnAck it, then drop it.
+        let client = accept().await;
+        wait_for(ComponentStatus::Running).await;
+        drop(client);
+        wait_for(ComponentStatus::Error).await;
+
+        // The driver reconnects after its back-off.
+        let _client = accept().await;
+        wait_for(ComponentStatus::Running).await;
+        reaction.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stop_ends_broker_drivers() {
+        use drasi_mqtt_connection::fake_broker::{FakeBroker, CONNECT, DISCONNECT};
+
+        let broker = FakeBroker::bind().await;
+        let config = MqttReactionConfig::builder("r", "127.0.0.1", "alerts", vec!["q1".into()])
+            .port(broker.port())
+            .build();
+        let reaction = MqttReaction::new(config);
+        reaction.start().await.unwrap();
+        let mut client = broker.accept().await;
+        client.expect(CONNECT).await;
+        client.connack(0).await;
+
+        reaction.stop().await.unwrap();
+        client.expect(DISCONNECT).await;
+        assert!(reaction.drivers.read().await.is_empty());
+        drop(client);
+        let reconnect = broker.try_accept(Duration::from_millis(1500)).await;
+        assert!(reconnect.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed_start_aborts_broker_drivers() {
+        use crate::config::{BrokerEndpoint, TlsConfig};
+
+        let config = MqttReactionConfig::builder("r", "127.0.0.1", "alerts", vec!["q1".into()])
+            .add_broker(
+                BrokerEndpoint::new("secure", "127.0.0.1", 8883)
+                    .tls(TlsConfig::new("/nonexistent/ca.pem")),
+            )
+            .build();
+        let reaction = MqttReaction::new(config);
+
+        assert!(reaction.start().await.is_err());
+        assert!(reaction.drivers.read().await.is_empty());
+        assert!(reaction.clients.read().await.is_empty());
     }
 }
@@ -0,0 +1,292 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Republishing of retained messages after a reconnect.
+//!
+//! A broker that fails over without persistence loses its retained
+//! messages. The reaction keeps the last retained payload per topic and
+//! queues them again on the broker's fan-out buffer when its connection is
+//! re-established, behind any newer message for the same topic.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+use tracing::{info, warn};
+
+use crate::fanout::{FanOut, OutgoingMessage};
+
+/// The reaction's fan-out, set once it is built after the brokers connect.
+pub type FanOutSlot = Arc<OnceLock<Weak<FanOut>>>;
+
+/// Last retained message per topic, bounded to `capacity` topics.
+pub struct RetainedCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Topic → (message, update counter).
+    entries: HashMap<String, (OutgoingMessage, u64)>,
+    counter: u64,
+}
+
+impl RetainedCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Remember `msg` if it is retained.
+    ///
+    /// An empty retained payload clears the topic on the broker, so it also
+    /// clears the cached entry. When full, the least recently updated topic
+    /// is evicted.
+    pub fn record(&self, msg: &OutgoingMessage) {
+        if !msg.retain || self.capacity == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let CacheState { entries, counter } = &mut *state;
+        if msg.payload.is_empty() {
+            entries.remove(&msg.topic);
+            return;
+        }
+
+        if entries.len() >= self.capacity && !entries.contains_key(&msg.topic) {
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, (_, updated))| *updated)
+                .map(|(topic, _)| topic.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+
+        *counter += 1;
+        entries.insert(msg.topic.clone(), (msg.clone(), *counter));
+    }
+
+    /// Cached messages, least recently updated first.
+    pub fn snapshot(&self) -> Vec<OutgoingMessage> {
+        let state = self.state.lock().unwrap();
+        let mut messages: Vec<&(OutgoingMessage, u64)> = state.entries.values().collect();
+        messages.sort_by_key(|(_, updated)| *updated);
+        messages.into_iter().map(|(msg, _)| msg.clone()).collect()
+    }
+}
+
+/// Republishes the retained cache to one broker each time its connection
+/// comes back after the first ConnAck.
+pub struct Republisher {
+    reaction_id: String,
+    broker: String,
+    cache: Arc<RetainedCache>,
+    fanout: FanOutSlot,
+    connected_before: bool,
+}
+
+impl Republisher {
+    pub fn new(
+        reaction_id: impl Into<String>,
+        broker: impl Into<String>,
+        cache: Arc<RetainedCache>,
+        fanout: FanOutSlot,
+    ) -> Self {
+        Self {
+            reaction_id: reaction_id.into(),
+            broker: broker.into(),
+            cache,
+            fanout,
+            connected_before: false,
+        }
+    }
+
+    /// Handle a ConnAck from the broker's eventloop.
+    ///
+    /// On a reconnect, queues the cached messages in update order on the
+    /// broker's fan-out buffer and returns how many were queued.
+    pub fn on_connack(&mut self) -> usize {
+        if !std::mem::replace(&mut self.connected_before, true) {
+            return 0;
+        }
+
+        // The cache is updated before a message is queued, so a message
+        // queued after this snapshot is never older than the one it follows.
+        let messages = self.cache.snapshot();
+        if messages.is_empty() {
+            return 0;
+        }
+        let Some(fanout) = self.fanout.get().and_then(Weak::upgrade) else {
+            warn!(
+                "[{}] Not republishing retained messages to broker '{}': the reaction is not publishing",
+                self.reaction_id, self.broker
+            );
+            return 0;
+        };
+
+        let count = messages.len();
+        info!(
+            "[{}] Republishing {count} retained message(s) to broker '{}' after reconnect",
+            self.reaction_id, self.broker
+        );
+        fanout.replay(&self.broker, messages);
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::RecordingClient;
+    use crate::client::PublishClient;
+    use rumqttc::QoS;
+    use std::time::Duration;
+
+    fn retained(topic: &str, payload: &str) -> OutgoingMessage {
+        OutgoingMessage {
+            topic: topic.to_string(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            payload: payload.as_bytes().to_vec(),
+            user_properties: Vec::new(),
+            origin: None,
+        }
+    }
+
+    #[test]
+    fn test_cache_keeps_latest_per_topic_and_evicts_oldest() {
+        let cache = RetainedCache::new(2);
+        cache.record(&retained("state/a", "1"));
+        cache.record(&retained("state/b", "1"));
+        cache.record(&retained("state/a", "2"));
+        cache.record(&OutgoingMessage {
+            retain: false,
+            ..retained("state/b", "ignored")
+        });
+        // Full: evicts "state/b", the least recently updated.
+        cache.record(&retained("state/c", "1"));
+
+        let cached: Vec<(String, Vec<u8>)> = cache
+            .snapshot()
+            .into_iter()
+            .map(|m| (m.topic, m.payload))
+            .collect();
+        assert_eq!(
+            cached,
+            vec![
+                ("state/a".to_string(), b"2".to_vec()),
+                ("state/c".to_string(), b"1".to_vec()),
+            ]
+        );
+
+        cache.record(&retained("state/a", ""));
+        assert_eq!(cache.snapshot().len(), 1);
+    }
+
+    /// A fan-out to one broker, "primary", recording what it publishes,
+    /// and a republisher for that broker.
+    fn republishing(
+        cache: &Arc<RetainedCache>,
+    ) -> (Arc<FanOut>, Arc<RecordingClient>, Republisher) {
+        let client = Arc::new(RecordingClient::default());
+        let fanout = Arc::new(FanOut::new(
+            "r1",
+            10,
+            None,
+            None,
+            vec![(
+                "primary".to_string(),
+                client.clone() as Arc<dyn PublishClient>,
+            )],
+        ));
+        let slot = FanOutSlot::default();
+        slot.set(Arc::downgrade(&fanout)).unwrap();
+        let republisher = Republisher::new("r1", "primary", cache.clone(), slot);
+        (fanout, client, republisher)
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    fn sent(client: &RecordingClient) -> Vec<(String, Vec<u8>)> {
+        client
+            .published
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| (p.topic.clone(), p.payload.clone()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_republishes_on_reconnect_in_update_order() {
+        let cache = Arc::new(RetainedCache::new(10));
+        let (_fanout, client, mut republisher) = republishing(&cache);
+
+        cache.record(&retained("discovery/sensor", "config"));
+        cache.record(&retained("state/a", "on"));
+        cache.record(&retained("state/b", "off"));
+        cache.record(&retained("state/a", "off"));
+
+        // Initial connect: nothing to restore.
+        assert_eq!(republisher.on_connack(), 0);
+        settle().await;
+        assert!(client.topics().is_empty());
+
+        assert_eq!(republisher.on_connack(), 3);
+        settle().await;
+
+        assert_eq!(
+            sent(&client),
+            vec![
+                ("discovery/sensor".to_string(), b"config".to_vec()),
+                ("state/b".to_string(), b"off".to_vec()),
+                ("state/a".to_string(), b"off".to_vec()),
+            ]
+        );
+        assert!(client
+            .published
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|p| p.retain && p.qos == QoS::AtLeastOnce));
+    }
+
+    #[tokio::test]
+    async fn test_republish_never_overtakes_newer_message() {
+        let cache = Arc::new(RetainedCache::new(10));
+        let (fanout, client, mut republisher) = republishing(&cache);
+        republisher.on_connack();
+
+        cache.record(&retained("state/a", "on"));
+        republisher.on_connack();
+        let newer = retained("state/a", "off");
+        cache.record(&newer);
+        fanout.publish(newer);
+        settle().await;
+
+        assert_eq!(
+            sent(&client),
+            vec![
+                ("state/a".to_string(), b"on".to_vec()),
+                ("state/a".to_string(), b"off".to_vec()),
+            ]
+        );
+    }
+}
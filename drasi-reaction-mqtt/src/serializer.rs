@@ -0,0 +1,625 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pluggable serialization of query results into MQTT messages.
+
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+use chrono::{DateTime, Utc};
+use handlebars::Handlebars;
+use rumqttc::QoS;
+use serde_json::Value;
+
+use crate::config::{DeletePayloadMode, MetadataConfig, MqttReactionConfig};
+use crate::publisher::{self, DiffBatch, JsonFormat, Renderer};
+
+/// The operation a result item represents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl Op {
+    /// Name of the operation as exposed to templates (`insert`, `update`, `delete`).
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Op::Insert => "insert",
+            Op::Update => "update",
+            Op::Delete => "delete",
+        }
+    }
+}
+
+/// Per-result information available to serializers.
+#[derive(Debug, Clone)]
+pub struct SerializeContext<'a> {
+    /// Id of the reaction publishing the result.
+    pub reaction_id: &'a str,
+    /// Monotonic sequence number of the result within this reaction run.
+    pub sequence: u64,
+    /// Host the reaction runs on.
+    pub hostname: &'a str,
+    /// Wall-clock time the result is being published at.
+    pub published_at: DateTime<Utc>,
+    /// Timestamp of the query result, if known.
+    pub result_timestamp: Option<DateTime<Utc>>,
+}
+
+impl<'a> SerializeContext<'a> {
+    /// Context for a result published now, without a result timestamp.
+    pub fn new(reaction_id: &'a str, sequence: u64) -> Self {
+        Self {
+            reaction_id,
+            sequence,
+            hostname: hostname(),
+            published_at: Utc::now(),
+            result_timestamp: None,
+        }
+    }
+}
+
+/// Name of the local host, looked up once.
+fn hostname() -> &'static str {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+    HOSTNAME.get_or_init(|| gethostname::gethostname().to_string_lossy().into_owned())
+}
+
+/// Converts query results into (topic, payload) pairs to publish.
+///
+/// Implement this to produce payloads Handlebars templates can't express
+/// (e.g. binary frames) and register it with
+/// [`MqttReaction::with_serializer`](crate::MqttReaction::with_serializer).
+pub trait ResultSerializer: Send + Sync {
+    /// Serialize a single result item.
+    fn serialize(
+        &self,
+        query_id: &str,
+        op: Op,
+        item: &Value,
+        ctx: &SerializeContext,
+    ) -> anyhow::Result<Vec<(String, Vec<u8>)>>;
+
+    /// Serialize all items of a query result.
+    ///
+    /// The default calls [`serialize`](Self::serialize) for every item, in
+    /// insert, update, delete order.
+    fn serialize_batch(
+        &self,
+        query_id: &str,
+        batch: &DiffBatch,
+        ctx: &SerializeContext,
+    ) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        let mut messages = Vec::new();
+        for (op, item) in batch.items() {
+            messages.extend(self.serialize(query_id, op, item, ctx)?);
+        }
+        Ok(messages)
+    }
+
+    /// Whether results of `query_id` are published as one message per item.
+    ///
+    /// Per-item QoS and retain flags and the clearing of retained messages
+    /// of removed items only apply to split queries. The default is `true`, matching [`serialize_batch`](Self::serialize_batch).
+    fn splits(&self, _query_id: &str) -> bool {
+        true
+    }
+}
+
+/// A serialized result message and how to publish it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResultMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+    /// The operation of the item the message was serialized from; for a
+    /// batch message, the operation shared by all its items, if any.
+    pub op: Option<Op>,
+}
+
+/// Serialize a query result into the messages to publish.
+///
+/// Split queries are serialized item by item, so each message carries its
+/// item's operation. Publish parameters may also depend on the item:
+/// * `qos_field`/`retain_field` name item fields overriding the QoS and
+///   retain flag of the item's messages. QoS values are clamped to 0..=2;
+///   absent or non-numeric/non-boolean values fall back to the defaults.
+/// * With `clear_retained_on_remove`, each removed item is published as an
+///   empty retained message on its topic, after the other messages,
+///   clearing the broker's retained state; its normal delete message is
+///   only kept with `keep_remove_payload`.
+pub fn result_messages(
+    serializer: &dyn ResultSerializer,
+    query_id: &str,
+    batch: &DiffBatch,
+    ctx: &SerializeContext,
+    config: &MqttReactionConfig,
+) -> anyhow::Result<Vec<ResultMessage>> {
+    if !serializer.splits(query_id) {
+        let messages = serializer.serialize_batch(query_id, batch, ctx)?;
+        return Ok(messages
+            .into_iter()
+            .map(|(topic, payload)| ResultMessage {
+                topic,
+                payload,
+                qos: DEFAULT_QOS,
+                retain: config.retain,
+                op: batch.single_op(),
+            })
+            .collect());
+    }
+
+    let mut messages = Vec::new();
+    let mut clears = Vec::new();
+    for (op, item) in batch.items() {
+        let qos = config
+            .qos_field
+            .as_ref()
+            .and_then(|field| item.get(field))
+            .and_then(qos_value)
+            .unwrap_or(DEFAULT_QOS);
+        let retain = config
+            .retain_field
+            .as_ref()
+            .and_then(|field| item.get(field))
+            .and_then(Value::as_bool)
+            .unwrap_or(config.retain);
+        let serialized = serializer.serialize(query_id, op, item, ctx)?;
+        if op == Op::Delete && config.clear_retained_on_remove {
+            clears.extend(serialized.iter().map(|(topic, _)| ResultMessage {
+                topic: topic.clone(),
+                payload: Vec::new(),
+                qos,
+                retain: true,
+                op: Some(op),
+            }));
+            if !config.keep_remove_payload {
+                continue;
+            }
+        }
+        messages.extend(
+            serialized
+                .into_iter()
+                .map(|(topic, payload)| ResultMessage {
+                    topic,
+                    payload,
+                    qos,
+                    retain,
+                    op: Some(op),
+                }),
+        );
+    }
+    messages.extend(clears);
+    Ok(messages)
+}
+
+/// QoS of result messages whose item does not choose one.
+const DEFAULT_QOS: QoS = QoS::AtLeastOnce;
+
+/// The QoS a numeric field value asks for, clamped to 0..=2.
+fn qos_value(value: &Value) -> Option<QoS> {
+    let level = value
+        .as_i64()
+        .or_else(|| value.as_f64().map(|f| f as i64))?;
+    Some(match level {
+        i64::MIN..=0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    })
+}
+
+/// Default serializer: Handlebars topic/payload templates with JSON fallback.
+///
+/// Publishes one message per item when the topic is templated or a payload
+/// template is set, and a single batched JSON message otherwise.
+/// Per-query topic and payload templates take precedence over the global ones.
+pub struct TemplateSerializer {
+    registry: Arc<Handlebars<'static>>,
+    topic_template: String,
+    payload_template: Option<String>,
+    query_topics: HashMap<String, String>,
+    query_payload_templates: HashMap<String, String>,
+    json: JsonFormat,
+    include_meta: bool,
+    correlation_ids: bool,
+    topic_prefix: Option<String>,
+    delete_payload: DeletePayloadMode,
+    split_metadata: MetadataConfig,
+}
+
+impl TemplateSerializer {
+    pub fn new(
+        registry: Arc<Handlebars<'static>>,
+        topic_template: impl Into<String>,
+        payload_template: Option<String>,
+    ) -> Self {
+        Self {
+            registry,
+            topic_template: topic_template.into(),
+            payload_template,
+            query_topics: HashMap::new(),
+            query_payload_templates: HashMap::new(),
+            json: JsonFormat::default(),
+            include_meta: false,
+            correlation_ids: false,
+            topic_prefix: None,
+            delete_payload: DeletePayloadMode::Full,
+            split_metadata: MetadataConfig::default(),
+        }
+    }
+
+    /// Build a serializer from the reaction config, including per-query overrides.
+    pub fn from_config(registry: Arc<Handlebars<'static>>, config: &MqttReactionConfig) -> Self {
+        Self {
+            query_topics: config.query_topics.clone(),
+            query_payload_templates: config.query_payload_templates.clone(),
+            json: JsonFormat {
+                pretty: config.json_pretty,
+                sort_keys: config.sort_keys,
+            },
+            include_meta: config.include_meta,
+            correlation_ids: config.correlation_ids,
+            topic_prefix: config.topic_prefix.clone(),
+            delete_payload: config.delete_payload.clone(),
+            split_metadata: config.split_metadata.clone(),
+            ..Self::new(
+                registry,
+                config.topic.clone(),
+                config.payload_template.clone(),
+            )
+        }
+    }
+
+    /// Check that every template compiles, so mistakes surface at start.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        publisher::validate_template("topic", &self.topic_template)?;
+        if let Some(tmpl) = &self.payload_template {
+            publisher::validate_template("payload_template", tmpl)?;
+        }
+        for (query_id, tmpl) in &self.query_topics {
+            publisher::validate_template(&format!("query_topics.{query_id}"), tmpl)?;
+        }
+        for (query_id, tmpl) in &self.query_payload_templates {
+            publisher::validate_template(&format!("query_payload_templates.{query_id}"), tmpl)?;
+        }
+        if let DeletePayloadMode::Custom(tmpl) = &self.delete_payload {
+            publisher::validate_template("delete_payload", tmpl)?;
+        }
+        self.split_metadata.validate()?;
+        Ok(())
+    }
+
+    /// Renderer using the templates that apply to `query_id`.
+    fn renderer_for(&self, query_id: &str) -> Renderer<'_> {
+        let topic = self
+            .query_topics
+            .get(query_id)
+            .unwrap_or(&self.topic_template);
+        let payload = self
+            .query_payload_templates
+            .get(query_id)
+            .or(self.payload_template.as_ref());
+        Renderer {
+            json: self.json,
+            include_meta: self.include_meta,
+            correlation_ids: self.correlation_ids,
+            topic_prefix: self.topic_prefix.as_deref(),
+            delete_payload: &self.delete_payload,
+            split_metadata: Some(&self.split_metadata),
+            ..Renderer::new(&self.registry, topic, payload.map(String::as_str))
+        }
+    }
+}
+
+impl ResultSerializer for TemplateSerializer {
+    fn serialize(
+        &self,
+        query_id: &str,
+        op: Op,
+        item: &Value,
+        ctx: &SerializeContext,
+    ) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        let message = self
+            .renderer_for(query_id)
+            .render_item(query_id, op, item, ctx)?;
+        Ok(vec![message])
+    }
+
+    fn serialize_batch(
+        &self,
+        query_id: &str,
+        batch: &DiffBatch,
+        ctx: &SerializeContext,
+    ) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        self.renderer_for(query_id)
+            .result_to_payload(query_id, batch, ctx)
+    }
+
+    fn splits(&self, query_id: &str) -> bool {
+        let renderer = self.renderer_for(query_id);
+        publisher::is_split_mode(renderer.topic_template, renderer.payload_template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Example custom serializer: one CSV line per item on a per-query topic.
+    struct CsvSerializer;
+
+    impl ResultSerializer for CsvSerializer {
+        fn serialize(
+            &self,
+            query_id: &str,
+            op: Op,
+            item: &Value,
+            ctx: &SerializeContext,
+        ) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+            let line = format!(
+                "{},{},{},{}",
+                ctx.sequence,
+                op.as_str(),
+                item["device"].as_str().unwrap_or_default(),
+                item["temp"]
+            );
+            Ok(vec![(format!("csv/{query_id}"), line.into_bytes())])
+        }
+    }
+
+    #[test]
+    fn test_custom_serializer_csv_line_per_item() {
+        let batch = DiffBatch {
+            added: vec![serde_json::json!({"device": "d1", "temp": 31})],
+            updated: vec![serde_json::json!({"device": "d2", "temp": 35})],
+            removed: vec![serde_json::json!({"device": "d3", "temp": 20})],
+        };
+        let ctx = SerializeContext::new("r1", 7);
+
+        let messages = CsvSerializer.serialize_batch("q1", &batch, &ctx).unwrap();
+
+        let lines: Vec<(&str, String)> = messages
+            .iter()
+            .map(|(topic, payload)| (topic.as_str(), String::from_utf8(payload.clone()).unwrap()))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                ("csv/q1", "7,insert,d1,31".to_string()),
+                ("csv/q1", "7,update,d2,35".to_string()),
+                ("csv/q1", "7,delete,d3,20".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_template_serializer_matches_result_to_payload() {
+        let registry = Arc::new(Handlebars::new());
+        let serializer = TemplateSerializer::new(registry, "static/topic", None);
+        let batch = DiffBatch {
+            added: vec![serde_json::json!({"device": "d1"})],
+            ..Default::default()
+        };
+        let ctx = SerializeContext::new("r1", 1);
+
+        let messages = serializer.serialize_batch("q1", &batch, &ctx).unwrap();
+
+        assert_eq!(messages.len(), 1);
+        let parsed: Value = serde_json::from_slice(&messages[0].1).unwrap();
+        assert_eq!(parsed["added"][0]["device"], "d1");
+    }
+
+    #[test]
+    fn test_clear_retained_on_remove_publishes_empty_retained_payload() {
+        let config =
+            MqttReactionConfig::builder("r1", "localhost", "devices/{{device}}", vec!["q1".into()])
+                .retain(true)
+                .clear_retained_on_remove(false)
+                .build();
+        let serializer = TemplateSerializer::from_config(Arc::new(Handlebars::new()), &config);
+        let batch = DiffBatch {
+            added: vec![serde_json::json!({"device": "d1"})],
+            removed: vec![serde_json::json!({"device": "d2"})],
+            ..Default::default()
+        };
+        let ctx = SerializeContext::new("r1", 1);
+
+        let messages = result_messages(&serializer, "q1", &batch, &ctx, &config).unwrap();
+
+        assert_eq!(messages.len(), 2);
+        let added = &messages[0];
+        assert_eq!(added.topic, "devices/d1");
+        assert!(added.retain);
+        let parsed: Value = serde_json::from_slice(&added.payload).unwrap();
+        assert_eq!(parsed["op"], "insert");
+        assert_eq!(
+            messages[1],
+            ResultMessage {
+                topic: "devices/d2".into(),
+                payload: Vec::new(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+                op: Some(Op::Delete),
+            }
+        );
+    }
+
+    #[test]
+    fn test_clear_retained_on_remove_can_keep_delete_payload() {
+        let config =
+            MqttReactionConfig::builder("r1", "localhost", "devices/{{device}}", vec!["q1".into()])
+                .clear_retained_on_remove(true)
+                .build();
+        let serializer = TemplateSerializer::from_config(Arc::new(Handlebars::new()), &config);
+        let batch = DiffBatch {
+            removed: vec![serde_json::json!({"device": "d2"})],
+            ..Default::default()
+        };
+        let ctx = SerializeContext::new("r1", 1);
+
+        let messages = result_messages(&serializer, "q1", &batch, &ctx, &config).unwrap();
+
+        assert_eq!(messages.len(), 2);
+        assert!(!messages[0].retain);
+        let parsed: Value = serde_json::from_slice(&messages[0].payload).unwrap();
+        assert_eq!(parsed["op"], "delete");
+        assert_eq!(messages[1].topic, "devices/d2");
+        assert!(messages[1].payload.is_empty() && messages[1].retain);
+
+        // Batched results have no per-item topic to clear.
+        let batched = MqttReactionConfig::builder("r1", "localhost", "alerts", vec!["q1".into()])
+            .clear_retained_on_remove(false)
+            .build();
+        let serializer = TemplateSerializer::from_config(Arc::new(Handlebars::new()), &batched);
+        let messages = result_messages(&serializer, "q1", &batch, &ctx, &batched).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(!messages[0].payload.is_empty());
+    }
+
+    #[test]
+    fn test_split_messages_carry_their_items_op() {
+        let batch = DiffBatch {
+            added: vec![serde_json::json!({"device": "d1"})],
+            updated: vec![serde_json::json!({"device": "d2"})],
+            removed: vec![serde_json::json!({"device": "d3"})],
+        };
+        let ctx = SerializeContext::new("r1", 1);
+
+        let split =
+            MqttReactionConfig::builder("r1", "localhost", "devices/{{device}}", vec!["q1".into()])
+                .build();
+        let serializer = TemplateSerializer::from_config(Arc::new(Handlebars::new()), &split);
+        let ops: Vec<Option<Op>> = result_messages(&serializer, "q1", &batch, &ctx, &split)
+            .unwrap()
+            .iter()
+            .map(|message| message.op)
+            .collect();
+        assert_eq!(
+            ops,
+            vec![Some(Op::Insert), Some(Op::Update), Some(Op::Delete)]
+        );
+
+        let batched =
+            MqttReactionConfig::builder("r1", "localhost", "alerts", vec!["q1".into()]).build();
+        let serializer = TemplateSerializer::from_config(Arc::new(Handlebars::new()), &batched);
+        let messages = result_messages(&serializer, "q1", &batch, &ctx, &batched).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].op, None);
+    }
+
+    #[tokio::test]
+    async fn test_per_item_qos_and_retain() {
+        use crate::client::testing::RecordingClient;
+        use crate::client::PublishClient;
+
+        let config =
+            MqttReactionConfig::builder("r1", "localhost", "valves/{{valve}}", vec!["q1".into()])
+                .qos_field("qos")
+                .retain_field("retain")
+                .build();
+        let serializer = TemplateSerializer::from_config(Arc::new(Handlebars::new()), &config);
+        let batch = DiffBatch {
+            added: vec![
+                serde_json::json!({"valve": "v1", "qos": 2, "retain": true}),
+                serde_json::json!({"valve": "v2", "qos": 0}),
+                serde_json::json!({"valve": "v3", "qos": 7, "retain": "yes"}),
+                serde_json::json!({"valve": "v4"}),
+            ],
+            ..Default::default()
+        };
+        let ctx = SerializeContext::new("r1", 1);
+        let client = RecordingClient::default();
+
+        for message in result_messages(&serializer, "q1", &batch, &ctx, &config).unwrap() {
+            client
+                .publish(message.topic, message.qos, message.retain, message.payload)
+                .await
+                .unwrap();
+        }
+
+        let published: Vec<(String, QoS, bool)> = client
+            .published
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| (p.topic.clone(), p.qos, p.retain))
+            .collect();
+        assert_eq!(
+            published,
+            vec![
+                ("valves/v1".to_string(), QoS::ExactlyOnce, true),
+                ("valves/v2".to_string(), QoS::AtMostOnce, false),
+                // Out of range: clamped; not a boolean: static retain.
+                ("valves/v3".to_string(), QoS::ExactlyOnce, false),
+                ("valves/v4".to_string(), QoS::AtLeastOnce, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_per_query_templates() {
+        let config = MqttReactionConfig::builder(
+            "r1",
+            "localhost",
+            "alerts/{{query_id}}",
+            vec!["high-temp-alert".into(), "door-open-alert".into()],
+        )
+        .payload_template("generic: {{op}}")
+        .query_payload_template("high-temp-alert", "temp={{temp}}")
+        .query_payload_template("door-open-alert", "door={{door}} open")
+        .query_topic("door-open-alert", "doors/{{door}}")
+        .build();
+        let serializer = TemplateSerializer::from_config(Arc::new(Handlebars::new()), &config);
+        serializer.validate().unwrap();
+        let ctx = SerializeContext::new("r1", 1);
+
+        let render = |query_id: &str, item: Value| {
+            let batch = DiffBatch {
+                added: vec![item],
+                ..Default::default()
+            };
+            let messages = serializer.serialize_batch(query_id, &batch, &ctx).unwrap();
+            let (topic, payload) = messages.into_iter().next().unwrap();
+            (topic, String::from_utf8(payload).unwrap())
+        };
+
+        assert_eq!(
+            render("high-temp-alert", serde_json::json!({"temp": 41})),
+            ("alerts/high-temp-alert".to_string(), "temp=41".to_string())
+        );
+        assert_eq!(
+            render("door-open-alert", serde_json::json!({"door": "d7"})),
+            ("doors/d7".to_string(), "door=d7 open".to_string())
+        );
+        // No override: falls back to the global templates.
+        assert_eq!(
+            render("other", serde_json::json!({})),
+            ("alerts/other".to_string(), "generic: insert".to_string())
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_broken_query_template() {
+        let config = MqttReactionConfig::builder("r1", "localhost", "alerts", vec!["q1".into()])
+            .query_payload_template("q1", "{{#if}}")
+            .build();
+        let serializer = TemplateSerializer::from_config(Arc::new(Handlebars::new()), &config);
+
+        let err = serializer.validate().unwrap_err();
+        assert!(err.to_string().contains("query_payload_templates.q1"));
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Full result sets of the subscribed queries, folded from their diffs, for
+//! consumers that join late.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use drasi_lib::channels::ResultDiff;
+use handlebars::Handlebars;
+use rumqttc::QoS;
+use serde_json::Value;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::fanout::{FanOut, OutgoingMessage};
+
+/// The current result set of one query.
+///
+/// Holds at most `capacity` rows; rows added beyond it are not kept and the
+/// snapshot is marked truncated.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResultSnapshot {
+    rows: Vec<Value>,
+    capacity: usize,
+    truncated: bool,
+}
+
+impl ResultSnapshot {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            rows: Vec::new(),
+            capacity,
+            truncated: false,
+        }
+    }
+
+    pub fn rows(&self) -> &[Value] {
+        &self.rows
+    }
+
+    /// Whether rows were dropped because the snapshot was full.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Fold a query result's diffs into the snapshot. `Aggregation` and
+    /// `Noop` diffs are ignored, as they are not published either.
+    pub fn apply(&mut self, diffs: &[ResultDiff]) {
+        for diff in diffs {
+            match diff {
+                ResultDiff::Add { data } => self.insert(data.clone()),
+                ResultDiff::Update { before, after, .. } => self.update(before, after.clone()),
+                ResultDiff::Delete { data } => self.remove(data),
+                ResultDiff::Aggregation { .. } | ResultDiff::Noop => {}
+            }
+        }
+    }
+
+    pub fn insert(&mut self, row: Value) {
+        if self.rows.len() < self.capacity {
+            self.rows.push(row);
+        } else {
+            self.truncated = true;
+        }
+    }
+
+    /// Replace the row equal to `before`, or insert `after` if there is none.
+    pub fn update(&mut self, before: &Value, after: Value) {
+        match self.rows.iter_mut().find(|row| *row == before) {
+            Some(row) => *row = after,
+            None => self.insert(after),
+        }
+    }
+
+    pub fn remove(&mut self, row: &Value) {
+        if let Some(index) = self.rows.iter().position(|r| r == row) {
+            self.rows.remove(index);
+        }
+    }
+
+    /// The snapshot payload, e.g.
+    /// `{"reaction_id":"r1","query_id":"q1","count":2,"truncated":false,"rows":[...]}`.
+    pub fn payload(&self, reaction_id: &str, query_id: &str) -> Vec<u8> {
+        serde_json::json!({
+            "reaction_id": reaction_id,
+            "query_id": query_id,
+            "count": self.rows.len(),
+            "truncated": self.truncated,
+            "rows": self.rows,
+        })
+        .to_string()
+        .into_bytes()
+    }
+}
+
+/// Snapshots of every subscribed query.
+pub struct Snapshots {
+    reaction_id: String,
+    topic: String,
+    queries: Mutex<HashMap<String, ResultSnapshot>>,
+}
+
+impl Snapshots {
+    /// Empty snapshots of `queries`, published to the `topic` template.
+    pub fn new(reaction_id: &str, topic: &str, queries: &[String], capacity: usize) -> Self {
+        Self {
+            reaction_id: reaction_id.to_string(),
+            topic: topic.to_string(),
+            queries: Mutex::new(
+                queries
+                    .iter()
+                    .map(|query_id| (query_id.clone(), ResultSnapshot::new(capacity)))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Fold a result of `query_id` into its snapshot. Results of queries the
+    /// reaction is not subscribed to are ignored.
+    pub fn apply(&self, query_id: &str, diffs: &[ResultDiff]) {
+        if let Some(snapshot) = self.queries.lock().unwrap().get_mut(query_id) {
+            snapshot.apply(diffs);
+        }
+    }
+
+    pub fn get(&self, query_id: &str) -> Option<ResultSnapshot> {
+        self.queries.lock().unwrap().get(query_id).cloned()
+    }
+
+    /// The message publishing the snapshot of `query_id`. The topic
+    /// template is rendered with `query_id` and `reaction_id`.
+    pub fn message(
+        &self,
+        registry: &Handlebars<'static>,
+        query_id: &str,
+    ) -> anyhow::Result<OutgoingMessage> {
+        let snapshot = self
+            .get(query_id)
+            .ok_or_else(|| anyhow::anyhow!("Not subscribed to query '{query_id}'"))?;
+        let context = serde_json::json!({
+            "query_id": query_id,
+            "reaction_id": self.reaction_id,
+        });
+        Ok(OutgoingMessage {
+            topic: registry.render_template(&self.topic, &context)?,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            payload: snapshot.payload(&self.reaction_id, query_id),
+            user_properties: Vec::new(),
+            origin: None,
+        })
+    }
+
+    fn query_ids(&self) -> Vec<String> {
+        let mut query_ids: Vec<String> = self.queries.lock().unwrap().keys().cloned().collect();
+        query_ids.sort();
+        query_ids
+    }
+}
+
+/// Spawn a task publishing the snapshot of every query every `interval`.
+///
+/// The first snapshots are sent after one interval. Abort the returned
+/// handle to stop it.
+pub fn spawn(
+    snapshots: Arc<Snapshots>,
+    interval: Duration,
+    registry: Arc<Handlebars<'static>>,
+    fanout: Arc<FanOut>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            for query_id in snapshots.query_ids() {
+                match snapshots.message(&registry, &query_id) {
+                    Ok(message) => fanout.publish(message),
+                    Err(e) => warn!(
+                        "[{}] Failed to publish snapshot of query '{query_id}': {e}",
+                        snapshots.reaction_id
+                    ),
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::RecordingClient;
+    use crate::client::PublishClient;
+    use serde_json::json;
+
+    #[test]
+    fn test_snapshot_folds_diffs() {
+        let mut snapshot = ResultSnapshot::new(10);
+        snapshot.apply(&[
+            ResultDiff::Add {
+                data: json!({"device": "d1", "temp": 20}),
+            },
+            ResultDiff::Add {
+                data: json!({"device": "d2", "temp": 21}),
+            },
+        ]);
+        snapshot.update(
+            &json!({"device": "d1", "temp": 20}),
+            json!({"device": "d1", "temp": 25}),
+        );
+        snapshot.apply(&[ResultDiff::Delete {
+            data: json!({"device": "d2", "temp": 21}),
+        }]);
+        snapshot.update(
+            &json!({"device": "d3"}),
+            json!({"device": "d3", "temp": 30}),
+        );
+        snapshot.remove(&json!({"device": "unknown"}));
+
+        assert_eq!(
+            snapshot.rows(),
+            [
+                json!({"device": "d1", "temp": 25}),
+                json!({"device": "d3", "temp": 30}),
+            ]
+        );
+        assert!(!snapshot.is_truncated());
+    }
+
+    #[test]
+    fn test_snapshot_capacity() {
+        let mut snapshot = ResultSnapshot::new(2);
+        for i in 0..3 {
+            snapshot.insert(json!({"id": i}));
+        }
+        assert_eq!(snapshot.rows(), [json!({"id": 0}), json!({"id": 1})]);
+        assert!(snapshot.is_truncated());
+
+        let payload: Value = serde_json::from_slice(&snapshot.payload("r1", "q1")).unwrap();
+        assert_eq!(payload["count"], 2);
+        assert_eq!(payload["truncated"], true);
+        assert_eq!(payload["rows"], json!([{"id": 0}, {"id": 1}]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_snapshots_published_on_interval() {
+        let client = Arc::new(RecordingClient::default());
+        let fanout = Arc::new(FanOut::new(
+            "r1",
+            10,
+            None,
+            None,
+            vec![(
+                "primary".to_string(),
+                client.clone() as Arc<dyn PublishClient>,
+            )],
+        ));
+        let snapshots = Arc::new(Snapshots::new(
+            "r1",
+            "snapshots/{{query_id}}",
+            &["q1".to_string(), "q2".to_string()],
+            100,
+        ));
+        snapshots.apply(
+            "q1",
+            &[ResultDiff::Add {
+                data: json!({"device": "d1"}),
+            }],
+        );
+        snapshots.apply(
+            "other",
+            &[ResultDiff::Add {
+                data: json!({"device": "d2"}),
+            }],
+        );
+
+        let handle = spawn(
+            snapshots,
+            Duration::from_millis(100),
+            Arc::new(Handlebars::new()),
+            fanout,
+        );
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        handle.abort();
+        tokio::task::yield_now().await;
+
+        assert_eq!(client.topics(), ["snapshots/q1", "snapshots/q2"]);
+        let records = client.published.lock().unwrap().clone();
+        let q1: Value = serde_json::from_slice(&records[0].payload).unwrap();
+        assert_eq!(q1["rows"], json!([{"device": "d1"}]));
+        let q2: Value = serde_json::from_slice(&records[1].payload).unwrap();
+        assert_eq!(q2["count"], 0);
+    }
+}
@@ -0,0 +1,474 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! MQTT 5 topic aliases for outgoing publishes.
+//!
+//! After a topic has been sent once together with an alias, later publishes
+//! to it carry only the two-byte alias. Aliases are scoped to a network
+//! connection, so the mapping is rebuilt whenever the broker's ConnAck is
+//! received.
+//!
+//! A new alias is only bound once the publish assigning it has been queued
+//! on the client, so a failed or cancelled publish never leaves a binding
+//! the broker has not seen. Publishes through one [`AliasingClient`] are
+//! queued one at a time, so assignments always precede their reuses.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use rumqttc::QoS;
+use tokio::sync::Mutex;
+
+use crate::client::PublishClient;
+
+/// How a publish should use topic aliases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AliasUse {
+    /// Send the full topic without an alias.
+    None,
+    /// Send the full topic and bind it to this alias.
+    Assign(u16),
+    /// Send an empty topic with this previously bound alias.
+    Reuse(u16),
+}
+
+/// Least-recently-used mapping of topics to aliases `1..=max`.
+#[derive(Debug, Default)]
+pub struct TopicAliases {
+    max: u16,
+    /// Topic → (alias, last use).
+    aliases: HashMap<String, (u16, u64)>,
+    clock: u64,
+}
+
+impl TopicAliases {
+    /// An empty mapping allowing up to `max` aliases (0 disables aliasing).
+    pub fn new(max: u16) -> Self {
+        Self {
+            max,
+            ..Default::default()
+        }
+    }
+
+    /// Decide how to publish to `topic`, without changing the mapping.
+    ///
+    /// When all aliases are in use, the least recently used one is to be
+    /// rebound.
+    pub fn plan(&self, topic: &str) -> AliasUse {
+        if self.max == 0 || topic.is_empty() {
+            return AliasUse::None;
+        }
+        if let Some((alias, _)) = self.aliases.get(topic) {
+            return AliasUse::Reuse(*alias);
+        }
+        if self.aliases.len() < self.max as usize {
+            return AliasUse::Assign(self.aliases.len() as u16 + 1);
+        }
+        let (alias, _) = self
+            .aliases
+            .values()
+            .min_by_key(|(_, last_use)| *last_use)
+            .expect("aliases is full, so not empty");
+        AliasUse::Assign(*alias)
+    }
+
+    /// Record that a publish to `topic` planned as `alias_use` was sent.
+    pub fn commit(&mut self, topic: &str, alias_use: AliasUse) {
+        self.clock += 1;
+        match alias_use {
+            AliasUse::None => {}
+            AliasUse::Reuse(_) => {
+                if let Some((_, last_use)) = self.aliases.get_mut(topic) {
+                    *last_use = self.clock;
+                }
+            }
+            AliasUse::Assign(alias) => {
+                self.aliases.retain(|_, (bound, _)| *bound != alias);
+                self.aliases.insert(topic.to_string(), (alias, self.clock));
+            }
+        }
+    }
+
+    /// Decide how to publish to `topic` and record it as sent.
+    pub fn resolve(&mut self, topic: &str) -> AliasUse {
+        let alias_use = self.plan(topic);
+        self.commit(topic, alias_use);
+        alias_use
+    }
+}
+
+/// The broker's topic alias maximum, updated from each ConnAck.
+#[derive(Debug, Default)]
+pub struct AliasLimit {
+    max: AtomicU16,
+    connection: AtomicU64,
+}
+
+impl AliasLimit {
+    /// Record a new connection whose ConnAck advertised `topic_alias_max`.
+    pub fn on_connack(&self, topic_alias_max: Option<u16>) {
+        self.max
+            .store(topic_alias_max.unwrap_or(0), Ordering::SeqCst);
+        self.connection.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn current(&self) -> (u64, u16) {
+        (
+            self.connection.load(Ordering::SeqCst),
+            self.max.load(Ordering::SeqCst),
+        )
+    }
+}
+
+/// The publishing half of an MQTT 5 client.
+#[async_trait]
+pub trait PublishWithProperties: Send + Sync {
+    async fn publish_with_properties(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        properties: PublishProperties,
+    ) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+impl PublishWithProperties for rumqttc::v5::AsyncClient {
+    async fn publish_with_properties(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        properties: PublishProperties,
+    ) -> anyhow::Result<()> {
+        rumqttc::v5::AsyncClient::publish_with_properties(
+            self,
+            topic,
+            crate::client::v5_qos(qos),
+            retain,
+            payload,
+            properties,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Publishes through an MQTT 5 client, using topic aliases for repeat topics
+/// up to the limit the broker advertised. Without a limit (or a limit of 0)
+/// every publish carries its full topic.
+pub struct AliasingClient<C> {
+    inner: C,
+    limit: Arc<AliasLimit>,
+    /// Aliases of the connection identified by the first field.
+    aliases: Mutex<(u64, TopicAliases)>,
+}
+
+impl<C: PublishWithProperties> AliasingClient<C> {
+    pub fn new(inner: C, limit: Arc<AliasLimit>) -> Self {
+        Self {
+            inner,
+            limit,
+            aliases: Mutex::new((0, TopicAliases::new(0))),
+        }
+    }
+}
+
+#[async_trait]
+impl<C: PublishWithProperties> PublishClient for AliasingClient<C> {
+    async fn publish(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        self.publish_with_user_properties(topic, qos, retain, payload, Vec::new())
+            .await
+    }
+
+    async fn publish_with_user_properties(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+    ) -> anyhow::Result<()> {
+        // Held until the publish is queued, so the wire order of assignments
+        // and reuses is the order they were planned in.
+        let mut aliases = self.aliases.lock().await;
+        let (connection, max) = self.limit.current();
+        if aliases.0 != connection {
+            *aliases = (connection, TopicAliases::new(max));
+        }
+        let alias_use = aliases.1.plan(&topic);
+        let (sent_topic, topic_alias) = match alias_use {
+            AliasUse::None => (topic.clone(), None),
+            AliasUse::Assign(alias) => (topic.clone(), Some(alias)),
+            AliasUse::Reuse(alias) => (String::new(), Some(alias)),
+        };
+        let properties = PublishProperties {
+            topic_alias,
+            user_properties,
+            ..Default::default()
+        };
+        // Dropping this future before the publish is queued leaves the
+        // mapping untouched.
+        self.inner
+            .publish_with_properties(sent_topic, qos, retain, payload, properties)
+            .await?;
+        aliases.1.commit(&topic, alias_use);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Records the (topic, alias) and user properties of every publish,
+    /// queued or not.
+    #[derive(Default)]
+    struct FakeV5Client {
+        sent: Mutex<Vec<(String, Option<u16>)>>,
+        user_properties: Mutex<Vec<Vec<(String, String)>>>,
+        /// Reject the next publish.
+        fail_next: AtomicBool,
+        /// Never finish queueing the next publish.
+        stall_next: AtomicBool,
+    }
+
+    #[async_trait]
+    impl PublishWithProperties for Arc<FakeV5Client> {
+        async fn publish_with_properties(
+            &self,
+            topic: String,
+            _qos: QoS,
+            _retain: bool,
+            _payload: Vec<u8>,
+            properties: PublishProperties,
+        ) -> anyhow::Result<()> {
+            // Let other publishers run, as a full request queue would.
+            tokio::task::yield_now().await;
+            self.sent
+                .lock()
+                .unwrap()
+                .push((topic, properties.topic_alias));
+            self.user_properties
+                .lock()
+                .unwrap()
+                .push(properties.user_properties);
+            if self.stall_next.swap(false, Ordering::SeqCst) {
+                std::future::pending::<()>().await;
+            }
+            if self.fail_next.swap(false, Ordering::SeqCst) {
+                anyhow::bail!("request queue closed");
+            }
+            Ok(())
+        }
+    }
+
+    async fn send(client: &AliasingClient<Arc<FakeV5Client>>, topics: &[&str]) {
+        for topic in topics {
+            client
+                .publish(topic.to_string(), QoS::AtLeastOnce, false, Vec::new())
+                .await
+                .unwrap();
+        }
+    }
+
+    fn sent(fake: &FakeV5Client) -> Vec<(String, Option<u16>)> {
+        std::mem::take(&mut *fake.sent.lock().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_aliases_assigned_and_reused() {
+        let fake = Arc::new(FakeV5Client::default());
+        let limit = Arc::new(AliasLimit::default());
+        limit.on_connack(Some(2));
+        let client = AliasingClient::new(fake.clone(), limit);
+
+        send(&client, &["a", "b", "a", "b", "c", "a", "b"]).await;
+
+        assert_eq!(
+            sent(&fake),
+            vec![
+                ("a".to_string(), Some(1)),
+                ("b".to_string(), Some(2)),
+                (String::new(), Some(1)),
+                (String::new(), Some(2)),
+                // Both aliases in use: "a" is least recently used.
+                ("c".to_string(), Some(1)),
+                ("a".to_string(), Some(2)),
+                ("b".to_string(), Some(1)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zero_alias_maximum_sends_full_topics() {
+        let fake = Arc::new(FakeV5Client::default());
+        let limit = Arc::new(AliasLimit::default());
+        limit.on_connack(None);
+        let client = AliasingClient::new(fake.clone(), limit.clone());
+
+        send(&client, &["a", "a"]).await;
+        assert_eq!(
+            sent(&fake),
+            vec![("a".to_string(), None), ("a".to_string(), None)]
+        );
+
+        limit.on_connack(Some(0));
+        send(&client, &["a"]).await;
+        assert_eq!(sent(&fake), vec![("a".to_string(), None)]);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_resets_aliases() {
+        let fake = Arc::new(FakeV5Client::default());
+        let limit = Arc::new(AliasLimit::default());
+        limit.on_connack(Some(5));
+        let client = AliasingClient::new(fake.clone(), limit.clone());
+
+        send(&client, &["a", "a"]).await;
+        limit.on_connack(Some(5));
+        send(&client, &["a"]).await;
+
+        assert_eq!(
+            sent(&fake),
+            vec![
+                ("a".to_string(), Some(1)),
+                (String::new(), Some(1)),
+                ("a".to_string(), Some(1)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_user_properties_sent_with_aliases() {
+        let fake = Arc::new(FakeV5Client::default());
+        let limit = Arc::new(AliasLimit::default());
+        limit.on_connack(Some(1));
+        let client = AliasingClient::new(fake.clone(), limit);
+        let user_properties = vec![("query".to_string(), "q1".to_string())];
+
+        for _ in 0..2 {
+            client
+                .publish_with_user_properties(
+                    "a".to_string(),
+                    QoS::AtLeastOnce,
+                    false,
+                    Vec::new(),
+                    user_properties.clone(),
+                )
+                .await
+                .unwrap();
+        }
+        send(&client, &["a"]).await;
+
+        assert_eq!(
+            sent(&fake),
+            vec![
+                ("a".to_string(), Some(1)),
+                (String::new(), Some(1)),
+                (String::new(), Some(1)),
+            ]
+        );
+        assert_eq!(
+            *fake.user_properties.lock().unwrap(),
+            vec![user_properties.clone(), user_properties, Vec::new()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_publish_binds_no_alias() {
+        let fake = Arc::new(FakeV5Client::default());
+        let limit = Arc::new(AliasLimit::default());
+        limit.on_connack(Some(2));
+        let client = AliasingClient::new(fake.clone(), limit);
+
+        fake.fail_next.store(true, Ordering::SeqCst);
+        assert!(client
+            .publish("a".to_string(), QoS::AtLeastOnce, false, Vec::new())
+            .await
+            .is_err());
+        send(&client, &["a", "a"]).await;
+
+        // The broker never saw the first assignment, so it is sent again.
+        assert_eq!(
+            sent(&fake),
+            vec![
+                ("a".to_string(), Some(1)),
+                ("a".to_string(), Some(1)),
+                (String::new(), Some(1)),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelled_publish_binds_no_alias() {
+        let fake = Arc::new(FakeV5Client::default());
+        let limit = Arc::new(AliasLimit::default());
+        limit.on_connack(Some(2));
+        let client = AliasingClient::new(fake.clone(), limit);
+
+        // A publish timeout gives up on the stuck assignment.
+        fake.stall_next.store(true, Ordering::SeqCst);
+        let publish = client.publish("a".to_string(), QoS::AtLeastOnce, false, Vec::new());
+        assert!(tokio::time::timeout(Duration::from_millis(50), publish)
+            .await
+            .is_err());
+        send(&client, &["a", "a"]).await;
+
+        assert_eq!(
+            sent(&fake),
+            vec![
+                ("a".to_string(), Some(1)),
+                ("a".to_string(), Some(1)),
+                (String::new(), Some(1)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_publishes_assign_before_reuse() {
+        let fake = Arc::new(FakeV5Client::default());
+        let limit = Arc::new(AliasLimit::default());
+        limit.on_connack(Some(2));
+        let client = Arc::new(AliasingClient::new(fake.clone(), limit));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move { send(&client, &["a"]).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let sent = sent(&fake);
+        assert_eq!(sent[0], ("a".to_string(), Some(1)));
+        assert!(sent[1..].iter().all(|s| *s == (String::new(), Some(1))));
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! JMESPath transforms of result items.
+//!
+//! Reshapes each item before it reaches the serializer, e.g. to unwrap the
+//! node object returned by `RETURN s` into flat fields templates can use.
+
+use anyhow::Context;
+use jmespath::Expression;
+use serde_json::Value;
+
+use crate::publisher::DiffBatch;
+
+/// A compiled `result_transform` expression.
+pub struct ResultTransform {
+    expression: Expression<'static>,
+}
+
+impl ResultTransform {
+    /// Compile a JMESPath expression.
+    pub fn compile(expression: &str) -> anyhow::Result<Self> {
+        let expression = jmespath::compile(expression)
+            .with_context(|| format!("Invalid result_transform '{expression}'"))?;
+        Ok(Self { expression })
+    }
+
+    /// Evaluate the expression against a single item.
+    pub fn apply(&self, item: &Value) -> anyhow::Result<Value> {
+        let result = self
+            .expression
+            .search(item)
+            .with_context(|| format!("result_transform '{}' failed", self.expression))?;
+        Ok(serde_json::to_value(&*result)?)
+    }
+
+    /// Transform every item of `batch` in place.
+    ///
+    /// Items the expression fails on are dropped from the batch; their
+    /// errors are returned.
+    pub fn apply_batch(&self, batch: &mut DiffBatch) -> Vec<anyhow::Error> {
+        let mut errors = Vec::new();
+        for items in [&mut batch.added, &mut batch.updated, &mut batch.removed] {
+            *items = std::mem::take(items)
+                .iter()
+                .filter_map(|item| self.apply(item).map_err(|e| errors.push(e)).ok())
+                .collect();
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use handlebars::Handlebars;
+    use serde_json::json;
+
+    use super::*;
+    use crate::serializer::{ResultSerializer, SerializeContext, TemplateSerializer};
+
+    #[test]
+    fn test_transform_unwraps_nested_node_for_templates() {
+        let transform =
+            ResultTransform::compile("{device: s.device_id, temp: s.temperature}").unwrap();
+        let mut batch = DiffBatch {
+            added: vec![json!({"s": {"device_id": "d1", "temperature": 41, "room": "r2"}})],
+            ..Default::default()
+        };
+
+        assert!(transform.apply_batch(&mut batch).is_empty());
+        assert_eq!(batch.added, vec![json!({"device": "d1", "temp": 41})]);
+
+        let serializer = TemplateSerializer::new(
+            Arc::new(Handlebars::new()),
+            "devices/{{device}}",
+            Some("temp={{temp}}".into()),
+        );
+        let ctx = SerializeContext::new("r1", 1);
+        let messages = serializer.serialize_batch("q1", &batch, &ctx).unwrap();
+        assert_eq!(
+            messages,
+            vec![("devices/d1".to_string(), b"temp=41".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_transform_errors_skip_items() {
+        assert!(ResultTransform::compile("{device: ").is_err());
+
+        let transform = ResultTransform::compile("join('/', path)").unwrap();
+        let mut batch = DiffBatch {
+            added: vec![json!({"path": ["a", "b"]}), json!({"path": "a"})],
+            removed: vec![json!({"path": [1]})],
+            ..Default::default()
+        };
+
+        let errors = transform.apply_batch(&mut batch);
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(batch.added, vec![json!("a/b")]);
+        assert!(batch.removed.is_empty());
+    }
+}
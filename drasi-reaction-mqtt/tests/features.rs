@@ -0,0 +1,56 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Behavior that depends on the enabled Cargo features.
+//!
+//! Run with the default features and with `--no-default-features` so both
+//! sides of every feature are covered.
+
+use drasi_reaction_mqtt::client;
+use drasi_reaction_mqtt::{BrokerEndpoint, TlsConfig};
+
+fn tls_endpoint() -> BrokerEndpoint {
+    BrokerEndpoint::new("secure", "localhost", 8883).tls(TlsConfig::new("/nonexistent/ca.pem"))
+}
+
+#[test]
+fn plain_tcp_needs_no_feature() {
+    let endpoint = BrokerEndpoint::new("plain", "localhost", 1883);
+    assert!(client::mqtt_options(&endpoint).is_ok());
+    assert!(client::mqtt5_options(&endpoint).is_ok());
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn tls_loads_certificates() {
+    let err = client::mqtt_options(&tls_endpoint()).unwrap_err();
+    assert!(
+        err.to_string().contains("Failed to read CA certificate"),
+        "{err}"
+    );
+}
+
+#[cfg(not(feature = "tls"))]
+#[test]
+fn tls_rejected_without_feature() {
+    for err in [
+        client::mqtt_options(&tls_endpoint()).unwrap_err(),
+        client::mqtt5_options(&tls_endpoint()).unwrap_err(),
+    ] {
+        assert!(
+            err.to_string().contains("without the `tls` feature"),
+            "{err}"
+        );
+    }
+}
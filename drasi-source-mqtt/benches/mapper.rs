@@ -0,0 +1,87 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of payload mapping, without a broker.
+//!
+//! Run with `cargo bench -p drasi-source-mqtt`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::collections::HashMap;
+
+use drasi_source_mqtt::config::{Coercion, OperationMode};
+use drasi_source_mqtt::mapper::{payload_to_source_change, Coercions, PayloadFormat};
+
+const FIXTURES: [(&str, &[u8]); 3] = [
+    ("small", include_bytes!("fixtures/small.json")),
+    ("medium", include_bytes!("fixtures/medium.json")),
+    ("large", include_bytes!("fixtures/large.json")),
+];
+
+fn map(payload: &[u8], format: &PayloadFormat) {
+    payload_to_source_change(
+        payload,
+        "s",
+        &["id"],
+        "Sensor",
+        OperationMode::Insert,
+        format,
+    )
+    .unwrap();
+}
+
+fn bench_payload_to_source_change(c: &mut Criterion) {
+    let mut group = c.benchmark_group("payload_to_source_change");
+    let plain = PayloadFormat::default();
+    for (name, payload) in FIXTURES {
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        group.bench_with_input(BenchmarkId::new("plain", name), payload, |b, payload| {
+            b.iter(|| map(payload, &plain))
+        });
+    }
+    group.finish();
+}
+
+fn bench_format_options(c: &mut Criterion) {
+    let mut group = c.benchmark_group("payload_format");
+    let medium = FIXTURES[1].1;
+
+    let coerced = PayloadFormat {
+        coercions: Coercions {
+            fields: HashMap::from([("online".to_string(), Coercion::Bool)]),
+            bool_true_tokens: vec!["on".to_string()],
+            bool_false_tokens: vec!["off".to_string()],
+        },
+        defaults: HashMap::from([("battery".to_string(), serde_json::json!(100))]),
+        ..Default::default()
+    };
+    group.bench_function("coercion_and_defaults", |b| {
+        b.iter(|| map(medium, &coerced))
+    });
+
+    let inner = String::from_utf8(medium.to_vec()).unwrap();
+    let wrapped = serde_json::to_vec(&serde_json::json!({ "data": inner })).unwrap();
+    let nested = PayloadFormat {
+        nested_json_field: Some("data".to_string()),
+        ..Default::default()
+    };
+    group.bench_function("nested_json", |b| b.iter(|| map(&wrapped, &nested)));
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_payload_to_source_change,
+    bench_format_options
+);
+criterion_main!(benches);
@@ -14,10 +14,25 @@
 
 //! Configuration types for the MQTT source plugin.
 
-use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use drasi_lib::channels::DispatchMode;
+use drasi_mqtt_connection::{ClientIdSuffix, ConnectionConfig, TlsConfig};
+pub use drasi_mqtt_connection::{CredentialsFn, CredentialsProvider};
+use rumqttc::QoS;
+use serde::{Deserialize, Deserializer};
+
+use crate::mapper::{IdGenerator, SharedIdGenerator};
+
+/// `id_fields` entry that derives the entity ID from a hash of the whole payload.
+///
+/// Identical payloads map to the same node, while any change in content
+/// produces a new node. Object key order does not affect the hash.
+pub const PAYLOAD_HASH_ID: &str = "@hash";
 
 /// Operation mode for the source.
-#[derive(Debug, Clone, Copy, Deserialize, Default)]
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum OperationMode {
     /// Always treat incoming payloads as new entities (Insert).
@@ -28,34 +43,793 @@ pub enum OperationMode {
     // Future: Upsert (requires Drasi support)
 }
 
+/// Order in which changes are dispatched when several dispatch workers run.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DispatchOrdering {
+    /// Changes for the same entity id are dispatched in arrival order
+    /// (default); changes for different ids may overtake each other.
+    #[default]
+    PerId,
+    /// No ordering guarantee: changes are spread over the workers in turn.
+    None,
+}
+
+/// How entity ids containing control characters or invalid text, or longer
+/// than `max_id_bytes`, are handled.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdPolicy {
+    /// Reject the whole payload.
+    Reject,
+    /// Replace each control character and each U+FFFD (from byte sequences
+    /// invalid in the payload's encoding) with `_`, and cut overlong ids.
+    Replace,
+    /// Keep ids as they are (default). Overlong ids are rejected.
+    #[default]
+    Passthrough,
+}
+
+/// Normalization of entity ids, so `" Sensor-1 "` and `"sensor-1"` map to
+/// the same node. The default keeps ids as sent.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+pub struct IdNormalize {
+    /// Strip leading and trailing whitespace.
+    #[serde(default)]
+    pub trim: bool,
+    /// Lowercase the id.
+    #[serde(default)]
+    pub lowercase: bool,
+}
+
+/// What to do with a node exceeding `max_properties` or `max_property_value_bytes`.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizePolicy {
+    /// Reject the whole payload (default).
+    #[default]
+    Reject,
+    /// Keep `max_properties` properties, cut string values to
+    /// `max_property_value_bytes` and drop other oversized values. The id
+    /// fields and `correlation_field` are never cut or dropped; the other
+    /// properties are kept in key order.
+    Truncate,
+}
+
+/// What to do with a payload whose top-level JSON value is not an object,
+/// e.g. `[1, 2, 3]` or `"hello"`.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NonObjectPolicy {
+    /// Skip the message (default).
+    #[default]
+    Skip,
+    /// Map the value to a node with a single
+    /// [`WRAPPED_VALUE_PROPERTY`](crate::mapper::WRAPPED_VALUE_PROPERTY)
+    /// property.
+    Wrap,
+    /// Map the value to a node without properties.
+    Empty,
+}
+
+/// What to do when the broker refuses the connection for bad credentials
+/// or missing authorization.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthErrorPolicy {
+    /// Stop the event loop and set the source's status to `Error` (default).
+    #[default]
+    FailFast,
+    /// Keep reconnecting, like after any other connection error. Useful with
+    /// a `credentials_provider` that may hand out fixed credentials later.
+    Retry,
+}
+
+/// What to do with messages received over `rate_limit`.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAction {
+    /// Drop the message and count it (default).
+    #[default]
+    Drop,
+    /// Hold the message's change back until it fits the rate. Once the
+    /// dispatch buffer is full, reading from the broker waits for room, so
+    /// messages queue up at the broker instead.
+    Pause,
+}
+
+/// Source named by the [`ElementReference`](drasi_core::models::ElementReference)
+/// of every element the source emits.
+///
+/// Queries `MATCH` on labels and properties, so the choice does not change
+/// which elements a pattern selects. It decides element identity: two
+/// elements with the same id are one node only if their references also name
+/// the same source, which is what `Update`s and `Delete`s, and joins with
+/// other sources, are resolved against.
+#[derive(Debug, Clone, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReferenceSource {
+    /// The source component id (default).
+    #[default]
+    SourceId,
+    /// The element's label, as earlier versions did. Sources sharing a
+    /// label then collide.
+    NodeLabel,
+    /// A fixed name, e.g. to keep element identity stable when the source
+    /// component is renamed, or to let several sources feed the same nodes.
+    Custom(String),
+}
+
+/// Type a payload field is converted to before it becomes a node property.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Coercion {
+    /// A JSON bool, from a string (or number) listed in `bool_true_tokens`
+    /// or `bool_false_tokens`.
+    Bool,
+}
+
+/// An additional topic filter to subscribe to, with its own QoS.
+///
+/// Deserializes from `{"filter": "alarms/#", "qos": 2}` or from a bare
+/// filter string, which is subscribed with the default QoS.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(from = "TopicSubscriptionSpec")]
+pub struct TopicSubscription {
+    /// MQTT topic filter (supports wildcards like `alarms/#`).
+    pub filter: String,
+    /// Requested QoS level: 0, 1 or 2 (default: 1).
+    pub qos: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TopicSubscriptionSpec {
+    Filter(String),
+    Full {
+        filter: String,
+        #[serde(default = "default_qos")]
+        qos: u8,
+    },
+}
+
+impl From<TopicSubscriptionSpec> for TopicSubscription {
+    fn from(spec: TopicSubscriptionSpec) -> Self {
+        match spec {
+            TopicSubscriptionSpec::Filter(filter) => Self {
+                filter,
+                qos: default_qos(),
+            },
+            TopicSubscriptionSpec::Full { filter, qos } => Self { filter, qos },
+        }
+    }
+}
+
+impl TopicSubscription {
+    pub fn new(filter: impl Into<String>, qos: QoS) -> Self {
+        Self {
+            filter: filter.into(),
+            qos: qos as u8,
+        }
+    }
+}
+
+/// How messages on topics matching a [`TopicRule`] filter are mapped.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TopicAction {
+    /// Map the payload as usual, e.g. to exempt topics from a later, broader rule.
+    Map,
+    /// Drop the message.
+    Ignore,
+    /// An `online`/`offline` payload (plain, or as a JSON `state` field, in
+    /// any case)
+    /// for the entity named by the topic without its last level. Sets
+    /// `property` to `true`/`false` on the entity's node, keeping its other
+    /// properties; with `delete_on_offline`, `offline` deletes the node.
+    Availability {
+        property: String,
+        #[serde(default)]
+        delete_on_offline: bool,
+    },
+    /// Delete the node whose id is at JSON Pointer `id_pointer` in the
+    /// payload, if every pointer in `require` holds the given value.
+    Delete {
+        id_pointer: String,
+        #[serde(default)]
+        require: HashMap<String, serde_json::Value>,
+    },
+}
+
+/// Maps messages on topics matching `filter` with `action` instead of as
+/// regular payloads.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct TopicRule {
+    /// MQTT topic filter (supports wildcards).
+    pub filter: String,
+    #[serde(flatten)]
+    pub action: TopicAction,
+}
+
+impl TopicRule {
+    pub fn new(filter: impl Into<String>, action: TopicAction) -> Self {
+        Self {
+            filter: filter.into(),
+            action,
+        }
+    }
+}
+
+/// Mapping settings for a well-known publisher, expanded into the topic
+/// mapping options (see [`crate::topic_mapping`]).
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+pub enum Preset {
+    /// Zigbee2MQTT with its default `zigbee2mqtt` base topic.
+    #[serde(rename = "zigbee2mqtt")]
+    Zigbee2Mqtt {
+        /// Delete a device's node when it goes offline, instead of setting
+        /// `available` to `false`.
+        #[serde(default)]
+        delete_on_offline: bool,
+    },
+    /// Tasmota devices, publishing telemetry under `tele/<device>/` and
+    /// command results under `stat/<device>/`.
+    #[serde(rename = "tasmota")]
+    Tasmota {
+        /// Delete a device's node when its LWT says `Offline`, instead of
+        /// setting `online` to `false`.
+        #[serde(default)]
+        delete_on_offline: bool,
+    },
+}
+
+/// Suspends dispatch after repeated failures; see
+/// [`CircuitBreaker`](crate::dispatch::CircuitBreaker).
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive dispatch failures that open the circuit.
+    pub threshold: u32,
+    /// How long the circuit stays open before a trial dispatch, in ms.
+    pub cooldown_ms: u64,
+}
+
+/// A broker the source can connect to besides the primary one.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct BrokerEndpoint {
+    /// How to connect. An empty client id defaults to the primary broker's.
+    #[serde(flatten)]
+    pub connection: ConnectionConfig,
+}
+
+impl BrokerEndpoint {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            connection: ConnectionConfig::new("", host, port),
+        }
+    }
+
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.connection.client_id = client_id.into();
+        self
+    }
+
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.connection = self.connection.credentials(username, password);
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.connection = self.connection.tls(tls);
+        self
+    }
+
+    /// Connection settings for this broker.
+    pub fn connection(&self) -> ConnectionConfig {
+        self.connection.clone()
+    }
+}
+
+fn default_max_reconnect_attempts() -> u32 {
+    5
+}
+
+fn default_sys_metrics_interval_ms() -> u64 {
+    10_000
+}
+
+fn default_schema_sample_size() -> usize {
+    1000
+}
+
+fn default_message_spans() -> bool {
+    true
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_id_fields() -> Vec<String> {
+    vec!["id".to_string()]
+}
+
+/// Accept either a single field name or a list of them.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(field) => vec![field],
+        OneOrMany::Many(fields) => fields,
+    })
+}
+
+fn default_bool_true_tokens() -> Vec<String> {
+    ["true", "1", "on", "yes"].map(String::from).to_vec()
+}
+
+fn default_bool_false_tokens() -> Vec<String> {
+    ["false", "0", "off", "no"].map(String::from).to_vec()
+}
+
+fn default_stop_drain_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_blocking_payload_bytes() -> usize {
+    1024
+}
+
+fn default_dispatch_workers() -> usize {
+    1
+}
+
+fn default_auto_start() -> bool {
+    true
+}
+
+fn default_skip_unsubscribed_labels() -> bool {
+    true
+}
+
+fn default_degraded_after_ms() -> u64 {
+    10_000
+}
+
+fn default_entity_cache_capacity() -> usize {
+    10_000
+}
+
 /// Configuration for the MQTT source.
 #[derive(Debug, Clone, Deserialize)]
 pub struct MqttSourceConfig {
     /// Unique source identifier (used by queries to reference this source).
     pub id: String,
-    /// MQTT broker hostname or IP.
-    pub broker_host: String,
-    /// MQTT broker port (default: 1883).
-    pub port: u16,
+    /// How to connect to the primary broker. The client id defaults to
+    /// `"drasi-source-{id}"`.
+    #[serde(flatten)]
+    pub connection: ConnectionConfig,
     /// MQTT topic filter to subscribe to (supports wildcards like `sensors/#`).
+    /// Subscribed with QoS 1.
     pub topic: String,
-    /// MQTT client ID. Defaults to `"drasi-source-{id}"`.
-    pub client_id: String,
-    /// Optional MQTT username for authentication.
-    pub username: Option<String>,
-    /// Optional MQTT password for authentication.
-    pub password: Option<String>,
+    /// Further topic filters to subscribe to, each with its own QoS.
+    #[serde(default)]
+    pub topics: Vec<TopicSubscription>,
+    /// Appended to the client id of every broker connection, so instances
+    /// deployed with the same configuration don't take over each other's
+    /// session (default: none).
+    #[serde(default)]
+    pub client_id_suffix: Option<ClientIdSuffix>,
     /// Label applied to graph nodes produced by this source (default: `"MqttMessage"`).
     pub node_label: String,
-    /// JSON field name used as the entity ID (default: `"id"`).
-    /// If the field is missing from a payload, a UUID is generated.
-    pub id_field: String,
+    /// Source named by element references (default: the source id).
+    #[serde(default)]
+    pub reference_source: ReferenceSource,
+    /// JSON field names tried in order for the entity ID (default: `["id"]`);
+    /// the first one holding a string or number wins. If none is present, a
+    /// UUID is generated. A [`PAYLOAD_HASH_ID`] (`"@hash"`) entry hashes the
+    /// payload instead. Also accepted as a single `id_field` string.
+    #[serde(
+        alias = "id_field",
+        default = "default_id_fields",
+        deserialize_with = "one_or_many"
+    )]
+    pub id_fields: Vec<String>,
     /// Operation mode for the source (default: `insert`).
     #[serde(default)]
     pub mode: OperationMode,
+    /// How long the broker connection must be down continuously before the
+    /// source reports itself as degraded (default: 10000 ms). Shorter
+    /// outages keep the source `Running`.
+    #[serde(default = "default_degraded_after_ms")]
+    pub degraded_after_ms: u64,
+    /// Text encoding of incoming payloads, as a WHATWG encoding label
+    /// (e.g. `"latin1"`, `"windows-1252"`). Payloads are transcoded to UTF-8
+    /// before JSON parsing. Defaults to UTF-8.
+    #[serde(default)]
+    pub text_encoding: Option<String>,
+    /// Field whose string value is itself JSON, for gateways that publish
+    /// double-encoded payloads (`{"data": "{\"id\": \"x\"}"}`). The nested
+    /// JSON is used as the payload; payloads without a valid nested value
+    /// are rejected.
+    #[serde(default)]
+    pub decode_nested_json: Option<String>,
+    /// Accept the non-standard `NaN`, `Infinity` and `-Infinity` number
+    /// tokens some devices send, parsing them as `null` instead of rejecting
+    /// the whole message (default: false, strict JSON).
+    #[serde(default)]
+    pub lenient_json: bool,
+    /// Whether DrasiLib starts the source together with itself (default:
+    /// `true`). When `false`, the source stays stopped until started explicitly.
+    #[serde(default = "default_auto_start")]
+    pub auto_start: bool,
+    /// How long `stop()` waits for changes still queued for dispatch to be
+    /// flushed (default: 5000 ms). Changes left after that are dropped and
+    /// counted in the log.
+    #[serde(default = "default_stop_drain_timeout_ms")]
+    pub stop_drain_timeout_ms: u64,
+    /// Give up on a message whose mapping takes longer than this; it is
+    /// skipped and counted. When set, payloads of `blocking_payload_bytes`
+    /// or more are parsed on the blocking thread pool so a slow payload
+    /// cannot hold up keep-alives. Disabled when unset.
+    #[serde(default)]
+    pub message_processing_timeout_ms: Option<u64>,
+    /// Smallest payload parsed on the blocking thread pool under
+    /// `message_processing_timeout_ms` (default: 1024 bytes). Smaller
+    /// payloads are mapped in the event loop, without a timeout.
+    #[serde(default = "default_blocking_payload_bytes")]
+    pub blocking_payload_bytes: usize,
+    /// Most messages per second the source processes, with bursts of up to
+    /// one second's worth. Unlimited when unset.
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+    /// What happens to messages over `rate_limit` (default: `drop`).
+    #[serde(default)]
+    pub rate_limit_action: RateLimitAction,
+    /// Tasks dispatching changes concurrently (default: 1). With a single
+    /// worker every change is dispatched in arrival order.
+    #[serde(default = "default_dispatch_workers")]
+    pub dispatch_workers: usize,
+    /// Ordering guarantee across dispatch workers (default: `per_id`).
+    #[serde(default)]
+    pub ordering: DispatchOrdering,
+    /// How drasi-lib delivers dispatched changes to subscribed queries
+    /// (default: `channel`). See [`MqttSourceConfigBuilder::dispatch_mode`].
+    #[serde(default)]
+    pub dispatch_mode: DispatchMode,
+    /// Stop attempting dispatch for a cooldown after repeated failures,
+    /// dropping and counting changes meanwhile. Disabled when unset.
+    #[serde(default)]
+    pub dispatch_circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Skip dispatching changes whose labels no subscribed query matches on
+    /// (default: true). See [`SubscribedLabels`](crate::subscription::SubscribedLabels).
+    #[serde(default = "default_skip_unsubscribed_labels")]
+    pub skip_unsubscribed_labels: bool,
+    /// Most properties a node may have. Unlimited when unset.
+    #[serde(default)]
+    pub max_properties: Option<usize>,
+    /// Largest property value in bytes (string length, or serialized JSON
+    /// length for other values). Unlimited when unset.
+    #[serde(default)]
+    pub max_property_value_bytes: Option<usize>,
+    /// How nodes exceeding the property limits are handled (default: `reject`).
+    #[serde(default)]
+    pub oversize_policy: OversizePolicy,
+    /// Marker appended to string values cut by the `truncate` policy, such as
+    /// `"…[truncated]"`. The value stays within `max_property_value_bytes`,
+    /// marker included, so the marker must be shorter than that. Requires
+    /// `oversize_policy: truncate`.
+    #[serde(default)]
+    pub truncate_with_marker: Option<String>,
+    /// How payloads that are not a JSON object are handled (default: `skip`).
+    #[serde(default)]
+    pub non_object_policy: NonObjectPolicy,
+    /// How ids with control characters or invalid text are handled
+    /// (default: `passthrough`).
+    #[serde(default)]
+    pub id_policy: IdPolicy,
+    /// Longest entity id in bytes. Unlimited when unset.
+    #[serde(default)]
+    pub max_id_bytes: Option<usize>,
+    /// Normalization of entity ids, applied before the id policy and length
+    /// limit (default: none).
+    #[serde(default)]
+    pub id_normalize: IdNormalize,
+    /// Conversions of top-level payload fields, by field name. Values that
+    /// cannot be converted are left unchanged with a warning, logged once per
+    /// field per minute.
+    #[serde(default)]
+    pub coerce: HashMap<String, Coercion>,
+    /// Values a `bool` coercion turns into `true`, compared case-insensitively
+    /// (default: `true`, `1`, `on`, `yes`).
+    #[serde(default = "default_bool_true_tokens")]
+    pub bool_true_tokens: Vec<String>,
+    /// Values a `bool` coercion turns into `false`, compared case-insensitively
+    /// (default: `false`, `0`, `off`, `no`).
+    #[serde(default = "default_bool_false_tokens")]
+    pub bool_false_tokens: Vec<String>,
+    /// Values for top-level payload fields a message omits, by field name,
+    /// e.g. `{"temperature": 0}`. Fields present in the message, even as
+    /// `null`, are left as sent.
+    #[serde(default)]
+    pub defaults: HashMap<String, serde_json::Value>,
+    /// Properties computed from payload fields, by property name, e.g.
+    /// `{"temp_f": "temp_c * 9 / 5 + 32"}`. Evaluated after coercions and
+    /// defaults, against the payload fields; a property whose expression
+    /// fails, e.g. on a missing field, is left absent.
+    #[serde(default)]
+    pub computed_properties: HashMap<String, String>,
+    /// Payload field holding the correlation id of the command a message
+    /// answers, e.g. one stamped by the MQTT reaction's `correlation_ids`.
+    /// Its value is copied to a `correlation_id` node property.
+    #[serde(default)]
+    pub correlation_field: Option<String>,
+    /// Take the entity ID from the topic, joining its levels from this
+    /// (0-based) level on, e.g. `1` for `devices/kitchen/lamp` gives
+    /// `kitchen/lamp`. Takes precedence over `id_fields`.
+    #[serde(default)]
+    pub topic_id_level: Option<usize>,
+    /// Number of topic levels the entity ID spans from `topic_id_level`,
+    /// e.g. `1` for `tele/lamp/SENSOR` with level `1` gives `lamp`. All
+    /// remaining levels when unset.
+    #[serde(default)]
+    pub topic_id_depth: Option<usize>,
+    /// JSON Pointer to a payload string used as the node label when present,
+    /// e.g. `/device/type`; `node_label` is used otherwise.
+    #[serde(default)]
+    pub label_pointer: Option<String>,
+    /// Special handling for topics, tried in order before the payload is
+    /// mapped as usual; the first rule whose filter matches applies.
+    #[serde(default)]
+    pub topic_rules: Vec<TopicRule>,
+    /// Mapping preset expanded into the topic mapping options above (its
+    /// rules after explicit ones), and subscribed to. Explicit settings take
+    /// precedence, except that a preset may set the operation mode.
+    #[serde(default)]
+    pub preset: Option<Preset>,
+    /// Merge each message into the last properties kept for its entity and
+    /// dispatch the merged node, so devices publishing partial state produce
+    /// complete updates (default: false). Pair with the `update` mode.
+    #[serde(default)]
+    pub merge_partial_updates: bool,
+    /// Most entities whose last properties are kept for merging and
+    /// availability; the least recently updated is evicted first
+    /// (default: 10000).
+    #[serde(default = "default_entity_cache_capacity")]
+    pub entity_cache_capacity: usize,
+    /// Forget the kept properties of entities not updated for this long, in
+    /// milliseconds. Kept until evicted when unset.
+    #[serde(default)]
+    pub entity_cache_ttl_ms: Option<u64>,
+    /// Keep the last N raw messages received for `MqttSource::recent_messages`.
+    /// Disabled when unset.
+    #[serde(default)]
+    pub debug_ring: Option<usize>,
+    /// Add `_mqtt_qos`, `_mqtt_retain` and `_mqtt_dup` properties recording
+    /// how each message arrived (default: false). They are added after the
+    /// entity ID is resolved and replace payload fields of the same name.
+    #[serde(default)]
+    pub capture_mqtt_meta: bool,
+    /// Warn when an entity id is mapped under more than one label, which
+    /// creates distinct nodes and often means a misconfigured mapping
+    /// (default: false). Keeps the labels of every id seen since start.
+    #[serde(default)]
+    pub warn_on_cross_label_id: bool,
+    /// Record the property names and types of mapped messages for
+    /// `MqttSource::inferred_schema` (default: false). Diagnostic only;
+    /// nothing is enforced.
+    #[serde(default)]
+    pub infer_schema: bool,
+    /// Most recent mapped messages the inferred schema covers (default: 1000).
+    #[serde(default = "default_schema_sample_size")]
+    pub schema_sample_size: usize,
+    /// Handle each message in a debug-level tracing span with its topic,
+    /// entity id and outcome (default: true). Turn off to skip the span on
+    /// very high message rates.
+    #[serde(default = "default_message_spans")]
+    pub message_spans: bool,
+    /// Also subscribe to the broker's `$SYS/#` topics and ingest each as a
+    /// `BrokerMetric` node, with the topic as id and the payload as its
+    /// `value` property (default: false).
+    #[serde(default)]
+    pub ingest_sys_metrics: bool,
+    /// Least time between two ingested messages on the same `$SYS` topic
+    /// (default: 10000 ms). Messages in between are skipped.
+    #[serde(default = "default_sys_metrics_interval_ms")]
+    pub sys_metrics_interval_ms: u64,
+    /// Broker to switch to once the current one has failed
+    /// `max_reconnect_attempts` connection attempts in a row. The source
+    /// alternates between the primary and fallback brokers from then on.
+    #[serde(default)]
+    pub fallback_broker: Option<BrokerEndpoint>,
+    /// Consecutive failed connection attempts before switching to the
+    /// fallback broker (default: 5). Unused without a fallback broker.
+    #[serde(default = "default_max_reconnect_attempts")]
+    pub max_reconnect_attempts: u32,
+    /// What to do when the broker refuses the credentials (default:
+    /// `failfast`). Other connection errors are always retried.
+    #[serde(default)]
+    pub on_auth_error: AuthErrorPolicy,
+    /// Supplies the primary broker's credentials at every (re)connect,
+    /// replacing `username` and `password`. Set through the builder only.
+    #[serde(skip)]
+    pub credentials_provider: Option<CredentialsProvider>,
+    /// Generates the ID of a node whose payload has none of the `id_fields`
+    /// (default: random UUIDs). Set through the builder only.
+    #[serde(skip)]
+    pub id_generator: SharedIdGenerator,
 }
 
 impl MqttSourceConfig {
+    /// Check topic filters, QoS levels and the text encoding.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        crate::subscription::subscribe_filters(self)?;
+        self.encoding()?;
+        if self.dispatch_workers == 0 {
+            anyhow::bail!("dispatch_workers must be at least 1");
+        }
+        if self.truncate_with_marker.is_some() && self.oversize_policy != OversizePolicy::Truncate {
+            anyhow::bail!("truncate_with_marker requires oversize_policy 'truncate'");
+        }
+        if let (Some(marker), Some(max)) =
+            (&self.truncate_with_marker, self.max_property_value_bytes)
+        {
+            if marker.len() >= max {
+                anyhow::bail!(
+                    "truncate_with_marker is {} bytes, but must be shorter than max_property_value_bytes ({max})",
+                    marker.len()
+                );
+            }
+        }
+        if self
+            .dispatch_circuit_breaker
+            .is_some_and(|breaker| breaker.threshold == 0)
+        {
+            anyhow::bail!("dispatch_circuit_breaker threshold must be at least 1");
+        }
+        if self.rate_limit == Some(0) {
+            anyhow::bail!("rate_limit must be at least 1 message per second");
+        }
+        Ok(())
+    }
+
+    /// The payload encoding, or `None` for UTF-8.
+    pub fn encoding(&self) -> anyhow::Result<Option<&'static encoding_rs::Encoding>> {
+        match &self.text_encoding {
+            None => Ok(None),
+            Some(label) => encoding_rs::Encoding::for_label(label.as_bytes())
+                .map(|encoding| (encoding != encoding_rs::UTF_8).then_some(encoding))
+                .ok_or_else(|| anyhow::anyhow!("Unknown text encoding '{label}'")),
+        }
+    }
+
+    /// The source named by the references of elements labeled `label`, as
+    /// picked by [`reference_source`](Self::reference_source).
+    pub fn reference_source_for<'a>(&'a self, label: &'a str) -> &'a str {
+        match &self.reference_source {
+            ReferenceSource::SourceId => &self.id,
+            ReferenceSource::NodeLabel => label,
+            ReferenceSource::Custom(source) => source,
+        }
+    }
+
+    /// The brokers the source can connect to, the primary broker first.
+    ///
+    /// Client ids carry `client_id_suffix`; a random suffix is generated
+    /// anew on every call.
+    pub fn brokers(&self) -> Vec<ConnectionConfig> {
+        let mut primary = self.connection.clone();
+        if primary.client_id.is_empty() {
+            primary.client_id = format!("drasi-source-{}", self.id);
+        }
+
+        let mut brokers = vec![primary];
+        if let Some(fallback) = &self.fallback_broker {
+            let mut fallback = fallback.connection();
+            if fallback.client_id.is_empty() {
+                fallback.client_id = brokers[0].client_id.clone();
+            }
+            brokers.push(fallback);
+        }
+        if let Some(suffix) = self.client_id_suffix.map(|suffix| suffix.generate()) {
+            for broker in &mut brokers {
+                broker.client_id.push_str(&suffix);
+            }
+        }
+        brokers
+    }
+
+    /// The parsed computed property expressions, by property name.
+    fn computed_expressions(&self) -> anyhow::Result<Vec<(String, crate::Expression)>> {
+        let mut expressions = self
+            .computed_properties
+            .iter()
+            .map(|(name, expression)| {
+                let expression = expression.parse().map_err(|e| {
+                    anyhow::anyhow!("Invalid expression for computed property '{name}': {e}")
+                })?;
+                Ok((name.clone(), expression))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        expressions.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(expressions)
+    }
+
+    /// How payloads are decoded, from the encoding, nesting, leniency,
+    /// coercion, default, id and property limit settings.
+    pub fn payload_format(&self) -> anyhow::Result<crate::mapper::PayloadFormat> {
+        Ok(crate::mapper::PayloadFormat {
+            encoding: self.encoding()?,
+            nested_json_field: self.decode_nested_json.clone(),
+            lenient_json: self.lenient_json,
+            limits: crate::mapper::PropertyLimits {
+                max_properties: self.max_properties,
+                max_value_bytes: self.max_property_value_bytes,
+                policy: self.oversize_policy,
+                truncation_marker: self.truncate_with_marker.clone(),
+            },
+            id_rules: crate::mapper::IdRules {
+                policy: self.id_policy,
+                max_bytes: self.max_id_bytes,
+                normalize: self.id_normalize,
+            },
+            coercions: crate::mapper::Coercions {
+                fields: self.coerce.clone(),
+                bool_true_tokens: self.bool_true_tokens.clone(),
+                bool_false_tokens: self.bool_false_tokens.clone(),
+                ..Default::default()
+            },
+            defaults: self.defaults.clone(),
+            computed: crate::mapper::ComputedProperties {
+                expressions: self.computed_expressions()?,
+                ..Default::default()
+            },
+            non_object: crate::mapper::NonObjectPayloads {
+                policy: self.non_object_policy,
+                ..Default::default()
+            },
+            correlation_field: self.correlation_field.clone(),
+            id_generator: self.id_generator.clone(),
+        })
+    }
+
+    /// Map a sample message on `topic` exactly as the running source would,
+    /// to check the mapping settings without a broker.
+    ///
+    /// Fails if `topic` matches none of the subscribed filters, or with the
+    /// error the source would log for the payload.
+    pub fn preview(
+        &self,
+        topic: &str,
+        payload: &[u8],
+    ) -> anyhow::Result<crate::mapper::MappingPreview> {
+        let filters = crate::subscription::subscribe_filters(self)?;
+        if !crate::subscription::matches_any(&filters, topic) {
+            anyhow::bail!("Topic '{topic}' matches none of the subscribed topic filters");
+        }
+        if self.ingest_sys_metrics && crate::sys_metrics::is_sys_topic(topic) {
+            return Ok(crate::sys_metrics::preview_sys_metric(
+                topic, payload, self.mode,
+            ));
+        }
+        let mapping = crate::topic_mapping::TopicMapping::from_config(self);
+        crate::topic_mapping::TopicMapper::new(mapping).preview(
+            topic,
+            payload,
+            &self.id_fields,
+            &self.node_label,
+            self.mode,
+            &self.payload_format()?,
+        )
+    }
+
     /// Start building a new config with the required fields.
     pub fn builder(
         id: impl Into<String>,
@@ -64,16 +838,65 @@ impl MqttSourceConfig {
     ) -> MqttSourceConfigBuilder {
         let id = id.into();
         MqttSourceConfigBuilder {
-            id: id.clone(),
-            broker_host: broker_host.into(),
+            connection: ConnectionConfig::new(format!("drasi-source-{id}"), broker_host, 1883),
+            id,
             topic: topic.into(),
-            port: 1883,
-            client_id: format!("drasi-source-{id}"),
-            username: None,
-            password: None,
+            topics: Vec::new(),
+            client_id_suffix: None,
             node_label: "MqttMessage".to_string(),
-            id_field: "id".to_string(),
+            reference_source: ReferenceSource::default(),
+            id_fields: default_id_fields(),
             mode: OperationMode::Insert,
+            degraded_after_ms: default_degraded_after_ms(),
+            text_encoding: None,
+            decode_nested_json: None,
+            lenient_json: false,
+            auto_start: default_auto_start(),
+            stop_drain_timeout_ms: default_stop_drain_timeout_ms(),
+            message_processing_timeout_ms: None,
+            blocking_payload_bytes: default_blocking_payload_bytes(),
+            rate_limit: None,
+            rate_limit_action: RateLimitAction::Drop,
+            dispatch_workers: default_dispatch_workers(),
+            ordering: DispatchOrdering::PerId,
+            dispatch_mode: DispatchMode::Channel,
+            dispatch_circuit_breaker: None,
+            skip_unsubscribed_labels: default_skip_unsubscribed_labels(),
+            max_properties: None,
+            max_property_value_bytes: None,
+            oversize_policy: OversizePolicy::Reject,
+            non_object_policy: NonObjectPolicy::Skip,
+            truncate_with_marker: None,
+            id_policy: IdPolicy::Passthrough,
+            max_id_bytes: None,
+            id_normalize: IdNormalize::default(),
+            coerce: HashMap::new(),
+            bool_true_tokens: default_bool_true_tokens(),
+            bool_false_tokens: default_bool_false_tokens(),
+            defaults: HashMap::new(),
+            computed_properties: HashMap::new(),
+            correlation_field: None,
+            topic_id_level: None,
+            topic_id_depth: None,
+            label_pointer: None,
+            topic_rules: Vec::new(),
+            preset: None,
+            merge_partial_updates: false,
+            entity_cache_capacity: default_entity_cache_capacity(),
+            entity_cache_ttl_ms: None,
+            debug_ring: None,
+            capture_mqtt_meta: false,
+            warn_on_cross_label_id: false,
+            infer_schema: false,
+            schema_sample_size: default_schema_sample_size(),
+            message_spans: default_message_spans(),
+            ingest_sys_metrics: false,
+            sys_metrics_interval_ms: default_sys_metrics_interval_ms(),
+            fallback_broker: None,
+            max_reconnect_attempts: default_max_reconnect_attempts(),
+            on_auth_error: AuthErrorPolicy::FailFast,
+            credentials_provider: None,
+            id_generator: SharedIdGenerator::default(),
         }
     }
 }
@@ -81,35 +904,141 @@ impl MqttSourceConfig {
 /// Builder for [`MqttSourceConfig`].
 pub struct MqttSourceConfigBuilder {
     id: String,
-    broker_host: String,
+    connection: ConnectionConfig,
     topic: String,
-    port: u16,
-    client_id: String,
-    username: Option<String>,
-    password: Option<String>,
+    topics: Vec<TopicSubscription>,
+    client_id_suffix: Option<ClientIdSuffix>,
     node_label: String,
-    id_field: String,
+    reference_source: ReferenceSource,
+    id_fields: Vec<String>,
     mode: OperationMode,
+    degraded_after_ms: u64,
+    text_encoding: Option<String>,
+    decode_nested_json: Option<String>,
+    lenient_json: bool,
+    auto_start: bool,
+    stop_drain_timeout_ms: u64,
+    message_processing_timeout_ms: Option<u64>,
+    blocking_payload_bytes: usize,
+    rate_limit: Option<u32>,
+    rate_limit_action: RateLimitAction,
+    dispatch_workers: usize,
+    ordering: DispatchOrdering,
+    dispatch_mode: DispatchMode,
+    dispatch_circuit_breaker: Option<CircuitBreakerConfig>,
+    skip_unsubscribed_labels: bool,
+    max_properties: Option<usize>,
+    max_property_value_bytes: Option<usize>,
+    oversize_policy: OversizePolicy,
+    non_object_policy: NonObjectPolicy,
+    truncate_with_marker: Option<String>,
+    id_policy: IdPolicy,
+    max_id_bytes: Option<usize>,
+    id_normalize: IdNormalize,
+    coerce: HashMap<String, Coercion>,
+    bool_true_tokens: Vec<String>,
+    bool_false_tokens: Vec<String>,
+    defaults: HashMap<String, serde_json::Value>,
+    computed_properties: HashMap<String, String>,
+    correlation_field: Option<String>,
+    topic_id_level: Option<usize>,
+    topic_id_depth: Option<usize>,
+    label_pointer: Option<String>,
+    topic_rules: Vec<TopicRule>,
+    preset: Option<Preset>,
+    merge_partial_updates: bool,
+    entity_cache_capacity: usize,
+    entity_cache_ttl_ms: Option<u64>,
+    debug_ring: Option<usize>,
+    capture_mqtt_meta: bool,
+    warn_on_cross_label_id: bool,
+    infer_schema: bool,
+    schema_sample_size: usize,
+    message_spans: bool,
+    ingest_sys_metrics: bool,
+    sys_metrics_interval_ms: u64,
+    fallback_broker: Option<BrokerEndpoint>,
+    max_reconnect_attempts: u32,
+    on_auth_error: AuthErrorPolicy,
+    credentials_provider: Option<CredentialsProvider>,
+    id_generator: SharedIdGenerator,
 }
 
 impl MqttSourceConfigBuilder {
     pub fn port(mut self, port: u16) -> Self {
-        self.port = port;
+        self.connection.port = port;
+        self
+    }
+
+    /// Also subscribe to `filter` with the given QoS.
+    pub fn add_topic(mut self, filter: impl Into<String>, qos: QoS) -> Self {
+        self.topics.push(TopicSubscription::new(filter, qos));
         self
     }
 
     pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
-        self.client_id = client_id.into();
+        self.connection.client_id = client_id.into();
+        self
+    }
+
+    /// Append `suffix` to the client ids, e.g. to deploy several instances
+    /// with one configuration.
+    pub fn client_id_suffix(mut self, suffix: ClientIdSuffix) -> Self {
+        self.client_id_suffix = Some(suffix);
         self
     }
 
     pub fn username(mut self, username: impl Into<String>) -> Self {
-        self.username = Some(username.into());
+        self.connection.username = Some(username.into());
         self
     }
 
     pub fn password(mut self, password: impl Into<String>) -> Self {
-        self.password = Some(password.into());
+        self.connection.password = Some(password.into());
+        self
+    }
+
+    /// Connect to the primary broker over TLS.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.connection = self.connection.tls(tls);
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: std::time::Duration) -> Self {
+        self.connection = self.connection.keep_alive(keep_alive);
+        self
+    }
+
+    /// Fetch the primary broker's credentials from `provider` before every
+    /// connection attempt, e.g. to refresh short-lived tokens.
+    pub fn with_credentials_provider(mut self, provider: Arc<CredentialsFn>) -> Self {
+        self.credentials_provider = Some(CredentialsProvider(provider));
+        self
+    }
+
+    /// Generate the IDs of nodes without an ID field with `generator`
+    /// instead of random UUIDs.
+    pub fn with_id_generator(mut self, generator: Arc<dyn IdGenerator>) -> Self {
+        self.id_generator = SharedIdGenerator(generator);
+        self
+    }
+
+    /// Switch to `broker` after `max_reconnect_attempts` failed connection
+    /// attempts to the primary broker.
+    pub fn fallback_broker(mut self, broker: BrokerEndpoint) -> Self {
+        self.fallback_broker = Some(broker);
+        self
+    }
+
+    pub fn max_reconnect_attempts(mut self, attempts: u32) -> Self {
+        self.max_reconnect_attempts = attempts;
+        self
+    }
+
+    /// Choose whether refused credentials stop the source (default) or are
+    /// retried.
+    pub fn on_auth_error(mut self, policy: AuthErrorPolicy) -> Self {
+        self.on_auth_error = policy;
         self
     }
 
@@ -118,8 +1047,25 @@ impl MqttSourceConfigBuilder {
         self
     }
 
+    /// Choose the source named by element references (default: the source id).
+    pub fn reference_source(mut self, source: ReferenceSource) -> Self {
+        self.reference_source = source;
+        self
+    }
+
+    /// Use a single JSON field as the entity ID.
     pub fn id_field(mut self, field: impl Into<String>) -> Self {
-        self.id_field = field.into();
+        self.id_fields = vec![field.into()];
+        self
+    }
+
+    /// Try each JSON field in order for the entity ID, using the first present.
+    pub fn id_fields<I, S>(mut self, fields: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.id_fields = fields.into_iter().map(Into::into).collect();
         self
     }
     
@@ -128,19 +1074,540 @@ impl MqttSourceConfigBuilder {
         self
     }
 
+    /// Limit nodes to `max` properties.
+    pub fn max_properties(mut self, max: usize) -> Self {
+        self.max_properties = Some(max);
+        self
+    }
+
+    /// Limit each property value to `max` bytes.
+    pub fn max_property_value_bytes(mut self, max: usize) -> Self {
+        self.max_property_value_bytes = Some(max);
+        self
+    }
+
+    /// What to do with a node over `max_properties` or
+    /// `max_property_value_bytes`: [`OversizePolicy::Reject`] (the default)
+    /// skips the payload with a mapping error, [`OversizePolicy::Truncate`]
+    /// keeps the first properties by key, cuts long strings and drops other
+    /// oversized values, never touching the id fields or `correlation_field`.
+    pub fn oversize_policy(mut self, policy: OversizePolicy) -> Self {
+        self.oversize_policy = policy;
+        self
+    }
+
+    pub fn non_object_policy(mut self, policy: NonObjectPolicy) -> Self {
+        self.non_object_policy = policy;
+        self
+    }
+
+    /// Truncate oversized values, ending cut strings with `marker`.
+    pub fn truncate_with_marker(mut self, marker: impl Into<String>) -> Self {
+        self.oversize_policy = OversizePolicy::Truncate;
+        self.truncate_with_marker = Some(marker.into());
+        self
+    }
+
+    pub fn id_policy(mut self, policy: IdPolicy) -> Self {
+        self.id_policy = policy;
+        self
+    }
+
+    /// Limit entity ids to `max` bytes.
+    pub fn max_id_bytes(mut self, max: usize) -> Self {
+        self.max_id_bytes = Some(max);
+        self
+    }
+
+    /// Trim and/or lowercase entity ids, so differently formatted ids of
+    /// one device map to the same node.
+    pub fn id_normalize(mut self, normalize: IdNormalize) -> Self {
+        self.id_normalize = normalize;
+        self
+    }
+
+    /// Convert the payload field `field` to `target`.
+    pub fn coerce(mut self, field: impl Into<String>, target: Coercion) -> Self {
+        self.coerce.insert(field.into(), target);
+        self
+    }
+
+    /// Values converted to `true` by [`Coercion::Bool`].
+    pub fn bool_true_tokens<I, S>(mut self, tokens: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.bool_true_tokens = tokens.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Values converted to `false` by [`Coercion::Bool`].
+    pub fn bool_false_tokens<I, S>(mut self, tokens: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.bool_false_tokens = tokens.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Use `value` for payload field `field` when a message omits it.
+    pub fn default_value(mut self, field: impl Into<String>, value: serde_json::Value) -> Self {
+        self.defaults.insert(field.into(), value);
+        self
+    }
+
+    /// Set property `name` to the value of `expression` over the payload
+    /// fields, e.g. `temp_c * 9 / 5 + 32`.
+    pub fn computed_property(
+        mut self,
+        name: impl Into<String>,
+        expression: impl Into<String>,
+    ) -> Self {
+        self.computed_properties
+            .insert(name.into(), expression.into());
+        self
+    }
+
+    /// Copy the correlation id in payload field `field` to a
+    /// `correlation_id` node property.
+    pub fn correlation_field(mut self, field: impl Into<String>) -> Self {
+        self.correlation_field = Some(field.into());
+        self
+    }
+
+    /// Take the entity ID from the topic levels from `level` on.
+    pub fn topic_id_level(mut self, level: usize) -> Self {
+        self.topic_id_level = Some(level);
+        self
+    }
+
+    /// Take the entity ID from `depth` topic levels from `topic_id_level` on.
+    pub fn topic_id_depth(mut self, depth: usize) -> Self {
+        self.topic_id_depth = Some(depth);
+        self
+    }
+
+    /// Label nodes with the payload string at JSON Pointer `pointer`, when present.
+    pub fn label_pointer(mut self, pointer: impl Into<String>) -> Self {
+        self.label_pointer = Some(pointer.into());
+        self
+    }
+
+    /// Map messages on topics matching `filter` with `action`.
+    pub fn topic_rule(mut self, filter: impl Into<String>, action: TopicAction) -> Self {
+        self.topic_rules.push(TopicRule::new(filter, action));
+        self
+    }
+
+    /// Apply a mapping preset, e.g. for Zigbee2MQTT.
+    pub fn preset(mut self, preset: Preset) -> Self {
+        self.preset = Some(preset);
+        self
+    }
+
+    /// Merge partial messages into the last properties of their entity.
+    pub fn merge_partial_updates(mut self, merge: bool) -> Self {
+        self.merge_partial_updates = merge;
+        self
+    }
+
+    /// Bound the entities whose last properties are kept, forgetting those
+    /// not updated within `ttl`.
+    pub fn entity_cache(mut self, capacity: usize, ttl: Option<std::time::Duration>) -> Self {
+        self.entity_cache_capacity = capacity;
+        self.entity_cache_ttl_ms = ttl.map(|ttl| ttl.as_millis() as u64);
+        self
+    }
+
+    /// Keep the last `size` raw messages for inspection.
+    pub fn debug_ring(mut self, size: usize) -> Self {
+        self.debug_ring = Some(size);
+        self
+    }
+
+    /// Record each message's QoS, retain and dup flags as node properties.
+    pub fn capture_mqtt_meta(mut self, capture: bool) -> Self {
+        self.capture_mqtt_meta = capture;
+        self
+    }
+
+    /// Warn when the same entity id is mapped under different labels.
+    pub fn warn_on_cross_label_id(mut self, warn: bool) -> Self {
+        self.warn_on_cross_label_id = warn;
+        self
+    }
+
+    /// Infer the schema of the last `sample_size` mapped messages.
+    pub fn infer_schema(mut self, sample_size: usize) -> Self {
+        self.infer_schema = true;
+        self.schema_sample_size = sample_size;
+        self
+    }
+
+    /// Whether each message is handled in a tracing span (default: true).
+    pub fn message_spans(mut self, enabled: bool) -> Self {
+        self.message_spans = enabled;
+        self
+    }
+
+    /// Ingest the broker's `$SYS` metrics as `BrokerMetric` nodes, at most
+    /// one message per topic every `interval`.
+    pub fn ingest_sys_metrics(mut self, interval: std::time::Duration) -> Self {
+        self.ingest_sys_metrics = true;
+        self.sys_metrics_interval_ms = interval.as_millis() as u64;
+        self
+    }
+
+    /// Use the JSON held as a string in `field` as the payload.
+    pub fn decode_nested_json(mut self, field: impl Into<String>) -> Self {
+        self.decode_nested_json = Some(field.into());
+        self
+    }
+
+    /// Parse `NaN`, `Infinity` and `-Infinity` in payloads as `null`.
+    pub fn lenient_json(mut self, lenient: bool) -> Self {
+        self.lenient_json = lenient;
+        self
+    }
+
+    /// Decode payloads from the given encoding label (e.g. `"latin1"`).
+    pub fn text_encoding(mut self, label: impl Into<String>) -> Self {
+        self.text_encoding = Some(label.into());
+        self
+    }
+
+    /// Whether DrasiLib starts the source automatically (default: `true`).
+    pub fn auto_start(mut self, auto_start: bool) -> Self {
+        self.auto_start = auto_start;
+        self
+    }
+
+    /// Wait up to `timeout` on stop for queued changes to be dispatched.
+    pub fn stop_drain_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.stop_drain_timeout_ms = timeout.as_millis() as u64;
+        self
+    }
+
+    /// Skip messages whose mapping takes longer than `timeout`, parsing on
+    /// the blocking thread pool so the event loop stays responsive.
+    pub fn message_processing_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.message_processing_timeout_ms = Some(timeout.as_millis() as u64);
+        self
+    }
+
+    /// Parse payloads of at least `bytes` on the blocking thread pool under
+    /// the message processing timeout; smaller ones in the event loop.
+    pub fn blocking_payload_bytes(mut self, bytes: usize) -> Self {
+        self.blocking_payload_bytes = bytes;
+        self
+    }
+
+    /// Process at most `per_second` messages per second, handling the
+    /// excess with `action`.
+    pub fn rate_limit(mut self, per_second: u32, action: RateLimitAction) -> Self {
+        self.rate_limit = Some(per_second);
+        self.rate_limit_action = action;
+        self
+    }
+
+    /// How drasi-lib delivers dispatched changes to subscribed queries.
+    ///
+    /// `Channel` (the default) gives every subscribed query its own channel:
+    /// a slow query backs up only its own queue, at the cost of one send per
+    /// query for each change. `Broadcast` sends each change once to a channel
+    /// all queries read from, avoiding the per-query hop for sources with
+    /// high throughput or many queries; a query falling behind the shared
+    /// buffer misses changes instead of holding up the others.
+    pub fn dispatch_mode(mut self, mode: DispatchMode) -> Self {
+        self.dispatch_mode = mode;
+        self
+    }
+
+    /// Drop changes for `cooldown` once `threshold` consecutive dispatches
+    /// have failed, instead of attempting each one, then try a single change
+    /// to test whether the pipeline has recovered.
+    pub fn dispatch_circuit_breaker(
+        mut self,
+        threshold: u32,
+        cooldown: std::time::Duration,
+    ) -> Self {
+        self.dispatch_circuit_breaker = Some(CircuitBreakerConfig {
+            threshold,
+            cooldown_ms: cooldown.as_millis() as u64,
+        });
+        self
+    }
+
+    /// Whether to skip changes whose labels no subscribed query matches on.
+    /// Turn off to dispatch every change, e.g. for queries whose label
+    /// filters the source cannot see.
+    pub fn skip_unsubscribed_labels(mut self, skip: bool) -> Self {
+        self.skip_unsubscribed_labels = skip;
+        self
+    }
+
+    /// Dispatch changes with `workers` concurrent tasks, keeping `ordering`.
+    pub fn dispatch_workers(mut self, workers: usize, ordering: DispatchOrdering) -> Self {
+        self.dispatch_workers = workers;
+        self.ordering = ordering;
+        self
+    }
+
+    /// Report the source as degraded once the connection has been down for `grace`.
+    pub fn degraded_after(mut self, grace: std::time::Duration) -> Self {
+        self.degraded_after_ms = grace.as_millis() as u64;
+        self
+    }
+
     /// Build the config.
     pub fn build(self) -> MqttSourceConfig {
         MqttSourceConfig {
             id: self.id,
-            broker_host: self.broker_host,
-            port: self.port,
+            connection: self.connection,
             topic: self.topic,
-            client_id: self.client_id,
-            username: self.username,
-            password: self.password,
+            topics: self.topics,
+            client_id_suffix: self.client_id_suffix,
             node_label: self.node_label,
-            id_field: self.id_field,
+            reference_source: self.reference_source,
+            id_fields: self.id_fields,
             mode: self.mode,
+            degraded_after_ms: self.degraded_after_ms,
+            text_encoding: self.text_encoding,
+            decode_nested_json: self.decode_nested_json,
+            lenient_json: self.lenient_json,
+            auto_start: self.auto_start,
+            stop_drain_timeout_ms: self.stop_drain_timeout_ms,
+            message_processing_timeout_ms: self.message_processing_timeout_ms,
+            blocking_payload_bytes: self.blocking_payload_bytes,
+            rate_limit: self.rate_limit,
+            rate_limit_action: self.rate_limit_action,
+            dispatch_workers: self.dispatch_workers,
+            ordering: self.ordering,
+            dispatch_mode: self.dispatch_mode,
+            dispatch_circuit_breaker: self.dispatch_circuit_breaker,
+            skip_unsubscribed_labels: self.skip_unsubscribed_labels,
+            max_properties: self.max_properties,
+            max_property_value_bytes: self.max_property_value_bytes,
+            oversize_policy: self.oversize_policy,
+            non_object_policy: self.non_object_policy,
+            truncate_with_marker: self.truncate_with_marker,
+            id_policy: self.id_policy,
+            max_id_bytes: self.max_id_bytes,
+            id_normalize: self.id_normalize,
+            coerce: self.coerce,
+            bool_true_tokens: self.bool_true_tokens,
+            bool_false_tokens: self.bool_false_tokens,
+            defaults: self.defaults,
+            computed_properties: self.computed_properties,
+            correlation_field: self.correlation_field,
+            topic_id_level: self.topic_id_level,
+            topic_id_depth: self.topic_id_depth,
+            label_pointer: self.label_pointer,
+            topic_rules: self.topic_rules,
+            preset: self.preset,
+            merge_partial_updates: self.merge_partial_updates,
+            entity_cache_capacity: self.entity_cache_capacity,
+            entity_cache_ttl_ms: self.entity_cache_ttl_ms,
+            debug_ring: self.debug_ring,
+            capture_mqtt_meta: self.capture_mqtt_meta,
+            warn_on_cross_label_id: self.warn_on_cross_label_id,
+            infer_schema: self.infer_schema,
+            schema_sample_size: self.schema_sample_size,
+            message_spans: self.message_spans,
+            ingest_sys_metrics: self.ingest_sys_metrics,
+            sys_metrics_interval_ms: self.sys_metrics_interval_ms,
+            fallback_broker: self.fallback_broker,
+            max_reconnect_attempts: self.max_reconnect_attempts,
+            on_auth_error: self.on_auth_error,
+            credentials_provider: self.credentials_provider,
+            id_generator: self.id_generator,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(extra: &str) -> MqttSourceConfig {
+        let json = format!(
+            r#"{{"id": "s", "broker_host": "localhost", "port": 1883, "topic": "t/#",
+                "client_id": "c", "node_label": "N"{extra}}}"#
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_id_fields_accepts_string_or_list() {
+        assert_eq!(parse("").id_fields, vec!["id"]);
+        assert_eq!(parse(r#", "id_field": "serial""#).id_fields, vec!["serial"]);
+        assert_eq!(
+            parse(r#", "id_fields": ["id", "deviceId"]"#).id_fields,
+            vec!["id", "deviceId"]
+        );
+    }
+
+    #[test]
+    fn test_topics_accept_string_shorthand() {
+        let config = parse(r#", "topics": ["telemetry/#", {"filter": "alarms/#", "qos": 2}]"#);
+        assert_eq!(
+            config.topics,
+            vec![
+                TopicSubscription::new("telemetry/#", QoS::AtLeastOnce),
+                TopicSubscription::new("alarms/#", QoS::ExactlyOnce),
+            ]
+        );
+        assert!(parse(r#", "topics": [{"filter": "alarms/#", "qos": 3}]"#)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_truncation_marker_must_fit_value_limit() {
+        let config = |max| {
+            MqttSourceConfig::builder("s", "localhost", "sensors/#")
+                .max_property_value_bytes(max)
+                .truncate_with_marker("…[truncated]")
+                .build()
+        };
+        // The marker is 14 bytes.
+        assert!(config(15).validate().is_ok());
+        let err = config(14).validate().unwrap_err();
+        assert!(err.to_string().contains("must be shorter"));
+        assert!(config(4).validate().is_err());
+    }
+
+    #[test]
+    fn test_reference_source() {
+        let config = parse("");
+        assert_eq!(config.reference_source, ReferenceSource::SourceId);
+        assert_eq!(config.reference_source_for("N"), "s");
+        assert_eq!(
+            parse(r#", "reference_source": "node_label""#).reference_source_for("N"),
+            "N"
+        );
+        assert_eq!(
+            parse(r#", "reference_source": {"custom": "plant-a"}"#).reference_source_for("N"),
+            "plant-a"
+        );
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert!(parse("").defaults.is_empty());
+        let config = parse(r#", "defaults": {"temperature": 0, "unit": "C"}"#);
+        assert_eq!(config.defaults["temperature"], serde_json::json!(0));
+        assert_eq!(config.defaults["unit"], serde_json::json!("C"));
+    }
+
+    #[test]
+    fn test_brokers_keep_connection_settings() {
+        let config = parse(
+            r#", "keep_alive_secs": 60, "tls": {"ca_cert": "ca.pem"},
+                "fallback_broker": {"host": "backup", "keep_alive_secs": 5,
+                                    "tls": {"ca_cert": "backup.pem"}}"#,
+        );
+        let brokers = config.brokers();
+        assert_eq!(brokers[0].host, "localhost");
+        assert_eq!(brokers[0].keep_alive_secs, 60);
+        assert_eq!(brokers[0].tls, Some(TlsConfig::new("ca.pem")));
+        assert_eq!(brokers[1].host, "backup");
+        assert_eq!(brokers[1].client_id, "c");
+        assert_eq!(brokers[1].keep_alive_secs, 5);
+        assert_eq!(brokers[1].tls, Some(TlsConfig::new("backup.pem")));
+
+        let config = MqttSourceConfig::builder("s", "localhost", "t/#").build();
+        assert_eq!(config.brokers()[0].client_id, "drasi-source-s");
+    }
+
+    #[test]
+    fn test_coerce_and_bool_tokens() {
+        let config = parse(r#", "coerce": {"on": "bool"}, "bool_true_tokens": ["enabled"]"#);
+        assert_eq!(config.coerce["on"], Coercion::Bool);
+        assert_eq!(config.bool_true_tokens, vec!["enabled"]);
+        assert_eq!(config.bool_false_tokens, vec!["false", "0", "off", "no"]);
+    }
+
+    #[test]
+    fn test_preview_sys_metric() {
+        let builder = || MqttSourceConfig::builder("s", "localhost", "sensors/#");
+        let topic = "$SYS/broker/load/messages/received/1min";
+
+        let preview = builder()
+            .ingest_sys_metrics(std::time::Duration::from_secs(10))
+            .build()
+            .preview(topic, b"12.5")
+            .unwrap();
+        assert_eq!(preview.id, topic);
+        assert_eq!(preview.labels, vec!["BrokerMetric".to_string()]);
+        assert_eq!(preview.properties["value"], serde_json::json!(12.5));
+
+        // `#` does not match `$SYS` topics.
+        assert!(builder().build().preview(topic, b"12.5").is_err());
+    }
+
+    #[test]
+    fn test_preview_matches_live_mapping() {
+        use drasi_core::models::{ElementValue, SourceChange};
+
+        let builder = || MqttSourceConfig::builder("s", "localhost", "sensors/#");
+        let configs = [
+            builder().build(),
+            builder()
+                .id_fields(["serial", "id"])
+                .node_label("Thermostat")
+                .mode(OperationMode::Update)
+                .coerce("on", Coercion::Bool)
+                .default_value("unit", serde_json::json!("C"))
+                .build(),
+            builder()
+                .id_fields([PAYLOAD_HASH_ID])
+                .max_properties(2)
+                .oversize_policy(OversizePolicy::Truncate)
+                .build(),
+        ];
+        let payload = br#"{"id": "t1", "serial": 7, "on": "yes", "temp": 21}"#;
+
+        for config in configs {
+            let preview = config.preview("sensors/t1", payload).unwrap();
+            let live = crate::mapper::payload_to_source_change(
+                payload,
+                &config.id,
+                &config.id_fields,
+                &config.node_label,
+                config.mode,
+                &config.payload_format().unwrap(),
+            )
+            .unwrap();
+            let (operation, element) = match live {
+                SourceChange::Insert { element } => (OperationMode::Insert, element),
+                SourceChange::Update { element } => (OperationMode::Update, element),
+                _ => panic!("Expected Insert or Update"),
+            };
+
+            assert_eq!(preview.operation, operation);
+            assert_eq!(*preview.id, *element.get_reference().element_id);
+            assert_eq!(preview.labels, vec![config.node_label.clone()]);
+            assert_eq!(preview.effective_from, element.get_effective_from());
+            for (key, value) in &preview.properties {
+                assert_eq!(
+                    element.get_properties().get(key),
+                    Some(&ElementValue::from(value)),
+                    "{key}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_preview_reports_errors() {
+        let config = MqttSourceConfig::builder("s", "localhost", "sensors/#").build();
+
+        let preview = config.preview("sensors/t1", br#"{"id": "t1"}"#).unwrap();
+        assert_eq!(preview.id, "t1");
+        assert!(config.preview("alerts/t1", br#"{"id": "t1"}"#).is_err());
+        assert!(config.preview("sensors/t1", b"not json").is_err());
+    }
+}
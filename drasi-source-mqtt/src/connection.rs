@@ -0,0 +1,400 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connection-state tracking for the MQTT event loop.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use drasi_mqtt_connection::{system_clock, SharedClock};
+use rumqttc::{ConnectReturnCode, ConnectionError, Event, Incoming};
+use tokio::time::Instant;
+
+/// Callback invoked with the reconnect count (1 for the first reconnect)
+/// whenever the connection is re-established after a disconnect.
+pub type ReconnectHook = Arc<dyn Fn(u32) + Send + Sync>;
+
+/// A change in connection state observed by [`ConnectionMonitor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionTransition {
+    /// First successful connection since start.
+    Connected,
+    /// Connection re-established after a disconnect; carries the reconnect count.
+    Reconnected(u32),
+    /// An established connection was lost.
+    Disconnected,
+}
+
+/// Whether the connection has been down long enough to report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionHealth {
+    /// Connected, or down for less than the grace period.
+    Healthy,
+    /// Down continuously for at least the grace period.
+    Degraded,
+}
+
+/// Health of a connection that has been down since `disconnected_since`
+/// (`None` if it is up), evaluated at `now` against the `degraded_after` grace period.
+pub fn health(
+    disconnected_since: Option<Instant>,
+    now: Instant,
+    degraded_after: Duration,
+) -> ConnectionHealth {
+    match disconnected_since {
+        Some(since) if now.saturating_duration_since(since) >= degraded_after => {
+            ConnectionHealth::Degraded
+        }
+        _ => ConnectionHealth::Healthy,
+    }
+}
+
+/// Kind of a failed `EventLoop::poll()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The broker refused the credentials or the client's authorization.
+    Auth,
+    /// Anything else: network errors, timeouts, protocol errors and other
+    /// refusals.
+    Network,
+}
+
+/// Classify a connection error, to tell errors that retrying cannot fix.
+pub fn classify(error: &ConnectionError) -> ErrorClass {
+    match error {
+        ConnectionError::ConnectionRefused(
+            ConnectReturnCode::BadUserNamePassword | ConnectReturnCode::NotAuthorized,
+        ) => ErrorClass::Auth,
+        _ => ErrorClass::Network,
+    }
+}
+
+/// A duration in the largest fitting unit, e.g. `4.2s`, `37m` or `2h5m`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{:.1}s", duration.as_secs_f64()),
+        60..3600 => format!("{}m", secs / 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Tracks connection state from the results of `EventLoop::poll()`.
+pub struct ConnectionMonitor {
+    connected: bool,
+    has_connected: bool,
+    reconnects: u32,
+    failed_attempts: u32,
+    disconnected_since: Option<Instant>,
+    on_reconnect: Option<ReconnectHook>,
+    clock: SharedClock,
+    /// Start of the current connection, or of the attempts to establish one.
+    since: Instant,
+    /// Failed polls before the current connection, not reset on broker switches.
+    retries: u32,
+    /// Time the last transition ended: spent connecting, or connected.
+    last_duration: Duration,
+}
+
+impl ConnectionMonitor {
+    pub fn new(on_reconnect: Option<ReconnectHook>) -> Self {
+        let clock = system_clock();
+        Self {
+            connected: false,
+            has_connected: false,
+            reconnects: 0,
+            failed_attempts: 0,
+            disconnected_since: None,
+            on_reconnect,
+            since: clock.now_instant(),
+            clock,
+            retries: 0,
+            last_duration: Duration::ZERO,
+        }
+    }
+
+    /// Take disconnect times from `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.since = clock.now_instant();
+        self.clock = clock;
+        self
+    }
+
+    /// Whether the last observed event left the connection up.
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Number of reconnects observed since start.
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects
+    }
+
+    /// Failed polls since the last ConnAck (or [`reset_failed_attempts`](Self::reset_failed_attempts)).
+    pub fn failed_attempts(&self) -> u32 {
+        self.failed_attempts
+    }
+
+    /// Start counting failed attempts afresh, e.g. after switching brokers.
+    pub fn reset_failed_attempts(&mut self) {
+        self.failed_attempts = 0;
+    }
+
+    /// When the connection went down, if it is down.
+    ///
+    /// Starts at the first failed poll, including failed initial connects,
+    /// and is cleared by the next ConnAck.
+    pub fn disconnected_since(&self) -> Option<Instant> {
+        self.disconnected_since
+    }
+
+    /// A log line for `transition`, just returned by [`observe`](Self::observe),
+    /// with the retries and time it took to connect or how long the
+    /// connection was up.
+    pub fn describe(&self, transition: ConnectionTransition) -> String {
+        let retries = match self.retries {
+            1 => "1 retry".to_string(),
+            n => format!("{n} retries"),
+        };
+        let duration = format_duration(self.last_duration);
+        match transition {
+            ConnectionTransition::Connected => {
+                format!("Connected to MQTT broker after {retries} in {duration}")
+            }
+            ConnectionTransition::Reconnected(n) => {
+                format!("Reconnected to MQTT broker (reconnect #{n}) after {retries} in {duration}")
+            }
+            ConnectionTransition::Disconnected => {
+                format!("Disconnected from MQTT broker, was up {duration}")
+            }
+        }
+    }
+
+    /// Feed one poll result, returning the state transition it caused, if any.
+    ///
+    /// Invokes the reconnect hook when a ConnAck follows a prior disconnect.
+    pub fn observe<E>(&mut self, event: &Result<Event, E>) -> Option<ConnectionTransition> {
+        match event {
+            Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                let now = self.clock.now_instant();
+                if !self.connected {
+                    self.last_duration = now.saturating_duration_since(self.since);
+                    self.since = now;
+                }
+                self.connected = true;
+                self.failed_attempts = 0;
+                self.disconnected_since = None;
+                if !self.has_connected {
+                    self.has_connected = true;
+                    return Some(ConnectionTransition::Connected);
+                }
+
+                self.reconnects += 1;
+                if let Some(hook) = &self.on_reconnect {
+                    hook(self.reconnects);
+                }
+                Some(ConnectionTransition::Reconnected(self.reconnects))
+            }
+            Ok(_) => None,
+            Err(_) => {
+                let was_connected = self.connected;
+                self.connected = false;
+                self.failed_attempts += 1;
+                let now = self.clock.now_instant();
+                self.disconnected_since.get_or_insert(now);
+                if !was_connected {
+                    self.retries += 1;
+                    return None;
+                }
+                self.last_duration = now.saturating_duration_since(self.since);
+                self.since = now;
+                self.retries = 0;
+                Some(ConnectionTransition::Disconnected)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::ConnAck;
+    use std::sync::Mutex;
+
+    fn connack() -> Result<Event, ()> {
+        Ok(Event::Incoming(Incoming::ConnAck(ConnAck::new(
+            ConnectReturnCode::Success,
+            false,
+        ))))
+    }
+
+    fn disconnect() -> Result<Event, ()> {
+        Err(())
+    }
+
+    #[test]
+    fn test_reconnect_hook_receives_count() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let hook: ReconnectHook = Arc::new(move |n| recorded.lock().unwrap().push(n));
+        let mut monitor = ConnectionMonitor::new(Some(hook));
+
+        assert_eq!(
+            monitor.observe(&connack()),
+            Some(ConnectionTransition::Connected)
+        );
+        assert_eq!(
+            monitor.observe(&disconnect()),
+            Some(ConnectionTransition::Disconnected)
+        );
+        // Failed reconnect attempts don't count as further disconnects.
+        assert_eq!(monitor.observe(&disconnect()), None);
+        assert_eq!(
+            monitor.observe(&connack()),
+            Some(ConnectionTransition::Reconnected(1))
+        );
+        monitor.observe(&disconnect());
+        assert_eq!(
+            monitor.observe(&connack()),
+            Some(ConnectionTransition::Reconnected(2))
+        );
+
+        assert_eq!(*calls.lock().unwrap(), vec![1, 2]);
+        assert_eq!(monitor.reconnects(), 2);
+        assert_eq!(monitor.failed_attempts(), 0);
+        assert!(monitor.is_connected());
+    }
+
+    #[test]
+    fn test_initial_connect_failures_are_not_reconnects() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let hook: ReconnectHook = Arc::new(move |n| recorded.lock().unwrap().push(n));
+        let mut monitor = ConnectionMonitor::new(Some(hook));
+
+        assert_eq!(monitor.observe(&disconnect()), None);
+        assert_eq!(monitor.observe(&disconnect()), None);
+        assert_eq!(monitor.failed_attempts(), 2);
+        assert_eq!(
+            monitor.observe(&connack()),
+            Some(ConnectionTransition::Connected)
+        );
+
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_transitions_are_described_with_durations() {
+        use drasi_mqtt_connection::ManualClock;
+
+        let clock = Arc::new(ManualClock::new(0));
+        let mut monitor = ConnectionMonitor::new(None).with_clock(clock.clone());
+        let mut log = Vec::new();
+        let mut poll = |monitor: &mut ConnectionMonitor, event, after| {
+            clock.advance(after);
+            if let Some(transition) = monitor.observe(&event) {
+                log.push(monitor.describe(transition));
+            }
+        };
+
+        for _ in 0..3 {
+            poll(&mut monitor, disconnect(), Duration::from_millis(1400));
+        }
+        poll(&mut monitor, connack(), Duration::ZERO);
+        // Polls while connected are not transitions.
+        poll(
+            &mut monitor,
+            Ok(Event::Outgoing(rumqttc::Outgoing::PingReq)),
+            Duration::from_secs(60),
+        );
+        poll(
+            &mut monitor,
+            disconnect(),
+            Duration::from_secs(37 * 60 - 60),
+        );
+        poll(&mut monitor, disconnect(), Duration::from_secs(2));
+        poll(&mut monitor, connack(), Duration::from_millis(500));
+
+        assert_eq!(
+            log,
+            vec![
+                "Connected to MQTT broker after 3 retries in 4.2s",
+                "Disconnected from MQTT broker, was up 37m",
+                "Reconnected to MQTT broker (reconnect #1) after 1 retry in 2.5s",
+            ]
+        );
+    }
+
+    const GRACE: Duration = Duration::from_secs(5);
+
+    fn current_health(monitor: &ConnectionMonitor) -> ConnectionHealth {
+        health(monitor.disconnected_since(), Instant::now(), GRACE)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_short_disconnect_stays_healthy() {
+        let mut monitor = ConnectionMonitor::new(None);
+        monitor.observe(&connack());
+
+        monitor.observe(&disconnect());
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        monitor.observe(&disconnect());
+        assert_eq!(current_health(&monitor), ConnectionHealth::Healthy);
+
+        monitor.observe(&connack());
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(current_health(&monitor), ConnectionHealth::Healthy);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_long_disconnect_is_degraded() {
+        let mut monitor = ConnectionMonitor::new(None);
+        monitor.observe(&connack());
+
+        monitor.observe(&disconnect());
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        // Repeated failures don't restart the clock.
+        monitor.observe(&disconnect());
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(current_health(&monitor), ConnectionHealth::Degraded);
+
+        monitor.observe(&connack());
+        assert_eq!(current_health(&monitor), ConnectionHealth::Healthy);
+    }
+
+    #[test]
+    fn test_classify_errors() {
+        for code in [
+            ConnectReturnCode::BadUserNamePassword,
+            ConnectReturnCode::NotAuthorized,
+        ] {
+            assert_eq!(
+                classify(&ConnectionError::ConnectionRefused(code)),
+                ErrorClass::Auth
+            );
+        }
+        assert_eq!(
+            classify(&ConnectionError::ConnectionRefused(
+                ConnectReturnCode::ServiceUnavailable
+            )),
+            ErrorClass::Network
+        );
+        assert_eq!(
+            classify(&ConnectionError::Io(std::io::Error::from(
+                std::io::ErrorKind::ConnectionRefused
+            ))),
+            ErrorClass::Network
+        );
+    }
+}
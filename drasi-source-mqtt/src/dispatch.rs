@@ -0,0 +1,686 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Buffered dispatch of source changes.
+//!
+//! Changes mapped by the MQTT event loop are queued and dispatched by
+//! separate worker tasks, so a slow pipeline does not hold up keep-alives.
+//! With several workers, [`DispatchOrdering`] decides which worker a change
+//! goes to; `PerId` keeps each entity id on one worker, in order. On stop,
+//! the queue is flushed for up to a drain timeout; whatever is still queued
+//! after that is dropped and counted. The time from receipt of each message
+//! to dispatch of its change is recorded in a [`LatencyHistogram`]. A change
+//! can be held back until a given time, which paces dispatch for
+//! `RateLimitAction::Pause` without holding up the event loop.
+//!
+//! An optional [`CircuitBreaker`] stops dispatch attempts after repeated
+//! failures: while it is open, changes are dropped and counted, until a
+//! single trial dispatch after the cooldown succeeds.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use drasi_core::models::SourceChange;
+use drasi_lib::sources::base::SourceBase;
+use drasi_mqtt_connection::{system_clock, SharedClock};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{error, info, warn, Instrument, Span};
+
+use crate::config::DispatchOrdering;
+use crate::latency::LatencyHistogram;
+
+/// Changes queued for dispatch before the event loop waits for room.
+pub const DISPATCH_BUFFER_CAPACITY: usize = 1000;
+
+/// Destination of dispatched changes.
+#[async_trait]
+pub trait ChangeSink: Send + Sync + 'static {
+    async fn dispatch(&self, change: SourceChange) -> Result<()>;
+}
+
+#[async_trait]
+impl ChangeSink for SourceBase {
+    async fn dispatch(&self, change: SourceChange) -> Result<()> {
+        self.dispatch_source_change(change).await
+    }
+}
+
+/// A change in a worker's queue.
+struct Queued {
+    change: SourceChange,
+    /// When the message it was mapped from was received.
+    received: Instant,
+    /// Held back until then, if set.
+    not_before: Option<Instant>,
+    /// The span of the message it was mapped from.
+    span: Span,
+}
+
+/// Queues changes for the dispatcher workers.
+#[derive(Clone)]
+pub struct ChangeSender {
+    /// One queue per worker.
+    queues: Vec<mpsc::Sender<Queued>>,
+    ordering: DispatchOrdering,
+    /// Worker the next change goes to without an ordering guarantee.
+    next: Arc<AtomicUsize>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl ChangeSender {
+    /// Queue `change`, made from a message received at `received`, waiting
+    /// for room while the buffer is full. It is dispatched in the current
+    /// span.
+    pub async fn send(&self, change: SourceChange, received: Instant) {
+        self.send_paced(change, received, None).await
+    }
+
+    /// Like [`send`](Self::send), but the change is not dispatched before
+    /// `not_before`. Later changes on the same worker wait behind it.
+    pub async fn send_paced(
+        &self,
+        change: SourceChange,
+        received: Instant,
+        not_before: Option<Instant>,
+    ) {
+        let queue = &self.queues[self.worker_for(&change)];
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let queued = Queued {
+            change,
+            received,
+            not_before,
+            span: Span::current(),
+        };
+        if queue.send(queued).await.is_err() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Index of the worker dispatching `change`.
+    fn worker_for(&self, change: &SourceChange) -> usize {
+        let workers = self.queues.len();
+        if workers == 1 {
+            return 0;
+        }
+        match self.ordering {
+            DispatchOrdering::PerId => {
+                let mut hasher = DefaultHasher::new();
+                change.get_reference().element_id.hash(&mut hasher);
+                (hasher.finish() % workers as u64) as usize
+            }
+            DispatchOrdering::None => self.next.fetch_add(1, Ordering::Relaxed) % workers,
+        }
+    }
+}
+
+/// Stops dispatch attempts after `threshold` consecutive failures.
+///
+/// The circuit then stays open for `cooldown`, dropping changes. After that
+/// one change is let through as a trial (half-open): success closes the
+/// circuit, failure opens it for another cooldown.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+    dropped: AtomicU64,
+    clock: SharedClock,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+    /// A trial dispatch is in progress.
+    trial: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            state: Mutex::default(),
+            dropped: AtomicU64::new(0),
+            clock: system_clock(),
+        }
+    }
+
+    /// Time the cooldown with `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Whether a change may be dispatched now. Counts it as dropped if not.
+    fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let allowed = match state.open_until {
+            None => true,
+            Some(until) if self.clock.now_instant() >= until && !state.trial => {
+                state.trial = true;
+                true
+            }
+            Some(_) => false,
+        };
+        if !allowed {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Record the outcome of a dispatch let through by [`allow`](Self::allow).
+    fn record(&self, source_id: &str, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        if ok {
+            state.failures = 0;
+            if state.open_until.take().is_some() {
+                state.trial = false;
+                info!(
+                    "[{source_id}] Dispatch recovered, closing the circuit; {} change(s) dropped while open",
+                    self.dropped.load(Ordering::Relaxed)
+                );
+            }
+            return;
+        }
+        state.failures = state.failures.saturating_add(1);
+        if state.trial || state.failures == self.threshold {
+            state.trial = false;
+            state.open_until = Some(self.clock.now_instant() + self.cooldown);
+            warn!(
+                "[{source_id}] {} consecutive dispatch failure(s), dropping changes for {}ms",
+                state.failures,
+                self.cooldown.as_millis()
+            );
+        }
+    }
+
+    /// Whether dispatch is currently suspended.
+    pub fn is_open(&self) -> bool {
+        self.state.lock().unwrap().open_until.is_some()
+    }
+
+    /// Changes dropped while the circuit was open, since it was created.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// The worker tasks dispatching queued changes to a [`ChangeSink`].
+pub struct Dispatcher {
+    source_id: String,
+    tasks: Vec<JoinHandle<()>>,
+    pending: Arc<AtomicUsize>,
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl Dispatcher {
+    /// Spawn a single worker dispatching to `sink`, buffering up to
+    /// `capacity` changes and recording their delivery latency in `latency`.
+    pub fn spawn<S: ChangeSink>(
+        source_id: impl Into<String>,
+        sink: S,
+        capacity: usize,
+        latency: Arc<LatencyHistogram>,
+    ) -> (ChangeSender, Dispatcher) {
+        Self::spawn_workers(
+            source_id,
+            sink,
+            capacity,
+            latency,
+            1,
+            DispatchOrdering::PerId,
+            None,
+        )
+    }
+
+    /// Spawn `workers` tasks dispatching to `sink` concurrently, with
+    /// `ordering`. `capacity` is shared evenly between the workers' queues.
+    /// With a `breaker`, all workers stop dispatching while it is open.
+    pub fn spawn_workers<S: ChangeSink>(
+        source_id: impl Into<String>,
+        sink: S,
+        capacity: usize,
+        latency: Arc<LatencyHistogram>,
+        workers: usize,
+        ordering: DispatchOrdering,
+        breaker: Option<CircuitBreaker>,
+    ) -> (ChangeSender, Dispatcher) {
+        let source_id = source_id.into();
+        let workers = workers.max(1);
+        let sink = Arc::new(sink);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let breaker = breaker.map(Arc::new);
+
+        let mut queues = Vec::with_capacity(workers);
+        let mut tasks = Vec::with_capacity(workers);
+        for _ in 0..workers {
+            let (tx, mut rx) = mpsc::channel::<Queued>(capacity.div_ceil(workers).max(1));
+            queues.push(tx);
+
+            let sink = sink.clone();
+            let latency = latency.clone();
+            let task_pending = pending.clone();
+            let task_source_id = source_id.clone();
+            let breaker = breaker.clone();
+            tasks.push(tokio::spawn(async move {
+                while let Some(queued) = rx.recv().await {
+                    let Queued {
+                        change,
+                        received,
+                        not_before,
+                        span,
+                    } = queued;
+                    if let Some(not_before) = not_before {
+                        tokio::time::sleep_until(not_before).await;
+                    }
+                    if breaker.as_ref().is_some_and(|breaker| !breaker.allow()) {
+                        task_pending.fetch_sub(1, Ordering::SeqCst);
+                        continue;
+                    }
+                    let entity_id = change.get_reference().element_id.clone();
+                    let result = sink.dispatch(change).instrument(span.clone()).await;
+                    if let Some(breaker) = &breaker {
+                        breaker.record(&task_source_id, result.is_ok());
+                    }
+                    match result {
+                        Ok(()) => latency.record(received.elapsed()),
+                        Err(e) => span.in_scope(|| {
+                            error!(
+                                source_id = %task_source_id,
+                                entity_id = %entity_id,
+                                error_class = "dispatch",
+                                "[{task_source_id}] Failed to dispatch change: {e}"
+                            )
+                        }),
+                    }
+                    task_pending.fetch_sub(1, Ordering::SeqCst);
+                }
+            }));
+        }
+
+        (
+            ChangeSender {
+                queues,
+                ordering,
+                next: Arc::new(AtomicUsize::new(0)),
+                pending: pending.clone(),
+            },
+            Dispatcher {
+                source_id,
+                tasks,
+                pending,
+                breaker,
+            },
+        )
+    }
+
+    /// Changes queued but not yet dispatched.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// The circuit breaker shared by the workers, if any.
+    pub fn breaker(&self) -> Option<&CircuitBreaker> {
+        self.breaker.as_deref()
+    }
+
+    /// Wait up to `timeout` for the queued changes to be dispatched, then
+    /// stop the workers. Returns the number of changes dropped.
+    ///
+    /// All [`ChangeSender`]s must have been dropped, or this waits for the
+    /// full timeout.
+    pub async fn drain(mut self, timeout: Duration) -> usize {
+        let queued = self.pending();
+        let deadline = Instant::now() + timeout;
+        let mut finished = true;
+        for task in &mut self.tasks {
+            if tokio::time::timeout_at(deadline, task).await.is_err() {
+                finished = false;
+                break;
+            }
+        }
+        if finished {
+            if queued > 0 {
+                info!(
+                    "[{}] Flushed {queued} buffered change(s) on stop",
+                    self.source_id
+                );
+            }
+            return 0;
+        }
+
+        for task in &self.tasks {
+            task.abort();
+        }
+        let dropped = self.pending();
+        if dropped > 0 {
+            warn!(
+                "[{}] Dropped {dropped} buffered change(s) not dispatched within the {}ms stop drain timeout",
+                self.source_id,
+                timeout.as_millis()
+            );
+        }
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use drasi_core::models::{
+        Element, ElementMetadata, ElementPropertyMap, ElementReference, ElementValue,
+    };
+    use std::sync::Mutex;
+
+    /// Records dispatched element ids, taking `delay` per change.
+    struct SlowSink {
+        delay: Duration,
+        dispatched: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl ChangeSink for SlowSink {
+        async fn dispatch(&self, change: SourceChange) -> Result<()> {
+            tokio::time::sleep(self.delay).await;
+            let id = change.get_reference().element_id.to_string();
+            self.dispatched.lock().unwrap().push(id);
+            Ok(())
+        }
+    }
+
+    fn change(id: &str) -> SourceChange {
+        SourceChange::Insert {
+            element: Element::Node {
+                metadata: ElementMetadata {
+                    reference: ElementReference::new("mqtt", id),
+                    labels: vec![Arc::from("Sensor")].into(),
+                    effective_from: 0,
+                },
+                properties: ElementPropertyMap::new(),
+            },
+        }
+    }
+
+    async fn queue_five(
+        delay: Duration,
+        latency: Arc<LatencyHistogram>,
+    ) -> (Dispatcher, Arc<Mutex<Vec<String>>>) {
+        let dispatched = Arc::new(Mutex::new(Vec::new()));
+        let sink = SlowSink {
+            delay,
+            dispatched: dispatched.clone(),
+        };
+        let (sender, dispatcher) = Dispatcher::spawn("s1", sink, 10, latency);
+        for i in 0..5 {
+            sender.send(change(&format!("c{i}")), Instant::now()).await;
+        }
+        assert_eq!(dispatcher.pending(), 5);
+        (dispatcher, dispatched)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_flushes_pending_changes() {
+        let (dispatcher, dispatched) =
+            queue_five(Duration::from_millis(100), Default::default()).await;
+
+        let dropped = dispatcher.drain(Duration::from_secs(1)).await;
+
+        assert_eq!(dropped, 0);
+        assert_eq!(
+            *dispatched.lock().unwrap(),
+            vec!["c0", "c1", "c2", "c3", "c4"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_timeout_counts_dropped_changes() {
+        let (dispatcher, dispatched) =
+            queue_five(Duration::from_millis(100), Default::default()).await;
+
+        // Room for two dispatches; the third is interrupted.
+        let dropped = dispatcher.drain(Duration::from_millis(250)).await;
+
+        assert_eq!(dropped, 3);
+        assert_eq!(*dispatched.lock().unwrap(), vec!["c0", "c1"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latency_recorded_on_dispatch() {
+        let latency = Arc::new(LatencyHistogram::default());
+        let (dispatcher, _) = queue_five(Duration::from_millis(100), latency.clone()).await;
+        dispatcher.drain(Duration::from_secs(1)).await;
+
+        // Dispatched one after another, 100ms to 500ms after receipt.
+        let counts: Vec<(Option<Duration>, u64)> = latency
+            .snapshot()
+            .into_iter()
+            .filter(|bucket| bucket.count > 0)
+            .map(|bucket| (bucket.le, bucket.count))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (Some(Duration::from_millis(100)), 1),
+                (Some(Duration::from_millis(250)), 1),
+                (Some(Duration::from_millis(500)), 3),
+            ]
+        );
+    }
+
+    /// Records the `seq` property of every dispatched change by id, yielding
+    /// a varying number of times so that workers interleave.
+    struct RecordingSink {
+        dispatched: Arc<Mutex<Vec<(String, i64)>>>,
+    }
+
+    #[async_trait]
+    impl ChangeSink for RecordingSink {
+        async fn dispatch(&self, change: SourceChange) -> Result<()> {
+            let SourceChange::Insert { element } = change else {
+                panic!("Expected Insert");
+            };
+            let Some(ElementValue::Integer(seq)) = element.get_properties().get("seq").cloned()
+            else {
+                panic!("Expected seq");
+            };
+            for _ in 0..seq % 4 {
+                tokio::task::yield_now().await;
+            }
+            let id = element.get_reference().element_id.to_string();
+            self.dispatched.lock().unwrap().push((id, seq));
+            Ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_per_id_ordering_across_workers() {
+        const IDS: usize = 8;
+        const UPDATES: i64 = 200;
+
+        let dispatched = Arc::new(Mutex::new(Vec::new()));
+        let sink = RecordingSink {
+            dispatched: dispatched.clone(),
+        };
+        let (sender, dispatcher) = Dispatcher::spawn_workers(
+            "s1",
+            sink,
+            64,
+            Default::default(),
+            4,
+            DispatchOrdering::PerId,
+            None,
+        );
+
+        // One producer per id, all running at once.
+        let producers: Vec<_> = (0..IDS)
+            .map(|i| {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    for seq in 0..UPDATES {
+                        let mut change = change(&format!("device-{i}"));
+                        if let SourceChange::Insert {
+                            element: Element::Node { properties, .. },
+                        } = &mut change
+                        {
+                            properties.insert("seq", ElementValue::Integer(seq));
+                        }
+                        sender.send(change, Instant::now()).await;
+                    }
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.await.unwrap();
+        }
+        drop(sender);
+        assert_eq!(dispatcher.drain(Duration::from_secs(10)).await, 0);
+
+        let dispatched = dispatched.lock().unwrap();
+        assert_eq!(dispatched.len(), IDS * UPDATES as usize);
+        for i in 0..IDS {
+            let id = format!("device-{i}");
+            let seqs: Vec<i64> = dispatched
+                .iter()
+                .filter(|(dispatched_id, _)| *dispatched_id == id)
+                .map(|(_, seq)| *seq)
+                .collect();
+            assert_eq!(seqs, (0..UPDATES).collect::<Vec<_>>(), "{id}");
+        }
+    }
+
+    /// Fails every dispatch while `failing` is set, counting attempts.
+    struct FlakySink {
+        failing: Arc<std::sync::atomic::AtomicBool>,
+        attempts: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ChangeSink for FlakySink {
+        async fn dispatch(&self, _change: SourceChange) -> Result<()> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            if self.failing.load(Ordering::SeqCst) {
+                anyhow::bail!("pipeline overloaded");
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_breaker_opens_and_recovers() {
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let sink = FlakySink {
+            failing: failing.clone(),
+            attempts: attempts.clone(),
+        };
+        let (sender, dispatcher) = Dispatcher::spawn_workers(
+            "s1",
+            sink,
+            10,
+            Default::default(),
+            1,
+            DispatchOrdering::PerId,
+            Some(CircuitBreaker::new(3, Duration::from_secs(1))),
+        );
+        let send = |id: &str| {
+            let sender = sender.clone();
+            let change = change(id);
+            let dispatcher = &dispatcher;
+            async move {
+                sender.send(change, Instant::now()).await;
+                while dispatcher.pending() > 0 {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        };
+        let breaker = dispatcher.breaker().unwrap();
+
+        for i in 0..3 {
+            assert!(!breaker.is_open());
+            send(&format!("c{i}")).await;
+        }
+        assert!(breaker.is_open());
+
+        // Open: dropped without being attempted.
+        send("c3").await;
+        send("c4").await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(breaker.dropped(), 2);
+
+        // The trial after the cooldown succeeds and closes the circuit.
+        failing.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        send("c5").await;
+        assert!(!breaker.is_open());
+        send("c6").await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 5);
+        assert_eq!(breaker.dropped(), 2);
+    }
+
+    #[test]
+    fn test_failed_trial_reopens_circuit() {
+        let clock = Arc::new(drasi_mqtt_connection::ManualClock::new(0));
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(1)).with_clock(clock.clone());
+        for _ in 0..2 {
+            assert!(breaker.allow());
+            breaker.record("s1", false);
+        }
+        assert!(!breaker.allow());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(breaker.allow());
+        // Only one trial at a time.
+        assert!(!breaker.allow());
+        breaker.record("s1", false);
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_failure_logged_in_message_span() {
+        use drasi_mqtt_connection::trace_capture::EventCapture;
+
+        let capture = EventCapture::new();
+        let _guard = capture.set_default();
+        let sink = FlakySink {
+            failing: Arc::new(std::sync::atomic::AtomicBool::new(true)),
+            attempts: Arc::new(AtomicUsize::new(0)),
+        };
+        let (sender, dispatcher) = Dispatcher::spawn("s1", sink, 10, Default::default());
+        let span = crate::trace::message_span(true, "s1", "sensors/a");
+        sender
+            .send(change("c1"), Instant::now())
+            .instrument(span)
+            .await;
+        dispatcher.drain(Duration::from_secs(1)).await;
+
+        let errors = capture.at(tracing::Level::ERROR);
+        assert_eq!(errors.len(), 1);
+        for (name, value) in [
+            ("source_id", "s1"),
+            ("topic", "sensors/a"),
+            ("entity_id", "c1"),
+            ("error_class", "dispatch"),
+        ] {
+            assert_eq!(errors[0].field(name), Some(value), "{name}");
+        }
+    }
+}
@@ -0,0 +1,320 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Arithmetic and string expressions over payload fields, e.g.
+//! `temp_c * 9 / 5 + 32` or `'room-' + room`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+use serde_json::{Map, Number, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Literal(Value),
+    Field(String),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, Op, Box<Expr>),
+}
+
+/// A parsed expression of number and string literals, top-level payload
+/// fields, `+ - * /`, unary minus and parentheses.
+///
+/// Integer operands stay integers under `+`, `-` and `*`; division and any
+/// float operand give a float. `+` with a string operand concatenates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    source: String,
+    expr: Expr,
+}
+
+impl Expression {
+    /// Evaluate against the fields of a payload. Fails if a referenced field
+    /// is missing or an operand has the wrong type for its operator.
+    pub fn evaluate(&self, fields: &Map<String, Value>) -> anyhow::Result<Value> {
+        evaluate(&self.expr, fields)
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for Expression {
+    type Err = anyhow::Error;
+
+    fn from_str(source: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            next: 0,
+        };
+        let expr = parser.sum()?;
+        if let Some(token) = parser.tokens.get(parser.next) {
+            bail!("Unexpected {token:?} in expression '{source}'");
+        }
+        Ok(Self {
+            source: source.to_string(),
+            expr,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Number),
+    String(String),
+    Ident(String),
+    Op(Op),
+    Open,
+    Close,
+}
+
+fn tokenize(source: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '+' => Token::Op(Op::Add),
+            '-' => Token::Op(Op::Sub),
+            '*' => Token::Op(Op::Mul),
+            '/' => Token::Op(Op::Div),
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '\'' | '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, end)) if end == c => break,
+                        Some((_, ch)) => text.push(ch),
+                        None => bail!("Unterminated string in expression '{source}'"),
+                    }
+                }
+                Token::String(text)
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, ch)) = chars.peek() {
+                    if !(ch.is_ascii_digit() || ch == '.') {
+                        break;
+                    }
+                    end = i + ch.len_utf8();
+                    chars.next();
+                }
+                let text = &source[start..end];
+                let number = match text.parse::<i64>() {
+                    Ok(n) => Number::from(n),
+                    Err(_) => text
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(Number::from_f64)
+                        .ok_or_else(|| anyhow!("Invalid number '{text}' in expression"))?,
+                };
+                Token::Number(number)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = c.to_string();
+                while let Some(&(_, ch)) = chars.peek() {
+                    if !(ch.is_alphanumeric() || ch == '_') {
+                        break;
+                    }
+                    ident.push(ch);
+                    chars.next();
+                }
+                Token::Ident(ident)
+            }
+            c => bail!("Unexpected '{c}' in expression '{source}'"),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek_op(&self, ops: &[Op]) -> Option<Op> {
+        match self.tokens.get(self.next) {
+            Some(Token::Op(op)) if ops.contains(op) => Some(*op),
+            _ => None,
+        }
+    }
+
+    /// `product (('+' | '-') product)*`
+    fn sum(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.product()?;
+        while let Some(op) = self.peek_op(&[Op::Add, Op::Sub]) {
+            self.next += 1;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    /// `unary (('*' | '/') unary)*`
+    fn product(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.unary()?;
+        while let Some(op) = self.peek_op(&[Op::Mul, Op::Div]) {
+            self.next += 1;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    /// `'-' unary | number | string | field | '(' sum ')'`
+    fn unary(&mut self) -> anyhow::Result<Expr> {
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        match token {
+            Some(Token::Op(Op::Sub)) => Ok(Expr::Neg(Box::new(self.unary()?))),
+            Some(Token::Number(n)) => Ok(Expr::Literal(Value::Number(n))),
+            Some(Token::String(s)) => Ok(Expr::Literal(Value::String(s))),
+            Some(Token::Ident(field)) => Ok(Expr::Field(field)),
+            Some(Token::Open) => {
+                let expr = self.sum()?;
+                match self.tokens.get(self.next) {
+                    Some(Token::Close) => {
+                        self.next += 1;
+                        Ok(expr)
+                    }
+                    _ => bail!("Missing ')' in expression"),
+                }
+            }
+            Some(token) => bail!("Unexpected {token:?} in expression"),
+            None => bail!("Expression ends unexpectedly"),
+        }
+    }
+}
+
+fn evaluate(expr: &Expr, fields: &Map<String, Value>) -> anyhow::Result<Value> {
+    match expr {
+        Expr::Literal(value) => Ok(value.clone()),
+        Expr::Field(field) => fields
+            .get(field)
+            .cloned()
+            .ok_or_else(|| anyhow!("Field '{field}' is missing")),
+        Expr::Neg(operand) => match evaluate(operand, fields)? {
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => i
+                    .checked_neg()
+                    .map(Value::from)
+                    .ok_or_else(|| anyhow!("Integer overflow")),
+                None => float(-n.as_f64().unwrap_or(f64::NAN)),
+            },
+            value => bail!("Cannot negate {value}"),
+        },
+        Expr::Binary(left, op, right) => {
+            apply(*op, evaluate(left, fields)?, evaluate(right, fields)?)
+        }
+    }
+}
+
+fn apply(op: Op, left: Value, right: Value) -> anyhow::Result<Value> {
+    let (a, b) = match (&left, &right) {
+        (Value::Number(a), Value::Number(b)) => (a, b),
+        (Value::String(_), _) | (_, Value::String(_)) if op == Op::Add => {
+            return Ok(Value::String(text(left) + &text(right)));
+        }
+        _ => bail!("Cannot apply {op:?} to {left} and {right}"),
+    };
+    if let (Some(a), Some(b), false) = (a.as_i64(), b.as_i64(), op == Op::Div) {
+        let result = match op {
+            Op::Add => a.checked_add(b),
+            Op::Sub => a.checked_sub(b),
+            _ => a.checked_mul(b),
+        };
+        return result
+            .map(Value::from)
+            .ok_or_else(|| anyhow!("Integer overflow"));
+    }
+    let (a, b) = (
+        a.as_f64().unwrap_or(f64::NAN),
+        b.as_f64().unwrap_or(f64::NAN),
+    );
+    float(match op {
+        Op::Add => a + b,
+        Op::Sub => a - b,
+        Op::Mul => a * b,
+        Op::Div => a / b,
+    })
+}
+
+fn float(value: f64) -> anyhow::Result<Value> {
+    Number::from_f64(value)
+        .map(Value::Number)
+        .ok_or_else(|| anyhow!("Result {value} is not a finite number"))
+}
+
+fn text(value: Value) -> String {
+    match value {
+        Value::String(s) => s,
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn eval(expression: &str, fields: Value) -> anyhow::Result<Value> {
+        let Value::Object(fields) = fields else {
+            panic!("fields must be an object");
+        };
+        expression.parse::<Expression>()?.evaluate(&fields)
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let fields = json!({"temp_c": 25, "raw": 2048});
+        assert_eq!(
+            eval("temp_c * 9 / 5 + 32", fields.clone()).unwrap(),
+            json!(77.0)
+        );
+        assert_eq!(
+            eval("-(temp_c - 30) * 2", fields.clone()).unwrap(),
+            json!(10)
+        );
+        assert_eq!(
+            eval("raw / 4095 * 100", fields)
+                .unwrap()
+                .as_f64()
+                .map(f64::round),
+            Some(50.0)
+        );
+        assert_eq!(eval("'room-' + 12", json!({})).unwrap(), json!("room-12"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!("temp_c *".parse::<Expression>().is_err());
+        assert!("(1 + 2".parse::<Expression>().is_err());
+        assert!(eval("temp_c + 1", json!({})).is_err());
+        assert!(eval("on * 2", json!({"on": true})).is_err());
+        assert!(eval("1 / 0", json!({})).is_err());
+    }
+}
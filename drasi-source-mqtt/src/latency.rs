@@ -0,0 +1,84 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Histogram of delivery latency, from receipt of a message to dispatch of
+//! its change.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets, in milliseconds. Latencies above
+/// the last bound fall in a final, unbounded bucket.
+pub const LATENCY_BUCKETS_MS: [u64; 8] = [1, 5, 10, 50, 100, 250, 500, 1000];
+
+/// Message counts by delivery latency.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    counts: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+/// One bucket of a [`LatencyHistogram`] snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyBucket {
+    /// Largest latency counted in this bucket (inclusive); `None` for the
+    /// bucket above the last bound.
+    pub le: Option<Duration>,
+    /// Messages delivered with a latency in this bucket and above the
+    /// previous bucket's bound.
+    pub count: u64,
+}
+
+impl LatencyHistogram {
+    /// Count a message delivered after `latency`.
+    pub fn record(&self, latency: Duration) {
+        let millis = latency.as_millis();
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&le| millis <= le as u128)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current counts of every bucket, shortest latencies first.
+    pub fn snapshot(&self) -> Vec<LatencyBucket> {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| LatencyBucket {
+                le: LATENCY_BUCKETS_MS
+                    .get(i)
+                    .map(|&ms| Duration::from_millis(ms)),
+                count: count.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_bounds_are_inclusive() {
+        let histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_micros(300));
+        histogram.record(Duration::from_millis(5));
+        histogram.record(Duration::from_millis(6));
+        histogram.record(Duration::from_secs(3));
+
+        let counts: Vec<u64> = histogram.snapshot().iter().map(|b| b.count).collect();
+        assert_eq!(counts, vec![1, 1, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(histogram.snapshot()[8].le, None);
+    }
+}
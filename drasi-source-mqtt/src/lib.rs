@@ -33,8 +33,31 @@
 //! ```
 
 pub mod config;
+pub mod connection;
+pub mod dispatch;
+pub mod expression;
+pub mod latency;
 pub mod mapper;
+pub mod rate_limit;
+pub mod recent;
+pub mod schema;
 pub mod source;
+pub mod subscription;
+pub mod sys_metrics;
+pub mod topic_mapping;
+pub mod trace;
 
-pub use config::{MqttSourceConfig, MqttSourceConfigBuilder};
-pub use source::MqttSource;
+pub use config::{
+    AuthErrorPolicy, BrokerEndpoint, CircuitBreakerConfig, Coercion, CredentialsFn, DispatchOrdering, IdNormalize, IdPolicy,
+    MqttSourceConfig, MqttSourceConfigBuilder, NonObjectPolicy, OversizePolicy, Preset, RateLimitAction,
+    ReferenceSource, TopicAction, TopicRule, TopicSubscription,
+};
+pub use connection::ReconnectHook;
+pub use expression::Expression;
+pub use drasi_mqtt_connection::{ClientIdSuffix, ConnectionConfig, MqttConnectionManager};
+pub use latency::LatencyBucket;
+pub use recent::RecentMessage;
+pub use schema::{InferredSchema, JsonType, LabelSchema};
+pub use source::{MessageFilter, MqttSource};
+pub use subscription::SubscriptionInfo;
+pub use trace::MessageOutcome;
@@ -14,122 +14,1536 @@
 
 //! Payload mapping utilities for converting MQTT JSON payloads to [`SourceChange`].
 
-//! Payload mapping utilities for converting MQTT JSON payloads to [`SourceChange`].
- 
- use drasi_core::models::{ElementMetadata, ElementPropertyMap, ElementReference, SourceChange};
- use serde_json::Value;
- use std::sync::Arc;
- 
- use crate::config::OperationMode;
- 
- /// Converts a raw JSON payload into a [`SourceChange`].
- ///
- /// Uses `operation_mode` to determine whether to emit Insert or Update.
- ///
- /// # Arguments
- /// * `payload` - Raw JSON bytes from MQTT.
- /// * `id_field` - Name of the JSON field to use as entity ID.
- /// * `node_label` - Graph node label (e.g. `"SensorReading"`).
- /// * `mode` - Operation mode (Insert or Update).
- pub fn payload_to_source_change(
-     payload: &[u8],
-     id_field: &str,
-     node_label: &str,
-     mode: OperationMode,
- ) -> Result<SourceChange, serde_json::Error> {
-     let json: Value = serde_json::from_slice(payload)?;
- 
-     // Extract entity ID from the configured field, or generate a UUID.
-     let entity_id = json
-         .get(id_field)
-         .and_then(|v| match v {
-             Value::String(s) => Some(s.clone()),
-             Value::Number(n) => Some(n.to_string()),
-             _ => None,
-         })
-         .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
- 
-     // Build property map
-     let mut properties = ElementPropertyMap::new();
-     if let Value::Object(map) = &json {
-         for (key, value) in map {
-             properties.insert(key.as_str(), value.into());
-         }
-     }
- 
-     let metadata = ElementMetadata {
-         reference: ElementReference::new(node_label, &entity_id),
-         labels: vec![Arc::from(node_label)].into(),
-         effective_from: 0,
-     };
- 
-     let element = drasi_core::models::Element::Node {
-         metadata,
-         properties,
-     };
- 
-     let change = match mode {
-         OperationMode::Insert => SourceChange::Insert { element },
-         OperationMode::Update => SourceChange::Update { element },
-     };
- 
-     Ok(change)
- }
- 
- #[cfg(test)]
- mod tests {
-     use super::*;
- 
-     #[test]
-     fn test_insert_mode() {
-         let payload = br#"{"id": "sensor-1", "temp": 25.5}"#;
-         let change = payload_to_source_change(payload, "id", "Sensor", OperationMode::Insert).unwrap();
- 
-         match change {
-             SourceChange::Insert { element } => {
-                 assert_eq!(element.get_reference().element_id.as_ref(), "sensor-1");
-             }
-             _ => panic!("Expected Insert"),
-         }
-     }
- 
-     #[test]
-     fn test_update_mode() {
-         let payload = br#"{"id": "sensor-1", "temp": 30.0}"#;
-         let change = payload_to_source_change(payload, "id", "Sensor", OperationMode::Update).unwrap();
- 
-         match change {
-             SourceChange::Update { element } => {
-                 assert_eq!(element.get_reference().element_id.as_ref(), "sensor-1");
-             }
-             _ => panic!("Expected Update"),
-         }
-     }
- 
-     #[test]
-     fn test_uuid_fallback_when_id_missing() {
-         let payload = br#"{"temp": 25.5}"#;
-         let change = payload_to_source_change(payload, "id", "Sensor", OperationMode::Insert).unwrap();
- 
-         match change {
-             SourceChange::Insert { element } => {
-                 assert!(!element.get_reference().element_id.is_empty());
-             }
-             _ => panic!("Expected Insert"),
-         }
-     }
- 
-     #[test]
-     fn test_numeric_id_field() {
-         let payload = br#"{"device_id": 42, "temp": 20.0}"#;
-         let change = payload_to_source_change(payload, "device_id", "Sensor", OperationMode::Insert).unwrap();
- 
-         assert_eq!(change.get_reference().element_id.as_ref(), "42");
-     }
- 
-     #[test]
-     fn test_invalid_json() {
-         let payload = b"not json";
-         assert!(payload_to_source_change(payload, "id", "Sensor", OperationMode::Insert).is_err());
-     }
- }
+use drasi_core::models::{
+    Element, ElementMetadata, ElementPropertyMap, ElementReference, ElementValue, SourceChange,
+};
+use drasi_mqtt_connection::{LogLimiter, SharedClock};
+use encoding_rs::Encoding;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, warn};
+
+use crate::config::{
+    Coercion, IdNormalize, IdPolicy, NonObjectPolicy, OperationMode, OversizePolicy,
+    PAYLOAD_HASH_ID,
+};
+use crate::expression::Expression;
+
+/// How payload bytes are decoded into node properties. The default parses
+/// UTF-8 JSON as is, without limits.
+#[derive(Debug, Clone, Default)]
+pub struct PayloadFormat {
+    /// Text encoding of the payload; `None` for UTF-8.
+    pub encoding: Option<&'static Encoding>,
+    /// Field of the outer object whose string value is the actual,
+    /// double-encoded JSON payload.
+    pub nested_json_field: Option<String>,
+    /// Parse `NaN`, `Infinity` and `-Infinity` as `null` instead of
+    /// rejecting the payload.
+    pub lenient_json: bool,
+    /// Property count and size limits.
+    pub limits: PropertyLimits,
+    /// Checks of the entity id.
+    pub id_rules: IdRules,
+    /// Type conversions of payload fields.
+    pub coercions: Coercions,
+    /// Values of top-level fields a payload omits, by field name.
+    pub defaults: HashMap<String, Value>,
+    /// Properties derived from the payload fields.
+    pub computed: ComputedProperties,
+    /// Handling of payloads that are not a JSON object.
+    pub non_object: NonObjectPayloads,
+    /// Field whose string or number value is copied to a `correlation_id`
+    /// property.
+    pub correlation_field: Option<String>,
+    /// Generates the ID of a node whose payload has none of the ID fields.
+    pub id_generator: SharedIdGenerator,
+}
+
+/// Generates fallback entity IDs, e.g. ULIDs, snowflake IDs or fixed IDs in
+/// tests.
+pub trait IdGenerator: Send + Sync {
+    fn generate(&self) -> String;
+}
+
+impl<F: Fn() -> String + Send + Sync> IdGenerator for F {
+    fn generate(&self) -> String {
+        self()
+    }
+}
+
+/// Generates random (v4) UUIDs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomUuid;
+
+impl IdGenerator for RandomUuid {
+    fn generate(&self) -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+}
+
+/// An [`IdGenerator`] shared between the source and its mapper. The default
+/// is [`RandomUuid`].
+#[derive(Clone)]
+pub struct SharedIdGenerator(pub Arc<dyn IdGenerator>);
+
+impl Default for SharedIdGenerator {
+    fn default() -> Self {
+        Self(Arc::new(RandomUuid))
+    }
+}
+
+impl fmt::Debug for SharedIdGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedIdGenerator(..)")
+    }
+}
+
+/// Property holding the correlation id copied from `correlation_field`.
+pub const CORRELATION_ID_PROPERTY: &str = "correlation_id";
+
+/// Type conversions of top-level payload fields. The default converts
+/// nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Coercions {
+    /// Target type by field name.
+    pub fields: HashMap<String, Coercion>,
+    /// Values converted to `true` by [`Coercion::Bool`], case-insensitively.
+    pub bool_true_tokens: Vec<String>,
+    /// Values converted to `false` by [`Coercion::Bool`], case-insensitively.
+    pub bool_false_tokens: Vec<String>,
+    /// Where values left unchanged are reported; set by the source. Logged
+    /// at debug level when unset.
+    pub log: Option<CoercionLog>,
+}
+
+impl Coercions {
+    /// Convert the configured fields of `properties`. Values that cannot be
+    /// converted are left unchanged and reported to [`log`](Self::log).
+    fn apply(&self, properties: &mut Map<String, Value>) {
+        for (field, target) in &self.fields {
+            let Some(value) = properties.get_mut(field) else {
+                continue;
+            };
+            match target {
+                Coercion::Bool => match self.bool_value(value) {
+                    Some(b) => *value = Value::Bool(b),
+                    None => match &self.log {
+                        Some(log) => log.unconverted(field, value),
+                        None => debug!("Field '{field}' value {value} is not a known boolean token; left unchanged"),
+                    },
+                },
+            }
+        }
+    }
+
+    fn bool_value(&self, value: &Value) -> Option<bool> {
+        let token = match value {
+            Value::Bool(b) => return Some(*b),
+            Value::String(s) => s.trim().to_string(),
+            Value::Number(n) => n.to_string(),
+            _ => return None,
+        };
+        let matches = |tokens: &[String]| tokens.iter().any(|t| t.eq_ignore_ascii_case(&token));
+        if matches(&self.bool_true_tokens) {
+            Some(true)
+        } else if matches(&self.bool_false_tokens) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// Warnings about values a coercion left unchanged, prefixed with the source
+/// id and logged once per field per suppression window.
+#[derive(Clone)]
+pub struct CoercionLog {
+    source_id: Arc<str>,
+    clock: SharedClock,
+    limiter: Arc<Mutex<LogLimiter>>,
+}
+
+impl CoercionLog {
+    pub fn new(source_id: &str, clock: SharedClock) -> Self {
+        Self {
+            source_id: source_id.into(),
+            clock,
+            limiter: Arc::new(Mutex::new(LogLimiter::default())),
+        }
+    }
+
+    fn unconverted(&self, field: &str, value: &Value) {
+        let source_id = &self.source_id;
+        let now = self.clock.now_instant();
+        let mut limiter = self.limiter.lock().unwrap();
+        for suppressed in limiter.summaries(now) {
+            warn!("[{source_id}] {suppressed}");
+        }
+        if limiter.admit(field, "coercion", now) {
+            warn!(
+                source_id = %source_id,
+                error_class = "coercion",
+                "[{source_id}] Field '{field}' value {value} is not a known boolean token; left unchanged"
+            );
+        }
+    }
+}
+
+impl fmt::Debug for CoercionLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CoercionLog")
+            .field("source_id", &self.source_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Properties computed from payload fields by expressions.
+#[derive(Debug, Clone, Default)]
+pub struct ComputedProperties {
+    /// Expression by property name.
+    pub expressions: Vec<(String, Expression)>,
+    /// Evaluations that failed, leaving their property absent.
+    pub errors: Arc<AtomicU64>,
+}
+
+impl ComputedProperties {
+    /// Add the computed properties to `properties`. Every expression sees
+    /// the payload fields, not other computed properties.
+    fn apply(&self, properties: &mut Map<String, Value>) {
+        let values: Vec<_> = self
+            .expressions
+            .iter()
+            .filter_map(|(name, expression)| match expression.evaluate(properties) {
+                Ok(value) => Some((name.clone(), value)),
+                Err(e) => {
+                    debug!("Computed property '{name}' = {expression} not set: {e}");
+                    self.errors.fetch_add(1, Ordering::Relaxed);
+                    None
+                }
+            })
+            .collect();
+        properties.extend(values);
+    }
+}
+
+/// Property holding a payload value wrapped by [`NonObjectPolicy::Wrap`].
+pub const WRAPPED_VALUE_PROPERTY: &str = "value";
+
+/// Handling of payloads whose top-level value is not a JSON object. The
+/// default skips them.
+#[derive(Debug, Clone, Default)]
+pub struct NonObjectPayloads {
+    pub policy: NonObjectPolicy,
+    /// Payloads skipped by [`NonObjectPolicy::Skip`].
+    pub skipped: Arc<AtomicU64>,
+}
+
+impl NonObjectPayloads {
+    /// Apply the policy to the top-level value of a payload. Fails for a
+    /// skipped payload.
+    fn apply(&self, json: Value) -> anyhow::Result<Value> {
+        let kind = match &json {
+            Value::Object(_) => return Ok(json),
+            Value::Array(_) => "an array",
+            Value::String(_) => "a string",
+            Value::Number(_) => "a number",
+            Value::Bool(_) => "a boolean",
+            Value::Null => "null",
+        };
+        match self.policy {
+            NonObjectPolicy::Skip => {
+                self.skipped.fetch_add(1, Ordering::Relaxed);
+                anyhow::bail!("Payload is {kind}, not a JSON object; skipped")
+            }
+            NonObjectPolicy::Wrap => {
+                let mut map = Map::new();
+                map.insert(WRAPPED_VALUE_PROPERTY.to_string(), json);
+                Ok(Value::Object(map))
+            }
+            NonObjectPolicy::Empty => Ok(json),
+        }
+    }
+}
+
+/// Checks of the entity id. The default keeps every id.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdRules {
+    pub policy: IdPolicy,
+    /// Longest id in bytes.
+    pub max_bytes: Option<usize>,
+    /// Trimming and lowercasing, applied first.
+    pub normalize: IdNormalize,
+}
+
+impl IdRules {
+    /// Normalize `id` and check it against the policy and length limit,
+    /// sanitizing it if the policy allows it.
+    pub(crate) fn apply(&self, id: String) -> anyhow::Result<String> {
+        let id = match (self.normalize.trim, self.normalize.lowercase) {
+            (true, true) => id.trim().to_lowercase(),
+            (true, false) => id.trim().to_string(),
+            (false, true) => id.to_lowercase(),
+            (false, false) => id,
+        };
+        let unsafe_char = |c: char| c.is_control() || c == char::REPLACEMENT_CHARACTER;
+        let mut id = match self.policy {
+            IdPolicy::Reject if id.contains(unsafe_char) => {
+                anyhow::bail!("Entity id {id:?} contains control characters or invalid text")
+            }
+            IdPolicy::Replace if id.contains(unsafe_char) => id.replace(unsafe_char, "_"),
+            _ => id,
+        };
+
+        if let Some(max) = self.max_bytes {
+            if id.len() > max {
+                if self.policy != IdPolicy::Replace {
+                    anyhow::bail!(
+                        "Entity id is {} bytes, more than the limit of {max}",
+                        id.len()
+                    );
+                }
+                let mut end = max;
+                while !id.is_char_boundary(end) {
+                    end -= 1;
+                }
+                id.truncate(end);
+            }
+        }
+        Ok(id)
+    }
+}
+
+/// Limits on the properties of a mapped node. The default has no limits.
+#[derive(Debug, Clone, Default)]
+pub struct PropertyLimits {
+    /// Most properties a node may have.
+    pub max_properties: Option<usize>,
+    /// Largest value in bytes: the length of a string, or of the serialized
+    /// JSON of any other value.
+    pub max_value_bytes: Option<usize>,
+    pub policy: OversizePolicy,
+    /// Appended to truncated strings, within the byte limit.
+    pub truncation_marker: Option<String>,
+}
+
+impl PropertyLimits {
+    /// Check `properties` against the limits, truncating them if the policy
+    /// allows it.
+    ///
+    /// Truncation never touches the properties named in `keep` (id and
+    /// correlation fields): they are kept whole, and the other properties
+    /// fill the remaining room in key order.
+    fn enforce(&self, properties: &mut Map<String, Value>, keep: &[&str]) -> anyhow::Result<()> {
+        if let Some(max) = self.max_properties {
+            if properties.len() > max {
+                if self.policy == OversizePolicy::Reject {
+                    anyhow::bail!(
+                        "Node has {} properties, more than the limit of {max}",
+                        properties.len()
+                    );
+                }
+                let kept = properties
+                    .keys()
+                    .filter(|key| keep.contains(&key.as_str()))
+                    .count();
+                let excess: Vec<String> = properties
+                    .keys()
+                    .filter(|key| !keep.contains(&key.as_str()))
+                    .skip(max.saturating_sub(kept))
+                    .cloned()
+                    .collect();
+                for key in excess {
+                    properties.remove(&key);
+                }
+            }
+        }
+
+        if let Some(max) = self.max_value_bytes {
+            let mut oversized = Vec::new();
+            for (key, value) in properties.iter_mut() {
+                let size = match &*value {
+                    Value::String(s) => s.len(),
+                    other => other.to_string().len(),
+                };
+                if size <= max {
+                    continue;
+                }
+                if self.policy == OversizePolicy::Truncate && keep.contains(&key.as_str()) {
+                    continue;
+                }
+                match (self.policy, value) {
+                    (OversizePolicy::Reject, _) => anyhow::bail!(
+                        "Property '{key}' is {size} bytes, more than the limit of {max}"
+                    ),
+                    (OversizePolicy::Truncate, Value::String(s)) => {
+                        let marker = self.truncation_marker.as_deref().unwrap_or_default();
+                        let mut end = max.saturating_sub(marker.len());
+                        while !s.is_char_boundary(end) {
+                            end -= 1;
+                        }
+                        s.truncate(end);
+                        s.push_str(marker);
+                    }
+                    (OversizePolicy::Truncate, _) => oversized.push(key.clone()),
+                }
+            }
+            for key in oversized {
+                properties.remove(&key);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Converts a raw JSON payload into a [`SourceChange`].
+///
+/// Uses `operation_mode` to determine whether to emit Insert or Update.
+///
+/// # Arguments
+/// * `payload` - Raw JSON bytes from MQTT.
+/// * `source_id` - Source the element reference names, normally the Drasi
+///   source id.
+/// * `id_fields` - JSON fields tried in order for the entity ID; a
+///   [`PAYLOAD_HASH_ID`] entry derives the ID from the payload content.
+/// * `node_label` - Graph node label (e.g. `"SensorReading"`).
+/// * `mode` - Operation mode (Insert or Update).
+/// * `format` - Encoding, nesting, coercions, defaults, correlation field and
+///   property limits of the payload; the entity ID is resolved before the
+///   rest is applied.
+pub fn payload_to_source_change<S: AsRef<str>>(
+    payload: &[u8],
+    source_id: &str,
+    id_fields: &[S],
+    node_label: &str,
+    mode: OperationMode,
+    format: &PayloadFormat,
+) -> anyhow::Result<SourceChange> {
+    let (entity_id, properties) = payload_to_properties(payload, id_fields, None, format)?;
+    let element = node_element(source_id, node_label, &entity_id, &properties);
+    Ok(change_for_mode(element, mode))
+}
+
+/// The change `mode` makes for `element`.
+pub(crate) fn change_for_mode(element: Element, mode: OperationMode) -> SourceChange {
+    match mode {
+        OperationMode::Insert => SourceChange::Insert { element },
+        OperationMode::Update => SourceChange::Update { element },
+    }
+}
+
+/// A payload as it would be ingested, for checking a mapping configuration
+/// against sample messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappingPreview {
+    /// Entity ID of the node.
+    pub id: String,
+    pub labels: Vec<String>,
+    /// Node properties after coercions, defaults and limits.
+    pub properties: Map<String, Value>,
+    pub operation: OperationMode,
+    pub effective_from: u64,
+}
+
+/// Map a payload like [`payload_to_source_change`] does, returning the
+/// resulting node in inspectable form.
+pub fn preview_payload<S: AsRef<str>>(
+    payload: &[u8],
+    id_fields: &[S],
+    node_label: &str,
+    mode: OperationMode,
+    format: &PayloadFormat,
+) -> anyhow::Result<MappingPreview> {
+    let (id, properties) = payload_to_properties(payload, id_fields, None, format)?;
+    Ok(MappingPreview {
+        labels: vec![node_label.to_string()],
+        effective_from: 0,
+        id,
+        properties,
+        operation: mode,
+    })
+}
+
+/// Decode a payload into its entity ID and the properties of its node.
+/// `topic_id`, if given, is used as the entity ID instead of `id_fields`.
+pub(crate) fn payload_to_properties<S: AsRef<str>>(
+    payload: &[u8],
+    id_fields: &[S],
+    topic_id: Option<String>,
+    format: &PayloadFormat,
+) -> anyhow::Result<(String, Map<String, Value>)> {
+    let mut json = parse_payload(payload, format)?;
+    if let Some(field) = &format.nested_json_field {
+        json = decode_nested_json(&json, field)?;
+    }
+    let json = format.non_object.apply(json)?;
+
+    let entity_id = match topic_id {
+        Some(id) => id,
+        None => resolve_entity_id(&json, id_fields, &format.id_generator),
+    };
+    let entity_id = format.id_rules.apply(entity_id)?;
+
+    let Value::Object(mut map) = json else {
+        return Ok((entity_id, Map::new()));
+    };
+    format.coercions.apply(&mut map);
+    for (field, value) in &format.defaults {
+        map.entry(field.as_str()).or_insert_with(|| value.clone());
+    }
+    format.computed.apply(&mut map);
+    if let Some(field) = &format.correlation_field {
+        match map.get(field) {
+            Some(Value::String(id)) => {
+                let id = Value::String(id.clone());
+                map.insert(CORRELATION_ID_PROPERTY.to_string(), id);
+            }
+            Some(Value::Number(n)) => {
+                let id = Value::String(n.to_string());
+                map.insert(CORRELATION_ID_PROPERTY.to_string(), id);
+            }
+            _ => {}
+        }
+    }
+    let mut keep: Vec<&str> = id_fields.iter().map(AsRef::as_ref).collect();
+    if let Some(field) = &format.correlation_field {
+        keep.extend([field.as_str(), CORRELATION_ID_PROPERTY]);
+    }
+    format.limits.enforce(&mut map, &keep)?;
+    Ok((entity_id, map))
+}
+
+/// The node `entity_id` of source `source_id`, labeled `node_label`, with
+/// `properties`.
+pub(crate) fn node_element(
+    source_id: &str,
+    node_label: &str,
+    entity_id: &str,
+    properties: &Map<String, Value>,
+) -> Element {
+    let mut element_properties = ElementPropertyMap::new();
+    for (key, value) in properties {
+        element_properties.insert(key.as_str(), value.into());
+    }
+
+    let metadata = ElementMetadata {
+        reference: ElementReference::new(source_id, entity_id),
+        labels: vec![Arc::from(node_label)].into(),
+        effective_from: 0,
+    };
+
+    Element::Node {
+        metadata,
+        properties: element_properties,
+    }
+}
+
+/// Flags of the MQTT publish a payload arrived in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublishMeta {
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+}
+
+impl From<&rumqttc::Publish> for PublishMeta {
+    fn from(publish: &rumqttc::Publish) -> Self {
+        Self {
+            qos: publish.qos as u8,
+            retain: publish.retain,
+            dup: publish.dup,
+        }
+    }
+}
+
+/// Add `_mqtt_qos`, `_mqtt_retain` and `_mqtt_dup` properties to the node of
+/// `change`, replacing payload fields of the same name.
+pub fn insert_publish_meta(change: &mut SourceChange, meta: &PublishMeta) {
+    let element = match change {
+        SourceChange::Insert { element } | SourceChange::Update { element } => element,
+        _ => return,
+    };
+    if let Element::Node { properties, .. } = element {
+        properties.insert("_mqtt_qos", ElementValue::Integer(meta.qos.into()));
+        properties.insert("_mqtt_retain", ElementValue::Bool(meta.retain));
+        properties.insert("_mqtt_dup", ElementValue::Bool(meta.dup));
+    }
+}
+
+/// Parse a JSON payload, transcoding it to UTF-8 from `format.encoding`
+/// first if set.
+///
+/// Byte sequences invalid in the encoding become U+FFFD replacement
+/// characters. With `format.lenient_json`, `NaN`, `Infinity` and `-Infinity`
+/// outside strings parse as `null`; strict JSON has no such tokens and
+/// rejects them.
+pub fn parse_payload(payload: &[u8], format: &PayloadFormat) -> Result<Value, serde_json::Error> {
+    let decoded;
+    let mut bytes = payload;
+    if let Some(encoding) = format.encoding {
+        decoded = encoding.decode(payload).0;
+        bytes = decoded.as_bytes();
+    }
+    if format.lenient_json {
+        if let Cow::Owned(replaced) = non_finite_to_null(bytes) {
+            return serde_json::from_slice(&replaced);
+        }
+    }
+    serde_json::from_slice(bytes)
+}
+
+/// Replace the `NaN`, `Infinity` and `-Infinity` tokens outside strings
+/// with `null`.
+fn non_finite_to_null(json: &[u8]) -> Cow<'_, [u8]> {
+    const TOKENS: [&[u8]; 3] = [b"-Infinity", b"Infinity", b"NaN"];
+
+    let mut replaced: Option<Vec<u8>> = None;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut i = 0;
+    while i < json.len() {
+        let byte = json[i];
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if byte == b'\\' {
+                escaped = true;
+            } else if byte == b'"' {
+                in_string = false;
+            }
+        } else if byte == b'"' {
+            in_string = true;
+        } else if let Some(token) = TOKENS.iter().find(|token| json[i..].starts_with(token)) {
+            replaced
+                .get_or_insert_with(|| json[..i].to_vec())
+                .extend_from_slice(b"null");
+            i += token.len();
+            continue;
+        }
+        if let Some(replaced) = &mut replaced {
+            replaced.push(byte);
+        }
+        i += 1;
+    }
+    match replaced {
+        Some(replaced) => Cow::Owned(replaced),
+        None => Cow::Borrowed(json),
+    }
+}
+
+/// Parse the string value of `field` in `json` as the actual JSON payload.
+fn decode_nested_json(json: &Value, field: &str) -> anyhow::Result<Value> {
+    match json.get(field) {
+        Some(Value::String(nested)) => serde_json::from_str(nested)
+            .map_err(|e| anyhow::anyhow!("Field '{field}' does not hold valid JSON: {e}")),
+        Some(_) => anyhow::bail!("Field '{field}' is not a JSON-encoded string"),
+        None => anyhow::bail!("Payload has no field '{field}'"),
+    }
+}
+
+/// Extract the entity ID from the first configured field holding a string or
+/// number, or generate one with `generator` if there is none.
+fn resolve_entity_id<S: AsRef<str>>(
+    json: &Value,
+    id_fields: &[S],
+    generator: &SharedIdGenerator,
+) -> String {
+    for id_field in id_fields {
+        let id_field = id_field.as_ref();
+        if id_field == PAYLOAD_HASH_ID {
+            return payload_hash_id(json);
+        }
+
+        match json.get(id_field) {
+            Some(Value::String(s)) => return s.clone(),
+            Some(Value::Number(n)) => return n.to_string(),
+            _ => {}
+        }
+    }
+
+    generator.0.generate()
+}
+
+/// Derive a stable ID from the canonical form of a JSON payload.
+///
+/// Identical payloads (regardless of object key order or whitespace) map to
+/// the same name-based (v5) UUID; any change in content yields a new one.
+pub fn payload_hash_id(json: &Value) -> String {
+    let mut canonical = String::new();
+    write_canonical_json(json, &mut canonical);
+    uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_OID, canonical.as_bytes()).to_string()
+}
+
+/// Serialize `value` as compact JSON with object keys sorted at every level.
+fn write_canonical_json(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical_json(&map[key], out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(item, out);
+            }
+            out.push(']');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_mode() {
+        let payload = br#"{"id": "sensor-1", "temp": 25.5}"#;
+        let change = payload_to_source_change(
+            payload,
+            "src",
+            &["id"],
+            "Sensor",
+            OperationMode::Insert,
+            &PayloadFormat::default(),
+        )
+        .unwrap();
+
+        match change {
+            SourceChange::Insert { element } => {
+                assert_eq!(element.get_reference().element_id.as_ref(), "sensor-1");
+                assert_eq!(element.get_reference().source_id.as_ref(), "src");
+                assert_eq!(element.get_metadata().labels[0].as_ref(), "Sensor");
+            }
+            _ => panic!("Expected Insert"),
+        }
+    }
+
+    #[test]
+    fn test_update_mode() {
+        let payload = br#"{"id": "sensor-1", "temp": 30.0}"#;
+        let change = payload_to_source_change(
+            payload,
+            "src",
+            &["id"],
+            "Sensor",
+            OperationMode::Update,
+            &PayloadFormat::default(),
+        )
+        .unwrap();
+
+        match change {
+            SourceChange::Update { element } => {
+                assert_eq!(element.get_reference().element_id.as_ref(), "sensor-1");
+            }
+            _ => panic!("Expected Update"),
+        }
+    }
+
+    #[test]
+    fn test_uuid_fallback_when_id_missing() {
+        let payload = br#"{"temp": 25.5}"#;
+        let change = payload_to_source_change(
+            payload,
+            "src",
+            &["id"],
+            "Sensor",
+            OperationMode::Insert,
+            &PayloadFormat::default(),
+        )
+        .unwrap();
+
+        match change {
+            SourceChange::Insert { element } => {
+                assert!(!element.get_reference().element_id.is_empty());
+            }
+            _ => panic!("Expected Insert"),
+        }
+    }
+
+    #[test]
+    fn test_numeric_id_field() {
+        let payload = br#"{"device_id": 42, "temp": 20.0}"#;
+        let change = payload_to_source_change(
+            payload,
+            "src",
+            &["device_id"],
+            "Sensor",
+            OperationMode::Insert,
+            &PayloadFormat::default(),
+        )
+        .unwrap();
+
+        assert_eq!(change.get_reference().element_id.as_ref(), "42");
+    }
+
+    #[test]
+    fn test_payload_hash_id_ignores_key_order() {
+        let a = br#"{"device": "d1", "temp": 20.5, "meta": {"fw": "1.2", "site": "a"}}"#;
+        let b = br#"{"meta": {"site": "a", "fw": "1.2"}, "temp": 20.5, "device": "d1"}"#;
+
+        let id_a = payload_to_source_change(
+            a,
+            "src",
+            &[PAYLOAD_HASH_ID],
+            "Sensor",
+            OperationMode::Insert,
+            &PayloadFormat::default(),
+        )
+        .unwrap()
+        .get_reference()
+        .element_id
+        .clone();
+        let id_b = payload_to_source_change(
+            b,
+            "src",
+            &[PAYLOAD_HASH_ID],
+            "Sensor",
+            OperationMode::Insert,
+            &PayloadFormat::default(),
+        )
+        .unwrap()
+        .get_reference()
+        .element_id
+        .clone();
+
+        assert_eq!(id_a, id_b);
+    }
+
+    #[test]
+    fn test_payload_hash_id_changes_with_content() {
+        let a = serde_json::json!({"device": "d1", "temp": 20.5});
+        let b = serde_json::json!({"device": "d1", "temp": 20.6});
+
+        assert_ne!(payload_hash_id(&a), payload_hash_id(&b));
+    }
+
+    const FIRMWARE_ID_FIELDS: [&str; 3] = ["id", "deviceId", "serial"];
+
+    fn resolved_id(payload: &[u8]) -> String {
+        payload_to_source_change(
+            payload,
+            "src",
+            &FIRMWARE_ID_FIELDS,
+            "Sensor",
+            OperationMode::Insert,
+            &PayloadFormat::default(),
+        )
+        .unwrap()
+        .get_reference()
+        .element_id
+        .to_string()
+    }
+
+    #[test]
+    fn test_id_fields_first_present() {
+        assert_eq!(
+            resolved_id(br#"{"id": "a1", "deviceId": "b2", "serial": "c3"}"#),
+            "a1"
+        );
+    }
+
+    #[test]
+    fn test_id_fields_falls_back_to_next() {
+        // Non-scalar values are skipped like missing ones.
+        assert_eq!(resolved_id(br#"{"id": null, "deviceId": "b2"}"#), "b2");
+        assert_eq!(resolved_id(br#"{"serial": 7}"#), "7");
+    }
+
+    #[test]
+    fn test_id_fields_all_missing_generates_uuid() {
+        let id = resolved_id(br#"{"temp": 25.5}"#);
+        assert!(uuid::Uuid::parse_str(&id).is_ok());
+    }
+
+    #[test]
+    fn test_fallback_id_from_custom_generator() {
+        let counter = std::sync::atomic::AtomicUsize::new(0);
+        let format = PayloadFormat {
+            id_generator: SharedIdGenerator(Arc::new(move || {
+                let n = counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                format!("sensor-{n}")
+            })),
+            ..Default::default()
+        };
+
+        for expected in ["sensor-0", "sensor-1"] {
+            let change = payload_to_source_change(
+                br#"{"temp": 25.5}"#,
+                "src",
+                &["id"],
+                "Sensor",
+                OperationMode::Insert,
+                &format,
+            )
+            .unwrap();
+            let SourceChange::Insert { element } = change else {
+                panic!("Expected Insert");
+            };
+            assert_eq!(element.get_reference().element_id.as_ref(), expected);
+        }
+    }
+
+    fn id_under(
+        payload: &[u8],
+        rules: IdRules,
+        encoding: Option<&'static Encoding>,
+    ) -> anyhow::Result<String> {
+        let format = PayloadFormat {
+            encoding,
+            id_rules: rules,
+            ..Default::default()
+        };
+        payload_to_source_change(
+            payload,
+            "src",
+            &["id"],
+            "Sensor",
+            OperationMode::Insert,
+            &format,
+        )
+        .map(|change| change.get_reference().element_id.to_string())
+    }
+
+    fn rules(policy: IdPolicy, max_bytes: Option<usize>) -> IdRules {
+        IdRules {
+            policy,
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_id_normalize_merges_padded_and_cased_ids() {
+        let normalized = IdRules {
+            normalize: IdNormalize {
+                trim: true,
+                lowercase: true,
+            },
+            ..Default::default()
+        };
+        for payload in [
+            br#"{"id": " Sensor-1 "}"#.as_slice(),
+            br#"{"id": "SENSOR-1"}"#,
+            br#"{"id": "sensor-1\t"}"#,
+        ] {
+            assert_eq!(id_under(payload, normalized, None).unwrap(), "sensor-1");
+        }
+        assert_eq!(
+            id_under(br#"{"id": " Sensor-1 "}"#, IdRules::default(), None).unwrap(),
+            " Sensor-1 "
+        );
+    }
+
+    #[test]
+    fn test_id_with_control_characters() {
+        let payload = br#"{"id": "dev\n1\u0007"}"#;
+        assert_eq!(
+            id_under(payload, rules(IdPolicy::Passthrough, None), None).unwrap(),
+            "dev\n1\u{7}"
+        );
+        assert_eq!(
+            id_under(payload, rules(IdPolicy::Replace, None), None).unwrap(),
+            "dev_1_"
+        );
+        assert!(id_under(payload, rules(IdPolicy::Reject, None), None).is_err());
+    }
+
+    #[test]
+    fn test_id_with_invalid_bytes() {
+        // UTF-16LE `{"id":"a?"}` where ? is a lone surrogate, decoded as U+FFFD.
+        let mut payload: Vec<u8> =
+            r#"{"id":"a"#.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        payload.extend_from_slice(&0xD800u16.to_le_bytes());
+        payload.extend(r#""}"#.encode_utf16().flat_map(u16::to_le_bytes));
+        let utf16 = Encoding::for_label(b"utf-16le");
+
+        assert_eq!(
+            id_under(&payload, rules(IdPolicy::Passthrough, None), utf16).unwrap(),
+            "a\u{FFFD}"
+        );
+        assert_eq!(
+            id_under(&payload, rules(IdPolicy::Replace, None), utf16).unwrap(),
+            "a_"
+        );
+        assert!(id_under(&payload, rules(IdPolicy::Reject, None), utf16).is_err());
+    }
+
+    #[test]
+    fn test_overlong_id() {
+        let payload = br#"{"id": "sensor-\u00e9\u00e9"}"#;
+        assert_eq!(
+            id_under(payload, rules(IdPolicy::Passthrough, Some(32)), None).unwrap(),
+            "sensor-éé"
+        );
+        // Cut at a character boundary: "é" is two bytes.
+        assert_eq!(
+            id_under(payload, rules(IdPolicy::Replace, Some(8)), None).unwrap(),
+            "sensor-"
+        );
+        assert!(id_under(payload, rules(IdPolicy::Passthrough, Some(8)), None).is_err());
+        assert!(id_under(payload, rules(IdPolicy::Reject, Some(8)), None).is_err());
+    }
+
+    #[test]
+    fn test_lenient_json_parses_non_finite_numbers_as_null() {
+        let payload = br#"{"id": "s1", "temp": NaN, "limits": [-Infinity, Infinity], "note": "NaN \"Infinity\""}"#;
+
+        assert!(parse_payload(payload, &PayloadFormat::default()).is_err());
+
+        let lenient = PayloadFormat {
+            lenient_json: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_payload(payload, &lenient).unwrap(),
+            serde_json::json!({
+                "id": "s1",
+                "temp": null,
+                "limits": [null, null],
+                "note": "NaN \"Infinity\""
+            })
+        );
+    }
+
+    #[test]
+    fn test_latin1_payload_decoded() {
+        // "Café" with é as the single Latin-1 byte 0xE9, which is not UTF-8.
+        let payload = b"{\"id\": \"s1\", \"room\": \"Caf\xe9\"}";
+        assert!(payload_to_source_change(
+            payload,
+            "src",
+            &["id"],
+            "Sensor",
+            OperationMode::Insert,
+            &PayloadFormat::default(),
+        )
+        .is_err());
+
+        let latin1 = Encoding::for_label(b"latin1");
+        let change = payload_to_source_change(
+            payload,
+            "src",
+            &["id"],
+            "Sensor",
+            OperationMode::Insert,
+            &PayloadFormat {
+                encoding: latin1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        match change {
+            SourceChange::Insert { element } => {
+                assert_eq!(
+                    element.get_properties()["room"],
+                    ElementValue::String(Arc::from("Café"))
+                );
+            }
+            _ => panic!("Expected Insert"),
+        }
+    }
+
+    fn node_properties(
+        payload: &[u8],
+        limits: &PropertyLimits,
+    ) -> anyhow::Result<ElementPropertyMap> {
+        let change = payload_to_source_change(
+            payload,
+            "src",
+            &["id"],
+            "Sensor",
+            OperationMode::Insert,
+            &PayloadFormat {
+                limits: limits.clone(),
+                ..Default::default()
+            },
+        )?;
+        match change {
+            SourceChange::Insert { element } => Ok(element.get_properties().clone()),
+            _ => panic!("Expected Insert"),
+        }
+    }
+
+    #[test]
+    fn test_too_many_properties() {
+        let payload = br#"{"id": "s1", "a": 1, "b": 2, "c": 3}"#;
+        let limits = PropertyLimits {
+            max_properties: Some(3),
+            ..Default::default()
+        };
+
+        let err = node_properties(payload, &limits).unwrap_err();
+        assert!(err.to_string().contains("4 properties"));
+
+        let truncate = PropertyLimits {
+            policy: OversizePolicy::Truncate,
+            ..limits
+        };
+        // The id is always kept; the rest fill up in key order.
+        let properties = node_properties(payload, &truncate).unwrap();
+        assert!(properties.get("id").is_some());
+        assert!(properties.get("a").is_some());
+        assert!(properties.get("b").is_some());
+        assert!(properties.get("c").is_none());
+    }
+
+    #[test]
+    fn test_truncation_keeps_correlation_field() {
+        let format = PayloadFormat {
+            correlation_field: Some("cid".to_string()),
+            limits: PropertyLimits {
+                max_properties: Some(2),
+                max_value_bytes: Some(3),
+                policy: OversizePolicy::Truncate,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let change = payload_to_source_change(
+            br#"{"id": "d1", "cid": "6f1c", "a": 1, "b": 2}"#,
+            "src",
+            &["id"],
+            "Ack",
+            OperationMode::Insert,
+            &format,
+        )
+        .unwrap();
+        let SourceChange::Insert { element } = change else {
+            panic!("Expected Insert");
+        };
+        let properties = element.get_properties();
+
+        // Over both limits, but none of the protected properties is cut.
+        assert_eq!(properties["cid"], ElementValue::String(Arc::from("6f1c")));
+        assert_eq!(
+            properties[CORRELATION_ID_PROPERTY],
+            ElementValue::String(Arc::from("6f1c"))
+        );
+        assert!(properties.get("id").is_some());
+        assert!(properties.get("a").is_none());
+        assert!(properties.get("b").is_none());
+    }
+
+    #[test]
+    fn test_property_value_too_large() {
+        let payload = r#"{"id": "s1", "note": "héllo", "tags": ["x", "y"]}"#.as_bytes();
+        let limits = PropertyLimits {
+            max_value_bytes: Some(3),
+            ..Default::default()
+        };
+
+        let err = node_properties(payload, &limits).unwrap_err();
+        assert!(err.to_string().contains("'note' is 6 bytes"));
+
+        // Strings are cut at a char boundary; other values are dropped.
+        let truncate = PropertyLimits {
+            policy: OversizePolicy::Truncate,
+            ..limits
+        };
+        let properties = node_properties(payload, &truncate).unwrap();
+        assert_eq!(properties["note"], ElementValue::String(Arc::from("hé")));
+        assert!(properties.get("tags").is_none());
+    }
+
+    #[test]
+    fn test_truncation_marker() {
+        // 3-byte chars: the limit of 10 bytes leaves room for "…" (3 bytes)
+        // and two whole chars, not the first byte of a third.
+        let payload = r#"{"id": "s1", "note": "日本語テキスト"}"#.as_bytes();
+        let limits = PropertyLimits {
+            max_value_bytes: Some(10),
+            policy: OversizePolicy::Truncate,
+            truncation_marker: Some("…".to_string()),
+            ..Default::default()
+        };
+
+        let properties = node_properties(payload, &limits).unwrap();
+        assert_eq!(properties["note"], ElementValue::String(Arc::from("日本…")));
+        // Values within the limit are left alone.
+        assert_eq!(properties["id"], ElementValue::String(Arc::from("s1")));
+    }
+
+    #[test]
+    fn test_publish_meta_properties() {
+        let mut publish = rumqttc::Publish::new(
+            "sensors/s1",
+            rumqttc::QoS::ExactlyOnce,
+            r#"{"id": "s1", "_mqtt_dup": "from payload"}"#,
+        );
+        publish.retain = true;
+        publish.dup = true;
+
+        let mut change = payload_to_source_change(
+            &publish.payload,
+            "src",
+            &["id"],
+            "Sensor",
+            OperationMode::Insert,
+            &PayloadFormat::default(),
+        )
+        .unwrap();
+        insert_publish_meta(&mut change, &PublishMeta::from(&publish));
+
+        let SourceChange::Insert { element } = change else {
+            panic!("Expected Insert");
+        };
+        let properties = element.get_properties();
+        assert_eq!(properties["_mqtt_qos"], ElementValue::Integer(2));
+        assert_eq!(properties["_mqtt_retain"], ElementValue::Bool(true));
+        assert_eq!(properties["_mqtt_dup"], ElementValue::Bool(true));
+        assert_eq!(element.get_reference().element_id.as_ref(), "s1");
+    }
+
+    fn nested(payload: &[u8]) -> anyhow::Result<SourceChange> {
+        let format = PayloadFormat {
+            nested_json_field: Some("data".to_string()),
+            ..Default::default()
+        };
+        payload_to_source_change(
+            payload,
+            "src",
+            &["id"],
+            "Sensor",
+            OperationMode::Insert,
+            &format,
+        )
+    }
+
+    #[test]
+    fn test_nested_json_string_decoded() {
+        let change =
+            nested(br#"{"gateway": "gw1", "data": "{\"id\": \"x\", \"temp\": 21}"}"#).unwrap();
+
+        let SourceChange::Insert { element } = change else {
+            panic!("Expected Insert");
+        };
+        assert_eq!(element.get_reference().element_id.as_ref(), "x");
+        let properties = element.get_properties();
+        assert_eq!(properties["temp"], ElementValue::Integer(21));
+        assert!(properties.get("gateway").is_none());
+    }
+
+    #[test]
+    fn test_nested_json_rejects_non_string_or_invalid() {
+        let err = nested(br#"{"data": {"id": "x"}}"#).unwrap_err();
+        assert!(err.to_string().contains("not a JSON-encoded string"));
+
+        let err = nested(br#"{"data": "{\"id\": "}"#).unwrap_err();
+        assert!(err.to_string().contains("does not hold valid JSON"));
+
+        assert!(nested(br#"{"id": "x"}"#).is_err());
+    }
+
+    fn coerced(value: &str) -> ElementValue {
+        let config = crate::config::MqttSourceConfig::builder("s", "localhost", "t/#")
+            .coerce("on", Coercion::Bool)
+            .build();
+        let payload = format!(r#"{{"id": "lamp", "on": {value}}}"#);
+        let change = payload_to_source_change(
+            payload.as_bytes(),
+            "src",
+            &["id"],
+            "Lamp",
+            OperationMode::Insert,
+            &config.payload_format().unwrap(),
+        )
+        .unwrap();
+        match change {
+            SourceChange::Insert { element } => element.get_properties()["on"].clone(),
+            _ => panic!("Expected Insert"),
+        }
+    }
+
+    #[test]
+    fn test_bool_coercion_default_tokens() {
+        for token in [
+            r#""true""#,
+            r#""1""#,
+            r#""on""#,
+            r#""yes""#,
+            r#""ON""#,
+            "1",
+            "true",
+        ] {
+            assert_eq!(coerced(token), ElementValue::Bool(true), "{token}");
+        }
+        for token in [
+            r#""false""#,
+            r#""0""#,
+            r#""off""#,
+            r#""no""#,
+            r#""No""#,
+            "0",
+            "false",
+        ] {
+            assert_eq!(coerced(token), ElementValue::Bool(false), "{token}");
+        }
+    }
+
+    #[test]
+    fn test_bool_coercion_leaves_unknown_token() {
+        assert_eq!(
+            coerced(r#""maybe""#),
+            ElementValue::String(Arc::from("maybe"))
+        );
+        assert_eq!(coerced("2"), ElementValue::Integer(2));
+    }
+
+    #[test]
+    fn test_unknown_bool_token_warned_once_per_window() {
+        use drasi_mqtt_connection::trace_capture::EventCapture;
+        use drasi_mqtt_connection::ManualClock;
+        use std::time::Duration;
+
+        let capture = EventCapture::new();
+        let _guard = capture.set_default();
+        let clock = Arc::new(ManualClock::new(0));
+        let config = crate::config::MqttSourceConfig::builder("s", "localhost", "t/#")
+            .coerce("on", Coercion::Bool)
+            .build();
+        let mut format = config.payload_format().unwrap();
+        format.coercions.log = Some(CoercionLog::new("s", clock.clone()));
+        let map = |payload: &str| {
+            payload_to_source_change(
+                payload.as_bytes(),
+                "src",
+                &["id"],
+                "Lamp",
+                OperationMode::Insert,
+                &format,
+            )
+            .unwrap();
+        };
+
+        map(r#"{"id": "a", "on": "maybe"}"#);
+        map(r#"{"id": "b", "on": "dunno"}"#);
+        clock.advance(Duration::from_secs(61));
+        map(r#"{"id": "c", "on": "later"}"#);
+
+        // The repeat inside the window is only counted, and summarized
+        // once the window is over.
+        let warnings = capture.at(tracing::Level::WARN);
+        let messages: Vec<_> = warnings
+            .iter()
+            .map(|warning| warning.field("message").unwrap())
+            .collect();
+        assert_eq!(messages.len(), 2, "{messages:?}");
+        assert!(
+            messages[0].starts_with("[s] Field 'on' value \"maybe\""),
+            "{messages:?}"
+        );
+        assert_eq!(warnings[0].field("source_id"), Some("s"));
+        assert_eq!(
+            messages[1],
+            "[s] suppressed 1 coercion error(s) on on in the last 60s"
+        );
+    }
+
+    #[test]
+    fn test_computed_properties() {
+        let config = crate::config::MqttSourceConfig::builder("s", "localhost", "t/#")
+            .coerce("on", Coercion::Bool)
+            .computed_property("temp_f", "temp_c * 9 / 5 + 32")
+            .computed_property("state", "'on=' + on")
+            .build();
+        let format = config.payload_format().unwrap();
+        let properties = |payload: &str| match payload_to_source_change(
+            payload.as_bytes(),
+            "src",
+            &["id"],
+            "Sensor",
+            OperationMode::Insert,
+            &format,
+        )
+        .unwrap()
+        {
+            SourceChange::Insert { element } => element.get_properties().clone(),
+            _ => panic!("Expected Insert"),
+        };
+
+        let computed = properties(r#"{"id": "a", "temp_c": 25, "on": "yes"}"#);
+        assert_eq!(computed["temp_f"], ElementValue::from(&Value::from(77.0)));
+        // Computed after coercion.
+        assert_eq!(
+            computed["state"],
+            ElementValue::String(Arc::from("on=true"))
+        );
+        assert_eq!(format.computed.errors.load(Ordering::Relaxed), 0);
+
+        let missing = properties(r#"{"id": "a", "on": "no"}"#);
+        assert!(missing.get("temp_f").is_none());
+        assert_eq!(
+            missing["state"],
+            ElementValue::String(Arc::from("on=false"))
+        );
+        assert_eq!(format.computed.errors.load(Ordering::Relaxed), 1);
+
+        let invalid = crate::config::MqttSourceConfig::builder("s", "localhost", "t/#")
+            .computed_property("x", "temp_c *")
+            .build();
+        assert!(invalid.payload_format().is_err());
+    }
+
+    fn with_defaults(payload: &[u8]) -> ElementPropertyMap {
+        let format = PayloadFormat {
+            defaults: HashMap::from([("temperature".to_string(), Value::from(0))]),
+            ..Default::default()
+        };
+        match payload_to_source_change(
+            payload,
+            "src",
+            &["id"],
+            "Sensor",
+            OperationMode::Insert,
+            &format,
+        ) {
+            Ok(SourceChange::Insert { element }) => element.get_properties().clone(),
+            _ => panic!("Expected Insert"),
+        }
+    }
+
+    #[test]
+    fn test_default_fills_absent_field() {
+        let properties = with_defaults(br#"{"id": "s1", "humidity": 40}"#);
+        assert_eq!(properties["temperature"], ElementValue::Integer(0));
+        assert_eq!(properties["humidity"], ElementValue::Integer(40));
+    }
+
+    #[test]
+    fn test_default_does_not_overwrite_present_field() {
+        let properties = with_defaults(br#"{"id": "s1", "temperature": 21}"#);
+        assert_eq!(properties["temperature"], ElementValue::Integer(21));
+    }
+
+    #[test]
+    fn test_correlation_field_copied_to_property() {
+        let format = PayloadFormat {
+            correlation_field: Some("cid".to_string()),
+            ..Default::default()
+        };
+        let properties = |payload: &[u8]| match payload_to_source_change(
+            payload,
+            "src",
+            &["id"],
+            "Ack",
+            OperationMode::Insert,
+            &format,
+        ) {
+            Ok(SourceChange::Insert { element }) => element.get_properties().clone(),
+            _ => panic!("Expected Insert"),
+        };
+
+        let acked = properties(br#"{"id": "d1", "cid": "6f1c", "status": "ok"}"#);
+        assert_eq!(
+            acked[CORRELATION_ID_PROPERTY],
+            ElementValue::String(Arc::from("6f1c"))
+        );
+        assert_eq!(acked["cid"], ElementValue::String(Arc::from("6f1c")));
+
+        let numeric = properties(br#"{"id": "d1", "cid": 42}"#);
+        assert_eq!(
+            numeric[CORRELATION_ID_PROPERTY],
+            ElementValue::String(Arc::from("42"))
+        );
+
+        let unrelated = properties(br#"{"id": "d1", "status": "ok"}"#);
+        assert!(unrelated.get(CORRELATION_ID_PROPERTY).is_none());
+    }
+
+    #[test]
+    fn test_invalid_json() {
+        let payload = b"not json";
+        assert!(payload_to_source_change(
+            payload,
+            "src",
+            &["id"],
+            "Sensor",
+            OperationMode::Insert,
+            &PayloadFormat::default(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_non_object_policies() {
+        let payloads: [(&[u8], Value); 5] = [
+            (b"[1, 2, 3]", serde_json::json!([1, 2, 3])),
+            (br#""hello""#, serde_json::json!("hello")),
+            (b"21.5", serde_json::json!(21.5)),
+            (b"true", serde_json::json!(true)),
+            (b"null", Value::Null),
+        ];
+        let format = |policy| PayloadFormat {
+            non_object: NonObjectPayloads {
+                policy,
+                ..Default::default()
+            },
+            id_generator: SharedIdGenerator(Arc::new(|| "generated".to_string())),
+            ..Default::default()
+        };
+        let preview = |payload: &[u8], format: &PayloadFormat| {
+            preview_payload(payload, &["id"], "Sensor", OperationMode::Insert, format)
+        };
+
+        let skip = format(NonObjectPolicy::Skip);
+        let wrap = format(NonObjectPolicy::Wrap);
+        let empty = format(NonObjectPolicy::Empty);
+        for (payload, value) in &payloads {
+            let err = preview(payload, &skip).unwrap_err();
+            assert!(err.to_string().contains("not a JSON object"), "{err}");
+
+            let wrapped = preview(payload, &wrap).unwrap();
+            assert_eq!(wrapped.id, "generated");
+            assert_eq!(
+                wrapped.properties,
+                Map::from_iter([(WRAPPED_VALUE_PROPERTY.to_string(), value.clone())])
+            );
+
+            let unwrapped = preview(payload, &empty).unwrap();
+            assert_eq!(unwrapped.id, "generated");
+            assert!(unwrapped.properties.is_empty());
+        }
+        assert_eq!(skip.non_object.skipped.load(Ordering::Relaxed), 5);
+
+        // Objects are mapped the same under every policy.
+        for format in [&skip, &wrap, &empty] {
+            let object = preview(br#"{"id": "s1", "temp": 20}"#, format).unwrap();
+            assert_eq!(object.id, "s1");
+            assert_eq!(object.properties["temp"], serde_json::json!(20));
+        }
+        assert_eq!(skip.non_object.skipped.load(Ordering::Relaxed), 5);
+
+        // The policy applies to the decoded value of a nested JSON payload.
+        let nested = PayloadFormat {
+            nested_json_field: Some("data".to_string()),
+            ..format(NonObjectPolicy::Wrap)
+        };
+        let inner = preview(br#"{"data": "[1, 2]"}"#, &nested).unwrap();
+        assert_eq!(inner.properties["value"], serde_json::json!([1, 2]));
+        let nested_skip = PayloadFormat {
+            nested_json_field: Some("data".to_string()),
+            ..format(NonObjectPolicy::Skip)
+        };
+        assert!(preview(br#"{"data": "42"}"#, &nested_skip).is_err());
+        assert_eq!(nested_skip.non_object.skipped.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Token-bucket rate limiting of received messages.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Admits up to `per_second` messages per second, with bursts of up to one
+/// second's worth of messages.
+///
+/// The bucket starts full and refills continuously.
+#[derive(Debug)]
+pub struct TokenBucket {
+    per_second: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(per_second: u32, now: Instant) -> Self {
+        let per_second = f64::from(per_second.max(1));
+        Self {
+            per_second,
+            tokens: per_second,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.per_second).min(self.per_second);
+        self.last_refill = now;
+    }
+
+    /// Take a token for a message received at `now`, if one is available.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take a token for a message received at `now`, returning how long to
+    /// wait before processing it (zero if a token was available).
+    pub fn reserve(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.per_second)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drop_admits_one_second_of_messages_per_second() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, start);
+        let admitted = (0..100).filter(|_| bucket.try_take(start)).count();
+        assert_eq!(admitted, 10);
+
+        // Half a second refills five tokens.
+        let later = start + Duration::from_millis(500);
+        let admitted = (0..100).filter(|_| bucket.try_take(later)).count();
+        assert_eq!(admitted, 5);
+    }
+
+    #[test]
+    fn test_pause_paces_messages_at_the_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, start);
+
+        // 100 messages at once: the first 10 go through, then one every 100ms.
+        let mut now = start;
+        let mut waits = Vec::new();
+        for _ in 0..100 {
+            let wait = bucket.reserve(now);
+            now += wait;
+            waits.push(wait);
+        }
+        assert!(waits[..10].iter().all(|wait| wait.is_zero()));
+        for wait in &waits[10..] {
+            assert!(
+                wait.abs_diff(Duration::from_millis(100)) < Duration::from_micros(1),
+                "{wait:?}"
+            );
+        }
+        let total = now - start;
+        assert!(total.abs_diff(Duration::from_secs(9)) < Duration::from_millis(1));
+    }
+}
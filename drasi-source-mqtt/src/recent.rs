@@ -0,0 +1,97 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ring buffer of the last raw messages received, for debugging.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// A raw message as received from the broker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub received_at: SystemTime,
+}
+
+/// The last `capacity` messages, oldest first.
+pub struct RecentMessages {
+    capacity: usize,
+    messages: Mutex<VecDeque<RecentMessage>>,
+}
+
+impl RecentMessages {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            messages: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Remember a message received at `received_at`, evicting the oldest
+    /// when full.
+    pub fn push(&self, topic: &str, payload: &[u8], received_at: SystemTime) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(RecentMessage {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            received_at,
+        });
+    }
+
+    /// The buffered messages, oldest first.
+    pub fn snapshot(&self) -> Vec<RecentMessage> {
+        self.messages.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn clear(&self) {
+        self.messages.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_last_messages_in_order() {
+        let recent = RecentMessages::new(2);
+        recent.push("sensors/a", b"1", SystemTime::now());
+        recent.push("sensors/b", b"2", SystemTime::now());
+        recent.push("sensors/c", b"3", SystemTime::now());
+
+        let kept: Vec<(String, Vec<u8>)> = recent
+            .snapshot()
+            .into_iter()
+            .map(|m| (m.topic, m.payload))
+            .collect();
+        assert_eq!(
+            kept,
+            vec![
+                ("sensors/b".to_string(), b"2".to_vec()),
+                ("sensors/c".to_string(), b"3".to_vec()),
+            ]
+        );
+
+        recent.clear();
+        assert!(recent.snapshot().is_empty());
+    }
+}
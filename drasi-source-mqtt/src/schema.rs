@@ -0,0 +1,225 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Property names and types observed in mapped messages, as an aid for
+//! writing queries. Nothing is enforced.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
+
+use serde_json::{Map, Value};
+use tracing::debug;
+
+/// The JSON type of a property value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum JsonType {
+    Null,
+    Bool,
+    Integer,
+    Float,
+    String,
+    Array,
+    Object,
+}
+
+impl JsonType {
+    pub fn of(value: &Value) -> Self {
+        match value {
+            Value::Null => JsonType::Null,
+            Value::Bool(_) => JsonType::Bool,
+            Value::Number(n) if n.is_f64() => JsonType::Float,
+            Value::Number(_) => JsonType::Integer,
+            Value::String(_) => JsonType::String,
+            Value::Array(_) => JsonType::Array,
+            Value::Object(_) => JsonType::Object,
+        }
+    }
+}
+
+impl fmt::Display for JsonType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JsonType::Null => "null",
+            JsonType::Bool => "bool",
+            JsonType::Integer => "integer",
+            JsonType::Float => "float",
+            JsonType::String => "string",
+            JsonType::Array => "array",
+            JsonType::Object => "object",
+        })
+    }
+}
+
+/// What the sampled messages of one label looked like.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LabelSchema {
+    /// Sampled messages mapped to the label. A property seen in fewer
+    /// messages is optional.
+    pub messages: usize,
+    /// The types each property was seen with.
+    pub properties: BTreeMap<String, BTreeSet<JsonType>>,
+}
+
+/// Schemas by node label.
+pub type InferredSchema = BTreeMap<String, LabelSchema>;
+
+/// Infers the schema of the last `capacity` mapped messages.
+#[derive(Debug)]
+pub struct SchemaSampler {
+    capacity: usize,
+    /// Label and property types of each sampled message, oldest first.
+    window: VecDeque<(String, Vec<(String, JsonType)>)>,
+    /// Sampled messages by label.
+    messages: HashMap<String, usize>,
+    /// Sampled messages by label, property and type.
+    types: HashMap<(String, String, JsonType), usize>,
+}
+
+impl SchemaSampler {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            window: VecDeque::new(),
+            messages: HashMap::new(),
+            types: HashMap::new(),
+        }
+    }
+
+    /// Sample a message mapped to a node labeled `label` with `properties`,
+    /// forgetting the oldest sampled message once full. Property types not
+    /// in the sample before are logged at debug level.
+    pub fn observe(&mut self, label: &str, properties: &Map<String, Value>) {
+        if self.window.len() == self.capacity {
+            if let Some((label, types)) = self.window.pop_front() {
+                for (name, json_type) in types {
+                    decrement(&mut self.types, (label.clone(), name, json_type));
+                }
+                decrement(&mut self.messages, label);
+            }
+        }
+
+        let types: Vec<(String, JsonType)> = properties
+            .iter()
+            .map(|(name, value)| (name.clone(), JsonType::of(value)))
+            .collect();
+        for (name, json_type) in &types {
+            let count = self
+                .types
+                .entry((label.to_string(), name.clone(), *json_type))
+                .or_default();
+            if *count == 0 {
+                debug!("Label '{label}' property '{name}' seen as {json_type}");
+            }
+            *count += 1;
+        }
+        *self.messages.entry(label.to_string()).or_default() += 1;
+        self.window.push_back((label.to_string(), types));
+    }
+
+    /// The property names and types of the sampled messages, by label.
+    pub fn schema(&self) -> InferredSchema {
+        let mut schema = InferredSchema::new();
+        for (label, messages) in &self.messages {
+            schema.entry(label.clone()).or_default().messages = *messages;
+        }
+        for (label, name, json_type) in self.types.keys() {
+            schema
+                .entry(label.clone())
+                .or_default()
+                .properties
+                .entry(name.clone())
+                .or_default()
+                .insert(*json_type);
+        }
+        schema
+    }
+}
+
+fn decrement<K: Eq + std::hash::Hash>(counts: &mut HashMap<K, usize>, key: K) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn observe(sampler: &mut SchemaSampler, label: &str, payload: Value) {
+        let Value::Object(properties) = payload else {
+            panic!("payload must be an object");
+        };
+        sampler.observe(label, &properties);
+    }
+
+    #[test]
+    fn test_schema_is_union_of_sampled_fields() {
+        let mut sampler = SchemaSampler::new(10);
+        observe(&mut sampler, "Sensor", json!({"id": "s1", "temp": 21}));
+        observe(
+            &mut sampler,
+            "Sensor",
+            json!({"id": "s2", "temp": 21.5, "on": true}),
+        );
+        observe(
+            &mut sampler,
+            "Sensor",
+            json!({"id": 3, "tags": ["a"], "meta": null}),
+        );
+        observe(&mut sampler, "Door", json!({"id": "d1", "open": false}));
+
+        let schema = sampler.schema();
+        let types = |label: &str, name: &str| -> Vec<JsonType> {
+            schema[label].properties[name].iter().copied().collect()
+        };
+        assert_eq!(schema["Sensor"].messages, 3);
+        assert_eq!(
+            schema["Sensor"].properties.keys().collect::<Vec<_>>(),
+            ["id", "meta", "on", "tags", "temp"]
+        );
+        assert_eq!(types("Sensor", "id"), [JsonType::Integer, JsonType::String]);
+        assert_eq!(
+            types("Sensor", "temp"),
+            [JsonType::Integer, JsonType::Float]
+        );
+        assert_eq!(types("Sensor", "on"), [JsonType::Bool]);
+        assert_eq!(types("Sensor", "tags"), [JsonType::Array]);
+        assert_eq!(types("Sensor", "meta"), [JsonType::Null]);
+        assert_eq!(schema["Door"].messages, 1);
+        assert_eq!(types("Door", "open"), [JsonType::Bool]);
+    }
+
+    #[test]
+    fn test_oldest_messages_leave_the_sample() {
+        let mut sampler = SchemaSampler::new(2);
+        observe(&mut sampler, "Sensor", json!({"temp": "21"}));
+        observe(&mut sampler, "Sensor", json!({"temp": 21}));
+        observe(&mut sampler, "Sensor", json!({"temp": 22, "on": true}));
+
+        let schema = sampler.schema();
+        assert_eq!(schema["Sensor"].messages, 2);
+        assert_eq!(
+            schema["Sensor"].properties["temp"],
+            BTreeSet::from([JsonType::Integer])
+        );
+
+        observe(&mut sampler, "Door", json!({"open": true}));
+        observe(&mut sampler, "Door", json!({"open": false}));
+        assert_eq!(sampler.schema().keys().collect::<Vec<_>>(), ["Door"]);
+    }
+}
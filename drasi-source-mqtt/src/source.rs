@@ -15,47 +15,429 @@
 //! MQTT source implementation of the [`Source`] trait.
 
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
-use log::{error, info, warn};
-use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
+use drasi_core::models::SourceChange;
+use drasi_mqtt_connection::{
+    system_clock, ConnectionConfig, ConnectionEvent, ConnectionEvents, ConnectionHandle,
+    LogLimiter, MqttConnectionManager, SharedClock, TakeoverDetector,
+};
+use rumqttc::{
+    AsyncClient, ClientError, Event, EventLoop, Incoming, MqttOptions, QoS, SubscribeFilter,
+};
 use serde_json::Value;
 use tokio::sync::RwLock;
+use tokio::time::Instant;
+use tracing::{error, info, warn, Instrument};
 
 use drasi_lib::channels::{ComponentStatus, DispatchMode, SubscriptionResponse};
 use drasi_lib::context::SourceRuntimeContext;
 use drasi_lib::sources::base::{SourceBase, SourceBaseParams};
 use drasi_lib::Source;
 
-use crate::config::MqttSourceConfig;
-use crate::mapper;
+use crate::config::{AuthErrorPolicy, CredentialsProvider, MqttSourceConfig, RateLimitAction};
+use crate::connection::{
+    self, ConnectionHealth, ConnectionMonitor, ConnectionTransition, ErrorClass, ReconnectHook,
+};
+use crate::dispatch::{CircuitBreaker, Dispatcher, DISPATCH_BUFFER_CAPACITY};
+use crate::latency::{LatencyBucket, LatencyHistogram};
+use crate::mapper::{self, CoercionLog, PublishMeta};
+use crate::rate_limit::TokenBucket;
+use crate::recent::{RecentMessage, RecentMessages};
+use crate::schema::{InferredSchema, SchemaSampler};
+use crate::subscription::{self, SubscribedLabels, SubscriptionInfo, Subscriptions};
+use crate::sys_metrics::{self, Sampler};
+use crate::topic_mapping::{BlockingRunner, CrossLabelIds, RunOutcome, TopicMapper, TopicMapping};
+use crate::trace::{self, MessageOutcome};
+
+/// Decides from its topic and payload whether a received message is
+/// ingested; messages it returns `false` for are skipped.
+pub type MessageFilter = Arc<dyn Fn(&str, &[u8]) -> bool + Send + Sync>;
 
 /// MQTT source plugin for drasi-lib.
 ///
 /// Subscribes to an MQTT broker topic, parses incoming JSON payloads into
 /// graph-node `SourceChange` events, and dispatches them through the Drasi
 /// pipeline via [`SourceBase`].
+///
+/// While the broker connection has been down for longer than
+/// `degraded_after_ms`, [`status`](Source::status) reports
+/// [`ComponentStatus::Error`]; it returns to `Running` once reconnected.
 pub struct MqttSource {
     base: SourceBase,
     config: MqttSourceConfig,
     /// MQTT client handle (set on start, cleared on stop).
     client: Arc<RwLock<Option<AsyncClient>>>,
+    /// Shared connection used instead of connecting to the configured brokers.
+    shared: Option<Arc<MqttConnectionManager>>,
+    /// Handle on the shared connection (set on start, released on stop).
+    connection: Arc<RwLock<Option<ConnectionHandle>>>,
+    /// Topic filters currently subscribed to (reset on start).
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    /// Node labels subscribed queries match on.
+    subscribed_labels: Arc<Mutex<SubscribedLabels>>,
+    /// Called when the connection is re-established after a disconnect.
+    on_reconnect: Option<ReconnectHook>,
+    /// Skips received messages before they are mapped.
+    message_filter: Option<MessageFilter>,
+    /// Topic filters whose messages are received but not mapped.
+    disabled_mappings: Arc<Mutex<Vec<String>>>,
+    /// When the broker connection went down, if it is down.
+    disconnected_since: Arc<Mutex<Option<Instant>>>,
+    /// Dispatcher of changes queued by the event loop (set on start, drained on stop).
+    dispatcher: Arc<RwLock<Option<Dispatcher>>>,
+    /// Time from receipt of each message to dispatch of its change.
+    latency: Arc<LatencyHistogram>,
+    /// Last raw messages received, when `debug_ring` is set.
+    recent: Option<Arc<RecentMessages>>,
+    /// Property types of the last mapped messages, when `infer_schema` is set.
+    schema: Option<Arc<Mutex<SchemaSampler>>>,
+    /// Messages skipped for exceeding `message_processing_timeout_ms`.
+    timed_out_messages: Arc<AtomicU64>,
+    /// Computed properties whose expression failed.
+    computed_property_errors: Arc<AtomicU64>,
+    /// Payloads skipped for not being a JSON object.
+    non_object_payloads: Arc<AtomicU64>,
+    /// Messages dropped for exceeding `rate_limit`.
+    rate_limited_messages: Arc<AtomicU64>,
+    /// Entity ids seen under an additional label.
+    cross_label_ids: Arc<AtomicU64>,
+    /// Messages dropped because their topic mapping is disabled.
+    disabled_mapping_messages: Arc<AtomicU64>,
+    /// Time source for receipt times and connection health.
+    clock: SharedClock,
 }
 
 impl MqttSource {
     /// Create a new MQTT source from the given config.
     pub fn new(config: MqttSourceConfig) -> Result<Self> {
-        let params = SourceBaseParams::new(&config.id);
+        config.validate()?;
+        // SourceBase rejects dispatch settings it does not support.
+        let params = SourceBaseParams::new(&config.id)
+            .with_dispatch_mode(config.dispatch_mode)
+            .with_auto_start(config.auto_start);
         let base = SourceBase::new(params)?;
 
+        let recent = config
+            .debug_ring
+            .map(|size| Arc::new(RecentMessages::new(size)));
+        let schema = config
+            .infer_schema
+            .then(|| Arc::new(Mutex::new(SchemaSampler::new(config.schema_sample_size))));
+
         Ok(Self {
             base,
             config,
             client: Arc::new(RwLock::new(None)),
+            shared: None,
+            connection: Arc::new(RwLock::new(None)),
+            subscriptions: Arc::new(Mutex::new(Subscriptions::default())),
+            subscribed_labels: Arc::new(Mutex::new(SubscribedLabels::default())),
+            on_reconnect: None,
+            message_filter: None,
+            disabled_mappings: Arc::new(Mutex::new(Vec::new())),
+            disconnected_since: Arc::new(Mutex::new(None)),
+            dispatcher: Arc::new(RwLock::new(None)),
+            latency: Arc::new(LatencyHistogram::default()),
+            recent,
+            schema,
+            timed_out_messages: Arc::new(AtomicU64::new(0)),
+            computed_property_errors: Arc::new(AtomicU64::new(0)),
+            non_object_payloads: Arc::new(AtomicU64::new(0)),
+            rate_limited_messages: Arc::new(AtomicU64::new(0)),
+            cross_label_ids: Arc::new(AtomicU64::new(0)),
+            disabled_mapping_messages: Arc::new(AtomicU64::new(0)),
+            clock: system_clock(),
         })
     }
+
+    /// Create a source receiving over the connection owned by `manager`.
+    ///
+    /// The configured brokers, fallback and credentials are not used; the
+    /// source subscribes to its topics on the shared session and only
+    /// handles publishes matching them.
+    pub fn with_connection(
+        config: MqttSourceConfig,
+        manager: Arc<MqttConnectionManager>,
+    ) -> Result<Self> {
+        let mut source = Self::new(config)?;
+        source.shared = Some(manager);
+        Ok(source)
+    }
+
+    /// Register a callback invoked with the reconnect count (1 for the first
+    /// reconnect) each time the broker connection comes back after a disconnect.
+    pub fn with_on_reconnect(mut self, hook: ReconnectHook) -> Self {
+        self.on_reconnect = Some(hook);
+        self
+    }
+
+    /// Read the time from `clock` instead of the system clock, e.g. to move
+    /// time deterministically in tests.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Register a filter deciding which received messages are ingested, e.g.
+    /// to skip the echo of messages published over a shared connection.
+    pub fn with_message_filter(mut self, filter: MessageFilter) -> Self {
+        self.message_filter = Some(filter);
+        self
+    }
+
+    /// Connect to the primary broker and disconnect again, to find a wrong
+    /// host, port, TLS setup or credentials before the source is started.
+    ///
+    /// Connects as `<client_id>-check`, so checking a running source does
+    /// not take over its session.
+    ///
+    /// Fails with the connection error, the broker's refusal code, or after
+    /// waiting 5 seconds for the broker to answer.
+    pub async fn connect_check(&self) -> Result<()> {
+        let mut broker = self.config.brokers().swap_remove(0);
+        broker.client_id.push_str(CONNECT_CHECK_SUFFIX);
+        let address = format!("{}:{}", broker.host, broker.port);
+        let mqtt_opts = mqtt_options(&broker, self.config.credentials_provider.as_ref())?;
+        let (client, mut eventloop) = AsyncClient::new(mqtt_opts, 10);
+
+        let connack = tokio::time::timeout(CONNECT_CHECK_TIMEOUT, async {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Incoming::ConnAck(_))) => return Ok(()),
+                    Ok(_) => {}
+                    Err(rumqttc::ConnectionError::ConnectionRefused(code)) => {
+                        anyhow::bail!("MQTT broker {address} refused the connection: {code:?}")
+                    }
+                    Err(e) => anyhow::bail!("Cannot connect to MQTT broker {address}: {e}"),
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| {
+            anyhow::bail!(
+                "MQTT broker {address} did not answer within {}s",
+                CONNECT_CHECK_TIMEOUT.as_secs()
+            )
+        });
+
+        if connack.is_ok() {
+            // Send the disconnect; the connection may already be closing.
+            let _ = client.disconnect().await;
+            let _ = tokio::time::timeout(Duration::from_secs(1), eventloop.poll()).await;
+        }
+        connack
+    }
+
+    /// Move a running source to `new_topics`, without restarting it.
+    ///
+    /// Unsubscribes from the current filters missing from `new_topics` and
+    /// subscribes to the new ones (or those whose QoS changed). The change
+    /// lasts until the source is restarted, which subscribes to the
+    /// configured topics again. If a broker call fails, the source keeps
+    /// reporting the subscriptions it had.
+    pub async fn update_subscription(&self, new_topics: Vec<(String, QoS)>) -> Result<()> {
+        let filters: Vec<SubscribeFilter> = new_topics
+            .into_iter()
+            .map(|(path, qos)| SubscribeFilter::new(path, qos))
+            .collect();
+        if filters.is_empty() {
+            anyhow::bail!("At least one topic filter is required");
+        }
+        subscription::validate_filters(&filters)?;
+
+        let own = self.client.read().await.clone();
+        let connection = self.connection.read().await;
+        let client = match (own, connection.as_ref()) {
+            (Some(client), _) => Subscriber::Own(client),
+            (None, Some(handle)) => Subscriber::Shared(handle),
+            (None, None) => anyhow::bail!("[{}] MQTT source is not running", self.config.id),
+        };
+
+        let (removed, added) = self.subscriptions.lock().unwrap().diff(&filters);
+        for path in removed {
+            client
+                .unsubscribe(path)
+                .await
+                .map_err(|e| anyhow::anyhow!("MQTT unsubscribe failed: {e}"))?;
+        }
+        if !added.is_empty() {
+            client
+                .subscribe_many(added.clone())
+                .await
+                .map_err(|e| anyhow::anyhow!("MQTT subscribe failed: {e}"))?;
+        }
+        self.subscriptions.lock().unwrap().update(filters, added);
+        Ok(())
+    }
+
+    /// Stop or resume mapping messages on topics matching `filter`, keeping
+    /// the broker subscription.
+    ///
+    /// Messages of a disabled mapping are dropped before they are mapped and
+    /// counted in [`disabled_mapping_messages`](Self::disabled_mapping_messages).
+    /// The setting survives reconnects and restarts.
+    pub async fn set_mapping_enabled(&self, filter: &str, enabled: bool) {
+        let mut disabled = self.disabled_mappings.lock().unwrap();
+        let position = disabled.iter().position(|f| f == filter);
+        match (enabled, position) {
+            (true, Some(position)) => {
+                disabled.remove(position);
+            }
+            (false, None) => disabled.push(filter.to_string()),
+            _ => return,
+        }
+        let state = if enabled { "enabled" } else { "disabled" };
+        info!("[{}] Mapping of '{filter}' {state}", self.config.id);
+    }
+
+    /// Messages dropped because their topic mapping was disabled, counted
+    /// since the source was created.
+    pub fn disabled_mapping_messages(&self) -> u64 {
+        self.disabled_mapping_messages.load(Ordering::Relaxed)
+    }
+
+    /// The topic filters the source is subscribed to, with the QoS requested
+    /// and the QoS the broker granted. Empty when not running.
+    pub fn subscriptions(&self) -> Vec<SubscriptionInfo> {
+        self.subscriptions.lock().unwrap().info()
+    }
+
+    /// Delivery latency histogram: how long messages took from receipt to
+    /// dispatch of their change, counted since the source was created.
+    pub fn delivery_latency(&self) -> Vec<LatencyBucket> {
+        self.latency.snapshot()
+    }
+
+    /// Messages skipped because mapping them took longer than
+    /// `message_processing_timeout_ms`, counted since the source was created.
+    pub fn timed_out_messages(&self) -> u64 {
+        self.timed_out_messages.load(Ordering::Relaxed)
+    }
+
+    /// Computed properties left absent because their expression failed,
+    /// counted since the source was created.
+    pub fn computed_property_errors(&self) -> u64 {
+        self.computed_property_errors.load(Ordering::Relaxed)
+    }
+
+    /// Payloads skipped for not being a JSON object under
+    /// [`NonObjectPolicy::Skip`](crate::config::NonObjectPolicy::Skip),
+    /// counted since the source was created.
+    pub fn non_object_payloads(&self) -> u64 {
+        self.non_object_payloads.load(Ordering::Relaxed)
+    }
+
+    /// Messages dropped for exceeding `rate_limit` with
+    /// [`RateLimitAction::Drop`], counted since the source was created.
+    pub fn rate_limited_messages(&self) -> u64 {
+        self.rate_limited_messages.load(Ordering::Relaxed)
+    }
+
+    /// Times an entity id was mapped under a label in addition to the ones it
+    /// had, with `warn_on_cross_label_id` set.
+    pub fn cross_label_ids(&self) -> u64 {
+        self.cross_label_ids.load(Ordering::Relaxed)
+    }
+
+    /// The last raw messages received, oldest first. Empty unless `debug_ring`
+    /// is configured; cleared on stop.
+    pub fn recent_messages(&self) -> Vec<RecentMessage> {
+        self.recent
+            .as_ref()
+            .map(|recent| recent.snapshot())
+            .unwrap_or_default()
+    }
+
+    /// The property names and JSON types of the last `schema_sample_size`
+    /// mapped messages, by node label, or `None` unless `infer_schema` is
+    /// set. Kept across restarts.
+    pub fn inferred_schema(&self) -> Option<InferredSchema> {
+        self.schema
+            .as_ref()
+            .map(|schema| schema.lock().unwrap().schema())
+    }
+}
+
+/// Where the event loop reads connection events from.
+enum Events {
+    /// A connection owned by the source.
+    Own(Box<EventLoop>),
+    /// A connection shared through an [`MqttConnectionManager`].
+    Shared(ConnectionEvents),
+}
+
+impl Events {
+    async fn next(&mut self) -> ConnectionEvent {
+        match self {
+            Events::Own(eventloop) => eventloop.poll().await.map_err(Arc::new),
+            Events::Shared(events) => events.next().await,
+        }
+    }
+}
+
+/// Where subscription changes are sent.
+enum Subscriber<'a> {
+    /// The source's own client.
+    Own(AsyncClient),
+    /// A shared connection, which keeps filters other users still need.
+    Shared(&'a ConnectionHandle),
+}
+
+impl Subscriber<'_> {
+    async fn subscribe_many(&self, filters: Vec<SubscribeFilter>) -> Result<(), ClientError> {
+        match self {
+            Subscriber::Own(client) => client.subscribe_many(filters).await,
+            Subscriber::Shared(handle) => handle.subscribe_many(filters).await,
+        }
+    }
+
+    async fn unsubscribe(&self, filter: String) -> Result<(), ClientError> {
+        match self {
+            Subscriber::Own(client) => client.unsubscribe(filter).await,
+            Subscriber::Shared(handle) => handle.unsubscribe(&filter).await,
+        }
+    }
+}
+
+/// How long [`MqttSource::connect_check`] waits for the broker's ConnAck.
+const CONNECT_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Appended to the client id [`MqttSource::connect_check`] connects with.
+const CONNECT_CHECK_SUFFIX: &str = "-check";
+
+/// Client options for `broker`.
+///
+/// Credentials come from `credentials` when given, else from `broker`.
+fn mqtt_options(
+    broker: &ConnectionConfig,
+    credentials: Option<&CredentialsProvider>,
+) -> Result<MqttOptions> {
+    let mut connection = broker.clone();
+    if let Some(provider) = credentials {
+        let (user, pass) = provider.credentials();
+        connection = connection.credentials(user, pass);
+    }
+    connection.build_mqtt_options()
+}
+
+/// Create a client for `broker` and queue the subscription to `filters`,
+/// which is sent once the eventloop connects.
+async fn connect(
+    broker: &ConnectionConfig,
+    filters: &[SubscribeFilter],
+    credentials: Option<&CredentialsProvider>,
+) -> Result<(AsyncClient, EventLoop)> {
+    let (client, eventloop) = AsyncClient::new(mqtt_options(broker, credentials)?, 100);
+    client
+        .subscribe_many(filters.to_vec())
+        .await
+        .map_err(|e| anyhow::anyhow!("MQTT subscribe failed: {e}"))?;
+    Ok((client, eventloop))
 }
 
 #[async_trait]
@@ -70,16 +452,38 @@ impl Source for MqttSource {
 
     fn properties(&self) -> HashMap<String, Value> {
         let mut props = HashMap::new();
-        props.insert("broker_host".into(), Value::String(self.config.broker_host.clone()));
-        props.insert("port".into(), Value::Number(self.config.port.into()));
+        props.insert("broker_host".into(), Value::String(self.config.connection.host.clone()));
+        props.insert("port".into(), Value::Number(self.config.connection.port.into()));
         props.insert("topic".into(), Value::String(self.config.topic.clone()));
+        // The filters subscribed to while running, else the configured ones.
+        let live = self.subscriptions();
+        let topics = if live.is_empty() {
+            self.config
+                .topics
+                .iter()
+                .map(|sub| serde_json::json!({"filter": sub.filter, "qos": sub.qos}))
+                .collect()
+        } else {
+            live.iter()
+                .map(|sub| {
+                    serde_json::json!({
+                        "filter": sub.filter,
+                        "qos": sub.qos as u8,
+                        "granted_qos": sub.granted_qos.map(|qos| qos as u8),
+                    })
+                })
+                .collect()
+        };
+        props.insert("topics".into(), Value::Array(topics));
         props.insert("node_label".into(), Value::String(self.config.node_label.clone()));
-        props.insert("id_field".into(), Value::String(self.config.id_field.clone()));
+        props.insert("id_fields".into(), Value::from(self.config.id_fields.clone()));
+        let disabled = self.disabled_mappings.lock().unwrap().clone();
+        props.insert("disabled_mappings".into(), Value::from(disabled));
         props
     }
 
     fn dispatch_mode(&self) -> DispatchMode {
-        DispatchMode::Channel
+        self.base.dispatch_mode
     }
 
     fn auto_start(&self) -> bool {
@@ -93,38 +497,130 @@ impl Source for MqttSource {
     async fn start(&self) -> Result<()> {
         info!(
             "[{}] Starting MQTT source (broker={}:{}, topic={})",
-            self.config.id, self.config.broker_host, self.config.port, self.config.topic
-        );
-
-        // Build MQTT options.
-        let mut mqtt_opts = MqttOptions::new(
-            &self.config.client_id,
-            &self.config.broker_host,
-            self.config.port,
+            self.config.id,
+            self.config.connection.host,
+            self.config.connection.port,
+            self.config.topic
         );
-        mqtt_opts.set_keep_alive(std::time::Duration::from_secs(30));
-
-        if let (Some(user), Some(pass)) = (&self.config.username, &self.config.password) {
-            mqtt_opts.set_credentials(user, pass);
-        }
 
-        let (client, mut eventloop) = AsyncClient::new(mqtt_opts, 100);
-
-        // Subscribe to the configured topic.
-        client
-            .subscribe(&self.config.topic, QoS::AtLeastOnce)
-            .await
-            .map_err(|e| anyhow::anyhow!("MQTT subscribe failed: {e}"))?;
-
-        // Store client for later disconnect.
-        *self.client.write().await = Some(client);
+        // Connect to the primary broker and subscribe to the configured topics.
+        let brokers = self.config.brokers();
+        let filters = subscription::subscribe_filters(&self.config)?;
+        self.subscriptions.lock().unwrap().reset(filters.clone());
+        let subscriptions = self.subscriptions.clone();
+        let credentials = self.config.credentials_provider.clone();
+        let shared = self.shared.is_some();
+        // Whether the connection was up before the initial SUBSCRIBE, so the
+        // next ConnAck is a reconnect rather than the one it waited for.
+        let mut connected_before = false;
+        let mut events = match &self.shared {
+            Some(manager) => {
+                let handle = manager.acquire().await;
+                let events = handle.events();
+                connected_before = handle.is_connected();
+                let subscribed = handle.subscribe_many(filters.clone()).await;
+                if let Err(e) = subscribed {
+                    handle.release().await;
+                    anyhow::bail!("MQTT subscribe failed: {e}");
+                }
+                *self.connection.write().await = Some(handle);
+                Events::Shared(events)
+            }
+            None => {
+                let (client, eventloop) =
+                    connect(&brokers[0], &filters, credentials.as_ref()).await?;
+                // Store client for later disconnect.
+                *self.client.write().await = Some(client);
+                Events::Own(Box::new(eventloop))
+            }
+        };
+        let client_slot = self.client.clone();
+        let connection_slot = self.connection.clone();
+        let mut active_broker = 0;
+        let max_reconnect_attempts = self.config.max_reconnect_attempts;
 
         // Clone what we need for the spawned task.
-        let base = self.base.clone_shared();
-        let id_field = self.config.id_field.clone();
-        let node_label = self.config.node_label.clone();
+        let (changes, dispatcher) = Dispatcher::spawn_workers(
+            &self.config.id,
+            self.base.clone_shared(),
+            DISPATCH_BUFFER_CAPACITY,
+            self.latency.clone(),
+            self.config.dispatch_workers,
+            self.config.ordering,
+            self.config.dispatch_circuit_breaker.map(|breaker| {
+                CircuitBreaker::new(
+                    breaker.threshold,
+                    Duration::from_millis(breaker.cooldown_ms),
+                )
+                .with_clock(self.clock.clone())
+            }),
+        );
+        *self.dispatcher.write().await = Some(dispatcher);
+        let id_fields: Arc<[String]> = self.config.id_fields.clone().into();
+        let node_label: Arc<str> = self.config.node_label.as_str().into();
         let mode = self.config.mode;
+        let subscribed_labels = self
+            .config
+            .skip_unsubscribed_labels
+            .then(|| self.subscribed_labels.clone());
+        let wanted = move |change: &SourceChange| {
+            subscribed_labels
+                .as_ref()
+                .is_none_or(|labels| labels.lock().unwrap().wants(change))
+        };
+        let mut format = self.config.payload_format()?;
+        format.coercions.log = Some(CoercionLog::new(&self.config.id, self.clock.clone()));
+        format.computed.errors = self.computed_property_errors.clone();
+        format.non_object.skipped = self.non_object_payloads.clone();
+        let format = Arc::new(format);
+        let topic_mapper = Arc::new(Mutex::new(
+            TopicMapper::new(TopicMapping::from_config(&self.config))
+                .with_clock(self.clock.clone())
+                .with_schema(self.schema.clone()),
+        ));
+        let processing_timeout = self
+            .config
+            .message_processing_timeout_ms
+            .map(Duration::from_millis);
+        let blocking_payload_bytes = self.config.blocking_payload_bytes;
+        let blocking = BlockingRunner::default();
+        let timed_out_messages = self.timed_out_messages.clone();
+        let mut rate_limiter = self
+            .config
+            .rate_limit
+            .map(|per_second| TokenBucket::new(per_second, self.clock.now_instant()));
+        let rate_limit_action = self.config.rate_limit_action;
+        let rate_limited_messages = self.rate_limited_messages.clone();
+        let sys_reference_source = self
+            .config
+            .reference_source_for(sys_metrics::BROKER_METRIC_LABEL)
+            .to_string();
+        let recent = self.recent.clone();
+        let message_filter = self.message_filter.clone();
+        let disabled_mappings = self.disabled_mappings.clone();
+        let disabled_mapping_messages = self.disabled_mapping_messages.clone();
+        let capture_mqtt_meta = self.config.capture_mqtt_meta;
+        let mut id_labels = self.config.warn_on_cross_label_id.then(|| {
+            CrossLabelIds::new(
+                Some(self.config.entity_cache_capacity),
+                self.config.entity_cache_ttl_ms.map(Duration::from_millis),
+            )
+        });
+        let cross_label_ids = self.cross_label_ids.clone();
+        let mut sys_sampler = self
+            .config
+            .ingest_sys_metrics
+            .then(|| Sampler::new(Duration::from_millis(self.config.sys_metrics_interval_ms)));
         let source_id = self.config.id.clone();
+        let mut monitor =
+            ConnectionMonitor::new(self.on_reconnect.clone()).with_clock(self.clock.clone());
+        let clock = self.clock.clone();
+        let disconnected_since = self.disconnected_since.clone();
+        *disconnected_since.lock().unwrap() = None;
+        let degraded_after = Duration::from_millis(self.config.degraded_after_ms);
+        let on_auth_error = self.config.on_auth_error;
+        let message_spans = self.config.message_spans;
+        let base = self.base.clone_shared();
 
         // Create shutdown channel.
         let (shutdown_tx, mut shutdown_rx) = tokio::sync::oneshot::channel::<()>();
@@ -133,35 +629,288 @@ impl Source for MqttSource {
         // Spawn the MQTT event loop task.
         let handle = tokio::spawn(async move {
             info!("[{source_id}] MQTT event loop started");
+            let mut degraded = false;
+            let mut error_log = LogLimiter::default();
+            let mut takeovers = TakeoverDetector::default();
             loop {
                 tokio::select! {
                     _ = &mut shutdown_rx => {
                         info!("[{source_id}] Shutdown signal received");
                         break;
                     }
-                    event = eventloop.poll() => {
+                    event = events.next() => {
+                        for suppressed in error_log.summaries(clock.now_instant()) {
+                            warn!("[{source_id}] {suppressed}");
+                        }
+                        let transition = monitor.observe(&event);
+                        if let Some(transition) = transition {
+                            info!("[{source_id}] {}", monitor.describe(transition));
+                        }
+                        match transition {
+                            Some(ConnectionTransition::Connected | ConnectionTransition::Reconnected(_)) => {
+                                takeovers.on_connack(clock.now_instant());
+                            }
+                            Some(ConnectionTransition::Disconnected) => {
+                                if let Some(n) = takeovers.on_disconnect(clock.now_instant()) {
+                                    warn!(
+                                        "[{source_id}] MQTT connection dropped {n} times shortly after connecting; another client is probably using client id '{}' (set a unique client_id or a client_id_suffix)",
+                                        brokers[active_broker].client_id
+                                    );
+                                }
+                            }
+                            None => {}
+                        }
+
+                        if let (Err(_), Events::Own(eventloop), Some(provider)) =
+                            (&event, &mut events, &credentials)
+                        {
+                            if active_broker == 0 {
+                                let (user, pass) = provider.credentials();
+                                eventloop.mqtt_options.set_credentials(user, pass);
+                            }
+                        }
+
+                        if !shared && brokers.len() > 1 && monitor.failed_attempts() >= max_reconnect_attempts {
+                            monitor.reset_failed_attempts();
+                            active_broker = (active_broker + 1) % brokers.len();
+                            let broker = &brokers[active_broker];
+                            warn!(
+                                "[{source_id}] {max_reconnect_attempts} failed connection attempts; switching to broker {}:{}",
+                                broker.host, broker.port
+                            );
+                            // Only the primary broker uses the credentials provider.
+                            let broker_credentials =
+                                credentials.as_ref().filter(|_| active_broker == 0);
+                            let filters = subscriptions.lock().unwrap().current().to_vec();
+                            match connect(broker, &filters, broker_credentials).await {
+                                Ok((client, next_eventloop)) => {
+                                    subscriptions.lock().unwrap().reset(filters);
+                                    connected_before = false;
+                                    events = Events::Own(Box::new(next_eventloop));
+                                    *client_slot.write().await = Some(client);
+                                }
+                                Err(e) => error!("[{source_id}] Failed to switch broker: {e}"),
+                            }
+                        }
+
+                        let down_since = monitor.disconnected_since();
+                        *disconnected_since.lock().unwrap() = down_since;
+                        match connection::health(down_since, clock.now_instant(), degraded_after) {
+                            ConnectionHealth::Degraded if !degraded => {
+                                degraded = true;
+                                warn!(
+                                    "[{source_id}] MQTT connection down for over {}ms; source degraded",
+                                    degraded_after.as_millis()
+                                );
+                            }
+                            ConnectionHealth::Healthy => degraded = false,
+                            ConnectionHealth::Degraded => {}
+                        }
+
                         match event {
-                            Ok(Event::Incoming(Incoming::Publish(publish))) => {
-                                match mapper::payload_to_source_change(
-                                    &publish.payload,
-                                    &id_field,
-                                    &node_label,
-                                    mode,
-                                ) {
-                                    Ok(change) => {
-                                        if let Err(e) = base.dispatch_source_change(change).await {
-                                            error!("[{source_id}] Failed to dispatch change: {e}");
+                            // A shared session also carries other users' topics.
+                            Ok(Event::Incoming(Incoming::Publish(publish)))
+                                if (!shared
+                                    || subscription::matches_any(
+                                        subscriptions.lock().unwrap().current(),
+                                        &publish.topic,
+                                    ))
+                                    && message_filter
+                                        .as_ref()
+                                        .is_none_or(|f| f(&publish.topic, &publish.payload)) =>
+                            {
+                                let received = clock.now_instant();
+                                let span = trace::message_span(message_spans, &source_id, &publish.topic);
+                                let outcome = async {
+                                    if let Some(recent) = &recent {
+                                        recent.push(&publish.topic, &publish.payload, clock.now_system());
+                                    }
+                                    if disabled_mappings
+                                        .lock()
+                                        .unwrap()
+                                        .iter()
+                                        .any(|filter| subscription::matches(&publish.topic, filter))
+                                    {
+                                        disabled_mapping_messages.fetch_add(1, Ordering::Relaxed);
+                                        return MessageOutcome::Disabled;
+                                    }
+                                    // With `Pause`, changes are paced by the dispatcher so
+                                    // the event loop keeps polling (and answering pings).
+                                    let mut not_before = None;
+                                    if let Some(bucket) = &mut rate_limiter {
+                                        match rate_limit_action {
+                                            RateLimitAction::Drop => {
+                                                if !bucket.try_take(received) {
+                                                    rate_limited_messages.fetch_add(1, Ordering::Relaxed);
+                                                    return MessageOutcome::RateLimited;
+                                                }
+                                            }
+                                            RateLimitAction::Pause => {
+                                                let wait = bucket.reserve(received);
+                                                if !wait.is_zero() {
+                                                    not_before = Some(received + wait);
+                                                }
+                                            }
+                                        }
+                                    }
+                                    if let Some(sampler) = sys_sampler
+                                        .as_mut()
+                                        .filter(|_| sys_metrics::is_sys_topic(&publish.topic))
+                                    {
+                                        if sampler.admit(&publish.topic, received) {
+                                            let change = sys_metrics::sys_metric_to_source_change(
+                                                &sys_reference_source,
+                                                &publish.topic,
+                                                &publish.payload,
+                                                mode,
+                                            );
+                                            if wanted(&change) {
+                                                changes.send_paced(change, received, not_before).await;
+                                            }
+                                        }
+                                        return MessageOutcome::SysMetric;
+                                    }
+                                    let offload = processing_timeout
+                                        .filter(|_| publish.payload.len() >= blocking_payload_bytes);
+                                    let mapped = match offload {
+                                        None => topic_mapper.lock().unwrap().map(
+                                            &publish.topic,
+                                            &publish.payload,
+                                            &id_fields[..],
+                                            &node_label,
+                                            mode,
+                                            &format,
+                                        ),
+                                        Some(limit) => {
+                                            // Only parsing runs on the blocking pool; the kept
+                                            // nodes are updated here once it succeeds.
+                                            let mapping = topic_mapper.lock().unwrap().mapping();
+                                            let topic = publish.topic.clone();
+                                            let payload = publish.payload.clone();
+                                            let work_id_fields = id_fields.clone();
+                                            let work_node_label = node_label.clone();
+                                            let work_format = format.clone();
+                                            let work = move || {
+                                                mapping.parse(
+                                                    &topic,
+                                                    &payload,
+                                                    &work_id_fields[..],
+                                                    &work_node_label,
+                                                    &work_format,
+                                                )
+                                            };
+                                            let parsed = match blocking.run(limit, work).await {
+                                                RunOutcome::Done(parsed) => parsed,
+                                                outcome => {
+                                                    timed_out_messages.fetch_add(1, Ordering::Relaxed);
+                                                    if error_log.admit(&publish.topic, "timeout", received) {
+                                                        let reason = match outcome {
+                                                            RunOutcome::Busy => "a mapping that timed out earlier is still running".to_string(),
+                                                            _ => format!("mapping took longer than {}ms", limit.as_millis()),
+                                                        };
+                                                        warn!(
+                                                            source_id = %source_id,
+                                                            topic = %publish.topic,
+                                                            error_class = "timeout",
+                                                            "[{source_id}] Skipped message on topic '{}': {reason}",
+                                                            publish.topic
+                                                        );
+                                                    }
+                                                    return MessageOutcome::TimedOut;
+                                                }
+                                            };
+                                            parsed.map(|parsed| topic_mapper.lock().unwrap().apply(parsed, &node_label, mode))
+                                        }
+                                    };
+                                    if let (Some(id_labels), Ok(Some(change))) = (&mut id_labels, &mapped) {
+                                        if let Some((id, before)) = id_labels.observe(change, received) {
+                                            cross_label_ids.fetch_add(1, Ordering::Relaxed);
+                                            warn!(
+                                                "[{source_id}] Entity id '{id}' on topic '{}' was already mapped under labels {before:?}; each label creates a distinct node",
+                                                publish.topic
+                                            );
                                         }
                                     }
-                                    Err(e) => {
-                                        warn!(
-                                            "[{source_id}] Failed to parse payload on topic '{}': {e}",
-                                            publish.topic
-                                        );
+                                    if let Ok(Some(change)) = &mapped {
+                                        span.record("entity_id", change.get_reference().element_id.as_ref());
                                     }
+                                    match mapped {
+                                        Ok(None) => MessageOutcome::Ignored,
+                                        Ok(Some(change)) if !wanted(&change) => MessageOutcome::Ignored,
+                                        Ok(Some(mut change)) => {
+                                            if capture_mqtt_meta {
+                                                mapper::insert_publish_meta(
+                                                    &mut change,
+                                                    &PublishMeta::from(&publish),
+                                                );
+                                            }
+                                            changes.send_paced(change, received, not_before).await;
+                                            MessageOutcome::Dispatched
+                                        }
+                                        Err(e) => {
+                                            if error_log.admit(&publish.topic, "mapping", received) {
+                                                warn!(
+                                                    source_id = %source_id,
+                                                    topic = %publish.topic,
+                                                    error_class = "mapping",
+                                                    "[{source_id}] Failed to map payload on topic '{}': {e}",
+                                                    publish.topic
+                                                );
+                                            }
+                                            MessageOutcome::MappingFailed
+                                        }
+                                    }
+                                }
+                                .instrument(span.clone())
+                                .await;
+                                span.record("outcome", outcome.as_str());
+                            }
+                            Ok(Event::Incoming(Incoming::SubAck(suback))) => {
+                                let granted_qos = subscriptions.lock().unwrap().acknowledge(&suback);
+                                for (filter, granted) in granted_qos {
+                                    match granted {
+                                        Some(qos) => info!(
+                                            "[{source_id}] Subscribed to '{filter}' (granted {qos:?})"
+                                        ),
+                                        None => error!(
+                                            "[{source_id}] Broker refused subscription to '{filter}'"
+                                        ),
+                                    }
+                                }
+                            }
+                            // A broker that kept no session forgot the subscriptions.
+                            Ok(Event::Incoming(Incoming::ConnAck(ack)))
+                                if std::mem::replace(&mut connected_before, true)
+                                    && !ack.session_present =>
+                            {
+                                let filters = subscriptions.lock().unwrap().resubscribe();
+                                info!(
+                                    "[{source_id}] Broker kept no session; subscribing to {} topic filter(s) again",
+                                    filters.len()
+                                );
+                                // Never wait here: this task drives the eventloop.
+                                let resubscribed = match connection_slot.read().await.as_ref() {
+                                    Some(handle) => handle.resubscribe(filters),
+                                    None => match client_slot.read().await.as_ref() {
+                                        Some(client) => client.try_subscribe_many(filters),
+                                        None => Ok(()),
+                                    },
+                                };
+                                if let Err(e) = resubscribed {
+                                    error!("[{source_id}] MQTT resubscribe failed: {e}");
                                 }
                             }
                             Ok(_) => {} // Ignore other events (ConnAck, PingResp, etc.)
+                            Err(e)
+                                if on_auth_error == AuthErrorPolicy::FailFast
+                                    && connection::classify(&e) == ErrorClass::Auth =>
+                            {
+                                error!(
+                                    "[{source_id}] MQTT broker refused the credentials: {e}; stopping (set on_auth_error to retry instead)"
+                                );
+                                base.set_status(ComponentStatus::Error).await;
+                                break;
+                            }
                             Err(e) => {
                                 error!("[{source_id}] MQTT connection error: {e}");
                                 // rumqttc will auto-reconnect on next poll()
@@ -179,21 +928,44 @@ impl Source for MqttSource {
     }
 
     async fn stop(&self) -> Result<()> {
-        // Disconnect the MQTT client.
+        // Disconnect the MQTT client, or leave the shared connection.
         if let Some(client) = self.client.write().await.take() {
             let _ = client.disconnect().await;
         }
-        self.base.stop_common().await
+        if let Some(handle) = self.connection.write().await.take() {
+            // Releasing unsubscribes from the filters no other user needs.
+            handle.release().await;
+        }
+        self.subscriptions.lock().unwrap().clear();
+        let result = self.base.stop_common().await;
+
+        // The event loop has ended; flush the changes it queued.
+        if let Some(dispatcher) = self.dispatcher.write().await.take() {
+            let timeout = Duration::from_millis(self.config.stop_drain_timeout_ms);
+            dispatcher.drain(timeout).await;
+        }
+        if let Some(recent) = &self.recent {
+            recent.clear();
+        }
+        result
     }
 
     async fn status(&self) -> ComponentStatus {
-        self.base.get_status().await
+        let status = self.base.get_status().await;
+        let down_since = *self.disconnected_since.lock().unwrap();
+        let degraded_after = Duration::from_millis(self.config.degraded_after_ms);
+        let health = connection::health(down_since, self.clock.now_instant(), degraded_after);
+        match (status, health) {
+            (ComponentStatus::Running, ConnectionHealth::Degraded) => ComponentStatus::Error,
+            (status, _) => status,
+        }
     }
 
     async fn subscribe(
         &self,
         settings: drasi_lib::SourceSubscriptionSettings,
     ) -> Result<SubscriptionResponse> {
+        self.subscribed_labels.lock().unwrap().add(&settings.nodes);
         self.base
             .subscribe_with_bootstrap(&settings, "mqtt")
             .await
@@ -210,3 +982,721 @@ impl Source for MqttSource {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BrokerEndpoint;
+    use drasi_mqtt_connection::fake_broker::{
+        closed_port, publish_packet, FakeBroker, CONNECT, DISCONNECT, SUBSCRIBE, UNSUBSCRIBE,
+    };
+
+    #[tokio::test]
+    async fn test_manual_start_source_waits_for_explicit_start() {
+        let config = MqttSourceConfig::builder("gated", "localhost", "sensors/#")
+            .auto_start(false)
+            .build();
+        let source = MqttSource::new(config).unwrap();
+
+        assert!(!source.auto_start());
+        assert_eq!(source.status().await, ComponentStatus::Stopped);
+
+        // The eventloop connects lazily, so start succeeds without a broker.
+        source.start().await.unwrap();
+        assert_eq!(source.status().await, ComponentStatus::Running);
+
+        source.stop().await.unwrap();
+        assert_eq!(source.status().await, ComponentStatus::Stopped);
+    }
+
+    #[tokio::test]
+    async fn test_recent_messages_cleared_on_stop() {
+        let config = MqttSourceConfig::builder("s", "localhost", "sensors/#")
+            .debug_ring(8)
+            .build();
+        let source = MqttSource::new(config).unwrap();
+        source.start().await.unwrap();
+
+        let recent = source.recent.as_ref().unwrap();
+        recent.push("sensors/a", br#"{"id": "a"}"#, source.clock.now_system());
+        recent.push("sensors/b", br#"{"id": "b"}"#, source.clock.now_system());
+        let topics: Vec<String> = source
+            .recent_messages()
+            .into_iter()
+            .map(|m| m.topic)
+            .collect();
+        assert_eq!(topics, vec!["sensors/a", "sensors/b"]);
+
+        source.stop().await.unwrap();
+        assert!(source.recent_messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_switches_to_fallback_after_failed_attempts() {
+        // Nothing listens on the primary's port, so every attempt is refused.
+        let primary_port = closed_port().await;
+        let fallback = FakeBroker::bind().await;
+
+        let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+            .port(primary_port)
+            .fallback_broker(BrokerEndpoint::new("127.0.0.1", fallback.port()))
+            .max_reconnect_attempts(2)
+            .build();
+        let source = MqttSource::new(config).unwrap();
+        source.start().await.unwrap();
+
+        let accepted = fallback.try_accept(Duration::from_secs(5)).await;
+        source.stop().await.unwrap();
+        assert!(accepted.is_some(), "no connection to the fallback broker");
+    }
+
+    #[tokio::test]
+    async fn test_degraded_status_follows_injected_clock() {
+        use drasi_mqtt_connection::ManualClock;
+
+        // Nothing listens on the port, so the connection never comes up.
+        let port = closed_port().await;
+
+        let clock = Arc::new(ManualClock::new(0));
+        let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+            .port(port)
+            .degraded_after(Duration::from_secs(30))
+            .build();
+        let source = MqttSource::new(config).unwrap().with_clock(clock.clone());
+        source.start().await.unwrap();
+        while source.disconnected_since.lock().unwrap().is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Down, but not for long enough as far as the clock is concerned.
+        assert_eq!(source.status().await, ComponentStatus::Running);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(source.status().await, ComponentStatus::Error);
+
+        source.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_credentials_provider_called_on_every_connect() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        let broker = FakeBroker::bind().await;
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = calls.clone();
+        let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+            .port(broker.port())
+            .with_credentials_provider(Arc::new(move || {
+                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                ("device".to_string(), format!("token-{n}"))
+            }))
+            .build();
+        let source = MqttSource::new(config).unwrap();
+        source.start().await.unwrap();
+
+        // Read each CONNECT packet, then drop the connection to force a
+        // reconnect.
+        let mut connects = Vec::new();
+        for _ in 0..2 {
+            connects.push(broker.accept().await.expect(CONNECT).await);
+        }
+        source.stop().await.unwrap();
+
+        assert!(connects[0].contains(b"token-1"));
+        assert!(connects[1].contains(b"token-2"));
+        assert!(calls.load(Ordering::SeqCst) >= 2);
+    }
+
+    #[tokio::test]
+    async fn test_shared_connection_only_handles_own_topics() {
+        let broker = FakeBroker::bind().await;
+        let manager =
+            MqttConnectionManager::new(MqttOptions::new("shared", "127.0.0.1", broker.port()));
+        let config = MqttSourceConfig::builder("s", "unused.invalid", "sensors/#")
+            .debug_ring(8)
+            .build();
+        let source = MqttSource::with_connection(config, manager.clone()).unwrap();
+        source.start().await.unwrap();
+
+        let mut client = broker.accept().await;
+        client.handshake().await;
+
+        // QoS 0 publishes: one for another user of the session, one for us.
+        for topic in ["alerts/a", "sensors/a"] {
+            client.publish(topic, "{}").await;
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while source.recent_messages().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        // Events arrive in order, so "alerts/a" has already been skipped.
+        let topics: Vec<String> = source
+            .recent_messages()
+            .into_iter()
+            .map(|m| m.topic)
+            .collect();
+        assert_eq!(topics, vec!["sensors/a"]);
+
+        source.stop().await.unwrap();
+        client.expect(DISCONNECT).await;
+        assert_eq!(manager.users().await, 0);
+        assert!(manager.filters().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sys_metrics_sampled_per_topic() {
+        let broker = FakeBroker::bind().await;
+        let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+            .port(broker.port())
+            .ingest_sys_metrics(Duration::from_secs(60))
+            .build();
+        let source = MqttSource::new(config).unwrap();
+        source.start().await.unwrap();
+
+        let mut client = broker.accept().await;
+        let subscribe = client.handshake().await;
+        assert!(subscribe.contains(b"$SYS/#"));
+
+        // QoS 0 publishes: three on one topic within the interval, one on another.
+        let messages = [
+            ("$SYS/broker/clients/connected", "5"),
+            ("$SYS/broker/clients/connected", "6"),
+            ("$SYS/broker/heap/current", "40960"),
+            ("$SYS/broker/clients/connected", "7"),
+        ];
+        for (topic, payload) in messages {
+            client.publish(topic, payload).await;
+        }
+
+        let dispatched = || -> u64 { source.delivery_latency().iter().map(|b| b.count).sum() };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while dispatched() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        source.stop().await.unwrap();
+        assert_eq!(dispatched(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_slow_message_skipped_without_stalling_event_loop() {
+        let broker = FakeBroker::bind().await;
+        // Payloads without an id take the generator's time to map.
+        let slow_ids = Arc::new(|| {
+            std::thread::sleep(Duration::from_secs(1));
+            "slow".to_string()
+        });
+        let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+            .port(broker.port())
+            .with_id_generator(slow_ids)
+            .message_processing_timeout(Duration::from_millis(50))
+            .blocking_payload_bytes(0)
+            .build();
+        let source = MqttSource::new(config).unwrap();
+        source.start().await.unwrap();
+
+        let mut client = broker.accept().await;
+        let subscribe = client.handshake().await;
+
+        // A QoS 0 publish without an id, then the SubAck.
+        client.publish("sensors/a", r#"{"temp": 1}"#).await;
+        client.suback(subscribe.packet_id(), 0x01).await;
+
+        // The loop moves on to the SubAck long before the mapping finishes.
+        tokio::time::timeout(Duration::from_millis(500), async {
+            while source.subscriptions()[0].granted_qos.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(source.timed_out_messages(), 1);
+
+        source.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_drops_excess_messages() {
+        use drasi_mqtt_connection::ManualClock;
+
+        let broker = FakeBroker::bind().await;
+        let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+            .port(broker.port())
+            .rate_limit(10, RateLimitAction::Drop)
+            .build();
+        // The clock stands still, so the bucket never refills.
+        let source = MqttSource::new(config)
+            .unwrap()
+            .with_clock(Arc::new(ManualClock::new(0)));
+        source.start().await.unwrap();
+
+        let mut client = broker.accept().await;
+        client.handshake().await;
+
+        let publishes: Vec<u8> = (0..100)
+            .flat_map(|i| publish_packet("sensors/a", format!(r#"{{"id": "d{i:02}"}}"#)))
+            .collect();
+        client.write(&publishes).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while source.rate_limited_messages() < 90 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        source.stop().await.unwrap();
+        let dispatched: u64 = source.delivery_latency().iter().map(|b| b.count).sum();
+        assert_eq!(dispatched, 10);
+        assert_eq!(source.rate_limited_messages(), 90);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_pause_paces_dispatch() {
+        use drasi_mqtt_connection::fake_broker::{publish_qos1_packet, PUBACK};
+
+        let broker = FakeBroker::bind().await;
+        let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+            .port(broker.port())
+            .rate_limit(10, RateLimitAction::Pause)
+            .build();
+        let source = MqttSource::new(config).unwrap();
+        source.start().await.unwrap();
+
+        let mut client = broker.accept().await;
+        client.handshake().await;
+
+        // 15 messages at 10 per second, the last at QoS 1 with packet id 1.
+        let payload = |i: usize| format!(r#"{{"id": "d{i:02}"}}"#);
+        let mut publishes: Vec<u8> = (0..14)
+            .flat_map(|i| publish_packet("sensors/a", payload(i)))
+            .collect();
+        publishes.extend(publish_qos1_packet("sensors/a", 1, payload(14)));
+        client.write(&publishes).await;
+
+        // Pacing the last five changes takes 500ms, but the event loop keeps
+        // reading meanwhile and acknowledges the last message right away.
+        let ack = tokio::time::timeout(Duration::from_millis(300), client.read_packet())
+            .await
+            .expect("PUBACK held up by pacing");
+        assert_eq!((ack.kind(), ack.packet_id()), (PUBACK, 1));
+        let dispatched = || -> u64 { source.delivery_latency().iter().map(|b| b.count).sum() };
+        assert!(dispatched() < 15);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while dispatched() < 15 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(source.rate_limited_messages(), 0);
+        source.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disabled_mapping_drops_messages() {
+        let broker = FakeBroker::bind().await;
+        let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+            .port(broker.port())
+            .build();
+        let source = MqttSource::new(config).unwrap();
+        source.start().await.unwrap();
+
+        let mut client = broker.accept().await;
+        client.handshake().await;
+
+        let payload = |id: &str| format!(r#"{{"id": "{id}"}}"#);
+        let dispatched = |source: &MqttSource| -> u64 {
+            source.delivery_latency().iter().map(|b| b.count).sum()
+        };
+
+        source.set_mapping_enabled("sensors/a/#", false).await;
+        assert_eq!(
+            source.properties()["disabled_mappings"],
+            serde_json::json!(["sensors/a/#"])
+        );
+        for (topic, id) in [
+            ("sensors/a/1", "a1"),
+            ("sensors/b/1", "b1"),
+            ("sensors/a/2", "a2"),
+        ] {
+            client.publish(topic, payload(id)).await;
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while dispatched(&source) < 1 || source.disabled_mapping_messages() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        source.set_mapping_enabled("sensors/a/#", true).await;
+        assert_eq!(
+            source.properties()["disabled_mappings"],
+            serde_json::json!([])
+        );
+        client.publish("sensors/a/3", payload("a3")).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while dispatched(&source) < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        source.stop().await.unwrap();
+        assert_eq!(dispatched(&source), 2);
+        assert_eq!(source.disabled_mapping_messages(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_update_subscription() {
+        let broker = FakeBroker::bind().await;
+        let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+            .port(broker.port())
+            .build();
+        let source = MqttSource::new(config).unwrap();
+        assert!(source
+            .update_subscription(vec![("alerts/#".into(), QoS::AtLeastOnce)])
+            .await
+            .is_err());
+        source.start().await.unwrap();
+
+        let mut client = broker.accept().await;
+        client.handshake().await;
+
+        assert!(source
+            .update_subscription(vec![("alerts/#/x".into(), QoS::AtLeastOnce)])
+            .await
+            .is_err());
+        source
+            .update_subscription(vec![("alerts/#".into(), QoS::AtLeastOnce)])
+            .await
+            .unwrap();
+
+        let unsubscribe = client.expect(UNSUBSCRIBE).await;
+        assert!(unsubscribe.contains(b"sensors/#"), "{unsubscribe:?}");
+        let subscribe = client.expect(SUBSCRIBE).await;
+        assert!(subscribe.contains(b"alerts/#"), "{subscribe:?}");
+
+        source.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_reflect_subscribe_and_update() {
+        async fn granted(source: &MqttSource, filter: &str) -> Option<QoS> {
+            for _ in 0..500 {
+                let info = source.subscriptions();
+                if let Some(qos) = info
+                    .iter()
+                    .find(|s| s.filter == filter)
+                    .and_then(|s| s.granted_qos)
+                {
+                    return Some(qos);
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            None
+        }
+
+        let broker = FakeBroker::bind().await;
+        let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+            .port(broker.port())
+            .build();
+        let source = MqttSource::new(config).unwrap();
+        assert!(source.subscriptions().is_empty());
+        source.start().await.unwrap();
+
+        let mut client = broker.accept().await;
+        let subscribe = client.handshake().await;
+        assert_eq!(
+            source.subscriptions(),
+            vec![SubscriptionInfo {
+                filter: "sensors/#".into(),
+                qos: QoS::AtLeastOnce,
+                granted_qos: None,
+            }]
+        );
+        client.suback(subscribe.packet_id(), 0x00).await;
+        assert_eq!(granted(&source, "sensors/#").await, Some(QoS::AtMostOnce));
+
+        source
+            .update_subscription(vec![("alerts/#".into(), QoS::ExactlyOnce)])
+            .await
+            .unwrap();
+        let info = source.subscriptions();
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0].filter, "alerts/#");
+        assert_eq!(info[0].qos, QoS::ExactlyOnce);
+        assert_eq!(info[0].granted_qos, None);
+
+        client.expect(UNSUBSCRIBE).await;
+        let subscribe = client.expect(SUBSCRIBE).await;
+        client.suback(subscribe.packet_id(), 0x02).await;
+        assert_eq!(granted(&source, "alerts/#").await, Some(QoS::ExactlyOnce));
+
+        source.stop().await.unwrap();
+        assert!(source.subscriptions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resubscribes_when_broker_kept_no_session() {
+        let broker = FakeBroker::bind().await;
+        let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+            .port(broker.port())
+            .build();
+        let source = MqttSource::new(config).unwrap();
+        source.start().await.unwrap();
+
+        let mut client = broker.accept().await;
+        let subscribe = client.handshake().await;
+        client.suback(subscribe.packet_id(), 0x01).await;
+        source
+            .update_subscription(vec![("alerts/#".into(), QoS::AtLeastOnce)])
+            .await
+            .unwrap();
+        client.expect(UNSUBSCRIBE).await;
+        client.expect(SUBSCRIBE).await;
+        drop(client);
+
+        // The broker comes back without the session.
+        let mut client = broker.accept().await;
+        client.expect(CONNECT).await;
+        client.connack(0).await;
+        let resubscribe = client.expect(SUBSCRIBE).await;
+        assert!(resubscribe.contains(b"alerts/#"), "{resubscribe:?}");
+        assert!(!resubscribe.contains(b"sensors/#"), "{resubscribe:?}");
+
+        let topics = source.properties()["topics"].clone();
+        assert_eq!(
+            topics,
+            serde_json::json!([{"filter": "alerts/#", "qos": 1, "granted_qos": null}])
+        );
+        source.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shared_connection_resubscribes_without_session() {
+        let broker = FakeBroker::bind().await;
+        let manager =
+            MqttConnectionManager::new(MqttOptions::new("shared", "127.0.0.1", broker.port()));
+        let config = MqttSourceConfig::builder("s", "unused.invalid", "sensors/#").build();
+        let source = MqttSource::with_connection(config, manager.clone()).unwrap();
+        source.start().await.unwrap();
+
+        let mut client = broker.accept().await;
+        client.handshake().await;
+        drop(client);
+
+        let mut client = broker.accept().await;
+        client.expect(CONNECT).await;
+        client.connack(0).await;
+        assert!(client.expect(SUBSCRIBE).await.contains(b"sensors/#"));
+
+        source.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_check() {
+        /// Accept one connection and answer its CONNECT with `return_code`.
+        async fn broker(return_code: u8) -> u16 {
+            let broker = FakeBroker::bind().await;
+            let port = broker.port();
+            tokio::spawn(async move {
+                let mut client = broker.accept().await;
+                let connect = client.expect(CONNECT).await;
+                assert!(connect.contains(b"drasi-source-s-check"), "{connect:?}");
+                client.connack(return_code).await;
+                client.next_packet().await;
+            });
+            port
+        }
+        let source = |port| {
+            let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+                .port(port)
+                .build();
+            MqttSource::new(config).unwrap()
+        };
+
+        source(broker(0).await).connect_check().await.unwrap();
+
+        // 5: not authorized.
+        let err = source(broker(5).await).connect_check().await.unwrap_err();
+        assert!(err.to_string().contains("refused the connection"), "{err}");
+
+        let port = closed_port().await;
+        let err = source(port).connect_check().await.unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with(&format!("Cannot connect to MQTT broker 127.0.0.1:{port}")),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_errors_fail_fast() {
+        /// Run a source against a broker that answers each CONNECT with
+        /// `return_code`, or drops the connection if `None`. Returns the
+        /// source's status and how many times it connected, up to 3.
+        async fn run(policy: AuthErrorPolicy, return_code: Option<u8>) -> (ComponentStatus, usize) {
+            let broker = FakeBroker::bind().await;
+            let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+                .port(broker.port())
+                .on_auth_error(policy)
+                .build();
+            let source = MqttSource::new(config).unwrap();
+            source.start().await.unwrap();
+
+            let mut connections = 0;
+            while connections < 3 {
+                let Some(mut client) = broker.try_accept(Duration::from_millis(500)).await else {
+                    break;
+                };
+                connections += 1;
+                client.expect(CONNECT).await;
+                if let Some(code) = return_code {
+                    client.connack(code).await;
+                }
+            }
+            let status = source.status().await;
+            source.stop().await.unwrap();
+            (status, connections)
+        }
+
+        // 4: bad user name or password.
+        assert_eq!(
+            run(AuthErrorPolicy::FailFast, Some(4)).await,
+            (ComponentStatus::Error, 1)
+        );
+        assert_eq!(
+            run(AuthErrorPolicy::Retry, Some(4)).await,
+            (ComponentStatus::Running, 3)
+        );
+        // A dropped connection is retried either way.
+        assert_eq!(
+            run(AuthErrorPolicy::FailFast, None).await,
+            (ComponentStatus::Running, 3)
+        );
+    }
+
+    #[test]
+    fn test_auto_start_by_default() {
+        let config = MqttSourceConfig::builder("s", "localhost", "sensors/#").build();
+        assert!(MqttSource::new(config).unwrap().auto_start());
+    }
+
+    #[test]
+    fn test_dispatch_mode_from_config() {
+        let config = MqttSourceConfig::builder("s", "localhost", "sensors/#").build();
+        let source = MqttSource::new(config).unwrap();
+        assert_eq!(source.dispatch_mode(), DispatchMode::Channel);
+
+        let config = MqttSourceConfig::builder("s", "localhost", "sensors/#")
+            .dispatch_mode(DispatchMode::Broadcast)
+            .build();
+        let source = MqttSource::new(config).unwrap();
+        assert_eq!(source.dispatch_mode(), DispatchMode::Broadcast);
+        assert_eq!(source.base.dispatch_mode, DispatchMode::Broadcast);
+    }
+
+    fn settings(query_id: &str, nodes: &[&str]) -> drasi_lib::SourceSubscriptionSettings {
+        drasi_lib::SourceSubscriptionSettings {
+            source_id: "s".to_string(),
+            enable_bootstrap: false,
+            query_id: query_id.to_string(),
+            nodes: nodes.iter().map(|label| label.to_string()).collect(),
+            relations: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_changes_limited_to_subscribed_labels() {
+        let config = MqttSourceConfig::builder("s", "localhost", "sensors/#").build();
+        let source = MqttSource::new(config).unwrap();
+        let change = |label: &str| {
+            mapper::payload_to_source_change(
+                br#"{"id": "a"}"#,
+                "s",
+                &["id".to_string()],
+                label,
+                Default::default(),
+                &Default::default(),
+            )
+            .unwrap()
+        };
+        let wants = |label: &str| {
+            source
+                .subscribed_labels
+                .lock()
+                .unwrap()
+                .wants(&change(label))
+        };
+
+        source.subscribe(settings("q1", &["Sensor"])).await.unwrap();
+        source.subscribe(settings("q2", &["Alarm"])).await.unwrap();
+        assert!(wants("Sensor"));
+        assert!(wants("Alarm"));
+        assert!(!wants("Valve"));
+
+        // A query matching any label needs everything.
+        source.subscribe(settings("q3", &[])).await.unwrap();
+        assert!(wants("Valve"));
+    }
+
+    #[tokio::test]
+    async fn test_message_spans_record_topic_entity_and_outcome() {
+        use drasi_mqtt_connection::trace_capture::EventCapture;
+
+        let capture = EventCapture::new();
+        let _guard = capture.set_default();
+        let broker = FakeBroker::bind().await;
+        let config = MqttSourceConfig::builder("s", "127.0.0.1", "sensors/#")
+            .port(broker.port())
+            .build();
+        let source = MqttSource::new(config).unwrap();
+        source.start().await.unwrap();
+
+        let mut client = broker.accept().await;
+        client.handshake().await;
+        for (topic, payload) in [("sensors/a", r#"{"id": "s1"}"#), ("sensors/b", "not json")] {
+            client.publish(topic, payload).await;
+        }
+
+        let span = |topic: &str| {
+            capture
+                .spans()
+                .into_iter()
+                .find(|span| span.name == "message" && span.field("topic") == Some(topic))
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while span("sensors/a").is_none() || span("sensors/b").is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        source.stop().await.unwrap();
+
+        let ingested = span("sensors/a").unwrap();
+        assert_eq!(ingested.field("source_id"), Some("s"));
+        assert_eq!(ingested.field("entity_id"), Some("s1"));
+        assert_eq!(ingested.field("outcome"), Some("dispatched"));
+        let failed = span("sensors/b").unwrap();
+        assert_eq!(failed.field("entity_id"), None);
+        assert_eq!(failed.field("outcome"), Some("mapping_failed"));
+
+        let warning = capture
+            .at(tracing::Level::WARN)
+            .into_iter()
+            .find(|event| event.field("error_class") == Some("mapping"))
+            .unwrap();
+        assert_eq!(warning.field("source_id"), Some("s"));
+        assert_eq!(warning.field("topic"), Some("sensors/b"));
+    }
+}
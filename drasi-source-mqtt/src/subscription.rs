@@ -0,0 +1,425 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Topic subscription helpers for the MQTT source.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use anyhow::{bail, Result};
+use drasi_core::models::SourceChange;
+use rumqttc::{QoS, SubAck, SubscribeFilter, SubscribeReasonCode};
+
+use crate::config::MqttSourceConfig;
+
+/// Build the subscribe filters for a config: `topic` at QoS 1 followed by
+/// every entry of `topics` with its own QoS, the `preset`'s topics at QoS 1
+/// unless already subscribed, and `$SYS/#` at QoS 0 if `ingest_sys_metrics`
+/// is set.
+///
+/// Fails if a filter is not a valid MQTT topic filter or a QoS is not 0, 1 or 2.
+pub fn subscribe_filters(config: &MqttSourceConfig) -> Result<Vec<SubscribeFilter>> {
+    let mut filters = vec![SubscribeFilter::new(config.topic.clone(), QoS::AtLeastOnce)];
+    for sub in &config.topics {
+        let qos = match rumqttc::qos(sub.qos) {
+            Ok(qos) => qos,
+            Err(_) => bail!(
+                "Invalid QoS {} for topic filter '{}': must be 0, 1 or 2",
+                sub.qos,
+                sub.filter
+            ),
+        };
+        filters.push(SubscribeFilter::new(sub.filter.clone(), qos));
+    }
+    if let Some(preset) = &config.preset {
+        for filter in preset.topic_filters() {
+            if !filters.iter().any(|f| f.path == filter) {
+                filters.push(SubscribeFilter::new(filter, QoS::AtLeastOnce));
+            }
+        }
+    }
+    if config.ingest_sys_metrics {
+        filters.push(SubscribeFilter::new(
+            crate::sys_metrics::SYS_TOPIC_FILTER.to_string(),
+            QoS::AtMostOnce,
+        ));
+    }
+
+    validate_filters(&filters)?;
+    Ok(filters)
+}
+
+/// Fail if a filter is not a valid MQTT topic filter.
+pub fn validate_filters(filters: &[SubscribeFilter]) -> Result<()> {
+    for filter in filters {
+        if filter.path.is_empty() || !rumqttc::valid_filter(&filter.path) {
+            bail!("Invalid MQTT topic filter '{}'", filter.path);
+        }
+    }
+    Ok(())
+}
+
+/// Pair each requested filter with the QoS the broker granted in `suback`
+/// (`None` if the subscription was refused).
+///
+/// Return codes are matched to filters by position, as a single SUBSCRIBE
+/// carries all filters.
+pub fn granted_qos(filters: &[SubscribeFilter], suback: &SubAck) -> Vec<(String, Option<QoS>)> {
+    filters
+        .iter()
+        .zip(&suback.return_codes)
+        .map(|(filter, code)| {
+            let granted = match code {
+                SubscribeReasonCode::Success(qos) => Some(*qos),
+                SubscribeReasonCode::Failure => None,
+            };
+            (filter.path.clone(), granted)
+        })
+        .collect()
+}
+
+/// One topic filter a source is subscribed to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionInfo {
+    pub filter: String,
+    /// QoS requested in the SUBSCRIBE.
+    pub qos: QoS,
+    /// QoS the broker granted; `None` until the SubAck arrives, or if the
+    /// broker refused the subscription.
+    pub granted_qos: Option<QoS>,
+}
+
+/// The filters a running source is subscribed to, shared by the source and
+/// its event loop.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    current: Vec<SubscribeFilter>,
+    /// Filters of each SUBSCRIBE sent, oldest first, until its SubAck arrives.
+    awaiting_ack: VecDeque<Vec<SubscribeFilter>>,
+    /// QoS granted for the current filters the broker acknowledged.
+    granted: HashMap<String, QoS>,
+}
+
+impl Subscriptions {
+    /// Start over with `filters`, subscribed in one SUBSCRIBE.
+    pub fn reset(&mut self, filters: Vec<SubscribeFilter>) {
+        self.awaiting_ack.clear();
+        self.awaiting_ack.push_back(filters.clone());
+        self.granted.clear();
+        self.current = filters;
+    }
+
+    /// Start the current filters over in one SUBSCRIBE, after the broker
+    /// lost the session, and return them.
+    pub fn resubscribe(&mut self) -> Vec<SubscribeFilter> {
+        let filters = self.current.clone();
+        self.reset(filters.clone());
+        filters
+    }
+
+    /// Forget all filters, e.g. once the source has stopped.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn current(&self) -> &[SubscribeFilter] {
+        &self.current
+    }
+
+    /// The current filters with their requested and granted QoS.
+    pub fn info(&self) -> Vec<SubscriptionInfo> {
+        self.current
+            .iter()
+            .map(|filter| SubscriptionInfo {
+                filter: filter.path.clone(),
+                qos: filter.qos,
+                granted_qos: self.granted.get(&filter.path).copied(),
+            })
+            .collect()
+    }
+
+    /// Filters to unsubscribe and to subscribe to move to `filters`. A filter
+    /// whose QoS changes is subscribed again.
+    pub fn diff(&self, filters: &[SubscribeFilter]) -> (Vec<String>, Vec<SubscribeFilter>) {
+        let removed = self
+            .current
+            .iter()
+            .filter(|old| !filters.iter().any(|new| new.path == old.path))
+            .map(|old| old.path.clone())
+            .collect();
+        let added = filters
+            .iter()
+            .filter(|new| !self.current.contains(new))
+            .cloned()
+            .collect();
+        (removed, added)
+    }
+
+    /// Record the move to `filters`, with `added` subscribed in one SUBSCRIBE.
+    pub fn update(&mut self, filters: Vec<SubscribeFilter>, added: Vec<SubscribeFilter>) {
+        self.granted
+            .retain(|path, _| filters.iter().any(|f| &f.path == path));
+        for filter in &added {
+            self.granted.remove(&filter.path);
+        }
+        if !added.is_empty() {
+            self.awaiting_ack.push_back(added);
+        }
+        self.current = filters;
+    }
+
+    /// Pair the oldest unacknowledged SUBSCRIBE's filters with `suback`
+    /// (see [`granted_qos`]).
+    pub fn acknowledge(&mut self, suback: &SubAck) -> Vec<(String, Option<QoS>)> {
+        let granted = match self.awaiting_ack.pop_front() {
+            Some(filters) => granted_qos(&filters, suback),
+            None => Vec::new(),
+        };
+        for (path, qos) in &granted {
+            match qos {
+                Some(qos) if self.current.iter().any(|f| &f.path == path) => {
+                    self.granted.insert(path.clone(), *qos);
+                }
+                _ => {
+                    self.granted.remove(path);
+                }
+            }
+        }
+        granted
+    }
+}
+
+/// The node labels queries subscribed to the source match on.
+///
+/// Drasi-lib delivers every dispatched change to every subscribed query, so
+/// a change with labels no query matches is only wasted work. Queries stay
+/// subscribed for the life of the source, so labels are never removed.
+#[derive(Debug, Default)]
+pub struct SubscribedLabels {
+    labels: HashSet<String>,
+    /// Some query matches nodes of any label.
+    any: bool,
+}
+
+impl SubscribedLabels {
+    /// Record a subscription for nodes with `labels`; empty means any label.
+    pub fn add(&mut self, labels: &HashSet<String>) {
+        if labels.is_empty() {
+            self.any = true;
+        } else {
+            self.labels.extend(labels.iter().cloned());
+        }
+    }
+
+    /// Whether some query matches `change`. Everything is wanted until a
+    /// query subscribes, and changes without labels always are.
+    pub fn wants(&self, change: &SourceChange) -> bool {
+        if self.any || self.labels.is_empty() {
+            return true;
+        }
+        let labels = match change {
+            SourceChange::Insert { element } | SourceChange::Update { element } => {
+                &element.get_metadata().labels
+            }
+            SourceChange::Delete { metadata } => &metadata.labels,
+            _ => return true,
+        };
+        labels
+            .iter()
+            .any(|label| self.labels.contains(label.as_ref()))
+    }
+}
+
+/// Whether `topic` matches any of `filters`.
+pub fn matches_any(filters: &[SubscribeFilter], topic: &str) -> bool {
+    filters.iter().any(|f| matches(topic, &f.path))
+}
+
+/// Whether `topic` matches `filter`.
+///
+/// `rumqttc::matches` never matches topics starting with `$`; as brokers do,
+/// they are matched by filters starting with the same `$` level, but not by
+/// a wildcard first level.
+pub(crate) fn matches(topic: &str, filter: &str) -> bool {
+    match topic.strip_prefix('$') {
+        Some(topic) => filter
+            .strip_prefix('$')
+            .is_some_and(|filter| rumqttc::matches(topic, filter)),
+        None => rumqttc::matches(topic, filter),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TopicSubscription;
+
+    fn config() -> MqttSourceConfig {
+        MqttSourceConfig::builder("src", "localhost", "telemetry/#")
+            .add_topic("control/#", QoS::ExactlyOnce)
+            .add_topic("debug/#", QoS::AtMostOnce)
+            .build()
+    }
+
+    #[test]
+    fn test_filters_carry_per_topic_qos() {
+        let filters = subscribe_filters(&config()).unwrap();
+
+        assert_eq!(
+            filters,
+            vec![
+                SubscribeFilter::new("telemetry/#".into(), QoS::AtLeastOnce),
+                SubscribeFilter::new("control/#".into(), QoS::ExactlyOnce),
+                SubscribeFilter::new("debug/#".into(), QoS::AtMostOnce),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_granted_qos_from_suback() {
+        let filters = subscribe_filters(&config()).unwrap();
+        let suback = SubAck::new(
+            1,
+            vec![
+                SubscribeReasonCode::Success(QoS::AtLeastOnce),
+                SubscribeReasonCode::Success(QoS::ExactlyOnce),
+                SubscribeReasonCode::Failure,
+            ],
+        );
+
+        assert_eq!(
+            granted_qos(&filters, &suback),
+            vec![
+                ("telemetry/#".to_string(), Some(QoS::AtLeastOnce)),
+                ("control/#".to_string(), Some(QoS::ExactlyOnce)),
+                ("debug/#".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_matches_any_filter() {
+        let filters = subscribe_filters(&config()).unwrap();
+        assert!(matches_any(&filters, "telemetry/room1/temp"));
+        assert!(matches_any(&filters, "control/valve"));
+        assert!(!matches_any(&filters, "alerts/high-temp"));
+    }
+
+    #[test]
+    fn test_sys_topics_need_a_sys_filter() {
+        let filters = subscribe_filters(&config()).unwrap();
+        let wildcard = [SubscribeFilter::new("#".into(), QoS::AtMostOnce)];
+        assert!(!matches_any(&filters, "$SYS/broker/uptime"));
+        assert!(!matches_any(&wildcard, "$SYS/broker/uptime"));
+
+        let sys = subscribe_filters(
+            &MqttSourceConfig::builder("src", "localhost", "telemetry/#")
+                .ingest_sys_metrics(std::time::Duration::from_secs(10))
+                .build(),
+        )
+        .unwrap();
+        assert!(matches_any(&sys, "$SYS/broker/uptime"));
+        assert!(matches_any(&sys, "telemetry/room1/temp"));
+    }
+
+    #[test]
+    fn test_subscription_diff() {
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.reset(subscribe_filters(&config()).unwrap());
+
+        let new = vec![
+            SubscribeFilter::new("telemetry/#".to_string(), QoS::AtLeastOnce),
+            SubscribeFilter::new("control/#".to_string(), QoS::AtLeastOnce),
+            SubscribeFilter::new("alerts/#".to_string(), QoS::AtMostOnce),
+        ];
+        let (removed, added) = subscriptions.diff(&new);
+
+        assert_eq!(removed, vec!["debug/#"]);
+        let added: Vec<(&str, QoS)> = added.iter().map(|f| (f.path.as_str(), f.qos)).collect();
+        // "control/#" changes from QoS 2 to 1.
+        assert_eq!(
+            added,
+            vec![
+                ("control/#", QoS::AtLeastOnce),
+                ("alerts/#", QoS::AtMostOnce)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subacks_matched_to_subscribes_in_order() {
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.reset(vec![SubscribeFilter::new(
+            "a/#".to_string(),
+            QoS::AtLeastOnce,
+        )]);
+        let b = vec![SubscribeFilter::new("b/#".to_string(), QoS::AtMostOnce)];
+        subscriptions.update(b.clone(), b);
+
+        let ack = |pkid| SubAck::new(pkid, vec![SubscribeReasonCode::Success(QoS::AtMostOnce)]);
+        assert_eq!(subscriptions.acknowledge(&ack(1))[0].0, "a/#");
+        assert_eq!(subscriptions.acknowledge(&ack(2))[0].0, "b/#");
+        assert!(subscriptions.acknowledge(&ack(3)).is_empty());
+    }
+
+    #[test]
+    fn test_info_tracks_granted_qos() {
+        let mut subscriptions = Subscriptions::default();
+        let a = SubscribeFilter::new("a/#".to_string(), QoS::ExactlyOnce);
+        let b = SubscribeFilter::new("b/#".to_string(), QoS::AtLeastOnce);
+        subscriptions.reset(vec![a.clone(), b.clone()]);
+        assert_eq!(subscriptions.info()[0].granted_qos, None);
+
+        subscriptions.acknowledge(&SubAck::new(
+            1,
+            vec![
+                SubscribeReasonCode::Success(QoS::AtLeastOnce),
+                SubscribeReasonCode::Failure,
+            ],
+        ));
+        let info = subscriptions.info();
+        assert_eq!(info[0].qos, QoS::ExactlyOnce);
+        assert_eq!(info[0].granted_qos, Some(QoS::AtLeastOnce));
+        assert_eq!(info[1].granted_qos, None);
+
+        // Subscribing again at another QoS waits for the new SubAck.
+        let a0 = SubscribeFilter::new("a/#".to_string(), QoS::AtMostOnce);
+        subscriptions.update(vec![a0.clone()], vec![a0]);
+        assert_eq!(
+            subscriptions.info(),
+            vec![SubscriptionInfo {
+                filter: "a/#".into(),
+                qos: QoS::AtMostOnce,
+                granted_qos: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_rejects_out_of_range_qos() {
+        let mut config = config();
+        config.topics.push(TopicSubscription {
+            filter: "bad/#".into(),
+            qos: 3,
+        });
+
+        let err = subscribe_filters(&config).unwrap_err();
+        assert!(err.to_string().contains("Invalid QoS 3"));
+    }
+
+    #[test]
+    fn test_rejects_invalid_filter() {
+        let config = MqttSourceConfig::builder("src", "localhost", "sensors/#/temp").build();
+        assert!(subscribe_filters(&config).is_err());
+    }
+}
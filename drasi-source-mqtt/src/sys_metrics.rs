@@ -0,0 +1,182 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Broker metrics published under `$SYS/`, ingested as `BrokerMetric` nodes.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use drasi_core::models::SourceChange;
+use serde_json::{Map, Value};
+use tokio::time::Instant;
+
+use crate::config::OperationMode;
+use crate::mapper::{self, MappingPreview};
+
+/// Topic filter of the broker's `$SYS` topics.
+pub const SYS_TOPIC_FILTER: &str = "$SYS/#";
+
+/// Label of the nodes broker metrics are mapped to.
+pub const BROKER_METRIC_LABEL: &str = "BrokerMetric";
+
+/// Whether `topic` is one of the broker's `$SYS` topics.
+pub fn is_sys_topic(topic: &str) -> bool {
+    topic.starts_with("$SYS/")
+}
+
+/// The value of a `$SYS` payload: a number if the text parses as one,
+/// otherwise the text itself.
+///
+/// Brokers send plain text such as `42`, `0.53` or `mosquitto version 2.0.18`.
+pub fn metric_value(payload: &[u8]) -> Value {
+    let text = String::from_utf8_lossy(payload);
+    let text = text.trim();
+    if let Ok(n) = text.parse::<i64>() {
+        return Value::from(n);
+    }
+    match text
+        .parse::<f64>()
+        .ok()
+        .and_then(serde_json::Number::from_f64)
+    {
+        Some(n) => Value::Number(n),
+        None => Value::String(text.to_string()),
+    }
+}
+
+/// Map a `$SYS` message to a [`BROKER_METRIC_LABEL`] node of source
+/// `source_id` whose id is the topic and whose `value` property holds the
+/// [`metric_value`].
+pub fn sys_metric_to_source_change(
+    source_id: &str,
+    topic: &str,
+    payload: &[u8],
+    mode: OperationMode,
+) -> SourceChange {
+    let properties = metric_properties(payload);
+    let element = mapper::node_element(source_id, BROKER_METRIC_LABEL, topic, &properties);
+    match mode {
+        OperationMode::Insert => SourceChange::Insert { element },
+        OperationMode::Update => SourceChange::Update { element },
+    }
+}
+
+/// Map a `$SYS` message like [`sys_metric_to_source_change`] does, returning
+/// the resulting node in inspectable form.
+pub fn preview_sys_metric(topic: &str, payload: &[u8], mode: OperationMode) -> MappingPreview {
+    MappingPreview {
+        id: topic.to_string(),
+        labels: vec![BROKER_METRIC_LABEL.to_string()],
+        properties: metric_properties(payload),
+        operation: mode,
+        effective_from: 0,
+    }
+}
+
+fn metric_properties(payload: &[u8]) -> Map<String, Value> {
+    let mut properties = Map::new();
+    properties.insert("value".to_string(), metric_value(payload));
+    properties
+}
+
+/// Admits at most one message per topic per interval.
+///
+/// Brokers publish some `$SYS` topics every few seconds; only the first
+/// message on a topic in each interval is ingested.
+#[derive(Debug)]
+pub struct Sampler {
+    interval: Duration,
+    last_admitted: HashMap<String, Instant>,
+}
+
+impl Sampler {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_admitted: HashMap::new(),
+        }
+    }
+
+    /// Whether a message on `topic` received at `now` is ingested.
+    pub fn admit(&mut self, topic: &str, now: Instant) -> bool {
+        match self.last_admitted.get_mut(topic) {
+            Some(last) if now.saturating_duration_since(*last) < self.interval => false,
+            Some(last) => {
+                *last = now;
+                true
+            }
+            None => {
+                self.last_admitted.insert(topic.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use drasi_core::models::ElementValue;
+
+    #[test]
+    fn test_metric_value() {
+        assert_eq!(metric_value(b"42"), Value::from(42));
+        assert_eq!(metric_value(b" 0.53\n"), Value::from(0.53));
+        assert_eq!(
+            metric_value(b"mosquitto version 2.0.18"),
+            Value::from("mosquitto version 2.0.18")
+        );
+        // Not representable as a JSON number.
+        assert_eq!(metric_value(b"NaN"), Value::from("NaN"));
+    }
+
+    #[test]
+    fn test_sys_metric_node() {
+        let change = sys_metric_to_source_change(
+            "src",
+            "$SYS/broker/clients/connected",
+            b"12",
+            OperationMode::Update,
+        );
+        let SourceChange::Update { element } = change else {
+            panic!("Expected Update");
+        };
+        let metadata = element.get_metadata();
+        assert_eq!(metadata.reference.source_id.as_ref(), "src");
+        assert_eq!(
+            metadata.reference.element_id.as_ref(),
+            "$SYS/broker/clients/connected"
+        );
+        assert_eq!(metadata.labels[0].as_ref(), BROKER_METRIC_LABEL);
+        assert_eq!(
+            element.get_properties().get("value"),
+            Some(&ElementValue::Integer(12))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sampler_admits_one_message_per_topic_per_interval() {
+        let mut sampler = Sampler::new(Duration::from_secs(10));
+        let clients = "$SYS/broker/clients/connected";
+        let heap = "$SYS/broker/heap/current";
+
+        assert!(sampler.admit(clients, Instant::now()));
+        assert!(sampler.admit(heap, Instant::now()));
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(!sampler.admit(clients, Instant::now()));
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(sampler.admit(clients, Instant::now()));
+        assert!(!sampler.admit(clients, Instant::now()));
+    }
+}
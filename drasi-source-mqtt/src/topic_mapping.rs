@@ -0,0 +1,1067 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Topic-aware mapping: entity ids taken from the topic, labels from the
+//! payload, per-topic rules, and presets for well-known publishers that
+//! expand into these options.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::bail;
+use drasi_core::models::{Element, ElementMetadata, ElementReference, SourceChange};
+use drasi_mqtt_connection::{system_clock, SharedClock};
+use serde_json::{Map, Value};
+use tokio::time::Instant;
+
+use crate::config::{MqttSourceConfig, OperationMode, Preset, TopicAction, TopicRule};
+use crate::mapper::{self, MappingPreview, PayloadFormat};
+use crate::schema::SchemaSampler;
+
+/// Base topic Zigbee2MQTT publishes under by default.
+pub const ZIGBEE2MQTT_BASE_TOPIC: &str = "zigbee2mqtt";
+
+impl Preset {
+    /// The topic filters covering every message of the preset.
+    pub fn topic_filters(&self) -> Vec<String> {
+        match self {
+            Preset::Zigbee2Mqtt { .. } => vec![format!("{ZIGBEE2MQTT_BASE_TOPIC}/#")],
+            Preset::Tasmota { .. } => vec!["tele/#".to_string(), "stat/#".to_string()],
+        }
+    }
+
+    /// Fill in the options `mapping` leaves unset and append the preset's rules.
+    ///
+    /// Zigbee2MQTT publishes device state to `zigbee2mqtt/<friendly_name>`,
+    /// availability to `.../availability` and bridge messages under
+    /// `zigbee2mqtt/bridge/`. Friendly names containing `/` are not supported.
+    ///
+    /// Tasmota publishes `tele/<device>/SENSOR` and `tele/<device>/STATE`
+    /// telemetry with nested objects, `stat/<device>/RESULT` command results
+    /// and an `Online`/`Offline` LWT on `tele/<device>/LWT`. They are merged
+    /// into one node per device, updated in place, with nested fields lifted
+    /// to lowercase top-level properties.
+    fn expand(&self, mapping: &mut TopicMapping) {
+        match *self {
+            Preset::Zigbee2Mqtt { delete_on_offline } => {
+                let base = ZIGBEE2MQTT_BASE_TOPIC;
+                mapping.id_level.get_or_insert(1);
+                mapping
+                    .label_pointer
+                    .get_or_insert_with(|| "/device/type".to_string());
+                mapping.rules.extend([
+                    TopicRule::new(
+                        format!("{base}/bridge/response/device/remove"),
+                        TopicAction::Delete {
+                            id_pointer: "/data/id".to_string(),
+                            require: HashMap::from([("/status".to_string(), Value::from("ok"))]),
+                        },
+                    ),
+                    TopicRule::new(format!("{base}/bridge/#"), TopicAction::Ignore),
+                    TopicRule::new(
+                        format!("{base}/+/availability"),
+                        TopicAction::Availability {
+                            property: "available".to_string(),
+                            delete_on_offline,
+                        },
+                    ),
+                    // Commands to devices, published by other clients.
+                    TopicRule::new(format!("{base}/+/set"), TopicAction::Ignore),
+                    TopicRule::new(format!("{base}/+/set/+"), TopicAction::Ignore),
+                    TopicRule::new(format!("{base}/+/get"), TopicAction::Ignore),
+                    TopicRule::new(format!("{base}/+/get/+"), TopicAction::Ignore),
+                ]);
+            }
+            Preset::Tasmota { delete_on_offline } => {
+                mapping.id_level.get_or_insert(1);
+                mapping.id_depth.get_or_insert(1);
+                mapping.mode = Some(OperationMode::Update);
+                mapping.merge = true;
+                mapping.flatten = true;
+                // Energy readings would clash with the relay state `POWER`.
+                mapping.flatten_prefixed = vec!["ENERGY".to_string()];
+                mapping.rules.extend([
+                    TopicRule::new(
+                        "tele/+/LWT",
+                        TopicAction::Availability {
+                            property: "online".to_string(),
+                            delete_on_offline,
+                        },
+                    ),
+                    TopicRule::new("tele/+/SENSOR", TopicAction::Map),
+                    TopicRule::new("tele/+/STATE", TopicAction::Map),
+                    TopicRule::new("stat/+/RESULT", TopicAction::Map),
+                    TopicRule::new("tele/#", TopicAction::Ignore),
+                    TopicRule::new("stat/#", TopicAction::Ignore),
+                    // Commands to devices, published by other clients.
+                    TopicRule::new("cmnd/#", TopicAction::Ignore),
+                ]);
+            }
+        }
+    }
+}
+
+/// The topic mapping options of a config, with its preset expanded.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TopicMapping {
+    /// Source named by element references (see
+    /// [`MqttSourceConfig::reference_source_for`]).
+    pub reference_source: String,
+    pub id_level: Option<usize>,
+    pub id_depth: Option<usize>,
+    pub label_pointer: Option<String>,
+    pub rules: Vec<TopicRule>,
+    /// Operation mode overriding the config's, set by presets.
+    pub mode: Option<OperationMode>,
+    /// Merge each message into the last properties of its entity.
+    pub merge: bool,
+    /// Most entities whose last properties are kept, unbounded when unset.
+    pub cache_capacity: Option<usize>,
+    /// Forget the kept properties of entities not updated for this long.
+    pub cache_ttl: Option<Duration>,
+    /// Lift the fields of nested objects to lowercase top-level properties,
+    /// set by presets.
+    pub flatten: bool,
+    /// Nested objects whose fields keep the object's name as a prefix when
+    /// flattened, e.g. `energy_power` for `{"ENERGY": {"Power": 12}}`.
+    pub flatten_prefixed: Vec<String>,
+}
+
+impl TopicMapping {
+    pub fn from_config(config: &MqttSourceConfig) -> Self {
+        let mut mapping = Self {
+            reference_source: config.reference_source_for(&config.node_label).to_string(),
+            id_level: config.topic_id_level,
+            id_depth: config.topic_id_depth,
+            label_pointer: config.label_pointer.clone(),
+            rules: config.topic_rules.clone(),
+            merge: config.merge_partial_updates,
+            cache_capacity: Some(config.entity_cache_capacity),
+            cache_ttl: config.entity_cache_ttl_ms.map(Duration::from_millis),
+            ..Default::default()
+        };
+        if let Some(preset) = &config.preset {
+            preset.expand(&mut mapping);
+        }
+        mapping
+    }
+
+    /// The action of the first rule whose filter matches `topic`.
+    fn action(&self, topic: &str) -> Option<&TopicAction> {
+        self.rules
+            .iter()
+            .find(|rule| crate::subscription::matches(topic, &rule.filter))
+            .map(|rule| &rule.action)
+    }
+
+    /// The entity ID `topic` names from `id_level` (or its first level) on,
+    /// or `None` if it has too few levels.
+    fn topic_id(&self, topic: &str) -> Option<String> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let start = self.id_level.unwrap_or(0);
+        let end = match self.id_depth {
+            Some(depth) => start + depth,
+            None => levels.len(),
+        };
+        (start < end && end <= levels.len()).then(|| levels[start..end].join("/"))
+    }
+
+    /// Parse a message on `topic`, ready to be
+    /// [`apply`](TopicMapper::apply)-ed. This is the costly part of mapping;
+    /// it reads no kept nodes, so it can run away from the mapper.
+    pub fn parse<S: AsRef<str>>(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        id_fields: &[S],
+        node_label: &str,
+        format: &PayloadFormat,
+    ) -> anyhow::Result<ParsedMessage> {
+        match self.action(topic) {
+            Some(TopicAction::Ignore) => Ok(ParsedMessage::Ignored),
+            Some(TopicAction::Availability {
+                property,
+                delete_on_offline,
+            }) => {
+                let entity_id = topic
+                    .rsplit_once('/')
+                    .and_then(|(entity, _)| self.topic_id(entity));
+                let Some(entity_id) = entity_id else {
+                    bail!("Availability topic '{topic}' names no entity");
+                };
+                Ok(ParsedMessage::Availability {
+                    entity_id: format.id_rules.apply(entity_id)?,
+                    property: property.clone(),
+                    online: parse_availability(payload)?,
+                    delete_on_offline: *delete_on_offline,
+                })
+            }
+            Some(TopicAction::Delete {
+                id_pointer,
+                require,
+            }) => {
+                let json = mapper::parse_payload(payload, format)?;
+                if require
+                    .iter()
+                    .any(|(pointer, value)| json.pointer(pointer) != Some(value))
+                {
+                    return Ok(ParsedMessage::Ignored);
+                }
+                let entity_id = match json.pointer(id_pointer) {
+                    Some(Value::String(id)) => id.clone(),
+                    Some(Value::Number(id)) => id.to_string(),
+                    _ => bail!("Payload has no id at '{id_pointer}'"),
+                };
+                Ok(ParsedMessage::Delete {
+                    entity_id: format.id_rules.apply(entity_id)?,
+                })
+            }
+            Some(TopicAction::Map) | None => {
+                let (entity_id, properties, label) =
+                    self.node(topic, payload, id_fields, node_label, format)?;
+                Ok(ParsedMessage::Node {
+                    entity_id,
+                    properties,
+                    label,
+                })
+            }
+        }
+    }
+
+    /// The entity ID, properties and label of a regular message.
+    fn node<S: AsRef<str>>(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        id_fields: &[S],
+        node_label: &str,
+        format: &PayloadFormat,
+    ) -> anyhow::Result<(String, Map<String, Value>, String)> {
+        let topic_id = self.id_level.and_then(|_| self.topic_id(topic));
+        let (entity_id, mut properties) =
+            mapper::payload_to_properties(payload, id_fields, topic_id, format)?;
+        let label = self
+            .label_pointer
+            .as_ref()
+            .and_then(|pointer| {
+                let mut tokens = pointer.strip_prefix('/')?.splitn(2, '/');
+                let first = tokens.next()?.replace("~1", "/").replace("~0", "~");
+                let rest = tokens.next().map(|rest| format!("/{rest}"));
+                properties
+                    .get(&first)?
+                    .pointer(&rest.unwrap_or_default())?
+                    .as_str()
+            })
+            .unwrap_or(node_label)
+            .to_string();
+        if self.flatten {
+            let mut flat = Map::new();
+            flatten_lowercase(properties, "", &self.flatten_prefixed, &mut flat);
+            properties = flat;
+        }
+        Ok((entity_id, properties, label))
+    }
+
+    /// Names of the properties set by availability rules.
+    fn availability_properties(&self) -> Vec<String> {
+        self.rules
+            .iter()
+            .filter_map(|rule| match &rule.action {
+                TopicAction::Availability { property, .. } => Some(property.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// A message parsed by [`TopicMapping::parse`], not yet applied to the kept
+/// nodes.
+#[derive(Debug)]
+pub enum ParsedMessage {
+    /// Dropped by a rule.
+    Ignored,
+    /// An availability change of an entity.
+    Availability {
+        entity_id: String,
+        property: String,
+        online: bool,
+        delete_on_offline: bool,
+    },
+    /// The deletion of an entity.
+    Delete { entity_id: String },
+    /// A regular message.
+    Node {
+        entity_id: String,
+        properties: Map<String, Value>,
+        label: String,
+    },
+}
+
+/// Whether an availability payload says `online` or `offline`, given
+/// plainly or as the `state` field of a JSON object.
+fn parse_availability(payload: &[u8]) -> anyhow::Result<bool> {
+    let text = String::from_utf8_lossy(payload);
+    let state = match serde_json::from_str::<Value>(&text) {
+        Ok(Value::Object(map)) => map.get("state").and_then(Value::as_str).map(str::to_string),
+        Ok(Value::String(state)) => Some(state),
+        _ => Some(text.trim().to_string()),
+    };
+    match state.map(|state| state.to_lowercase()).as_deref() {
+        Some("online") => Ok(true),
+        Some("offline") => Ok(false),
+        _ => bail!("Availability payload is neither 'online' nor 'offline'"),
+    }
+}
+
+/// Lift the fields of nested objects to the top level and lowercase every
+/// key, e.g. `{"AM2301": {"Temperature": 21.3}}` to `{"temperature": 21.3}`.
+/// Fields of objects named in `prefixed` keep the object's name as a prefix.
+/// Of fields with the same resulting name, the last one wins.
+fn flatten_lowercase(
+    map: Map<String, Value>,
+    prefix: &str,
+    prefixed: &[String],
+    out: &mut Map<String, Value>,
+) {
+    for (key, value) in map {
+        match value {
+            Value::Object(nested) if prefixed.contains(&key) => {
+                let prefix = format!("{prefix}{}_", key.to_lowercase());
+                flatten_lowercase(nested, &prefix, prefixed, out);
+            }
+            Value::Object(nested) => flatten_lowercase(nested, prefix, prefixed, out),
+            value => {
+                out.insert(format!("{prefix}{}", key.to_lowercase()), value);
+            }
+        }
+    }
+}
+
+fn with_label(mut element: Element, label: &str) -> Element {
+    if let Element::Node { metadata, .. } = &mut element {
+        metadata.labels = vec![Arc::from(label)].into();
+    }
+    element
+}
+
+fn delete(source_id: &str, entity_id: &str, label: &str) -> SourceChange {
+    SourceChange::Delete {
+        metadata: ElementMetadata {
+            reference: ElementReference::new(source_id, entity_id),
+            labels: vec![Arc::from(label)].into(),
+            effective_from: 0,
+        },
+    }
+}
+
+/// The last label and properties kept for an entity.
+#[derive(Debug)]
+struct KeptNode {
+    label: String,
+    properties: Map<String, Value>,
+}
+
+impl KeptNode {
+    fn new(label: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            properties: Map::new(),
+        }
+    }
+}
+
+/// A value kept for an entity, with when it was last updated.
+#[derive(Debug)]
+struct Kept<V> {
+    value: V,
+    updated: Instant,
+    /// Key of the entity in [`EntityCache::recency`].
+    seq: u64,
+}
+
+/// Values kept by entity ID, bounded by `capacity` (evicting the least
+/// recently updated) and forgetting values not updated within `ttl`.
+///
+/// Entities are also ordered by their last update, so the least recently
+/// updated is found in O(log n).
+#[derive(Debug)]
+struct EntityCache<V> {
+    entries: HashMap<String, Kept<V>>,
+    /// Entity IDs by an update sequence number, least recently updated
+    /// first.
+    recency: BTreeMap<u64, String>,
+    next_seq: u64,
+    capacity: Option<usize>,
+    ttl: Option<Duration>,
+}
+
+impl<V> EntityCache<V> {
+    fn new(capacity: Option<usize>, ttl: Option<Duration>) -> Self {
+        Self {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            next_seq: 0,
+            capacity,
+            ttl,
+        }
+    }
+
+    fn expired(&self, updated: Instant, now: Instant) -> bool {
+        self.ttl
+            .is_some_and(|ttl| now.saturating_duration_since(updated) >= ttl)
+    }
+
+    /// The value kept for `id`, unless it expired.
+    fn get(&self, id: &str, now: Instant) -> Option<&V> {
+        self.entries
+            .get(id)
+            .filter(|kept| !self.expired(kept.updated, now))
+            .map(|kept| &kept.value)
+    }
+
+    fn remove(&mut self, id: &str, now: Instant) -> Option<V> {
+        let kept = self.entries.remove(id)?;
+        self.recency.remove(&kept.seq);
+        (!self.expired(kept.updated, now)).then_some(kept.value)
+    }
+
+    /// The value kept for `id`, marked updated at `now`, starting from
+    /// `init()` if none is kept or it expired.
+    fn entry(&mut self, id: &str, now: Instant, init: impl FnOnce() -> V) -> &mut V {
+        if self
+            .entries
+            .get(id)
+            .is_some_and(|kept| self.expired(kept.updated, now))
+        {
+            self.remove(id, now);
+        }
+        if !self.entries.contains_key(id) {
+            self.make_room(now);
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let kept = self.entries.entry(id.to_string()).or_insert_with(|| Kept {
+            value: init(),
+            updated: now,
+            seq,
+        });
+        self.recency.remove(&kept.seq);
+        self.recency.insert(seq, id.to_string());
+        kept.seq = seq;
+        kept.updated = now;
+        &mut kept.value
+    }
+
+    /// Drop expired entries, then the least recently updated ones, until a
+    /// new entry fits.
+    fn make_room(&mut self, now: Instant) {
+        let Some(capacity) = self.capacity else {
+            return;
+        };
+        while let Some((_, id)) = self.recency.first_key_value() {
+            let updated = self.entries[id].updated;
+            if self.entries.len() < capacity && !self.expired(updated, now) {
+                break;
+            }
+            if let Some((_, id)) = self.recency.pop_first() {
+                self.entries.remove(&id);
+            }
+        }
+    }
+}
+
+/// Maps messages with a [`TopicMapping`].
+///
+/// With availability rules or `merge`, the last label and properties of each
+/// entity are kept, so an availability change keeps the node's other
+/// properties and a state message keeps its availability. With `merge`,
+/// every message updates the kept properties; an entity whose properties
+/// were evicted or expired starts over from its next message.
+#[derive(Debug)]
+pub struct TopicMapper {
+    mapping: Arc<TopicMapping>,
+    availability_properties: Vec<String>,
+    nodes: EntityCache<KeptNode>,
+    clock: SharedClock,
+    schema: Option<Arc<Mutex<SchemaSampler>>>,
+}
+
+impl TopicMapper {
+    pub fn new(mapping: TopicMapping) -> Self {
+        Self {
+            availability_properties: mapping.availability_properties(),
+            nodes: EntityCache::new(mapping.cache_capacity, mapping.cache_ttl),
+            mapping: Arc::new(mapping),
+            clock: system_clock(),
+            schema: None,
+        }
+    }
+
+    /// Expire kept properties by `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sample the label and properties of every mapped node into `schema`.
+    pub fn with_schema(mut self, schema: Option<Arc<Mutex<SchemaSampler>>>) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    /// The mapping, to [`parse`](TopicMapping::parse) messages away from
+    /// the mapper.
+    pub fn mapping(&self) -> Arc<TopicMapping> {
+        self.mapping.clone()
+    }
+
+    /// Map a message on `topic`, or return `None` if a rule drops it.
+    pub fn map<S: AsRef<str>>(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        id_fields: &[S],
+        node_label: &str,
+        mode: OperationMode,
+        format: &PayloadFormat,
+    ) -> anyhow::Result<Option<SourceChange>> {
+        let parsed = self
+            .mapping
+            .parse(topic, payload, id_fields, node_label, format)?;
+        Ok(self.apply(parsed, node_label, mode))
+    }
+
+    /// Apply a parsed message to the kept nodes, returning its change, or
+    /// `None` if a rule drops it.
+    pub fn apply(
+        &mut self,
+        parsed: ParsedMessage,
+        node_label: &str,
+        mode: OperationMode,
+    ) -> Option<SourceChange> {
+        let mode = self.mapping.mode.unwrap_or(mode);
+        let now = self.clock.now_instant();
+        let change = match parsed {
+            ParsedMessage::Ignored => return None,
+            ParsedMessage::Availability {
+                entity_id,
+                property,
+                online,
+                delete_on_offline,
+            } => {
+                if !online && delete_on_offline {
+                    let label = self.nodes.remove(&entity_id, now).map(|node| node.label);
+                    let label = label.as_deref().unwrap_or(node_label);
+                    return Some(delete(&self.mapping.reference_source, &entity_id, label));
+                }
+
+                let node = self
+                    .nodes
+                    .entry(&entity_id, now, || KeptNode::new(node_label));
+                node.properties.insert(property, Value::Bool(online));
+                let element = mapper::node_element(
+                    &self.mapping.reference_source,
+                    node_label,
+                    &entity_id,
+                    &node.properties,
+                );
+                mapper::change_for_mode(with_label(element, &node.label), mode)
+            }
+            ParsedMessage::Delete { entity_id } => {
+                let label = self.nodes.remove(&entity_id, now).map(|node| node.label);
+                let label = label.as_deref().unwrap_or(node_label);
+                delete(&self.mapping.reference_source, &entity_id, label)
+            }
+            ParsedMessage::Node {
+                entity_id,
+                mut properties,
+                mut label,
+            } => {
+                if self.mapping.merge {
+                    let last = self.nodes.entry(&entity_id, now, || KeptNode::new(&label));
+                    last.properties.extend(properties);
+                    if label != node_label {
+                        last.label = label;
+                    }
+                    properties = last.properties.clone();
+                    label = last.label.clone();
+                } else if !self.availability_properties.is_empty() {
+                    if let Some(last) = self.nodes.get(&entity_id, now) {
+                        for property in &self.availability_properties {
+                            if let Some(value) = last.properties.get(property) {
+                                properties
+                                    .entry(property.as_str())
+                                    .or_insert_with(|| value.clone());
+                            }
+                        }
+                    }
+                    let last = self.nodes.entry(&entity_id, now, || KeptNode::new(&label));
+                    last.label = label.clone();
+                    last.properties = properties.clone();
+                }
+                if let Some(schema) = &self.schema {
+                    schema.lock().unwrap().observe(&label, &properties);
+                }
+                let element = mapper::node_element(
+                    &self.mapping.reference_source,
+                    node_label,
+                    &entity_id,
+                    &properties,
+                );
+                mapper::change_for_mode(with_label(element, &label), mode)
+            }
+        };
+        Some(change)
+    }
+
+    /// Map a regular message on `topic` like [`map`](Self::map), returning
+    /// the node in inspectable form. Fails for topics handled by a rule.
+    pub fn preview<S: AsRef<str>>(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        id_fields: &[S],
+        node_label: &str,
+        mode: OperationMode,
+        format: &PayloadFormat,
+    ) -> anyhow::Result<MappingPreview> {
+        if self
+            .mapping
+            .action(topic)
+            .is_some_and(|action| *action != TopicAction::Map)
+        {
+            bail!("Topic '{topic}' is mapped by a topic rule, which preview does not cover");
+        }
+        let (id, properties, label) = self
+            .mapping
+            .node(topic, payload, id_fields, node_label, format)?;
+        Ok(MappingPreview {
+            id,
+            labels: vec![label],
+            properties,
+            operation: self.mapping.mode.unwrap_or(mode),
+            effective_from: 0,
+        })
+    }
+}
+
+/// The labels each entity id was mapped under, to spot ids shared across
+/// labels.
+///
+/// Bounded like the kept nodes of a [`TopicMapper`]: up to `capacity` ids,
+/// forgetting ids not seen within `ttl`. A deleted id is forgotten.
+#[derive(Debug)]
+pub struct CrossLabelIds {
+    labels: EntityCache<Vec<Arc<str>>>,
+}
+
+impl CrossLabelIds {
+    pub fn new(capacity: Option<usize>, ttl: Option<Duration>) -> Self {
+        Self {
+            labels: EntityCache::new(capacity, ttl),
+        }
+    }
+
+    /// Record the labels of `change`, seen at `now`, returning its id and
+    /// the labels it was mapped under before if it now has a label it did
+    /// not have.
+    pub fn observe(
+        &mut self,
+        change: &SourceChange,
+        now: Instant,
+    ) -> Option<(Arc<str>, Vec<Arc<str>>)> {
+        let metadata = match change {
+            SourceChange::Insert { element } | SourceChange::Update { element } => {
+                element.get_metadata()
+            }
+            SourceChange::Delete { metadata } => {
+                self.labels.remove(&metadata.reference.element_id, now);
+                return None;
+            }
+            _ => return None,
+        };
+        let id = &metadata.reference.element_id;
+        let known = self.labels.entry(id, now, Vec::new);
+        let before = known.clone();
+        let mut added = false;
+        for label in metadata.labels.iter() {
+            if !known.contains(label) {
+                known.push(label.clone());
+                added = true;
+            }
+        }
+        (added && !before.is_empty()).then(|| (id.clone(), before))
+    }
+}
+
+/// Outcome of [`BlockingRunner::run`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum RunOutcome<T> {
+    /// The work's result.
+    Done(T),
+    /// The work took longer than the timeout.
+    TimedOut,
+    /// The work was not started: earlier work that timed out still runs.
+    Busy,
+}
+
+/// Runs work on the blocking thread pool, giving up after a timeout.
+///
+/// Work that timed out cannot be cancelled: it runs to completion in the
+/// background and its result is discarded. Until it finishes, further work
+/// is refused rather than piling up behind it.
+#[derive(Debug, Default)]
+pub struct BlockingRunner {
+    /// Work that timed out is still running.
+    abandoned: Arc<AtomicBool>,
+}
+
+impl BlockingRunner {
+    pub async fn run<T: Send + 'static>(
+        &self,
+        timeout: Duration,
+        work: impl FnOnce() -> T + Send + 'static,
+    ) -> RunOutcome<T> {
+        if self.abandoned.load(Ordering::SeqCst) {
+            return RunOutcome::Busy;
+        }
+        let mut task = tokio::task::spawn_blocking(work);
+        match tokio::time::timeout(timeout, &mut task).await {
+            Ok(Ok(result)) => RunOutcome::Done(result),
+            Ok(Err(e)) => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => {
+                self.abandoned.store(true, Ordering::SeqCst);
+                let abandoned = self.abandoned.clone();
+                tokio::spawn(async move {
+                    let _ = task.await;
+                    abandoned.store(false, Ordering::SeqCst);
+                });
+                RunOutcome::TimedOut
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ReferenceSource;
+
+    fn mapper(
+        configure: impl FnOnce(crate::MqttSourceConfigBuilder) -> crate::MqttSourceConfigBuilder,
+    ) -> (TopicMapper, PayloadFormat) {
+        let config = configure(MqttSourceConfig::builder("s", "localhost", "devices/#")).build();
+        (
+            TopicMapper::new(TopicMapping::from_config(&config)),
+            config.payload_format().unwrap(),
+        )
+    }
+
+    fn map(
+        mapper: &mut TopicMapper,
+        format: &PayloadFormat,
+        topic: &str,
+        payload: &str,
+    ) -> Option<SourceChange> {
+        mapper
+            .map(
+                topic,
+                payload.as_bytes(),
+                &["id"],
+                "Device",
+                OperationMode::Insert,
+                format,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_id_from_topic_levels() {
+        let mapping = TopicMapping {
+            id_level: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            mapping.topic_id("devices/kitchen/lamp").as_deref(),
+            Some("kitchen/lamp")
+        );
+        assert_eq!(mapping.topic_id("devices"), None);
+        let mapping = TopicMapping {
+            id_depth: Some(1),
+            ..mapping
+        };
+        assert_eq!(
+            mapping.topic_id("tele/lamp/SENSOR").as_deref(),
+            Some("lamp")
+        );
+
+        let (mut mapper, format) = mapper(|b| b.topic_id_level(1));
+        let change = map(
+            &mut mapper,
+            &format,
+            "devices/lamp",
+            r#"{"id": "x", "on": true}"#,
+        )
+        .unwrap();
+        assert_eq!(change.get_reference().element_id.as_ref(), "lamp");
+    }
+
+    #[test]
+    fn test_label_from_pointer() {
+        let (topic_mapper, format) = mapper(|b| b.label_pointer("/device/type"));
+        let preview = topic_mapper
+            .preview(
+                "devices/a",
+                br#"{"id": "a", "device": {"type": "Router"}}"#,
+                &["id"],
+                "Device",
+                OperationMode::Insert,
+                &format,
+            )
+            .unwrap();
+        assert_eq!(preview.labels, vec!["Router"]);
+        let preview = topic_mapper
+            .preview(
+                "devices/a",
+                br#"{"id": "a"}"#,
+                &["id"],
+                "Device",
+                OperationMode::Insert,
+                &format,
+            )
+            .unwrap();
+        assert_eq!(preview.labels, vec!["Device"]);
+    }
+
+    #[test]
+    fn test_first_matching_rule_applies() {
+        let (mut mapper, format) = mapper(|b| {
+            b.topic_rule(
+                "devices/bridge/state",
+                TopicAction::Delete {
+                    id_pointer: "/id".to_string(),
+                    require: HashMap::new(),
+                },
+            )
+            .topic_rule("devices/bridge/#", TopicAction::Ignore)
+        });
+        assert!(map(
+            &mut mapper,
+            &format,
+            "devices/bridge/info",
+            r#"{"id": "a"}"#
+        )
+        .is_none());
+        assert!(matches!(
+            map(
+                &mut mapper,
+                &format,
+                "devices/bridge/state",
+                r#"{"id": "a"}"#
+            ),
+            Some(SourceChange::Delete { .. })
+        ));
+        assert!(matches!(
+            map(&mut mapper, &format, "devices/a", r#"{"id": "a"}"#),
+            Some(SourceChange::Insert { .. })
+        ));
+    }
+
+    #[test]
+    fn test_reference_names_the_source() {
+        let (mut topic_mapper, format) = mapper(|b| b.node_label("Device"));
+        let change = map(&mut topic_mapper, &format, "devices/a", r#"{"id": "a"}"#).unwrap();
+        assert_eq!(change.get_reference().source_id.as_ref(), "s");
+
+        let (mut topic_mapper, format) = mapper(|b| {
+            b.node_label("Device")
+                .reference_source(ReferenceSource::NodeLabel)
+        });
+        let change = map(&mut topic_mapper, &format, "devices/a", r#"{"id": "a"}"#).unwrap();
+        assert_eq!(change.get_reference().source_id.as_ref(), "Device");
+
+        let (mut topic_mapper, format) = mapper(|b| {
+            b.node_label("Device")
+                .reference_source(ReferenceSource::Custom("plant-a".into()))
+        });
+        let change = map(&mut topic_mapper, &format, "devices/a", r#"{"id": "a"}"#).unwrap();
+        assert_eq!(change.get_reference().source_id.as_ref(), "plant-a");
+    }
+
+    #[test]
+    fn test_merge_partial_updates() {
+        use drasi_core::models::ElementValue;
+        use drasi_mqtt_connection::ManualClock;
+
+        let clock = Arc::new(ManualClock::new(0));
+        let (topic_mapper, format) = mapper(|b| {
+            b.merge_partial_updates(true)
+                .entity_cache(1, Some(Duration::from_secs(60)))
+        });
+        let mut topic_mapper = topic_mapper.with_clock(clock.clone());
+        let mut update = |payload: &str| {
+            let change = topic_mapper
+                .map(
+                    "devices/a",
+                    payload.as_bytes(),
+                    &["id"],
+                    "Device",
+                    OperationMode::Update,
+                    &format,
+                )
+                .unwrap();
+            match change {
+                Some(SourceChange::Update { element }) => element.get_properties().clone(),
+                other => panic!("expected an update, got {other:?}"),
+            }
+        };
+
+        update(r#"{"id": "a", "temperature": 21}"#);
+        let merged = update(r#"{"id": "a", "humidity": 40}"#);
+        assert_eq!(merged["temperature"], ElementValue::Integer(21));
+        assert_eq!(merged["humidity"], ElementValue::Integer(40));
+
+        // Keeping `b` evicts `a`, whose next message starts over.
+        update(r#"{"id": "b", "temperature": 18}"#);
+        let fresh = update(r#"{"id": "a", "humidity": 41}"#);
+        assert!(fresh.get("temperature").is_none());
+
+        clock.advance(Duration::from_secs(61));
+        let expired = update(r#"{"id": "a", "temperature": 22}"#);
+        assert!(expired.get("humidity").is_none());
+    }
+
+    #[test]
+    fn test_cross_label_ids() {
+        let (mut topic_mapper, format) = mapper(|b| b.label_pointer("/device/type"));
+        let mut ids = CrossLabelIds::new(Some(10), None);
+        let now = Instant::now();
+        let mut observe = |payload: &str| {
+            let change = map(&mut topic_mapper, &format, "devices/a", payload).unwrap();
+            ids.observe(&change, now)
+        };
+
+        assert!(observe(r#"{"id": "a", "device": {"type": "Router"}}"#).is_none());
+        assert!(observe(r#"{"id": "a", "device": {"type": "Router"}}"#).is_none());
+        let (id, before) = observe(r#"{"id": "a", "device": {"type": "Lamp"}}"#).unwrap();
+        assert_eq!((id.as_ref(), before), ("a", vec![Arc::from("Router")]));
+        // Reported once per new label.
+        assert!(observe(r#"{"id": "a", "device": {"type": "Lamp"}}"#).is_none());
+        assert!(observe(r#"{"id": "b", "device": {"type": "Lamp"}}"#).is_none());
+    }
+
+    #[test]
+    fn test_cross_label_ids_forget_deleted_and_old_ids() {
+        let (mut topic_mapper, format) = mapper(|b| b.label_pointer("/device/type"));
+        let mut ids = CrossLabelIds::new(Some(1), Some(Duration::from_secs(60)));
+        let mut change = |id: &str, label: &str| {
+            let payload = format!(r#"{{"id": "{id}", "device": {{"type": "{label}"}}}}"#);
+            map(&mut topic_mapper, &format, "devices/a", &payload).unwrap()
+        };
+        let start = Instant::now();
+
+        // A deleted id starts over.
+        ids.observe(&change("a", "Router"), start);
+        ids.observe(&delete("s", "a", "Router"), start);
+        assert!(ids.observe(&change("a", "Lamp"), start).is_none());
+
+        // Only one id is kept, and only for a minute.
+        ids.observe(&change("b", "Router"), start);
+        assert!(ids.observe(&change("a", "Router"), start).is_none());
+        let later = start + Duration::from_secs(61);
+        assert!(ids.observe(&change("a", "Lamp"), later).is_none());
+        assert_eq!(ids.labels.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_availability_payloads() {
+        assert!(parse_availability(b"online").unwrap());
+        assert!(!parse_availability(b"offline").unwrap());
+        assert!(parse_availability(br#"{"state":"online"}"#).unwrap());
+        assert!(parse_availability(b"maybe").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blocking_runner_gives_up_on_slow_work() {
+        let runner = BlockingRunner::default();
+        let slow = runner.run(Duration::from_millis(20), || {
+            std::thread::sleep(Duration::from_millis(300));
+            1
+        });
+        let started = std::time::Instant::now();
+        assert_eq!(slow.await, RunOutcome::TimedOut);
+        assert!(started.elapsed() < Duration::from_millis(250));
+
+        // Nothing new starts while the abandoned work still runs.
+        assert_eq!(
+            runner.run(Duration::from_secs(5), || 2).await,
+            RunOutcome::Busy
+        );
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(
+            runner.run(Duration::from_secs(5), || 3).await,
+            RunOutcome::Done(3)
+        );
+    }
+
+    #[test]
+    fn test_parsed_message_changes_nothing_until_applied() {
+        let (mut topic_mapper, format) = mapper(|b| b.merge_partial_updates(true));
+        let mapping = topic_mapper.mapping();
+        let parse = |payload: &str| {
+            mapping
+                .parse("devices/a", payload.as_bytes(), &["id"], "Device", &format)
+                .unwrap()
+        };
+
+        // A message abandoned after parsing is not merged into later ones.
+        let _abandoned = parse(r#"{"id": "a", "temperature": 21}"#);
+        let parsed = parse(r#"{"id": "a", "humidity": 40}"#);
+        let change = topic_mapper
+            .apply(parsed, "Device", OperationMode::Update)
+            .unwrap();
+        let properties = match change {
+            SourceChange::Update { element } => element.get_properties().clone(),
+            other => panic!("expected an update, got {other:?}"),
+        };
+        assert!(properties.get("temperature").is_none());
+        assert!(properties.get("humidity").is_some());
+    }
+
+    #[test]
+    fn test_entity_cache_evicts_least_recently_updated() {
+        let start = Instant::now();
+        let mut cache = EntityCache::new(Some(2), Some(Duration::from_secs(60)));
+        *cache.entry("a", start, || 0) += 1;
+        *cache.entry("b", start, || 0) += 1;
+        *cache.entry("a", start, || 0) += 1;
+
+        // `b` is now the least recently updated.
+        cache.entry("c", start, || 0);
+        assert_eq!(cache.get("a", start), Some(&2));
+        assert_eq!(cache.get("b", start), None);
+        assert_eq!(cache.recency.len(), 2);
+
+        // `a` expired, which makes room for `d` without evicting `c`.
+        let later = start + Duration::from_secs(30);
+        cache.entry("c", later, || 0);
+        let expired = start + Duration::from_secs(61);
+        cache.entry("d", expired, || 0);
+        assert_eq!(cache.get("a", expired), None);
+        assert_eq!(cache.get("c", expired), Some(&0));
+        assert_eq!(cache.entries.len(), 2);
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The tracing span around the handling of each received message.
+//!
+//! A `message` span covers parsing, mapping and dispatch of one message,
+//! with `source_id` and `topic` fields, and the `entity_id` and `outcome`
+//! recorded once known. Events logged while handling the message, including
+//! dispatch failures in the dispatcher workers, carry these fields.
+//!
+//! The span is at debug level, so with `log` loggers it only shows up when
+//! debug logging is enabled. `message_spans(false)` skips it altogether.
+
+use tracing::field::Empty;
+use tracing::Span;
+
+/// How a received message was handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageOutcome {
+    /// Mapped to a change and queued for dispatch.
+    Dispatched,
+    /// Mapped to nothing, or to a change no query subscribed to.
+    Ignored,
+    /// Its topic's mapping is disabled.
+    Disabled,
+    /// Dropped by the rate limit.
+    RateLimited,
+    /// A `$SYS` topic, sampled as a broker metric.
+    SysMetric,
+    /// Mapping took longer than the processing timeout.
+    TimedOut,
+    /// The payload could not be parsed or mapped.
+    MappingFailed,
+}
+
+impl MessageOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageOutcome::Dispatched => "dispatched",
+            MessageOutcome::Ignored => "ignored",
+            MessageOutcome::Disabled => "disabled",
+            MessageOutcome::RateLimited => "rate_limited",
+            MessageOutcome::SysMetric => "sys_metric",
+            MessageOutcome::TimedOut => "timed_out",
+            MessageOutcome::MappingFailed => "mapping_failed",
+        }
+    }
+}
+
+/// Span for a message received on `topic`, or a disabled span if `enabled`
+/// is false.
+pub fn message_span(enabled: bool, source_id: &str, topic: &str) -> Span {
+    if !enabled {
+        return Span::none();
+    }
+    tracing::debug_span!(
+        "message",
+        source_id,
+        topic,
+        entity_id = Empty,
+        outcome = Empty
+    )
+}
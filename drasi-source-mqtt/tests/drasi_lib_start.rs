@@ -0,0 +1,87 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The source and reaction started by DrasiLib, against fake brokers: a
+//! source built with `auto_start(false)` stays stopped when DrasiLib starts,
+//! and once started explicitly its messages reach the reaction through a
+//! query.
+
+use std::time::Duration;
+
+use drasi_lib::channels::ComponentStatus;
+use drasi_lib::{DrasiLib, Query};
+use drasi_mqtt_connection::fake_broker::{FakeBroker, CONNECT, PUBLISH};
+use drasi_reaction_mqtt::{MqttReaction, MqttReactionConfig};
+use drasi_source_mqtt::{MqttSource, MqttSourceConfig};
+
+#[tokio::test]
+async fn manual_start_source_feeds_reaction_once_started() {
+    let devices = FakeBroker::bind().await;
+    let commands = FakeBroker::bind().await;
+
+    let source_config = MqttSourceConfig::builder("lamps", "127.0.0.1", "lamps/+/state")
+        .port(devices.port())
+        .node_label("Lamp")
+        .id_field("id")
+        .auto_start(false)
+        .build();
+    let reaction_config = MqttReactionConfig::builder(
+        "lamp-alerts",
+        "127.0.0.1",
+        "alerts/{{id}}",
+        vec!["lit-lamps".to_string()],
+    )
+    .port(commands.port())
+    .build();
+    let query = Query::cypher("lit-lamps")
+        .query("MATCH (l:Lamp) WHERE l.on = true RETURN l.id AS id")
+        .from_source("lamps")
+        .build();
+
+    let core = DrasiLib::builder()
+        .with_id("lamps-core")
+        .with_source(MqttSource::new(source_config).unwrap())
+        .with_reaction(MqttReaction::new(reaction_config))
+        .with_query(query)
+        .build()
+        .await
+        .unwrap();
+    core.start().await.unwrap();
+
+    // The reaction connects with DrasiLib; the source waits.
+    let mut reaction_client = commands.accept().await;
+    reaction_client.expect(CONNECT).await;
+    reaction_client.connack(0).await;
+    assert!(devices
+        .try_accept(Duration::from_millis(200))
+        .await
+        .is_none());
+    assert_eq!(
+        core.get_source_status("lamps").await.unwrap(),
+        ComponentStatus::Stopped
+    );
+
+    core.start_source("lamps").await.unwrap();
+    let mut device = devices.accept().await;
+    let subscribe = device.handshake().await;
+    assert!(subscribe.contains(b"lamps/+/state"), "{subscribe:?}");
+    device
+        .publish("lamps/desk/state", r#"{"id": "desk", "on": true}"#)
+        .await;
+
+    let published = reaction_client.expect(PUBLISH).await;
+    assert!(published.contains(b"alerts/desk"), "{published:?}");
+
+    core.stop().await.unwrap();
+}
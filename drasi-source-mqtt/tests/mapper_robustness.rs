@@ -0,0 +1,165 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Robustness of payload mapping against arbitrary network input.
+//!
+//! Every payload must map to a node or fail with an error; none may panic,
+//! and with truncating limits the node stays within them.
+
+use drasi_core::models::{ElementValue, SourceChange};
+use proptest::prelude::*;
+use serde_json::{Map, Value};
+
+use drasi_source_mqtt::config::{OperationMode, OversizePolicy, PAYLOAD_HASH_ID};
+use drasi_source_mqtt::mapper::{payload_to_source_change, PayloadFormat};
+use drasi_source_mqtt::{MqttSourceConfig, MqttSourceConfigBuilder};
+
+const MAX_PROPERTIES: usize = 8;
+const MAX_VALUE_BYTES: usize = 64;
+
+fn format(
+    configure: impl FnOnce(MqttSourceConfigBuilder) -> MqttSourceConfigBuilder,
+) -> PayloadFormat {
+    let builder = MqttSourceConfig::builder("s", "localhost", "sensors/#");
+    configure(builder).build().payload_format().unwrap()
+}
+
+/// Formats covering each decoding path.
+fn formats() -> Vec<PayloadFormat> {
+    vec![
+        PayloadFormat::default(),
+        format(|b| b.text_encoding("latin1")),
+        format(|b| b.text_encoding("utf-16le")),
+        format(|b| b.decode_nested_json("data")),
+        format(|b| {
+            b.max_properties(MAX_PROPERTIES)
+                .max_property_value_bytes(MAX_VALUE_BYTES)
+        }),
+    ]
+}
+
+fn map(payload: &[u8], format: &PayloadFormat) -> anyhow::Result<SourceChange> {
+    payload_to_source_change(
+        payload,
+        "s",
+        &["id", PAYLOAD_HASH_ID],
+        "Sensor",
+        OperationMode::Insert,
+        format,
+    )
+}
+
+/// Map `payload` with every format, checking each outcome is a node with an
+/// id or an error.
+fn map_all(payload: &[u8]) {
+    for format in formats() {
+        match map(payload, &format) {
+            Ok(SourceChange::Insert { element }) => {
+                assert!(!element.get_reference().element_id.is_empty());
+            }
+            Ok(other) => panic!("Expected Insert, got {other:?}"),
+            Err(e) => assert!(!e.to_string().is_empty()),
+        }
+    }
+}
+
+fn arb_json() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::Bool),
+        any::<i64>().prop_map(Value::from),
+        any::<u64>().prop_map(Value::from),
+        any::<f64>().prop_map(Value::from),
+        ".{0,80}".prop_map(Value::String),
+    ];
+    leaf.prop_recursive(6, 128, 10, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..10).prop_map(Value::Array),
+            prop::collection::btree_map(".{0,12}", inner, 0..10)
+                .prop_map(|fields| Value::Object(Map::from_iter(fields))),
+        ]
+    })
+}
+
+proptest! {
+    #[test]
+    fn random_bytes_do_not_panic(payload in prop::collection::vec(any::<u8>(), 0..512)) {
+        map_all(&payload);
+    }
+
+    #[test]
+    fn random_json_does_not_panic(json in arb_json()) {
+        map_all(&serde_json::to_vec(&json).unwrap());
+        let nested = serde_json::json!({ "data": json.to_string() });
+        map_all(&serde_json::to_vec(&nested).unwrap());
+    }
+
+    #[test]
+    fn deep_nesting_does_not_panic(depth in 0usize..2000, object in any::<bool>()) {
+        let (open, close) = if object { (r#"{"a":"#, "}") } else { ("[", "]") };
+        let payload = format!(
+            r#"{{"id":"d1","v":{}1{}}}"#,
+            open.repeat(depth),
+            close.repeat(depth)
+        );
+        map_all(payload.as_bytes());
+    }
+
+    #[test]
+    fn huge_numbers_do_not_panic(
+        digits in "-?[1-9][0-9]{0,400}",
+        fraction in "(\\.[0-9]{1,40})?",
+        exponent in "([eE][+-]?[0-9]{1,6})?",
+    ) {
+        let number = format!("{digits}{fraction}{exponent}");
+        map_all(format!(r#"{{"id": {number}, "v": {number}}}"#).as_bytes());
+    }
+
+    #[test]
+    fn invalid_utf8_keys_and_values_do_not_panic(
+        key in prop::collection::vec(any::<u8>(), 1..16),
+        value in prop::collection::vec(any::<u8>(), 0..16),
+    ) {
+        let mut payload = b"{\"".to_vec();
+        payload.extend_from_slice(&key);
+        payload.extend_from_slice(b"\": \"");
+        payload.extend_from_slice(&value);
+        payload.extend_from_slice(b"\"}");
+        map_all(&payload);
+    }
+
+    #[test]
+    fn truncated_nodes_stay_within_limits(
+        fields in prop::collection::btree_map("[a-z]{1,8}", arb_json(), 0..32)
+    ) {
+        let format = format(|b| {
+            b.max_properties(MAX_PROPERTIES)
+                .max_property_value_bytes(MAX_VALUE_BYTES)
+                .oversize_policy(OversizePolicy::Truncate)
+        });
+        let payload = serde_json::to_vec(&Value::Object(Map::from_iter(fields.clone()))).unwrap();
+        let Ok(SourceChange::Insert { element }) = map(&payload, &format) else {
+            panic!("Expected Insert");
+        };
+
+        let properties = element.get_properties();
+        let kept: Vec<&ElementValue> = fields.keys().filter_map(|key| properties.get(key)).collect();
+        prop_assert!(kept.len() <= MAX_PROPERTIES);
+        for value in kept {
+            if let ElementValue::String(s) = value {
+                prop_assert!(s.len() <= MAX_VALUE_BYTES);
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `$SYS` metrics ingestion against a real Mosquitto broker.
+//!
+//! Ignored by default. Start a broker and run with
+//! `MQTT_TEST_BROKER=localhost:1883 cargo test -p drasi-source-mqtt --test sys_metrics -- --ignored`,
+//! e.g. with `docker run -p 1883:1883 eclipse-mosquitto:2 mosquitto -c /mosquitto-no-auth.conf`.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use drasi_lib::Source;
+use drasi_source_mqtt::sys_metrics::is_sys_topic;
+use drasi_source_mqtt::{MqttSource, MqttSourceConfig};
+
+fn broker() -> (String, u16) {
+    let address = std::env::var("MQTT_TEST_BROKER").unwrap_or_else(|_| "localhost:1883".into());
+    let (host, port) = address
+        .rsplit_once(':')
+        .expect("MQTT_TEST_BROKER must be host:port");
+    (host.to_string(), port.parse().expect("invalid broker port"))
+}
+
+#[tokio::test]
+#[ignore = "needs a Mosquitto broker, see MQTT_TEST_BROKER"]
+async fn ingests_sampled_sys_metrics_from_mosquitto() {
+    let (host, port) = broker();
+    let config = MqttSourceConfig::builder("sys-metrics-test", host, "drasi-test/sensors/#")
+        .port(port)
+        .ingest_sys_metrics(Duration::from_secs(60))
+        .build();
+    // Record the topic of every $SYS message received.
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let record = received.clone();
+    let source = MqttSource::new(config)
+        .unwrap()
+        .with_message_filter(Arc::new(move |topic, _| {
+            if is_sys_topic(topic) {
+                record.lock().unwrap().push(topic.to_string());
+            }
+            true
+        }));
+    source.connect_check().await.unwrap();
+    source.start().await.unwrap();
+
+    // Mosquitto sends its retained $SYS topics on subscription and the rest
+    // every 10 seconds by default.
+    tokio::time::sleep(Duration::from_secs(25)).await;
+    source.stop().await.unwrap();
+
+    let received = received.lock().unwrap();
+    let topics: std::collections::HashSet<_> = received.iter().collect();
+    let dispatched: u64 = source.delivery_latency().iter().map(|b| b.count).sum();
+
+    assert!(!topics.is_empty(), "no $SYS messages received");
+    // One node per topic in the 60s interval, however often a topic was sent.
+    assert_eq!(dispatched, topics.len() as u64);
+    assert!(
+        received.len() as u64 > dispatched,
+        "no message was sampled out"
+    );
+}
@@ -0,0 +1,148 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mapping of Tasmota messages with the `Tasmota` preset, replaying a
+//! session of LWT, telemetry and command-result messages.
+
+use drasi_core::models::{Element, ElementPropertyMap, ElementValue, SourceChange};
+use serde_json::Value;
+
+use drasi_source_mqtt::topic_mapping::{TopicMapper, TopicMapping};
+use drasi_source_mqtt::{MqttSourceConfig, Preset};
+
+const SESSION: &str = include_str!("fixtures/tasmota.jsonl");
+
+/// Replay the fixture session, returning each message's topic and change.
+fn replay(delete_on_offline: bool) -> Vec<(String, Option<SourceChange>)> {
+    let config = MqttSourceConfig::builder("tasmota", "localhost", "tele/#")
+        .node_label("TasmotaDevice")
+        .preset(Preset::Tasmota { delete_on_offline })
+        .build();
+    let format = config.payload_format().unwrap();
+    let mut mapper = TopicMapper::new(TopicMapping::from_config(&config));
+
+    SESSION
+        .lines()
+        .map(|line| {
+            let message: Value = serde_json::from_str(line).unwrap();
+            let topic = message["topic"].as_str().unwrap().to_string();
+            let payload = message["payload"].as_str().unwrap();
+            let change = mapper
+                .map(
+                    &topic,
+                    payload.as_bytes(),
+                    &config.id_fields,
+                    &config.node_label,
+                    config.mode,
+                    &format,
+                )
+                .unwrap();
+            (topic, change)
+        })
+        .collect()
+}
+
+/// The id and properties of an update.
+fn update(change: &Option<SourceChange>) -> (String, ElementPropertyMap) {
+    let Some(SourceChange::Update {
+        element: Element::Node {
+            metadata,
+            properties,
+        },
+    }) = change
+    else {
+        panic!("Expected a node update, got {change:?}");
+    };
+    (
+        metadata.reference.element_id.to_string(),
+        properties.clone(),
+    )
+}
+
+fn property(properties: &ElementPropertyMap, name: &str) -> Option<ElementValue> {
+    properties.get(name).cloned()
+}
+
+#[test]
+fn other_topics_are_ignored() {
+    let changes = replay(false);
+    for index in [1, 5, 7] {
+        assert!(
+            changes[index].1.is_none(),
+            "{} should be ignored",
+            changes[index].0
+        );
+    }
+}
+
+#[test]
+fn sensor_objects_are_flattened() {
+    let changes = replay(false);
+    let (id, properties) = update(&changes[4].1);
+    assert_eq!(id, "climate_hall");
+    assert!(property(&properties, "temperature").is_some());
+    assert!(property(&properties, "humidity").is_some());
+    assert!(property(&properties, "am2301").is_none());
+}
+
+#[test]
+fn state_sensor_and_results_merge_into_one_node() {
+    let changes = replay(false);
+
+    let (id, properties) = update(&changes[3].1);
+    assert_eq!(id, "plug_desk");
+    assert_eq!(
+        property(&properties, "online"),
+        Some(ElementValue::Bool(true))
+    );
+    assert_eq!(
+        property(&properties, "rssi"),
+        Some(ElementValue::Integer(76))
+    );
+    assert_eq!(
+        property(&properties, "power"),
+        Some(ElementValue::String("ON".into()))
+    );
+    assert_eq!(
+        property(&properties, "energy_power"),
+        Some(ElementValue::Integer(12))
+    );
+
+    let (id, properties) = update(&changes[6].1);
+    assert_eq!(id, "plug_desk");
+    assert_eq!(
+        property(&properties, "power"),
+        Some(ElementValue::String("OFF".into()))
+    );
+    assert_eq!(
+        property(&properties, "energy_voltage"),
+        Some(ElementValue::Integer(229))
+    );
+
+    let (_, properties) = update(&changes[8].1);
+    assert_eq!(
+        property(&properties, "online"),
+        Some(ElementValue::Bool(false))
+    );
+    assert!(property(&properties, "energy_voltage").is_some());
+}
+
+#[test]
+fn offline_deletes_with_flag() {
+    let changes = replay(true);
+    let Some(SourceChange::Delete { metadata }) = &changes[8].1 else {
+        panic!("Expected a delete, got {:?}", changes[8].1);
+    };
+    assert_eq!(metadata.reference.element_id.as_ref(), "plug_desk");
+}
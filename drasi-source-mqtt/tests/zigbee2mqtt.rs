@@ -0,0 +1,140 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mapping of Zigbee2MQTT messages with the `Zigbee2Mqtt` preset, replaying
+//! a session of bridge, state, availability and device-remove messages.
+
+use drasi_core::models::{Element, ElementValue, SourceChange};
+use serde_json::Value;
+
+use drasi_source_mqtt::topic_mapping::{TopicMapper, TopicMapping};
+use drasi_source_mqtt::{MqttSourceConfig, Preset};
+
+const SESSION: &str = include_str!("fixtures/zigbee2mqtt.jsonl");
+
+/// Replay the fixture session, returning each message's topic and change.
+fn replay(delete_on_offline: bool) -> Vec<(String, Option<SourceChange>)> {
+    let config = MqttSourceConfig::builder("z2m", "localhost", "zigbee2mqtt/#")
+        .node_label("ZigbeeDevice")
+        .preset(Preset::Zigbee2Mqtt { delete_on_offline })
+        .build();
+    let format = config.payload_format().unwrap();
+    let mut mapper = TopicMapper::new(TopicMapping::from_config(&config));
+
+    SESSION
+        .lines()
+        .map(|line| {
+            let message: Value = serde_json::from_str(line).unwrap();
+            let topic = message["topic"].as_str().unwrap().to_string();
+            let payload = message["payload"].as_str().unwrap();
+            let change = mapper
+                .map(
+                    &topic,
+                    payload.as_bytes(),
+                    &config.id_fields,
+                    &config.node_label,
+                    config.mode,
+                    &format,
+                )
+                .unwrap();
+            (topic, change)
+        })
+        .collect()
+}
+
+/// The id, labels and `available` property of a node change.
+fn node(change: &SourceChange) -> (String, Vec<String>, Option<bool>) {
+    let (SourceChange::Insert { element } | SourceChange::Update { element }) = change else {
+        panic!("Expected a node, got {change:?}");
+    };
+    let Element::Node {
+        metadata,
+        properties,
+    } = element
+    else {
+        panic!("Expected a node");
+    };
+    let available = match properties.get("available") {
+        Some(ElementValue::Bool(available)) => Some(*available),
+        _ => None,
+    };
+    (
+        metadata.reference.element_id.to_string(),
+        metadata.labels.iter().map(|l| l.to_string()).collect(),
+        available,
+    )
+}
+
+#[test]
+fn bridge_messages_and_commands_are_ignored() {
+    for (topic, change) in replay(false) {
+        let ignored = topic.ends_with("/set")
+            || (topic.starts_with("zigbee2mqtt/bridge/")
+                && topic != "zigbee2mqtt/bridge/response/device/remove");
+        if ignored {
+            assert!(change.is_none(), "{topic} should be ignored");
+        }
+    }
+}
+
+#[test]
+fn devices_are_nodes_named_by_topic() {
+    let changes = replay(false);
+
+    let (id, labels, available) = node(changes[3].1.as_ref().unwrap());
+    assert_eq!(id, "kitchen_sensor");
+    assert_eq!(labels, vec!["EndDevice"]);
+    assert_eq!(available, Some(true));
+
+    let (id, labels, available) = node(changes[5].1.as_ref().unwrap());
+    assert_eq!(id, "hall_plug");
+    assert_eq!(labels, vec!["ZigbeeDevice"]);
+    assert_eq!(available, Some(true));
+}
+
+#[test]
+fn offline_keeps_the_device_state() {
+    let changes = replay(false);
+    let change = changes[7].1.as_ref().unwrap();
+    let (id, labels, available) = node(change);
+    assert_eq!(id, "kitchen_sensor");
+    assert_eq!(labels, vec!["EndDevice"]);
+    assert_eq!(available, Some(false));
+
+    let (SourceChange::Insert { element } | SourceChange::Update { element }) = change else {
+        unreachable!();
+    };
+    assert!(element.get_properties().get("temperature").is_some());
+}
+
+#[test]
+fn offline_deletes_with_flag() {
+    let changes = replay(true);
+    let Some(SourceChange::Delete { metadata }) = &changes[7].1 else {
+        panic!("Expected a delete, got {:?}", changes[7].1);
+    };
+    assert_eq!(metadata.reference.element_id.as_ref(), "kitchen_sensor");
+}
+
+#[test]
+fn removed_devices_are_deleted() {
+    let changes = replay(false);
+    let Some(SourceChange::Delete { metadata }) = &changes[9].1 else {
+        panic!("Expected a delete, got {:?}", changes[9].1);
+    };
+    assert_eq!(metadata.reference.element_id.as_ref(), "hall_plug");
+
+    // A failed remove deletes nothing.
+    assert!(changes[10].1.is_none());
+}
@@ -0,0 +1,105 @@
+// Copyright 2025 The Drasi Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command/ack round trip with correlation ids.
+//!
+//! Devices report their state on `devices/{id}/state` and answer commands on
+//! `devices/{id}/ack`, echoing the command's `cid`. The reaction sends a
+//! reboot command, stamped with a fresh correlation id, to every device
+//! reporting an error; the ack source copies the echoed `cid` into the
+//! `correlation_id` property of its `CommandAck` nodes, so queries can tell
+//! which command an ack answers.
+
+use anyhow::Result;
+use drasi_lib::{DrasiLib, Query};
+use drasi_reaction_mqtt::{MqttReaction, MqttReactionConfig};
+use drasi_source_mqtt::config::OperationMode;
+use drasi_source_mqtt::{MqttSource, MqttSourceConfig};
+use log::info;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+
+    info!("Starting command/ack example...");
+
+    // 1. Device state, one node per device
+    let state_config = MqttSourceConfig::builder("device-state", "localhost", "devices/+/state")
+        .client_id("drasi-command-ack-state")
+        .node_label("Device")
+        .id_field("device_id")
+        .mode(OperationMode::Update)
+        .build();
+    let state_source = MqttSource::new(state_config)?;
+
+    // 2. Command acks, carrying the correlation id of the command they answer
+    let ack_config = MqttSourceConfig::builder("command-acks", "localhost", "devices/+/ack")
+        .client_id("drasi-command-ack-acks")
+        .node_label("CommandAck")
+        .id_field("cid")
+        .correlation_field("cid")
+        .build();
+    let ack_source = MqttSource::new(ack_config)?;
+
+    // 3. Commands, each stamped with a new correlation id
+    let command_config = MqttReactionConfig::builder(
+        "reboot-commands",
+        "localhost",
+        "devices/{{device_id}}/command",
+        vec!["failed-devices".to_string()],
+    )
+    .client_id("drasi-command-ack-commands")
+    .correlation_ids(true)
+    .payload_template(r#"{"command": "reboot", "cid": "{{correlation_id}}"}"#)
+    .build();
+    let command_reaction = MqttReaction::new(command_config);
+
+    // 4. Acks, republished with their correlation id for downstream tracking
+    let ack_report_config = MqttReactionConfig::builder(
+        "ack-report",
+        "localhost",
+        "commands/acked",
+        vec!["acked-commands".to_string()],
+    )
+    .client_id("drasi-command-ack-report")
+    .build();
+    let ack_report = MqttReaction::new(ack_report_config);
+
+    let failed_devices = Query::cypher("failed-devices")
+        .query("MATCH (d:Device) WHERE d.status = 'error' RETURN d.device_id AS device_id")
+        .from_source("device-state")
+        .build();
+    let acked_commands = Query::cypher("acked-commands")
+        .query("MATCH (a:CommandAck) RETURN a.correlation_id AS correlation_id, a.result AS result")
+        .from_source("command-acks")
+        .build();
+
+    let core = DrasiLib::builder()
+        .with_id("command-ack-core")
+        .with_source(state_source)
+        .with_source(ack_source)
+        .with_reaction(command_reaction)
+        .with_reaction(ack_report)
+        .with_query(failed_devices)
+        .with_query(acked_commands)
+        .build()
+        .await?;
+
+    info!("DrasiLib configured successfully. Starting...");
+    core.start().await?;
+
+    tokio::signal::ctrl_c().await?;
+    info!("Shutdown signal received");
+    Ok(())
+}
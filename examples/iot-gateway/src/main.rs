@@ -16,8 +16,9 @@ use anyhow::Result;
 use drasi_lib::{DrasiLib, Query};
 use drasi_reaction_mqtt::{MqttReaction, MqttReactionConfig};
 use drasi_source_mqtt::config::OperationMode;
-use drasi_source_mqtt::{MqttSource, MqttSourceConfig};
+use drasi_source_mqtt::{MqttConnectionManager, MqttSource, MqttSourceConfig};
 use log::info;
+use rumqttc::MqttOptions;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -25,17 +26,19 @@ async fn main() -> Result<()> {
 
     info!("Starting IoT Gateway Drasi Example...");
 
+    // 0. Open one broker session for both the source and the reaction
+    let connection =
+        MqttConnectionManager::new(MqttOptions::new("drasi-iot-gateway", "localhost", 1883));
+
     // 1. Configure the MQTT Source
     // Subscribes to 'sensors/#' and maps JSON payloads to 'SensorReading' nodes
     let source_config = MqttSourceConfig::builder("mqtt-src", "localhost", "sensors/#")
-        .port(1883)
-        .client_id("drasi-iot-gateway-source")
         .node_label("SensorReading")
         .id_field("device_id")
         .mode(OperationMode::Update)
         .build();
 
-    let source = MqttSource::new(source_config)?;
+    let source = MqttSource::with_connection(source_config, connection.clone())?;
 
     // 2. Configure the MQTT Reaction
     // Publishes results of 'high-temp-alert' to 'alerts/high-temp'
@@ -45,12 +48,10 @@ async fn main() -> Result<()> {
         "alerts/high-temp",
         vec!["high-temp-alert".to_string()],
     )
-    .port(1883)
-    .client_id("drasi-iot-gateway-reaction")
     .payload_template(r#"{"command": "shutdown", "reason": "{{temp}}"}"#)
     .build();
 
-    let reaction = MqttReaction::new(reaction_config);
+    let reaction = MqttReaction::with_connection(reaction_config, connection.clone());
 
     // 3. Define the Continuous Query
     // Detects SensorReadings where temperature > 30 and no existing alert is active